@@ -1,7 +1,7 @@
 #[macro_use]
 extern crate quote;
 
-use darling::FromDeriveInput;
+use darling::{FromDeriveInput, FromField};
 use proc_macro::TokenStream;
 use syn::parse_macro_input;
 use syn::{Data, DeriveInput, Fields};
@@ -12,6 +12,15 @@ struct Options {
     name: String,
 }
 
+#[derive(FromField)]
+#[darling(attributes(lisp))]
+struct FieldOptions {
+    // Trailing fields marked as default may be omitted, which lets configs stay compatible when
+    // new fields are added
+    #[darling(default)]
+    default: bool,
+}
+
 #[proc_macro_derive(LispType, attributes(lisp))]
 pub fn derive_trait(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -19,14 +28,33 @@ pub fn derive_trait(input: TokenStream) -> TokenStream {
     let name = input.ident;
 
     let n_fields;
+    let n_required_fields;
     let fields = match input.data {
         Data::Struct(data) => match data.fields {
             Fields::Named(fields) => {
                 n_fields = fields.named.len();
-                let recurse = fields.named.iter().map(|field| {
+                n_required_fields = fields
+                    .named
+                    .iter()
+                    .rposition(|field| {
+                        !FieldOptions::from_field(field)
+                            .expect("Wrong field options")
+                            .default
+                    })
+                    .map_or(0, |i| i + 1);
+                let recurse = fields.named.iter().enumerate().map(|(i, field)| {
                     let name = &field.ident;
-                    quote! {
-                        #name: ::lisp::evaluate(it.next().unwrap(), state)?.to_native()?
+                    if i < n_required_fields {
+                        quote! {
+                            #name: ::lisp::evaluate(it.next().unwrap(), state)?.to_native()?
+                        }
+                    } else {
+                        quote! {
+                            #name: match it.next() {
+                                Some(param) => ::lisp::evaluate(param, state)?.to_native()?,
+                                None => ::std::default::Default::default(),
+                            }
+                        }
                     }
                 });
                 quote! {
@@ -48,11 +76,13 @@ pub fn derive_trait(input: TokenStream) -> TokenStream {
 
     let expanded = quote! {
         fn #cast_fn_name(call: ::lisp::term::CallTerm, state: &::lisp::State) -> ::std::result::Result<::lisp::TypedRef, ::lisp::Error> {
-            if call.params.len() == #n_fields {
+            if (#n_required_fields..=#n_fields).contains(&call.params.len()) {
                 let mut it = call.params.into_iter();
                 Ok(::lisp::TypedRef::new(#name { #fields }))
-            } else {
+            } else if #n_required_fields == #n_fields {
                 Err(::lisp::Error{message: format!("Expected {} arguments to ({} ...)", #n_fields, #lisp_name)})
+            } else {
+                Err(::lisp::Error{message: format!("Expected {} to {} arguments to ({} ...)", #n_required_fields, #n_fields, #lisp_name)})
             }
         }
 
@@ -168,13 +168,16 @@ int main() {
                                     [5, 0],  # cpu_time
                                     256 * 1024 * 1024  # memory
                                 ]
-                            }
+                            },
+                            [],  # group_time_budgets
+                            None,  # dialect
+                            None  # allowed_dialects
                         ]
                     }))
 
                     i += 1
             elif "NotifyCompilationStatus" in message:
-                submission_id, result = message["NotifyCompilationStatus"]
+                submission_id, result, diagnostics = message["NotifyCompilationStatus"]
 
                 core = int(submission_id.split("-")[-1])
 
@@ -182,7 +185,8 @@ int main() {
                     "PushToJudgementQueue": [
                         core,  # core
                         submission_id,  # submission_id
-                        list(range(1, 1001)),  # tests
+                        [[1, 1000]],  # tests
+                        [],  # speculative_tests
                     ]
                 }))
             elif "RequestFile" in message:
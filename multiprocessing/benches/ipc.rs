@@ -0,0 +1,49 @@
+use multiprocessing::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+#[multiprocessing::entrypoint]
+fn receive_all(rx: Receiver<Vec<u8>>) -> usize {
+    rx.map(|chunk| chunk.unwrap().len()).sum()
+}
+
+fn measure_once(size: usize, count: usize) -> Duration {
+    let payload = vec![0u8; size];
+    let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+    let mut child = receive_all.spawn(rx).unwrap();
+    let start = Instant::now();
+    for _ in 0..count {
+        tx.send(&payload).unwrap();
+    }
+    drop(tx);
+    assert_eq!(child.join().expect("receive_all failed"), size * count);
+    start.elapsed()
+}
+
+// Child startup and page faults make single runs noisy
+fn measure(size: usize, count: usize) -> Duration {
+    (0..5).map(|_| measure_once(size, count)).min().unwrap()
+}
+
+#[multiprocessing::main]
+fn main() {
+    for (size, count) in [
+        (256 << 10, 400),
+        (1 << 20, 100),
+        (4 << 20, 25),
+        (16 << 20, 10),
+        (64 << 20, 3),
+    ] {
+        multiprocessing::set_bulk_threshold(usize::MAX);
+        let framed = measure(size, count);
+        multiprocessing::set_bulk_threshold(0);
+        let bulk = measure(size, count);
+        println!(
+            "{:>5} KiB x {:>3}: framed {:>8.1?}, bulk {:>8.1?}, speedup {:.2}x",
+            size >> 10,
+            count,
+            framed,
+            bulk,
+            framed.as_secs_f64() / bulk.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use multiprocessing::SpawnOptions;
+use std::time::{Duration, Instant};
+
+#[multiprocessing::entrypoint]
+fn noop() {}
+
+fn measure_once(options: &SpawnOptions, count: usize) -> Duration {
+    let start = Instant::now();
+    for _ in 0..count {
+        unsafe { noop.spawn_with_options(options) }
+            .unwrap()
+            .join()
+            .expect("noop failed");
+    }
+    start.elapsed() / count as u32
+}
+
+fn measure(options: &SpawnOptions, count: usize) -> Duration {
+    (0..5).map(|_| measure_once(options, count)).min().unwrap()
+}
+
+#[multiprocessing::main]
+fn main() {
+    // fork has to copy the page tables, so its cost grows with the memory of the parent, which is
+    // simulated by a touched allocation
+    for size in [0usize, 64 << 20, 512 << 20, 2 << 30] {
+        let ballast = vec![1u8; size];
+        let fork = measure(&SpawnOptions::new(), 50);
+        let vfork = measure(&SpawnOptions::new().vfork(), 50);
+        println!(
+            "{:>5} MiB resident: fork {:>8.1?}, vfork {:>8.1?}, speedup {:.2}x",
+            size >> 20,
+            fork,
+            vfork,
+            fork.as_secs_f64() / vfork.as_secs_f64()
+        );
+        drop(ballast);
+    }
+}
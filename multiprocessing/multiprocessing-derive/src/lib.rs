@@ -1,15 +1,47 @@
 #[macro_use]
 extern crate quote;
 
+use darling::FromField;
 use proc_macro::TokenStream;
 use quote::ToTokens;
 use syn::parse_macro_input;
 use syn::DeriveInput;
 
+// Streaming entrypoints return impl Iterator<Item = T> or impl Stream<Item = T>
+fn stream_item_type(ty: &syn::Type) -> &syn::Type {
+    if let syn::Type::ImplTrait(ref impl_trait) = ty {
+        for bound in impl_trait.bounds.iter() {
+            if let syn::TypeParamBound::Trait(ref bound) = bound {
+                if let Some(segment) = bound.path.segments.last() {
+                    if let syn::PathArguments::AngleBracketed(ref args) = segment.arguments {
+                        for arg in args.args.iter() {
+                            if let syn::GenericArgument::Binding(ref binding) = arg {
+                                if binding.ident == "Item" {
+                                    return &binding.ty;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    panic!(
+        "#[entrypoint(stream)] functions must return impl Iterator<Item = T> or impl Stream<Item \
+         = T>"
+    );
+}
+
 #[proc_macro_attribute]
-pub fn entrypoint(_meta: TokenStream, input: TokenStream) -> TokenStream {
+pub fn entrypoint(meta: TokenStream, input: TokenStream) -> TokenStream {
     let mut input = parse_macro_input!(input as syn::ItemFn);
 
+    let streaming = match meta.to_string().as_str() {
+        "" => false,
+        "stream" => true,
+        meta => panic!("Unknown entrypoint option {meta}"),
+    };
+
     let tokio_attr_index = input.attrs.iter().position(|attr| {
         let path = &attr.path;
         (quote! {#path}).to_string().contains("tokio :: main")
@@ -21,6 +53,22 @@ pub fn entrypoint(_meta: TokenStream, input: TokenStream) -> TokenStream {
         syn::ReturnType::Type(_, ref ty) => quote! { #ty },
     };
 
+    // The type of values sent to the parent, and the type the function is type-erased to so that
+    // it can be boxed
+    let (output_type, call_output) = match input.sig.output {
+        syn::ReturnType::Type(_, ref ty) if streaming => {
+            let item = stream_item_type(ty);
+            let call_output = if tokio_attr.is_some() {
+                quote! { ::std::pin::Pin<::std::boxed::Box<dyn ::multiprocessing::imp::Stream<Item = #item>>> }
+            } else {
+                quote! { ::std::boxed::Box<dyn ::std::iter::Iterator<Item = #item>> }
+            };
+            (quote! { #item }, call_output)
+        }
+        _ if streaming => panic!("#[entrypoint(stream)] functions must return a value"),
+        _ => (return_type.clone(), return_type.clone()),
+    };
+
     let generic_params = &input.sig.generics;
     let generics = {
         let params: Vec<_> = input
@@ -134,15 +182,42 @@ pub fn entrypoint(_meta: TokenStream, input: TokenStream) -> TokenStream {
     let entrypoint;
 
     if let Some(tokio_attr) = tokio_attr {
+        let send_output;
+        let call;
+        if streaming {
+            send_output = quote! {
+                let mut stream = self.func.deserialize()().await;
+                while let Some(item) = ::multiprocessing::imp::StreamExt::next(&mut stream).await {
+                    output_tx.send(&item)
+                        .await
+                        .expect("Failed to send subprocess output");
+                }
+            };
+            call = quote! {
+                Box::pin(async move {
+                    let stream: #call_output = Box::pin(#type_ident::call(#(#args_from_tuple,)*).await);
+                    stream
+                })
+            };
+        } else {
+            send_output = quote! {
+                output_tx.send(&self.func.deserialize()().await)
+                    .await
+                    .expect("Failed to send subprocess output");
+            };
+            call = quote! {
+                Box::pin(#type_ident::call(#(#args_from_tuple,)*))
+            };
+        }
         entrypoint = quote! {
             #[derive(::multiprocessing::Object)]
             struct #entry_ident #generic_params {
-                func: ::multiprocessing::Delayed<::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #return_type>>>>>>,
+                func: ::multiprocessing::Delayed<::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #call_output>>>>>>,
                 #(#generic_phantom,)*
             }
 
             impl #generic_params #entry_ident #generics {
-                fn new(func: ::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #return_type>>>>>) -> Self {
+                fn new(func: ::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #call_output>>>>>) -> Self {
                     Self {
                         func: ::multiprocessing::Delayed::new(func),
                         #(#generic_phantom_build,)*
@@ -157,32 +232,51 @@ pub fn entrypoint(_meta: TokenStream, input: TokenStream) -> TokenStream {
                     let output_tx_fd = args.0;
                     use ::std::os::unix::io::FromRawFd;
                     let mut output_tx = unsafe {
-                        ::multiprocessing::tokio::Sender::<#return_type>::from_raw_fd(output_tx_fd)
+                        ::multiprocessing::tokio::Sender::<#output_type>::from_raw_fd(output_tx_fd)
                     };
-                    output_tx.send(&self.func.deserialize()().await)
-                        .await
-                        .expect("Failed to send subprocess output");
+                    #send_output
                     0
                 }
             }
 
             impl #generic_params ::multiprocessing::Entrypoint<(#(#fn_types,)*)> for #type_ident {
-                type Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #return_type>>>;
+                type Output = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #call_output>>>;
                 fn call(self, args: (#(#fn_types,)*)) -> Self::Output {
-                    Box::pin(#type_ident::call(#(#args_from_tuple,)*))
+                    #call
                 }
             }
         };
     } else {
+        let send_output;
+        let call;
+        if streaming {
+            send_output = quote! {
+                for item in self.func.deserialize()() {
+                    output_tx.send(&item)
+                        .expect("Failed to send subprocess output");
+                }
+            };
+            call = quote! {
+                Box::new(#type_ident::call(#(#args_from_tuple,)*))
+            };
+        } else {
+            send_output = quote! {
+                output_tx.send(&self.func.deserialize()())
+                    .expect("Failed to send subprocess output");
+            };
+            call = quote! {
+                #type_ident::call(#(#args_from_tuple,)*)
+            };
+        }
         entrypoint = quote! {
             #[derive(::multiprocessing::Object)]
             struct #entry_ident #generic_params {
-                func: ::multiprocessing::Delayed<::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = #return_type>>>,
+                func: ::multiprocessing::Delayed<::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = #call_output>>>,
                 #(#generic_phantom,)*
             }
 
             impl #generic_params #entry_ident #generics {
-                fn new(func: ::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = #return_type>>) -> Self {
+                fn new(func: ::std::boxed::Box<dyn ::multiprocessing::FnOnce<(), Output = #call_output>>) -> Self {
                     Self {
                         func: ::multiprocessing::Delayed::new(func),
                         #(#generic_phantom_build,)*
@@ -196,51 +290,90 @@ pub fn entrypoint(_meta: TokenStream, input: TokenStream) -> TokenStream {
                     let output_tx_fd = args.0;
                     use ::std::os::unix::io::FromRawFd;
                     let mut output_tx = unsafe {
-                        ::multiprocessing::Sender::<#return_type>::from_raw_fd(output_tx_fd)
+                        ::multiprocessing::Sender::<#output_type>::from_raw_fd(output_tx_fd)
                     };
-                    output_tx.send(&self.func.deserialize()())
-                        .expect("Failed to send subprocess output");
+                    #send_output
                     0
                 }
             }
 
             impl #generic_params ::multiprocessing::Entrypoint<(#(#fn_types,)*)> for #type_ident {
-                type Output = #return_type;
+                type Output = #call_output;
                 fn call(self, args: (#(#fn_types,)*)) -> Self::Output {
-                    #type_ident::call(#(#args_from_tuple,)*)
+                    #call
                 }
             }
         };
     }
 
+    let child_type;
+    let tokio_child_type;
+    let into_child;
+    let detached;
+    if streaming {
+        child_type = quote! { ::multiprocessing::StreamingChild<#output_type> };
+        tokio_child_type = quote! { ::multiprocessing::tokio::StreamingChild<#output_type> };
+        into_child = quote! { .map(::std::convert::From::from) };
+        // Nobody would read the values, so the child would block once the socket buffer fills up
+        detached = quote! {};
+    } else {
+        child_type = quote! { ::multiprocessing::Child<#output_type> };
+        tokio_child_type = quote! { ::multiprocessing::tokio::Child<#output_type> };
+        into_child = quote! {};
+        detached = quote! {
+            pub unsafe fn spawn_detached_with_options #generic_params(&self, options: &::multiprocessing::SpawnOptions, #(#fn_args,)*) -> ::multiprocessing::Result<::multiprocessing::DetachedChild> {
+                use ::multiprocessing::Bind;
+                ::multiprocessing::spawn_detached(Box::new(::multiprocessing::EntrypointWrapper::<#entry_ident #generics>(#entry_ident::new(Box::new(#bound)))), options)
+            }
+
+            pub unsafe fn spawn_detached_with_flags #generic_params(&self, flags: ::multiprocessing::libc::c_int, #(#fn_args,)*) -> ::multiprocessing::Result<::multiprocessing::DetachedChild> {
+                self.spawn_detached_with_options(&::multiprocessing::SpawnOptions::new().flags(flags), #(#arg_names,)*)
+            }
+
+            pub fn spawn_detached #generic_params(&self, #(#fn_args,)*) -> ::multiprocessing::Result<::multiprocessing::DetachedChild> {
+                unsafe { self.spawn_detached_with_flags(0, #(#arg_names,)*) }
+            }
+        };
+    }
+
     let expanded = quote! {
         #entrypoint
 
         #[allow(non_camel_case_types)]
-        #[derive(::multiprocessing::Object)]
+        #[derive(::multiprocessing::Object, Clone, Copy)]
         struct #type_ident;
 
         impl #type_ident {
             #[link_name = #link_name]
             #input
 
-            pub unsafe fn spawn_with_flags #generic_params(&self, flags: ::multiprocessing::libc::c_int, #(#fn_args,)*) -> ::std::io::Result<::multiprocessing::Child<#return_type>> {
+            pub unsafe fn spawn_with_options #generic_params(&self, options: &::multiprocessing::SpawnOptions, #(#fn_args,)*) -> ::multiprocessing::Result<#child_type> {
                 use ::multiprocessing::Bind;
-                ::multiprocessing::spawn(Box::new(::multiprocessing::EntrypointWrapper::<#entry_ident #generics>(#entry_ident::new(Box::new(#bound)))), flags)
+                ::multiprocessing::spawn(Box::new(::multiprocessing::EntrypointWrapper::<#entry_ident #generics>(#entry_ident::new(Box::new(#bound)))), options)#into_child
             }
 
-            pub async unsafe fn spawn_with_flags_tokio #generic_params(&self, flags: ::multiprocessing::libc::c_int, #(#fn_args,)*) -> ::std::io::Result<::multiprocessing::tokio::Child<#return_type>> {
+            pub async unsafe fn spawn_with_options_tokio #generic_params(&self, options: &::multiprocessing::SpawnOptions, #(#fn_args,)*) -> ::multiprocessing::Result<#tokio_child_type> {
                 use ::multiprocessing::Bind;
-                ::multiprocessing::tokio::spawn(Box::new(::multiprocessing::EntrypointWrapper::<#entry_ident #generics>(#entry_ident::new(Box::new(#bound)))), flags).await
+                ::multiprocessing::tokio::spawn(Box::new(::multiprocessing::EntrypointWrapper::<#entry_ident #generics>(#entry_ident::new(Box::new(#bound)))), options).await #into_child
+            }
+
+            pub unsafe fn spawn_with_flags #generic_params(&self, flags: ::multiprocessing::libc::c_int, #(#fn_args,)*) -> ::multiprocessing::Result<#child_type> {
+                self.spawn_with_options(&::multiprocessing::SpawnOptions::new().flags(flags), #(#arg_names,)*)
             }
 
-            pub fn spawn #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::multiprocessing::Child<#return_type>> {
+            pub async unsafe fn spawn_with_flags_tokio #generic_params(&self, flags: ::multiprocessing::libc::c_int, #(#fn_args,)*) -> ::multiprocessing::Result<#tokio_child_type> {
+                self.spawn_with_options_tokio(&::multiprocessing::SpawnOptions::new().flags(flags), #(#arg_names,)*).await
+            }
+
+            pub fn spawn #generic_params(&self, #(#fn_args,)*) -> ::multiprocessing::Result<#child_type> {
                 unsafe { self.spawn_with_flags(0, #(#arg_names,)*) }
             }
 
-            pub async fn spawn_tokio #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::multiprocessing::tokio::Child<#return_type>> {
+            pub async fn spawn_tokio #generic_params(&self, #(#fn_args,)*) -> ::multiprocessing::Result<#tokio_child_type> {
                 unsafe { self.spawn_with_flags_tokio(0, #(#arg_names,)*) }.await
             }
+
+            #detached
         }
 
         #[allow(non_upper_case_globals)]
@@ -276,7 +409,175 @@ pub fn main(_meta: TokenStream, input: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
-#[proc_macro_derive(Object)]
+// Applied to a trait, makes Box<dyn Trait> an Object that is transferred by type name rather than
+// by vtable. Applied to an implementation of such a trait for a concrete type, registers the type so
+// that it can be received.
+#[proc_macro_attribute]
+pub fn portable_trait(_meta: TokenStream, input: TokenStream) -> TokenStream {
+    match parse_macro_input!(input as syn::Item) {
+        syn::Item::Trait(trait_) => portable_trait_definition(trait_),
+        syn::Item::Impl(impl_) => portable_trait_implementation(impl_),
+        _ => panic!("#[portable_trait] can only be applied to traits and their implementations"),
+    }
+}
+
+fn portable_trait_definition(mut trait_: syn::ItemTrait) -> TokenStream {
+    if !trait_.generics.params.is_empty() {
+        panic!("#[portable_trait] does not support generic traits");
+    }
+
+    let ident = &trait_.ident;
+
+    // Box<dyn Trait> has to be Send + Sync to be an Object
+    if trait_.colon_token.is_none() {
+        trait_.colon_token = Some(Default::default());
+    }
+    trait_
+        .supertraits
+        .push(syn::parse_quote! { ::std::marker::Send });
+    trait_
+        .supertraits
+        .push(syn::parse_quote! { ::std::marker::Sync });
+
+    trait_.items.push(syn::parse_quote! {
+        #[doc(hidden)]
+        fn multiprocessing_portable_key(&self) -> &'static str;
+    });
+    trait_.items.push(syn::parse_quote! {
+        #[doc(hidden)]
+        fn multiprocessing_portable_serialize(&self, s: &mut ::multiprocessing::Serializer);
+    });
+
+    let expanded = quote! {
+        #trait_
+
+        impl ::multiprocessing::portable::PortableTrait for dyn #ident {
+            fn registry() -> &'static ::multiprocessing::portable::Registry<Self> {
+                ::multiprocessing::imp::lazy_static! {
+                    static ref REGISTRY: ::multiprocessing::portable::Registry<dyn #ident> =
+                        ::std::default::Default::default();
+                }
+                &REGISTRY
+            }
+        }
+
+        impl ::multiprocessing::Serialize for ::std::boxed::Box<dyn #ident> {
+            fn serialize_self(&self, s: &mut ::multiprocessing::Serializer) {
+                s.serialize(&self.multiprocessing_portable_key().to_string());
+                self.multiprocessing_portable_serialize(s);
+            }
+        }
+        impl ::multiprocessing::Deserialize for ::std::boxed::Box<dyn #ident> {
+            fn deserialize_self(d: &mut ::multiprocessing::Deserializer) -> Self {
+                let key: ::std::string::String = d.deserialize();
+                <dyn #ident as ::multiprocessing::portable::PortableTrait>::registry()
+                    .deserialize(&key, d)
+            }
+        }
+        impl<'serde> ::multiprocessing::DeserializeBoxed<'serde> for ::std::boxed::Box<dyn #ident> {
+            unsafe fn deserialize_on_heap(&self, d: &mut ::multiprocessing::Deserializer) -> ::std::boxed::Box<dyn ::multiprocessing::DeserializeBoxed<'serde> + 'serde> {
+                use ::multiprocessing::Deserialize;
+                ::std::boxed::Box::new(Self::deserialize_self(d))
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+fn portable_trait_implementation(mut impl_: syn::ItemImpl) -> TokenStream {
+    if !impl_.generics.params.is_empty() {
+        panic!(
+            "#[portable_trait] cannot register generic implementations; implement the trait for \
+             concrete types instead"
+        );
+    }
+
+    let trait_path = impl_
+        .trait_
+        .as_ref()
+        .expect("#[portable_trait] must be applied to a trait implementation")
+        .1
+        .clone();
+    let self_ty = impl_.self_ty.clone();
+
+    // The key only depends on the source code, so it survives rebuilds
+    let key = quote! { ::std::concat!(::std::module_path!(), "::", ::std::stringify!(#self_ty)) };
+
+    impl_.items.push(syn::parse_quote! {
+        fn multiprocessing_portable_key(&self) -> &'static str {
+            #key
+        }
+    });
+    impl_.items.push(syn::parse_quote! {
+        fn multiprocessing_portable_serialize(&self, s: &mut ::multiprocessing::Serializer) {
+            s.serialize(self);
+        }
+    });
+
+    let expanded = quote! {
+        #impl_
+
+        const _: () = {
+            #[::multiprocessing::imp::ctor]
+            fn multiprocessing_register_portable_type() {
+                <dyn #trait_path as ::multiprocessing::portable::PortableTrait>::registry().register(
+                    #key,
+                    |d| ::std::boxed::Box::new(d.deserialize::<#self_ty>()),
+                );
+            }
+        };
+    };
+
+    TokenStream::from(expanded)
+}
+
+#[derive(FromField)]
+#[darling(attributes(object))]
+struct FieldOptions {
+    // The field is not transferred and is reconstructed via Default::default() on the other side
+    #[darling(default)]
+    skip: bool,
+    // The path to a module with functions
+    //     fn serialize(value: &T, s: &mut Serializer);
+    //     fn deserialize(d: &mut Deserializer) -> T;
+    // to be used instead of the Object implementation of the field type
+    #[darling(default)]
+    with: Option<syn::Path>,
+}
+
+impl FieldOptions {
+    fn of(field: &syn::Field) -> Self {
+        let options = Self::from_field(field).expect("Wrong options");
+        if options.skip && options.with.is_some() {
+            panic!("#[object(skip)] and #[object(with = ...)] cannot be used together");
+        }
+        options
+    }
+
+    // value is an expression of type &T
+    fn serialize(&self, value: impl ToTokens) -> impl ToTokens {
+        if self.skip {
+            quote! { let _ = #value; }
+        } else if let Some(ref with) = self.with {
+            quote! { #with::serialize(#value, s); }
+        } else {
+            quote! { s.serialize(#value); }
+        }
+    }
+
+    fn deserialize(&self) -> impl ToTokens {
+        if self.skip {
+            quote! { ::std::default::Default::default() }
+        } else if let Some(ref with) = self.with {
+            quote! { #with::deserialize(d) }
+        } else {
+            quote! { d.deserialize() }
+        }
+    }
+}
+
+#[proc_macro_derive(Object, attributes(object))]
 pub fn derive_object(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
@@ -336,14 +637,13 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
             syn::Fields::Named(fields) => {
                 let serialize_fields = fields.named.iter().map(|field| {
                     let ident = &field.ident;
-                    quote! {
-                        s.serialize(&self.#ident);
-                    }
+                    FieldOptions::of(field).serialize(quote! { &self.#ident })
                 });
                 let deserialize_fields = fields.named.iter().map(|field| {
                     let ident = &field.ident;
+                    let value = FieldOptions::of(field).deserialize();
                     quote! {
-                        #ident: d.deserialize(),
+                        #ident: #value,
                     }
                 });
                 quote! {
@@ -368,15 +668,14 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                 }
             }
             syn::Fields::Unnamed(fields) => {
-                let serialize_fields = fields.unnamed.iter().enumerate().map(|(i, _)| {
+                let serialize_fields = fields.unnamed.iter().enumerate().map(|(i, field)| {
                     let i = syn::Index::from(i);
-                    quote! {
-                        s.serialize(&self.#i);
-                    }
+                    FieldOptions::of(field).serialize(quote! { &self.#i })
                 });
-                let deserialize_fields = fields.unnamed.iter().map(|_| {
+                let deserialize_fields = fields.unnamed.iter().map(|field| {
+                    let value = FieldOptions::of(field).deserialize();
                     quote! {
-                        d.deserialize(),
+                        #value,
                     }
                 });
                 quote! {
@@ -427,9 +726,10 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                     .fields
                     .iter()
                     .enumerate()
-                    .map(|(i, _)| {
+                    .map(|(i, field)| {
                         let ident = format_ident!("a{}", i);
-                        (quote! { ref #ident }, quote! { s.serialize(#ident); })
+                        let ser = FieldOptions::of(field).serialize(&ident);
+                        (quote! { ref #ident }, quote! { #ser })
                     })
                     .unzip();
                 let fields = if variant.fields.is_empty() {
@@ -453,10 +753,7 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                     let des: Vec<_> = variant
                         .fields
                         .iter()
-                        .map(|attr| {
-                            let ident = &attr.ident;
-                            quote! { d.deserialize(#ident) }
-                        })
+                        .map(|field| FieldOptions::of(field).deserialize())
                         .collect();
                     quote! { #i => Self::#ident(#(#des,)*) }
                 }
@@ -0,0 +1,181 @@
+use crate::{Deserialize, Deserializer, Receiver, Result, Sender, Serialize, Serializer};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Sending a lot of small messages one by one costs a syscall per message on both sides. A batched
+// sender serializes the messages into a single buffer as they arrive and sends the buffer as one
+// message when it grows past max_bytes, or when a message arrives more than max_delay after the
+// first message of the batch. There is no background timer, so a batch that is not followed by
+// another message stays in the buffer until flush() is called or the sender is dropped. deadline()
+// tells when that should happen.
+//
+// Both sides of the channel have to be batched, as the batch has its own wire format.
+
+// On the wire, every message is preceded by true, and the batch is terminated by false
+pub struct Batch<T> {
+    pub(crate) values: Vec<T>,
+}
+
+impl<T: Serialize> Serialize for Batch<T> {
+    fn serialize_self(&self, s: &mut Serializer) {
+        for value in &self.values {
+            s.serialize(&true);
+            s.serialize(value);
+        }
+        s.serialize(&false);
+    }
+}
+
+impl<T: Deserialize> Deserialize for Batch<T> {
+    fn deserialize_self(d: &mut Deserializer) -> Self {
+        let mut values = Vec::new();
+        while d.deserialize::<bool>() {
+            values.push(d.deserialize());
+        }
+        Batch { values }
+    }
+}
+
+// The part shared by the synchronous and the tokio senders
+pub(crate) struct Batcher<T: Serialize> {
+    // The messages are kept alive until the batch is sent, as the serializer refers to the file
+    // descriptors they own
+    values: Vec<T>,
+    s: Serializer,
+    started_at: Option<Instant>,
+    max_bytes: usize,
+    max_delay: Duration,
+}
+
+impl<T: Serialize> Batcher<T> {
+    pub(crate) fn new(max_bytes: usize, max_delay: Duration) -> Self {
+        Batcher {
+            values: Vec::new(),
+            s: Serializer::new(),
+            started_at: None,
+            max_bytes,
+            max_delay,
+        }
+    }
+
+    // Returns whether the batch should be sent now
+    pub(crate) fn push(&mut self, value: T) -> bool {
+        let now = Instant::now();
+        let started_at = *self.started_at.get_or_insert(now);
+        self.s.serialize(&true);
+        self.s.serialize(&value);
+        self.values.push(value);
+        self.s.len() >= self.max_bytes
+            || started_at
+                .checked_add(self.max_delay)
+                .map_or(false, |deadline| now >= deadline)
+    }
+
+    // None if there is no pending batch or it is never due
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.started_at?.checked_add(self.max_delay)
+    }
+
+    // Returns the serialized batch along with the messages it refers to, or None if the batch is
+    // empty
+    pub(crate) fn take(&mut self) -> Option<(Serializer, Vec<T>)> {
+        self.started_at.take()?;
+        let mut s = std::mem::replace(&mut self.s, Serializer::new());
+        s.serialize(&false);
+        Some((s, std::mem::take(&mut self.values)))
+    }
+}
+
+pub struct BatchedSender<T: Serialize> {
+    tx: Sender<Batch<T>>,
+    batcher: Batcher<T>,
+}
+
+impl<T: Serialize> BatchedSender<T> {
+    pub(crate) fn new(tx: Sender<Batch<T>>, max_bytes: usize, max_delay: Duration) -> Self {
+        BatchedSender {
+            tx,
+            batcher: Batcher::new(max_bytes, max_delay),
+        }
+    }
+
+    // Unlike Sender::send, this takes the message by value, as it has to outlive the call
+    pub fn send(&mut self, value: T) -> Result<()> {
+        if self.batcher.push(value) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self.batcher.take() {
+            Some((s, _values)) => self.tx.send_serialized(s),
+            None => Ok(()),
+        }
+    }
+
+    // When the pending batch is due, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.batcher.deadline()
+    }
+}
+
+impl<T: Serialize> Drop for BatchedSender<T> {
+    fn drop(&mut self) {
+        // Nobody to report the error to
+        let _ = self.flush();
+    }
+}
+
+pub struct BatchedReceiver<T: Deserialize> {
+    rx: Receiver<Batch<T>>,
+    pending: VecDeque<T>,
+    // See Receiver::next
+    failed: bool,
+}
+
+impl<T: Deserialize> BatchedReceiver<T> {
+    pub(crate) fn new(rx: Receiver<Batch<T>>) -> Self {
+        BatchedReceiver {
+            rx,
+            pending: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    pub fn recv(&mut self) -> Result<Option<T>> {
+        // Empty batches are never sent, but better safe than sorry
+        while self.pending.is_empty() {
+            match self.rx.recv()? {
+                Some(batch) => self.pending.extend(batch.values),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>> {
+        let deadline = Instant::now() + timeout;
+        while self.pending.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout)? {
+                Some(batch) => self.pending.extend(batch.values),
+                None => return Ok(None),
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+impl<T: Deserialize> Iterator for BatchedReceiver<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let item = self.recv().transpose();
+        self.failed = matches!(item, Some(Err(_)));
+        item
+    }
+}
@@ -147,28 +147,40 @@ impl<'a> DeserializeBoxed<'a> for () {
     }
 }
 
-impl<T: Serialize, U: Serialize> Serialize for (T, U) {
-    fn serialize_self(&self, s: &mut Serializer) {
-        s.serialize(&self.0);
-        s.serialize(&self.1);
-    }
-}
-impl<T: Deserialize, U: Deserialize> Deserialize for (T, U) {
-    fn deserialize_self(d: &mut Deserializer) -> Self {
-        let a = d.deserialize();
-        let b = d.deserialize();
-        (a, b)
-    }
-}
-impl<'a, T: 'a + Deserialize, U: 'a + Deserialize> DeserializeBoxed<'a> for (T, U) {
-    unsafe fn deserialize_on_heap(
-        &self,
-        d: &mut Deserializer,
-    ) -> Box<dyn DeserializeBoxed<'a> + 'a> {
-        Box::new(Self::deserialize_self(d))
-    }
+macro_rules! impl_serialize_for_tuple {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: Serialize),+> Serialize for ($($t,)+) {
+            fn serialize_self(&self, s: &mut Serializer) {
+                $(s.serialize(&self.$i);)+
+            }
+        }
+        impl<$($t: Deserialize),+> Deserialize for ($($t,)+) {
+            fn deserialize_self(d: &mut Deserializer) -> Self {
+                // Tuple expressions are evaluated left to right
+                ($(d.deserialize::<$t>(),)+)
+            }
+        }
+        impl<'a, $($t: 'a + Deserialize),+> DeserializeBoxed<'a> for ($($t,)+) {
+            unsafe fn deserialize_on_heap(
+                &self,
+                d: &mut Deserializer,
+            ) -> Box<dyn DeserializeBoxed<'a> + 'a> {
+                Box::new(Self::deserialize_self(d))
+            }
+        }
+    };
 }
 
+// Argument lists of remote functions are tuples, hence the arities
+impl_serialize_for_tuple!(T0 0);
+impl_serialize_for_tuple!(T0 0, T1 1);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2, T3 3);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2, T3 3, T4 4);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2, T3 3, T4 4, T5 5);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2, T3 3, T4 4, T5 5, T6 6);
+impl_serialize_for_tuple!(T0 0, T1 1, T2 2, T3 3, T4 4, T5 5, T6 6, T7 7);
+
 impl<T: Serialize> Serialize for Option<T> {
     fn serialize_self(&self, s: &mut Serializer) {
         match self {
@@ -209,7 +221,7 @@ fn extract_vtable_ptr<T: ?Sized>(metadata: &std::ptr::DynMetadata<T>) -> *const
     unsafe { *(metadata as *const std::ptr::DynMetadata<T> as *const *const ()) }
 }
 
-fn get_base_vtable_ptr() -> *const () {
+pub(crate) fn get_base_vtable_ptr() -> *const () {
     extract_vtable_ptr(&std::ptr::metadata(&BaseType as &dyn BaseTrait))
 }
 
@@ -479,7 +491,7 @@ macro_rules! impl_serialize_for_sequence {
         impl<T: Serialize $(+ $tbound1 $(+ $tbound2)*)* $(, $typaram: $bound1 $(+ $bound2)*,)*> Serialize
             for $ty<T $(, $typaram)*>
         {
-            fn serialize_self(&self, s: &mut Serializer) {
+            default fn serialize_self(&self, s: &mut Serializer) {
                 s.serialize(&self.len());
                 for item in self.iter() {
                     s.serialize(item);
@@ -489,7 +501,7 @@ macro_rules! impl_serialize_for_sequence {
         impl<T: Deserialize $(+ $tbound1 $(+ $tbound2)*)* $(, $typaram: $bound1 $(+ $bound2)*,)*> Deserialize
             for $ty<T $(, $typaram)*>
         {
-            fn deserialize_self(d: &mut Deserializer) -> Self {
+            default fn deserialize_self(d: &mut Deserializer) -> Self {
                 let $size: usize = d.deserialize();
                 let mut $seq = $with_capacity;
                 for _ in 0..$size {
@@ -565,6 +577,24 @@ macro_rules! impl_serialize_for_map {
 }
 
 impl_serialize_for_sequence!(Vec<T>, seq, size, Vec::with_capacity(size), Vec::push);
+
+// Byte buffers, e.g. compiled programs or logs, may be megabytes long, so they are copied at once
+// rather than byte by byte. The format is the same as for other vectors.
+impl Serialize for Vec<u8> {
+    fn serialize_self(&self, s: &mut Serializer) {
+        s.serialize(&self.len());
+        s.write(self);
+    }
+}
+impl Deserialize for Vec<u8> {
+    fn deserialize_self(d: &mut Deserializer) -> Self {
+        let size: usize = d.deserialize();
+        let mut seq = vec![0; size];
+        d.read(&mut seq);
+        seq
+    }
+}
+
 impl_serialize_for_sequence!(
     BinaryHeap<T: Ord>,
     seq,
@@ -698,6 +728,37 @@ impl<'a> DeserializeBoxed<'a> for std::os::unix::net::UnixStream {
     }
 }
 
+macro_rules! impl_serialize_for_std_fd {
+    ($t:ty) => {
+        impl Serialize for $t {
+            fn serialize_self(&self, s: &mut Serializer) {
+                let fd = s.add_fd(self.as_raw_fd());
+                s.serialize(&fd)
+            }
+        }
+        impl Deserialize for $t {
+            fn deserialize_self(d: &mut Deserializer) -> Self {
+                let fd: OwnedFd = d.deserialize();
+                Self::from(fd)
+            }
+        }
+        impl<'a> DeserializeBoxed<'a> for $t {
+            unsafe fn deserialize_on_heap(
+                &self,
+                d: &mut Deserializer,
+            ) -> Box<dyn DeserializeBoxed<'a> + 'a> {
+                Box::new(Self::deserialize_self(d))
+            }
+        }
+    };
+}
+
+impl_serialize_for_std_fd!(std::os::unix::net::UnixListener);
+impl_serialize_for_std_fd!(std::os::unix::net::UnixDatagram);
+impl_serialize_for_std_fd!(std::net::TcpStream);
+impl_serialize_for_std_fd!(std::net::TcpListener);
+impl_serialize_for_std_fd!(std::net::UdpSocket);
+
 impl Serialize for tokio::net::UnixStream {
     fn serialize_self(&self, s: &mut Serializer) {
         let fd = s.add_fd(self.as_raw_fd());
@@ -718,6 +779,26 @@ impl<'a> DeserializeBoxed<'a> for tokio::net::UnixStream {
     }
 }
 
+impl Serialize for tokio::net::TcpStream {
+    fn serialize_self(&self, s: &mut Serializer) {
+        let fd = s.add_fd(self.as_raw_fd());
+        s.serialize(&fd)
+    }
+}
+impl Deserialize for tokio::net::TcpStream {
+    fn deserialize_self(d: &mut Deserializer) -> Self {
+        Self::from_std(d.deserialize()).expect("Failed to deserialize tokio::net::TcpStream")
+    }
+}
+impl<'a> DeserializeBoxed<'a> for tokio::net::TcpStream {
+    unsafe fn deserialize_on_heap(
+        &self,
+        d: &mut Deserializer,
+    ) -> Box<dyn DeserializeBoxed<'a> + 'a> {
+        Box::new(Self::deserialize_self(d))
+    }
+}
+
 impl Serialize for tokio_seqpacket::UnixSeqpacket {
     fn serialize_self(&self, s: &mut Serializer) {
         let fd = s.add_fd(self.as_raw_fd());
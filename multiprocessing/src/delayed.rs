@@ -1,5 +1,5 @@
 use crate::{Deserialize, DeserializeBoxed, Deserializer, Object, Serialize, Serializer};
-use std::os::unix::io::OwnedFd;
+use std::os::unix::io::{AsRawFd, OwnedFd};
 
 pub enum Delayed<T: Object> {
     Serialized(Vec<u8>, Vec<OwnedFd>),
@@ -22,7 +22,16 @@ impl<T: Object> Delayed<T> {
 impl<T: Object> Serialize for Delayed<T> {
     fn serialize_self(&self, s: &mut Serializer) {
         match self {
-            Self::Serialized(_, _) => panic!("Cannot serialize a serialized Delayed value"),
+            // This happens when a value is forwarded further without being looked at, e.g. by the
+            // intermediate process of a detached spawn
+            Self::Serialized(data, fds) => {
+                let fds = fds
+                    .iter()
+                    .map(|fd| s.add_fd(fd.as_raw_fd()))
+                    .collect::<Vec<usize>>();
+                s.serialize(&fds);
+                s.serialize(data);
+            }
             Self::Deserialized(value) => {
                 let mut s1 = Serializer::new();
                 s1.serialize(value);
@@ -0,0 +1,85 @@
+use std::io::ErrorKind;
+
+// Syscall failures are kept as io::Error; everything else multiprocessing can detect on its own gets
+// a variant of its own rather than being squashed into ErrorKind::Other
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    // The peer does not follow the framing protocol, e.g. a message is truncated
+    Protocol(String),
+    // File descriptors could not be sent or received
+    FdPassing(String),
+    // The data cannot be interpreted as the expected type, e.g. because the peer runs a different
+    // executable
+    Serialization(String),
+    // A subprocess could not be started, crashed, or terminated without a result
+    Child(String),
+    Context(String, Box<Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn last_os_error() -> Self {
+        Error::Io(std::io::Error::last_os_error())
+    }
+
+    pub fn context<S: Into<String>>(self, message: S) -> Self {
+        Error::Context(message.into(), Box::new(self))
+    }
+
+    // Mimics io::Error::kind, so that e.g. timeouts can be checked for the same way
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::Context(_, e) => e.kind(),
+            Error::Serialization(_) => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Protocol(message) => write!(f, "Protocol violation: {message}"),
+            Error::FdPassing(message) => write!(f, "Failed to pass file descriptors: {message}"),
+            Error::Serialization(message) => write!(f, "Serialization failure: {message}"),
+            Error::Child(message) => write!(f, "Subprocess failure: {message}"),
+            Error::Context(message, e) => write!(f, "{message}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Context(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(e: nix::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+// For callers that still work with io::Result
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => std::io::Error::new(e.kind(), e),
+        }
+    }
+}
@@ -26,6 +26,19 @@ impl<Args, T: Entrypoint<Args>> std::ops::FnOnce<Args> for EntrypointWrapper<T>
     }
 }
 
+// Entrypoints are stateless, so they can be called repeatedly, e.g. via RemoteFn
+impl<Args, T: Entrypoint<Args> + Copy> std::ops::FnMut<Args> for EntrypointWrapper<T> {
+    extern "rust-call" fn call_mut(&mut self, args: Args) -> Self::Output {
+        self.0.call(args)
+    }
+}
+
+impl<Args, T: Entrypoint<Args> + Copy> std::ops::Fn<Args> for EntrypointWrapper<T> {
+    extern "rust-call" fn call(&self, args: Args) -> Self::Output {
+        self.0.call(args)
+    }
+}
+
 pub trait FnOnce<Args>: std::ops::FnOnce<Args> + TraitObject {}
 pub trait Fn<Args>: std::ops::Fn<Args> + TraitObject {}
 pub trait FnMut<Args>: std::ops::FnMut<Args> + TraitObject {}
@@ -0,0 +1,106 @@
+use crate::{imp, tokio, Receiver, Sender};
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// A child spawned with SpawnOptions::heartbeat gets a hidden channel to the parent and is expected
+// to call heartbeat() at least once per timeout. This lets the parent tell a child that is busy
+// doing legitimate work, e.g. waiting for a slow program, from one that is stuck. Pings are not sent
+// automatically, as a background thread would break unshare(2) and friends, which require the
+// process to be single-threaded.
+
+lazy_static! {
+    static ref HEARTBEAT_TX: Mutex<Option<Sender<Duration>>> = Mutex::new(None);
+}
+
+// Pings carry the time they were sent at, so that the parent does not mistake stale pings it has
+// not read yet for fresh ones. CLOCK_MONOTONIC is shared by all processes.
+fn monotonic_now() -> Duration {
+    let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)
+        .expect("CLOCK_MONOTONIC is unavailable");
+    Duration::new(now.tv_sec() as u64, now.tv_nsec() as u32)
+}
+
+pub(crate) fn install(tx: Sender<Duration>) {
+    // Never block on a full socket buffer: the parent has enough pings queued in that case
+    imp::enable_nonblock(tx.as_raw_fd()).expect("Failed to set O_NONBLOCK on heartbeat channel");
+    *HEARTBEAT_TX
+        .lock()
+        .expect("Failed to acquire heartbeat channel") = Some(tx);
+}
+
+// Tells the parent that the current process is alive. Does nothing if the process was spawned
+// without heartbeats.
+pub fn heartbeat() {
+    if let Some(ref mut tx) = *HEARTBEAT_TX
+        .lock()
+        .expect("Failed to acquire heartbeat channel")
+    {
+        let _ = tx.send(&monotonic_now());
+    }
+}
+
+// The parent side for synchronous children
+pub(crate) struct Monitor {
+    rx: Receiver<Duration>,
+    timeout: Duration,
+    last_beat: Duration,
+}
+
+impl Monitor {
+    pub(crate) fn new(rx: Receiver<Duration>, timeout: Duration) -> std::io::Result<Self> {
+        imp::enable_nonblock(rx.as_raw_fd())?;
+        Ok(Monitor {
+            rx,
+            timeout,
+            last_beat: monotonic_now(),
+        })
+    }
+
+    pub(crate) fn is_responsive(&mut self) -> bool {
+        // The socket is non-blocking, so this stops as soon as the queued pings are consumed
+        while let Ok(Some(time)) = self.rx.recv() {
+            self.last_beat = self.last_beat.max(time);
+        }
+        monotonic_now() < self.last_beat + self.timeout
+    }
+}
+
+// The parent side for asynchronous children, see tokio::Child::watcher
+pub struct Watcher {
+    rx: tokio::Receiver<Duration>,
+    timeout: Duration,
+    last_beat: Duration,
+}
+
+impl Watcher {
+    pub(crate) fn new(rx: tokio::Receiver<Duration>, timeout: Duration) -> Self {
+        Watcher {
+            rx,
+            timeout,
+            last_beat: monotonic_now(),
+        }
+    }
+
+    pub fn is_responsive(&mut self) -> bool {
+        while let Some(Ok(Some(time))) = self.rx.recv().now_or_never() {
+            self.last_beat = self.last_beat.max(time);
+        }
+        monotonic_now() < self.last_beat + self.timeout
+    }
+
+    // Resolves when the child has not sent a heartbeat for the timeout. If the child terminates,
+    // this never resolves, so that it can be raced against join() without false alarms.
+    pub async fn unresponsive(&mut self) {
+        loop {
+            let time_left = (self.last_beat + self.timeout).saturating_sub(monotonic_now());
+            match ::tokio::time::timeout(time_left, self.rx.recv()).await {
+                Ok(Ok(Some(time))) => self.last_beat = self.last_beat.max(time),
+                Ok(_) => futures::future::pending().await,
+                Err(_) => return,
+            }
+        }
+    }
+}
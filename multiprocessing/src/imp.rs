@@ -1,8 +1,11 @@
 pub use ctor::ctor;
+pub use futures::stream::{Stream, StreamExt};
 
-use crate::{FnOnce, Receiver};
-use lazy_static::lazy_static;
+use crate::{builtins, Duplex, FnOnce};
+pub use lazy_static::lazy_static;
 use nix::fcntl;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::sync::RwLock;
 
@@ -45,7 +48,20 @@ pub fn main() {
             enable_cloexec(fd).expect("Failed to set O_CLOEXEC for the file descriptor");
 
             let mut entry_rx =
-                unsafe { Receiver::<Box<dyn FnOnce<(RawFd,), Output = i32>>>::from_raw_fd(fd) };
+                unsafe { Duplex::<u64, Box<dyn FnOnce<(RawFd,), Output = i32>>>::from_raw_fd(fd) };
+
+            // The parent refuses to send the entry if our layout differs from its own, so we
+            // only have to report the checksum
+            #[allow(unused_mut)]
+            let mut checksum = layout_checksum();
+            // Tests set LAYOUT_SALT_VAR to pretend that the child runs a different executable
+            #[cfg(feature = "test-hooks")]
+            if std::env::var_os(LAYOUT_SALT_VAR).is_some() {
+                checksum = !checksum;
+            }
+            entry_rx
+                .send(&checksum)
+                .expect("Failed to send layout checksum for multiprocessing");
 
             let entry = entry_rx
                 .recv()
@@ -66,6 +82,44 @@ pub fn main() {
         )());
 }
 
+// Objects are passed between processes in a format that relies on the two processes running the
+// very same executable: trait objects are serialized as vtable offsets, structures are not tagged
+// with field names, etc. If the parent and the child disagree about the binary (say, the file was
+// replaced while the parent was running), deserialization would silently produce garbage. To catch
+// this early, the child reports a checksum of the layout-relevant properties of its executable
+// before anything else is transferred.
+pub fn layout_checksum() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    std::mem::size_of::<usize>().hash(&mut hasher);
+    // Code and vtables are laid out in the same image, so the distance between them is invariant
+    // under ASLR but changes whenever the binary is rebuilt
+    (main as usize)
+        .wrapping_sub(builtins::get_base_vtable_ptr() as usize)
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+// Only meant for testing the handshake, see main
+#[cfg(feature = "test-hooks")]
+pub const LAYOUT_SALT_VAR: &str = "_MULTIPROCESSING_LAYOUT_SALT";
+
+pub(crate) fn verify_layout_checksum(checksum: Option<u64>) -> crate::Result<()> {
+    match checksum {
+        None => Err(crate::Error::Child(
+            "The subprocess terminated before reporting its layout checksum".to_string(),
+        )),
+        Some(checksum) if checksum != layout_checksum() => {
+            Err(crate::Error::Serialization(format!(
+                "Layout checksum mismatch: the subprocess reported {checksum:016x}, expected \
+                 {:016x}; was the executable replaced while running?",
+                layout_checksum()
+            )))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
 pub fn disable_cloexec(fd: RawFd) -> std::io::Result<()> {
     fcntl::fcntl(fd, fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::empty()))?;
     Ok(())
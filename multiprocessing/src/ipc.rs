@@ -1,29 +1,128 @@
-use crate::{imp, Deserialize, Deserializer, Object, Serialize, Serializer};
+use crate::{
+    batched::{Batch, BatchedReceiver, BatchedSender},
+    imp, Deserialize, Deserializer, Error, Object, Result, Serialize, Serializer,
+};
 use nix::libc::{AF_UNIX, SOCK_CLOEXEC, SOCK_SEQPACKET};
-use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
 use std::marker::PhantomData;
 use std::os::unix::{
     io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     net::{AncillaryData, SocketAncillary, UnixStream},
 };
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 pub(crate) const MAX_PACKET_SIZE: usize = 16 * 1024;
 
+// Large messages are preceded by a packet that consists of BULK_MARKER and the length of the
+// serialized data. The receiver then allocates the buffer once, and both sides switch to larger
+// packets, which saves syscalls and reallocations. The packet size must stay below the default
+// SO_SNDBUF, or sending fails with EMSGSIZE.
+pub(crate) const BULK_PACKET_SIZE: usize = 128 * 1024;
+pub(crate) const BULK_MARKER: u8 = 2;
+
+static BULK_THRESHOLD: AtomicUsize = AtomicUsize::new(256 * 1024);
+
+// Messages whose serialized data is at least this long are sent in bulk mode. This only affects the
+// sending side: the receiver handles either mode regardless of the setting. usize::MAX disables
+// bulk mode.
+pub fn set_bulk_threshold(threshold: usize) {
+    BULK_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+pub(crate) fn is_bulk(len: usize) -> bool {
+    len >= BULK_THRESHOLD.load(Ordering::Relaxed)
+}
+
+// Messages sent over a channel with compression enabled are compressed with LZ4 if they are long
+// enough, and are then preceded by a packet that consists of COMPRESSED_MARKER and the length of the
+// original serialized data. This packet precedes the bulk header, if any, as bulk mode is decided
+// on by the compressed length. Receivers always understand compressed messages, so whether to
+// compress is up to the sending side of each channel alone.
+pub(crate) const COMPRESSED_MARKER: u8 = 3;
+
+// Returns the compression header if the data was compressed
+pub(crate) fn compress(
+    serialized: Vec<u8>,
+    threshold: Option<usize>,
+) -> (Vec<u8>, Option<[u8; 9]>) {
+    if threshold.map_or(true, |threshold| serialized.len() < threshold) {
+        return (serialized, None);
+    }
+    let compressed = lz4_flex::block::compress(&serialized);
+    // Incompressible data is better sent as is
+    if compressed.len() >= serialized.len() {
+        return (serialized, None);
+    }
+    let mut header = [COMPRESSED_MARKER; 9];
+    header[1..].copy_from_slice(&(serialized.len() as u64).to_le_bytes());
+    (compressed, Some(header))
+}
+
+pub(crate) fn parse_compression_header(
+    header: &[u8],
+    buffer_pos: usize,
+    bulk_len: Option<usize>,
+    original_len: Option<usize>,
+) -> Result<usize> {
+    if buffer_pos != 0 || bulk_len.is_some() || original_len.is_some() || header.len() != 8 {
+        return Err(Error::Protocol(
+            "Unexpected compression header on stream".to_string(),
+        ));
+    }
+    Ok(u64::from_le_bytes(header.try_into().unwrap()) as usize)
+}
+
+// A byte of an LZ4 block never expands to more than 255 bytes, so a larger original length can
+// only come from a malformed header. It is checked before the output buffer is allocated.
+const MAX_COMPRESSION_RATIO: usize = 255;
+
+pub(crate) fn decompress(serialized: Vec<u8>, original_len: Option<usize>) -> Result<Vec<u8>> {
+    let original_len = match original_len {
+        Some(original_len) => original_len,
+        None => return Ok(serialized),
+    };
+    if original_len > serialized.len().saturating_mul(MAX_COMPRESSION_RATIO) {
+        return Err(Error::Protocol(
+            "Compressed message is too short for its declared length".to_string(),
+        ));
+    }
+    lz4_flex::block::decompress(&serialized, original_len)
+        .map_err(|e| Error::Protocol(format!("Malformed compressed message: {e}")))
+}
+
+pub(crate) fn parse_bulk_header(
+    header: &[u8],
+    buffer_pos: usize,
+    bulk_len: Option<usize>,
+) -> Result<usize> {
+    if buffer_pos != 0 || bulk_len.is_some() || header.len() != 8 {
+        return Err(Error::Protocol(
+            "Unexpected bulk header on stream".to_string(),
+        ));
+    }
+    Ok(u64::from_le_bytes(header.try_into().unwrap()) as usize)
+}
+
 #[derive(Object)]
 pub struct Sender<T: Serialize> {
     fd: UnixStream,
+    compression_threshold: Option<usize>,
     marker: PhantomData<fn(T) -> T>,
 }
 
 #[derive(Object)]
 pub struct Receiver<T: Deserialize> {
     fd: UnixStream,
+    // Set once iteration yields an error, see Iterator::next
+    failed: bool,
     marker: PhantomData<fn(T) -> T>,
 }
 
 #[derive(Object)]
 pub struct Duplex<S: Serialize, R: Deserialize> {
     fd: UnixStream,
+    compression_threshold: Option<usize>,
     marker: PhantomData<fn(S, R) -> (S, R)>,
 }
 
@@ -33,7 +132,7 @@ pub fn channel<T: Serialize + Deserialize>() -> Result<(Sender<T>, Receiver<T>)>
         let mut fds = [0, 0];
         if nix::libc::socketpair(AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0, fds.as_mut_ptr()) == -1
         {
-            return Err(std::io::Error::last_os_error());
+            return Err(Error::last_os_error());
         }
         Ok((Sender::from_raw_fd(fds[0]), Receiver::from_raw_fd(fds[1])))
     }
@@ -46,43 +145,128 @@ pub fn duplex<A: Serialize + Deserialize, B: Serialize + Deserialize>(
         let mut fds = [0, 0];
         if nix::libc::socketpair(AF_UNIX, SOCK_SEQPACKET | SOCK_CLOEXEC, 0, fds.as_mut_ptr()) == -1
         {
-            return Err(std::io::Error::last_os_error());
+            return Err(Error::last_os_error());
         }
         Ok((Duplex::from_raw_fd(fds[0]), Duplex::from_raw_fd(fds[1])))
     }
 }
 
-fn send_on_fd<T: Serialize>(fd: &mut UnixStream, value: &T) -> Result<()> {
+// SO_RCVTIMEO and SO_SNDTIMEO apply to individual syscalls, so they are recomputed from the deadline
+// before each packet, and reset when the operation finishes, however it finishes
+struct TimeoutGuard<'a> {
+    fd: &'a UnixStream,
+}
+
+impl Drop for TimeoutGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.fd.set_read_timeout(None);
+        let _ = self.fd.set_write_timeout(None);
+    }
+}
+
+fn time_left(deadline: Instant) -> Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        Err(std::io::Error::new(ErrorKind::TimedOut, "Deadline expired").into())
+    } else {
+        Ok(left)
+    }
+}
+
+fn map_timeout(e: std::io::Error) -> std::io::Error {
+    // A blocking socket reports an expired timeout as EAGAIN
+    if e.kind() == ErrorKind::WouldBlock {
+        std::io::Error::new(ErrorKind::TimedOut, "Deadline expired")
+    } else {
+        e
+    }
+}
+
+// If the deadline expires in the middle of a message that spans several packets, the peer sees a
+// truncated message, so the channel should not be used afterwards
+fn send_on_fd<T: Serialize>(
+    fd: &UnixStream,
+    value: &T,
+    deadline: Option<Instant>,
+    compression_threshold: Option<usize>,
+) -> Result<()> {
     let mut s = Serializer::new();
     s.serialize(value);
+    send_serialized_on_fd(fd, s, deadline, compression_threshold)
+}
+
+fn send_serialized_on_fd(
+    fd: &UnixStream,
+    mut s: Serializer,
+    deadline: Option<Instant>,
+    compression_threshold: Option<usize>,
+) -> Result<()> {
+    let _guard = deadline.map(|_| TimeoutGuard { fd });
 
     let fds = s.drain_fds();
-    let serialized = s.into_vec();
+    let (serialized, compression_header) = compress(s.into_vec(), compression_threshold);
 
     let mut ancillary_buffer = [0; 253];
 
+    if let Some(header) = compression_header {
+        if let Some(deadline) = deadline {
+            fd.set_write_timeout(Some(time_left(deadline)?))?;
+        }
+        fd.send_vectored_with_ancillary(
+            &[IoSlice::new(&header)],
+            &mut SocketAncillary::new(&mut []),
+        )
+        .map_err(map_timeout)?;
+    }
+
+    let bulk = is_bulk(serialized.len());
+    if bulk {
+        if let Some(deadline) = deadline {
+            fd.set_write_timeout(Some(time_left(deadline)?))?;
+        }
+        fd.send_vectored_with_ancillary(
+            &[
+                IoSlice::new(&[BULK_MARKER]),
+                IoSlice::new(&(serialized.len() as u64).to_le_bytes()),
+            ],
+            &mut SocketAncillary::new(&mut []),
+        )
+        .map_err(map_timeout)?;
+    }
+    let packet_size = if bulk {
+        BULK_PACKET_SIZE
+    } else {
+        MAX_PACKET_SIZE
+    };
+
     // Send the data and pass file descriptors
     let mut buffer_pos: usize = 0;
     let mut fds_pos: usize = 0;
 
     loop {
-        let buffer_end = serialized.len().min(buffer_pos + MAX_PACKET_SIZE - 1);
+        let buffer_end = serialized.len().min(buffer_pos + packet_size - 1);
         let fds_end = fds.len().min(fds_pos + 253);
 
         let is_last = buffer_end == serialized.len() && fds_end == fds.len();
 
         let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
         if !ancillary.add_fds(&fds[fds_pos..fds_end]) {
-            return Err(Error::new(ErrorKind::Other, "Too many fds to pass"));
+            return Err(Error::FdPassing("Too many fds to pass".to_string()));
         }
 
-        let n_written = fd.send_vectored_with_ancillary(
-            &[
-                IoSlice::new(&[is_last as u8]),
-                IoSlice::new(&serialized[buffer_pos..buffer_end]),
-            ],
-            &mut ancillary,
-        )?;
+        if let Some(deadline) = deadline {
+            fd.set_write_timeout(Some(time_left(deadline)?))?;
+        }
+
+        let n_written = fd
+            .send_vectored_with_ancillary(
+                &[
+                    IoSlice::new(&[is_last as u8]),
+                    IoSlice::new(&serialized[buffer_pos..buffer_end]),
+                ],
+                &mut ancillary,
+            )
+            .map_err(map_timeout)?;
         buffer_pos += n_written - 1;
         fds_pos = fds_end;
 
@@ -94,7 +278,14 @@ fn send_on_fd<T: Serialize>(fd: &mut UnixStream, value: &T) -> Result<()> {
     Ok(())
 }
 
-fn recv_on_fd<T: Deserialize>(fd: &mut UnixStream) -> Result<Option<T>> {
+// The deadline only applies to the first packet of a message: once the peer has started sending,
+// the rest of the message follows immediately, and dropping it would desynchronize the channel
+fn recv_on_fd<T: Deserialize>(fd: &UnixStream, deadline: Option<Instant>) -> Result<Option<T>> {
+    let _guard = deadline.map(|_| TimeoutGuard { fd });
+    if let Some(deadline) = deadline {
+        fd.set_read_timeout(Some(time_left(deadline)?))?;
+    }
+
     // Read the data and the passed file descriptors
     let mut serialized: Vec<u8> = Vec::new();
     let mut buffer_pos: usize = 0;
@@ -102,19 +293,33 @@ fn recv_on_fd<T: Deserialize>(fd: &mut UnixStream) -> Result<Option<T>> {
     let mut ancillary_buffer = [0; 253];
     let mut received_fds: Vec<OwnedFd> = Vec::new();
 
+    let mut bulk_len: Option<usize> = None;
+    let mut original_len: Option<usize> = None;
+
     loop {
-        serialized.resize(buffer_pos + MAX_PACKET_SIZE - 1, 0);
+        let packet_size = if bulk_len.is_some() {
+            BULK_PACKET_SIZE
+        } else {
+            MAX_PACKET_SIZE
+        };
+        serialized.resize(buffer_pos + packet_size - 1, 0);
 
         let mut marker = [0];
         let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
 
-        let n_read = fd.recv_vectored_with_ancillary(
-            &mut [
-                IoSliceMut::new(&mut marker),
-                IoSliceMut::new(&mut serialized[buffer_pos..]),
-            ],
-            &mut ancillary,
-        )?;
+        let n_read = fd
+            .recv_vectored_with_ancillary(
+                &mut [
+                    IoSliceMut::new(&mut marker),
+                    IoSliceMut::new(&mut serialized[buffer_pos..]),
+                ],
+                &mut ancillary,
+            )
+            .map_err(map_timeout)?;
+
+        if deadline.is_some() && buffer_pos == 0 {
+            fd.set_read_timeout(None)?;
+        }
 
         for cmsg in ancillary.messages() {
             if let Ok(AncillaryData::ScmRights(rights)) = cmsg {
@@ -122,9 +327,8 @@ fn recv_on_fd<T: Deserialize>(fd: &mut UnixStream) -> Result<Option<T>> {
                     received_fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
                 }
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Unexpected kind of cmsg on stream"),
+                return Err(Error::FdPassing(
+                    "Unexpected kind of cmsg on stream".to_string(),
                 ));
             }
         }
@@ -133,27 +337,45 @@ fn recv_on_fd<T: Deserialize>(fd: &mut UnixStream) -> Result<Option<T>> {
             if buffer_pos == 0 && received_fds.is_empty() {
                 return Ok(None);
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Unterminated data on stream"),
-                ));
+                return Err(Error::Protocol("Unterminated data on stream".to_string()));
             }
         }
 
         if n_read == 0 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Unexpected empty message on stream"),
+            return Err(Error::Protocol(
+                "Unexpected empty message on stream".to_string(),
             ));
         }
 
+        if marker[0] == COMPRESSED_MARKER {
+            original_len = Some(parse_compression_header(
+                &serialized[..n_read - 1],
+                buffer_pos,
+                bulk_len,
+                original_len,
+            )?);
+            continue;
+        }
+
+        if marker[0] == BULK_MARKER {
+            let len = parse_bulk_header(&serialized[..n_read - 1], buffer_pos, bulk_len)?;
+            serialized.reserve_exact((len + BULK_PACKET_SIZE).saturating_sub(serialized.len()));
+            bulk_len = Some(len);
+            continue;
+        }
+
         buffer_pos += n_read - 1;
         if marker[0] == 1 {
             break;
         }
     }
 
+    if bulk_len.map_or(false, |len| len != buffer_pos) {
+        return Err(Error::Protocol("Bulk message length mismatch".to_string()));
+    }
+
     serialized.truncate(buffer_pos);
+    let serialized = decompress(serialized, original_len)?;
 
     let mut d = Deserializer::from(serialized, received_fds);
     Ok(Some(d.deserialize()))
@@ -163,12 +385,45 @@ impl<T: Serialize> Sender<T> {
     pub fn from_unix_stream(fd: UnixStream) -> Self {
         Sender {
             fd,
+            compression_threshold: None,
             marker: PhantomData,
         }
     }
 
     pub fn send(&mut self, value: &T) -> Result<()> {
-        send_on_fd(&mut self.fd, value)
+        send_on_fd(&self.fd, value, None, self.compression_threshold)
+    }
+
+    pub fn send_timeout(&mut self, value: &T, timeout: Duration) -> Result<()> {
+        send_on_fd(
+            &self.fd,
+            value,
+            Some(Instant::now() + timeout),
+            self.compression_threshold,
+        )
+    }
+
+    // The receiving side has to call Receiver::batched too
+    pub fn batched(self, max_bytes: usize, max_delay: Duration) -> BatchedSender<T> {
+        let tx = Sender {
+            fd: self.fd,
+            compression_threshold: self.compression_threshold,
+            marker: PhantomData,
+        };
+        BatchedSender::new(tx, max_bytes, max_delay)
+    }
+
+    // Messages whose serialized data is at least threshold bytes long are compressed. The setting
+    // is kept when the sender is passed to another process. None disables compression, which is
+    // the default.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+}
+
+impl<T: Serialize> Sender<Batch<T>> {
+    pub(crate) fn send_serialized(&mut self, s: Serializer) -> Result<()> {
+        send_serialized_on_fd(&self.fd, s, None, self.compression_threshold)
     }
 }
 
@@ -189,12 +444,36 @@ impl<T: Deserialize> Receiver<T> {
     pub fn from_unix_stream(fd: UnixStream) -> Self {
         Receiver {
             fd,
+            failed: false,
             marker: PhantomData,
         }
     }
 
     pub fn recv(&mut self) -> Result<Option<T>> {
-        recv_on_fd(&mut self.fd)
+        recv_on_fd(&self.fd, None)
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<T>> {
+        recv_on_fd(&self.fd, Some(Instant::now() + timeout))
+    }
+
+    pub fn batched(self) -> BatchedReceiver<T> {
+        BatchedReceiver::new(Receiver::from_unix_stream(self.fd))
+    }
+}
+
+impl<T: Deserialize> Iterator for Receiver<T> {
+    type Item = Result<T>;
+
+    // After an error, the position in the stream is unknown and the error is likely to repeat, so
+    // iteration stops there rather than spinning on a broken channel
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let item = self.recv().transpose();
+        self.failed = matches!(item, Some(Err(_)));
+        item
     }
 }
 
@@ -215,21 +494,45 @@ impl<S: Serialize, R: Deserialize> Duplex<S, R> {
     pub fn from_unix_stream(fd: UnixStream) -> Self {
         Duplex {
             fd,
+            compression_threshold: None,
             marker: PhantomData,
         }
     }
 
     pub fn send(&mut self, value: &S) -> Result<()> {
-        send_on_fd(&mut self.fd, value)
+        send_on_fd(&self.fd, value, None, self.compression_threshold)
+    }
+
+    pub fn send_timeout(&mut self, value: &S, timeout: Duration) -> Result<()> {
+        send_on_fd(
+            &self.fd,
+            value,
+            Some(Instant::now() + timeout),
+            self.compression_threshold,
+        )
     }
 
     pub fn recv(&mut self) -> Result<Option<R>> {
-        recv_on_fd(&mut self.fd)
+        recv_on_fd(&self.fd, None)
+    }
+
+    // See Sender::set_compression_threshold. Only applies to this side of the duplex
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<R>> {
+        recv_on_fd(&self.fd, Some(Instant::now() + timeout))
     }
 
     pub fn into_receiver(self) -> Receiver<R> {
         Receiver::from_unix_stream(self.fd)
     }
+
+    // The handshake happens before the channel is used for R, see imp::layout_checksum
+    pub(crate) fn recv_layout_checksum(&mut self) -> Result<Option<u64>> {
+        recv_on_fd(&self.fd, None)
+    }
 }
 
 impl<S: Serialize, R: Deserialize> AsRawFd for Duplex<S, R> {
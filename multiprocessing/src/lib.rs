@@ -15,6 +15,9 @@ extern crate self as multiprocessing;
 
 pub use multiprocessing_derive::*;
 
+pub mod error;
+pub use error::{Error, Result};
+
 pub mod imp;
 
 pub mod soundness;
@@ -24,7 +27,10 @@ pub mod serde;
 pub use crate::serde::*;
 
 pub mod ipc;
-pub use ipc::{channel, duplex, Duplex, Receiver, Sender};
+pub use ipc::{channel, duplex, set_bulk_threshold, Duplex, Receiver, Sender};
+
+pub mod batched;
+pub use batched::{BatchedReceiver, BatchedSender};
 
 pub mod tokio;
 
@@ -36,7 +42,20 @@ pub mod builtins;
 pub mod fns;
 pub use fns::*;
 
+pub mod heartbeat;
+pub use heartbeat::heartbeat;
+
+pub mod log;
+
+pub mod remote;
+pub use remote::{RemoteFn, RemoteFnMut};
+
+pub mod portable;
+
 pub mod delayed;
 pub use delayed::Delayed;
 
+pub mod value;
+pub use value::Value;
+
 pub use nix::libc;
@@ -0,0 +1,103 @@
+use crate::{tokio, Object, Receiver, Sender};
+use nix::libc::pid_t;
+use std::sync::Mutex;
+
+// A child spawned with SpawnOptions::forward_logs has a logger installed that ships every record to
+// the parent over a hidden channel. The parent replays the records into whatever logger it has
+// installed itself, with the PID of the child prepended to the message, so that output of sandbox
+// helpers ends up in the same place as the rest of the logs instead of being mixed into stderr.
+//
+// Only the log crate is supported. tracing subscribers receive the records too if they are set up
+// to consume log records.
+
+#[derive(Object)]
+pub(crate) struct Record {
+    level: usize,
+    target: String,
+    message: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+fn level_from_usize(level: usize) -> ::log::LevelFilter {
+    ::log::LevelFilter::iter()
+        .find(|filter| *filter as usize == level)
+        .unwrap_or(::log::LevelFilter::Trace)
+}
+
+// The level the child should log at, i.e. what the parent would log itself
+pub(crate) fn max_level() -> usize {
+    ::log::max_level() as usize
+}
+
+struct Forwarder {
+    tx: Mutex<Sender<Record>>,
+}
+
+impl ::log::Log for Forwarder {
+    fn enabled(&self, metadata: &::log::Metadata) -> bool {
+        metadata.level() <= ::log::max_level()
+    }
+
+    fn log(&self, record: &::log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let record = Record {
+            level: record.level() as usize,
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            module_path: record.module_path().map(|s| s.to_string()),
+            file: record.file().map(|s| s.to_string()),
+            line: record.line(),
+        };
+        // If the parent has stopped listening, there is nowhere else to put the record
+        if let Ok(mut tx) = self.tx.lock() {
+            let _ = tx.send(&record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub(crate) fn install(tx: Sender<Record>, max_level: usize) {
+    // The child is a fresh process, so no other logger can be installed yet
+    if ::log::set_boxed_logger(Box::new(Forwarder { tx: Mutex::new(tx) })).is_ok() {
+        ::log::set_max_level(level_from_usize(max_level));
+    }
+}
+
+fn replay(pid: pid_t, record: Record) {
+    let level = match level_from_usize(record.level).to_level() {
+        Some(level) => level,
+        None => return,
+    };
+    ::log::logger().log(
+        &::log::Record::builder()
+            .level(level)
+            .target(&record.target)
+            .module_path(record.module_path.as_deref())
+            .file(record.file.as_deref())
+            .line(record.line)
+            .args(format_args!("[pid {pid}] {}", record.message))
+            .build(),
+    );
+}
+
+// Replays the records on a background thread until the child closes the channel, i.e. terminates
+pub(crate) fn forward(pid: pid_t, mut rx: Receiver<Record>) {
+    std::thread::spawn(move || {
+        while let Ok(Some(record)) = rx.recv() {
+            replay(pid, record);
+        }
+    });
+}
+
+pub(crate) fn forward_tokio(pid: pid_t, mut rx: tokio::Receiver<Record>) {
+    ::tokio::spawn(async move {
+        while let Ok(Some(record)) = rx.recv().await {
+            replay(pid, record);
+        }
+    });
+}
@@ -0,0 +1,52 @@
+use crate::Deserializer;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+// By default, Box<dyn Trait> is transferred as a vtable offset, which only makes sense if both
+// processes run the very same build. Traits marked with #[portable_trait] transfer the name of the
+// implementing type instead, and the receiver looks the name up in a registry. Implementations are
+// added to the registry at startup, by #[portable_trait] applied to the impl blocks.
+type Constructor<T> = fn(&mut Deserializer) -> Box<T>;
+
+pub struct Registry<T: ?Sized> {
+    constructors: RwLock<HashMap<&'static str, Constructor<T>>>,
+}
+
+impl<T: ?Sized> Default for Registry<T> {
+    fn default() -> Self {
+        Registry {
+            constructors: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: ?Sized> Registry<T> {
+    pub fn register(&self, key: &'static str, constructor: Constructor<T>) {
+        if self
+            .constructors
+            .write()
+            .expect("Failed to acquire write access to the portable type registry")
+            .insert(key, constructor)
+            .is_some()
+        {
+            panic!("Type {key} is registered twice");
+        }
+    }
+
+    pub fn deserialize(&self, key: &str, d: &mut Deserializer) -> Box<T> {
+        let constructor = *self
+            .constructors
+            .read()
+            .expect("Failed to acquire read access to the portable type registry")
+            .get(key)
+            .unwrap_or_else(|| {
+                panic!("Type {key} is not registered; is #[portable_trait] missing on its impl?")
+            });
+        constructor(d)
+    }
+}
+
+// Implemented for dyn Trait of every portable trait
+pub trait PortableTrait {
+    fn registry() -> &'static Registry<Self>;
+}
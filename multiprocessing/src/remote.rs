@@ -0,0 +1,95 @@
+use crate::{duplex, Child, Duplex, Error, Object, Result};
+use std::sync::Mutex;
+
+// The child side of a remote function: arguments are received one call at a time and the results
+// are sent back until the parent closes the channel
+#[crate::entrypoint]
+fn serve<
+    F: std::ops::FnMut<Args, Output = Output> + Object + 'static,
+    Args: Object + 'static,
+    Output: Object + 'static,
+>(
+    mut func: F,
+    mut chan: Duplex<Output, Args>,
+) {
+    while let Ok(Some(args)) = chan.recv() {
+        if chan.send(&func.call_mut(args)).is_err() {
+            break;
+        }
+    }
+}
+
+// A function living in a child process that can be called many times. Useful when spawning a
+// process per call is too expensive, e.g. for helpers invoked once per test. The state of an FnMut
+// is preserved between calls, as it stays in the same process.
+pub struct RemoteFnMut<Args: Object, Output: Object> {
+    child: Child<()>,
+    chan: Duplex<Args, Output>,
+}
+
+impl<Args: Object + 'static, Output: Object + 'static> RemoteFnMut<Args, Output> {
+    pub fn new<F: std::ops::FnMut<Args, Output = Output> + Object + 'static>(
+        func: F,
+    ) -> Result<Self> {
+        let (chan, child_chan) = duplex::<Args, Output>()?;
+        let child = serve.spawn(func, child_chan)?;
+        Ok(RemoteFnMut { child, chan })
+    }
+
+    pub fn call(&mut self, args: Args) -> Result<Output> {
+        self.chan.send(&args)?;
+        self.chan
+            .recv()?
+            .ok_or_else(|| Error::Child("The remote function terminated".to_string()))
+    }
+
+    pub fn id(&self) -> nix::libc::pid_t {
+        self.child.id()
+    }
+
+    pub fn kill(&mut self) -> Result<()> {
+        self.child.kill()
+    }
+
+    // Closes the channel and waits for the child to terminate
+    pub fn join(self) -> Result<()> {
+        let RemoteFnMut { mut child, chan } = self;
+        drop(chan);
+        child.join()
+    }
+}
+
+// Same as RemoteFnMut, but can be called via a shared reference, e.g. from several threads. Calls
+// are serialized, as there is only one child.
+pub struct RemoteFn<Args: Object, Output: Object> {
+    inner: Mutex<RemoteFnMut<Args, Output>>,
+}
+
+impl<Args: Object + 'static, Output: Object + 'static> RemoteFn<Args, Output> {
+    pub fn new<F: std::ops::Fn<Args, Output = Output> + Object + 'static>(func: F) -> Result<Self> {
+        Ok(RemoteFn {
+            inner: Mutex::new(RemoteFnMut::new(func)?),
+        })
+    }
+
+    pub fn call(&self, args: Args) -> Result<Output> {
+        self.inner
+            .lock()
+            .expect("A call to the remote function panicked")
+            .call(args)
+    }
+
+    pub fn id(&self) -> nix::libc::pid_t {
+        self.inner
+            .lock()
+            .expect("A call to the remote function panicked")
+            .id()
+    }
+
+    pub fn join(self) -> Result<()> {
+        self.inner
+            .into_inner()
+            .expect("A call to the remote function panicked")
+            .join()
+    }
+}
@@ -53,6 +53,10 @@ impl Serializer {
         }
     }
 
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
     pub fn into_vec(self) -> Vec<u8> {
         self.data
     }
@@ -1,15 +1,19 @@
-use crate::{duplex, imp, Deserialize, FnOnce, Object, Receiver};
+use crate::{
+    duplex, heartbeat, imp, log, Deserialize, Error, FnOnce, Object, Receiver, Result, Sender,
+};
 use nix::{
     libc::{c_char, c_int, c_void, pid_t},
-    sys::signal,
+    sys::{signal, socket},
 };
 use std::ffi::CString;
-use std::io::Result;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct Child<T: Deserialize> {
     proc_pid: nix::unistd::Pid,
     output_rx: Receiver<T>,
+    heartbeat: Option<heartbeat::Monitor>,
 }
 
 impl<T: Deserialize> Child<T> {
@@ -17,7 +21,32 @@ impl<T: Deserialize> Child<T> {
         Child {
             proc_pid,
             output_rx,
+            heartbeat: None,
+        }
+    }
+
+    // False if the child has terminated or, if it was spawned with heartbeats, has not sent one for
+    // too long. The child is not reaped, so join() can still be called afterwards.
+    pub fn is_alive(&mut self) -> Result<bool> {
+        let mut info: nix::libc::siginfo_t = unsafe { std::mem::zeroed() };
+        if unsafe {
+            nix::libc::waitid(
+                nix::libc::P_PID,
+                self.proc_pid.as_raw() as nix::libc::id_t,
+                &mut info,
+                nix::libc::WEXITED | nix::libc::WNOHANG | nix::libc::WNOWAIT,
+            )
+        } == -1
+        {
+            return Err(Error::last_os_error());
+        }
+        if unsafe { info.si_pid() } != 0 {
+            return Ok(false);
         }
+        Ok(self
+            .heartbeat
+            .as_mut()
+            .map_or(true, |monitor| monitor.is_responsive()))
     }
 
     pub fn kill(&mut self) -> Result<()> {
@@ -34,87 +63,567 @@ impl<T: Deserialize> Child<T> {
         let status = nix::sys::wait::waitpid(self.proc_pid, None)?;
         if let nix::sys::wait::WaitStatus::Exited(_, 0) = status {
             value.ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "The subprocess terminated without returning a value",
-                )
+                Error::Child("The subprocess terminated without returning a value".to_string())
             })
         } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "The subprocess did not terminate successfully: {:?}",
-                    status
-                ),
-            ))
+            Err(Error::Child(format!(
+                "The subprocess did not terminate successfully: {:?}",
+                status
+            )))
+        }
+    }
+}
+
+// The child of an #[entrypoint(stream)] function. Values can be received from output() as the child
+// yields them; the receiver reports the end of stream when the child terminates.
+pub struct StreamingChild<T: Deserialize> {
+    child: Child<T>,
+}
+
+impl<T: Deserialize> StreamingChild<T> {
+    pub fn kill(&mut self) -> Result<()> {
+        self.child.kill()
+    }
+
+    pub fn id(&self) -> pid_t {
+        self.child.id()
+    }
+
+    pub fn output(&mut self) -> &mut Receiver<T> {
+        &mut self.child.output_rx
+    }
+
+    // Values that have not been received yet are discarded. Otherwise the child might block on a
+    // full socket buffer and never terminate.
+    pub fn join(&mut self) -> Result<()> {
+        while self.child.output_rx.recv()?.is_some() {}
+        let status = nix::sys::wait::waitpid(self.child.proc_pid, None)?;
+        if let nix::sys::wait::WaitStatus::Exited(_, 0) = status {
+            Ok(())
+        } else {
+            Err(Error::Child(format!(
+                "The subprocess did not terminate successfully: {:?}",
+                status
+            )))
         }
     }
 }
 
-pub(crate) unsafe fn _spawn_child(child_fd: RawFd, flags: c_int) -> Result<nix::unistd::Pid> {
-    let child_fd_str = CString::new(child_fd.to_string()).unwrap();
+impl<T: Deserialize> From<Child<T>> for StreamingChild<T> {
+    fn from(child: Child<T>) -> Self {
+        StreamingChild { child }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Object)]
+pub enum SchedPolicy {
+    Other,
+    Batch,
+    Idle,
+}
+
+type PreExecHook = Arc<dyn std::ops::Fn() -> std::io::Result<()> + Send + Sync>;
+
+// Parameters of the child process that have to be set before the entry starts running
+#[derive(Clone, Default, Object)]
+pub struct SpawnOptions {
+    flags: c_int,
+    nice: Option<c_int>,
+    sched_policy: Option<SchedPolicy>,
+    cpu_affinity: Option<Vec<usize>>,
+    pub(crate) heartbeat: Option<Duration>,
+    pub(crate) forward_logs: bool,
+    vfork: bool,
+    // Closures cannot cross process boundaries, see spawn_detached for how this is handled
+    #[object(skip)]
+    pre_exec: Vec<PreExecHook>,
+}
+
+impl SpawnOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Passed to clone(2) in addition to SIGCHLD
+    pub fn flags(mut self, flags: c_int) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn nice(mut self, nice: c_int) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    pub fn sched_policy(mut self, sched_policy: SchedPolicy) -> Self {
+        self.sched_policy = Some(sched_policy);
+        self
+    }
+
+    pub fn cpu_affinity(mut self, cpus: Vec<usize>) -> Self {
+        self.cpu_affinity = Some(cpus);
+        self
+    }
+
+    // The child is expected to call multiprocessing::heartbeat() at least once per timeout, see
+    // Child::is_alive and tokio::Child::watcher
+    pub fn heartbeat(mut self, timeout: Duration) -> Self {
+        self.heartbeat = Some(timeout);
+        self
+    }
+
+    // Records the child logs via the log crate are replayed into the logger of the parent, see
+    // multiprocessing::log
+    pub fn forward_logs(mut self) -> Self {
+        self.forward_logs = true;
+        self
+    }
+
+    // Starts the child with CLONE_VM | CLONE_VFORK instead of a plain fork, which is faster when the
+    // parent uses a lot of memory. The calling thread is blocked until the child calls exec, so
+    // pre_exec hooks should be quick. Writes to memory made by the hooks are visible to the parent.
+    pub fn vfork(mut self) -> Self {
+        self.vfork = true;
+        self
+    }
+
+    // Hooks are run in the order of addition, after the options above are applied. They run in the
+    // forked child before exec, where the same restrictions as for fork in a multithreaded program
+    // apply: in particular, allocating memory or taking locks may deadlock.
+    pub unsafe fn pre_exec<F: std::ops::Fn() -> std::io::Result<()> + Send + Sync + 'static>(
+        mut self,
+        hook: F,
+    ) -> Self {
+        self.pre_exec.push(Arc::new(hook));
+        self
+    }
+}
+
+// Everything the child needs between clone and exec. It has to be prepared in advance, because the
+// child must not allocate.
+struct ExecContext<'a> {
+    child_fd: RawFd,
+    child_fd_str: CString,
+    sched_policy: Option<c_int>,
+    cpu_set: Option<nix::libc::cpu_set_t>,
+    options: &'a SpawnOptions,
+}
+
+// The stack of the child in vfork mode only has to last until exec
+const VFORK_STACK_SIZE: usize = 256 << 10;
+
+pub(crate) unsafe fn _spawn_child(
+    child_fd: RawFd,
+    options: &SpawnOptions,
+) -> std::io::Result<nix::unistd::Pid> {
+    // Everything that allocates has to be prepared before clone
+    let context = ExecContext {
+        child_fd,
+        child_fd_str: CString::new(child_fd.to_string()).unwrap(),
+        sched_policy: options.sched_policy.map(|policy| match policy {
+            SchedPolicy::Other => nix::libc::SCHED_OTHER,
+            SchedPolicy::Batch => nix::libc::SCHED_BATCH,
+            SchedPolicy::Idle => nix::libc::SCHED_IDLE,
+        }),
+        cpu_set: options.cpu_affinity.as_ref().map(|cpus| {
+            let mut cpu_set: nix::libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                nix::libc::CPU_SET(*cpu, &mut cpu_set);
+            }
+            cpu_set
+        }),
+        options,
+    };
+
+    if options.vfork {
+        return spawn_vfork(&context);
+    }
 
     match nix::libc::syscall(
         nix::libc::SYS_clone,
-        nix::libc::SIGCHLD | flags,
+        nix::libc::SIGCHLD | options.flags,
         std::ptr::null::<c_void>(),
     ) {
         -1 => Err(std::io::Error::last_os_error()),
-        0 => {
-            // No heap allocations are allowed from now on
-            let res: Result<!> = try {
-                signal::sigprocmask(
-                    signal::SigmaskHow::SIG_SETMASK,
-                    Some(&signal::SigSet::empty()),
-                    None,
-                )?;
-                for i in 1..32 {
-                    if i != nix::libc::SIGKILL && i != nix::libc::SIGSTOP {
-                        signal::sigaction(
-                            signal::Signal::try_from(i).unwrap(),
-                            &signal::SigAction::new(
-                                signal::SigHandler::SigDfl,
-                                signal::SaFlags::empty(),
-                                signal::SigSet::empty(),
-                            ),
-                        )?;
-                    }
-                }
+        0 => exec_child(&context),
+        child_pid => Ok(nix::unistd::Pid::from_raw(child_pid as pid_t)),
+    }
+}
+
+// The child shares memory with the parent until it calls exec, and the calling thread is suspended
+// until then. This saves copying the page tables, which is what makes fork slow in processes with
+// a large address space. The child runs on a separate stack so that it cannot clobber the frames of
+// the suspended thread, like posix_spawn does in glibc.
+unsafe fn spawn_vfork(context: &ExecContext) -> std::io::Result<nix::unistd::Pid> {
+    extern "C" fn trampoline(arg: *mut c_void) -> c_int {
+        unsafe { exec_child(&*(arg as *const ExecContext)) }
+    }
+
+    let mut stack = vec![0u8; VFORK_STACK_SIZE];
+    // The stack grows down and has to be aligned
+    let stack_top = (stack.as_mut_ptr().add(VFORK_STACK_SIZE) as usize & !15) as *mut c_void;
+
+    // Signal handlers of the parent must not run in the child, as they would do so in the
+    // parent's memory. The child resets the handlers before unblocking signals.
+    let mut old_mask = signal::SigSet::empty();
+    signal::pthread_sigmask(
+        signal::SigmaskHow::SIG_SETMASK,
+        Some(&signal::SigSet::all()),
+        Some(&mut old_mask),
+    )?;
 
-                imp::disable_cloexec(child_fd)?;
+    let child_pid = nix::libc::clone(
+        trampoline,
+        stack_top,
+        nix::libc::CLONE_VM | nix::libc::CLONE_VFORK | nix::libc::SIGCHLD | context.options.flags,
+        context as *const ExecContext as *mut c_void,
+    );
+    let clone_error = std::io::Error::last_os_error();
 
-                // nix::unistd::execv uses allocations
-                nix::libc::execv(
-                    b"/proc/self/exe\0" as *const u8 as *const c_char,
-                    &[
-                        b"_multiprocessing_\0" as *const u8 as *const c_char,
-                        child_fd_str.as_ptr() as *const u8 as *const c_char,
-                        std::ptr::null(),
-                    ] as *const *const c_char,
-                );
+    signal::pthread_sigmask(signal::SigmaskHow::SIG_SETMASK, Some(&old_mask), None)?;
 
+    if child_pid == -1 {
+        Err(clone_error)
+    } else {
+        Ok(nix::unistd::Pid::from_raw(child_pid))
+    }
+}
+
+unsafe fn exec_child(context: &ExecContext) -> ! {
+    // No heap allocations are allowed from now on
+    let res: std::io::Result<!> = try {
+        // Handlers are reset before signals are unblocked, see spawn_vfork
+        for i in 1..32 {
+            if i != nix::libc::SIGKILL && i != nix::libc::SIGSTOP {
+                signal::sigaction(
+                    signal::Signal::try_from(i).unwrap(),
+                    &signal::SigAction::new(
+                        signal::SigHandler::SigDfl,
+                        signal::SaFlags::empty(),
+                        signal::SigSet::empty(),
+                    ),
+                )?;
+            }
+        }
+        signal::sigprocmask(
+            signal::SigmaskHow::SIG_SETMASK,
+            Some(&signal::SigSet::empty()),
+            None,
+        )?;
+
+        if let Some(policy) = context.sched_policy {
+            let param = nix::libc::sched_param { sched_priority: 0 };
+            if nix::libc::sched_setscheduler(0, policy, &param) == -1 {
+                Err(std::io::Error::last_os_error())?;
+            }
+        }
+        if let Some(nice) = context.options.nice {
+            if nix::libc::setpriority(nix::libc::PRIO_PROCESS, 0, nice) == -1 {
+                Err(std::io::Error::last_os_error())?;
+            }
+        }
+        if let Some(ref cpu_set) = context.cpu_set {
+            if nix::libc::sched_setaffinity(0, std::mem::size_of::<nix::libc::cpu_set_t>(), cpu_set)
+                == -1
+            {
                 Err(std::io::Error::last_os_error())?;
+            }
+        }
+
+        for hook in &context.options.pre_exec {
+            hook()?;
+        }
+
+        imp::disable_cloexec(context.child_fd)?;
 
-                unreachable!()
-            };
+        // nix::unistd::execv uses allocations
+        nix::libc::execv(
+            b"/proc/self/exe\0" as *const u8 as *const c_char,
+            &[
+                b"_multiprocessing_\0" as *const u8 as *const c_char,
+                context.child_fd_str.as_ptr() as *const u8 as *const c_char,
+                std::ptr::null(),
+            ] as *const *const c_char,
+        );
+
+        Err(std::io::Error::last_os_error())?;
+
+        unreachable!()
+    };
+
+    // In vfork mode the child shares memory with the parent, so neither stderr locks nor the
+    // allocator may be touched. The message is built on the stack and written with a raw syscall.
+    write_exec_error(res.into_err().raw_os_error());
+    nix::libc::_exit(127);
+}
 
-            eprintln!("{}", res.into_err());
-            std::process::abort();
+unsafe fn write_exec_error(errno: Option<c_int>) {
+    const PREFIX: &[u8] = b"multiprocessing: failed to start the child, errno ";
+    let mut buf = [0u8; PREFIX.len() + 12];
+    buf[..PREFIX.len()].copy_from_slice(PREFIX);
+    let mut len = PREFIX.len();
+    match errno {
+        Some(errno) => {
+            let mut digits = [0u8; 10];
+            let mut n_digits = 0;
+            let mut value = errno.unsigned_abs();
+            loop {
+                digits[n_digits] = b'0' + (value % 10) as u8;
+                n_digits += 1;
+                value /= 10;
+                if value == 0 {
+                    break;
+                }
+            }
+            for digit in digits[..n_digits].iter().rev() {
+                buf[len] = *digit;
+                len += 1;
+            }
         }
-        child_pid => Ok(nix::unistd::Pid::from_raw(child_pid as pid_t)),
+        None => {
+            buf[len] = b'?';
+            len += 1;
+        }
+    }
+    buf[len] = b'\n';
+    len += 1;
+    nix::libc::write(2, buf.as_ptr() as *const c_void, len);
+}
+
+// Installs the hidden channels in the child before running the actual entry
+#[derive(Object)]
+struct HiddenChannelsEntry {
+    entry: Box<dyn FnOnce<(RawFd,), Output = i32>>,
+    heartbeat_tx: Option<Sender<Duration>>,
+    // The channel and the maximum log level
+    log: Option<(Sender<log::Record>, usize)>,
+}
+
+impl std::ops::FnOnce<(RawFd,)> for HiddenChannelsEntry {
+    type Output = i32;
+    extern "rust-call" fn call_once(self, args: (RawFd,)) -> i32 {
+        if let Some(heartbeat_tx) = self.heartbeat_tx {
+            heartbeat::install(heartbeat_tx);
+        }
+        if let Some((log_tx, max_level)) = self.log {
+            log::install(log_tx, max_level);
+        }
+        self.entry.call_once(args)
+    }
+}
+
+type Entry = Box<dyn FnOnce<(RawFd,), Output = i32>>;
+
+// The parent's ends of the channels that are not visible to the entry
+#[derive(Default)]
+pub(crate) struct HiddenChannels {
+    pub(crate) heartbeat_rx: Option<OwnedFd>,
+    pub(crate) log_rx: Option<OwnedFd>,
+}
+
+fn hidden_channel<T: Object>() -> Result<(Sender<T>, OwnedFd)> {
+    let (tx, rx) = socket::socketpair(
+        socket::AddressFamily::Unix,
+        socket::SockType::SeqPacket,
+        None,
+        socket::SockFlag::SOCK_CLOEXEC,
+    )?;
+    unsafe { Ok((Sender::from_raw_fd(tx), OwnedFd::from_raw_fd(rx))) }
+}
+
+// Returns the entry to send to the child and the parent's ends of the channels enabled in options
+pub(crate) fn with_hidden_channels(
+    entry: Entry,
+    options: &SpawnOptions,
+) -> Result<(Entry, HiddenChannels)> {
+    if options.heartbeat.is_none() && !options.forward_logs {
+        return Ok((entry, HiddenChannels::default()));
+    }
+    let mut channels = HiddenChannels::default();
+    let mut heartbeat_tx = None;
+    if options.heartbeat.is_some() {
+        let (tx, rx) = hidden_channel()?;
+        heartbeat_tx = Some(tx);
+        channels.heartbeat_rx = Some(rx);
+    }
+    let mut log = None;
+    if options.forward_logs {
+        let (tx, rx) = hidden_channel()?;
+        log = Some((tx, log::max_level()));
+        channels.log_rx = Some(rx);
     }
+    Ok((
+        Box::new(HiddenChannelsEntry {
+            entry,
+            heartbeat_tx,
+            log,
+        }),
+        channels,
+    ))
 }
 
 pub unsafe fn spawn<T: Object>(
     entry: Box<dyn FnOnce<(RawFd,), Output = i32>>,
-    flags: c_int,
+    options: &SpawnOptions,
 ) -> Result<Child<T>> {
+    let (entry, channels) = with_hidden_channels(entry, options)?;
     let (mut local, child) = duplex::<Box<dyn FnOnce<(RawFd,), Output = i32>>, T>()?;
 
     let child_fd = child.as_raw_fd();
 
-    let pid = _spawn_child(child_fd, flags)?;
+    let pid = _spawn_child(child_fd, options)?;
+    // Close our copy of the child's end so that we notice if the child dies before the handshake
+    drop(child);
+
+    if let Err(e) = imp::verify_layout_checksum(local.recv_layout_checksum()?) {
+        let _ = signal::kill(pid, signal::Signal::SIGKILL);
+        let _ = nix::sys::wait::waitpid(pid, None);
+        return Err(e);
+    }
 
     local.send(&entry)?;
-    Ok(Child::new(pid, local.into_receiver()))
+    let mut child = Child::new(pid, local.into_receiver());
+    if let (Some(rx), Some(timeout)) = (channels.heartbeat_rx, options.heartbeat) {
+        child.heartbeat = Some(heartbeat::Monitor::new(
+            Receiver::from_raw_fd(rx.into_raw_fd()),
+            timeout,
+        )?);
+    }
+    if let Some(rx) = channels.log_rx {
+        log::forward(pid.as_raw(), Receiver::from_raw_fd(rx.into_raw_fd()));
+    }
+    Ok(child)
+}
+
+pub struct DetachedChild {
+    proc_pid: nix::unistd::Pid,
+    pidfd: OwnedFd,
+}
+
+impl DetachedChild {
+    pub fn kill(&mut self) -> Result<()> {
+        // The process is not our child, so its PID may be reused as soon as it terminates
+        if unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_pidfd_send_signal,
+                self.pidfd.as_raw_fd(),
+                nix::libc::SIGKILL,
+                std::ptr::null::<nix::libc::siginfo_t>(),
+                0,
+            )
+        } == -1
+        {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn id(&self) -> pid_t {
+        self.proc_pid.as_raw()
+    }
+
+    pub fn pidfd(&self) -> BorrowedFd<'_> {
+        self.pidfd.as_fd()
+    }
+
+    pub fn into_pidfd(self) -> OwnedFd {
+        self.pidfd
+    }
+}
+
+// A detached process has nobody to report its result to, but the entry still expects an output
+// channel. We hand it a socketpair and keep the other end open until the entry returns, so the
+// result is silently discarded. This only works for results that fit into the socket buffer, which
+// is fine for fire-and-forget helpers.
+#[derive(Object)]
+struct DetachedEntry(Box<dyn FnOnce<(RawFd,), Output = i32>>);
+
+impl std::ops::FnOnce<(RawFd,)> for DetachedEntry {
+    type Output = i32;
+    extern "rust-call" fn call_once(self, args: (RawFd,)) -> i32 {
+        // The intermediate process has no use for the connection
+        drop(unsafe { OwnedFd::from_raw_fd(args.0) });
+
+        let (output_tx, output_rx) = socket::socketpair(
+            socket::AddressFamily::Unix,
+            socket::SockType::SeqPacket,
+            None,
+            socket::SockFlag::SOCK_CLOEXEC,
+        )
+        .expect("Failed to create a socketpair for detached process output");
+        let _output_rx = unsafe { OwnedFd::from_raw_fd(output_rx) };
+
+        self.0(output_tx)
+    }
+}
+
+// Runs in the intermediate process of a double fork: starts the actual process in a new session
+// and exits right away, so that the process is reparented to init (or the nearest subreaper)
+#[derive(Object)]
+struct DetachIntermediate {
+    entry: Box<dyn FnOnce<(RawFd,), Output = i32>>,
+    options: SpawnOptions,
+}
+
+impl std::ops::FnOnce<(RawFd,)> for DetachIntermediate {
+    type Output = i32;
+    extern "rust-call" fn call_once(self, args: (RawFd,)) -> i32 {
+        let mut output_tx =
+            unsafe { Sender::<std::result::Result<(pid_t, OwnedFd), String>>::from_raw_fd(args.0) };
+
+        let result: Result<(pid_t, OwnedFd)> = try {
+            nix::unistd::setsid()?;
+
+            let child = unsafe { spawn::<()>(Box::new(DetachedEntry(self.entry)), &self.options)? };
+            let pid = child.id();
+
+            // We have not reaped the process, so the PID is guaranteed to be valid here, unlike in
+            // the original parent
+            let pidfd = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, pid, 0) };
+            if pidfd == -1 {
+                Err(Error::last_os_error())?;
+            }
+
+            (pid, unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) })
+        };
+
+        output_tx
+            .send(&result.map_err(|e| e.to_string()))
+            .expect("Failed to send detached process information");
+        0
+    }
+}
+
+// Starts a process that is not a child of the current process and does not report its result back.
+// This call blocks until the intermediate process terminates, which should not take long.
+pub unsafe fn spawn_detached(
+    entry: Box<dyn FnOnce<(RawFd,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<DetachedChild> {
+    // The hooks are lost when the options are passed to the intermediate process, so they are run
+    // there instead. Whatever they configure (rlimits, cgroups, capabilities) is inherited by the
+    // actual process.
+    let mut intermediate = spawn::<std::result::Result<(pid_t, OwnedFd), String>>(
+        Box::new(DetachIntermediate {
+            entry,
+            // Nobody would listen to the heartbeats or replay the logs
+            options: SpawnOptions {
+                heartbeat: None,
+                forward_logs: false,
+                ..options.clone()
+            },
+        }),
+        &SpawnOptions {
+            pre_exec: options.pre_exec.clone(),
+            ..SpawnOptions::new()
+        },
+    )?;
+    let (pid, pidfd) = intermediate
+        .join()
+        .map_err(|e| e.context("The intermediate process failed"))?
+        .map_err(Error::Child)?;
+    Ok(DetachedChild {
+        proc_pid: nix::unistd::Pid::from_raw(pid),
+        pidfd,
+    })
 }
@@ -1,11 +1,25 @@
 use crate::{
-    imp, ipc::MAX_PACKET_SIZE, subprocess, Deserialize, Deserializer, FnOnce, Object, Serialize,
+    batched::{Batch, Batcher},
+    heartbeat, imp,
+    ipc::{
+        compress, decompress, is_bulk, parse_bulk_header, parse_compression_header, BULK_MARKER,
+        BULK_PACKET_SIZE, COMPRESSED_MARKER, MAX_PACKET_SIZE,
+    },
+    log, subprocess, Deserialize, Deserializer, Error, FnOnce, Object, Result, Serialize,
     Serializer,
 };
+use futures::{future::poll_fn, ready, Stream};
 use nix::libc::pid_t;
-use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::collections::VecDeque;
+use std::io::{IoSlice, IoSliceMut};
 use std::marker::PhantomData;
-use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
 use tokio_seqpacket::{
     ancillary::{AncillaryData, SocketAncillary},
     UnixSeqpacket,
@@ -14,18 +28,24 @@ use tokio_seqpacket::{
 #[derive(Object)]
 pub struct Sender<T: Serialize> {
     fd: UnixSeqpacket,
+    compression_threshold: Option<usize>,
     marker: PhantomData<fn(T) -> T>,
 }
 
 #[derive(Object)]
 pub struct Receiver<T: Deserialize> {
     fd: UnixSeqpacket,
+    partial: PartialMessage,
+    // Set once the stream yields an error, see ipc::Receiver::next
+    failed: bool,
     marker: PhantomData<fn(T) -> T>,
 }
 
 #[derive(Object)]
 pub struct Duplex<S: Serialize, R: Deserialize> {
     fd: UnixSeqpacket,
+    partial: PartialMessage,
+    compression_threshold: Option<usize>,
     marker: PhantomData<fn(S, R) -> (S, R)>,
 }
 
@@ -46,28 +66,66 @@ pub fn duplex<A: Serialize + Deserialize, B: Serialize + Deserialize>(
     ))
 }
 
-async fn send_on_fd<T: Serialize>(fd: &mut UnixSeqpacket, value: &T) -> Result<()> {
+async fn send_on_fd<T: Serialize>(
+    fd: &mut UnixSeqpacket,
+    value: &T,
+    compression_threshold: Option<usize>,
+) -> Result<()> {
     let mut s = Serializer::new();
     s.serialize(value);
+    send_serialized_on_fd(fd, s, compression_threshold).await
+}
 
+async fn send_serialized_on_fd(
+    fd: &mut UnixSeqpacket,
+    mut s: Serializer,
+    compression_threshold: Option<usize>,
+) -> Result<()> {
     let fds = s.drain_fds();
-    let serialized = s.into_vec();
+    let (serialized, compression_header) = compress(s.into_vec(), compression_threshold);
 
     let mut ancillary_buffer = [0; 253];
 
+    // See ipc::COMPRESSED_MARKER
+    if let Some(header) = compression_header {
+        fd.send_vectored_with_ancillary(
+            &[IoSlice::new(&header)],
+            &mut SocketAncillary::new(&mut []),
+        )
+        .await?;
+    }
+
+    // See ipc::BULK_PACKET_SIZE
+    let bulk = is_bulk(serialized.len());
+    if bulk {
+        fd.send_vectored_with_ancillary(
+            &[
+                IoSlice::new(&[BULK_MARKER]),
+                IoSlice::new(&(serialized.len() as u64).to_le_bytes()),
+            ],
+            &mut SocketAncillary::new(&mut []),
+        )
+        .await?;
+    }
+    let packet_size = if bulk {
+        BULK_PACKET_SIZE
+    } else {
+        MAX_PACKET_SIZE
+    };
+
     // Send the data and pass file descriptors
     let mut buffer_pos: usize = 0;
     let mut fds_pos: usize = 0;
 
     loop {
-        let buffer_end = serialized.len().min(buffer_pos + MAX_PACKET_SIZE - 1);
+        let buffer_end = serialized.len().min(buffer_pos + packet_size - 1);
         let fds_end = fds.len().min(fds_pos + 253);
 
         let is_last = buffer_end == serialized.len() && fds_end == fds.len();
 
         let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
         if !ancillary.add_fds(&fds[fds_pos..fds_end]) {
-            return Err(Error::new(ErrorKind::Other, "Too many fds to pass"));
+            return Err(Error::FdPassing("Too many fds to pass".to_string()));
         }
 
         let n_written = fd
@@ -90,83 +148,160 @@ async fn send_on_fd<T: Serialize>(fd: &mut UnixSeqpacket, value: &T) -> Result<(
     Ok(())
 }
 
-async fn recv_on_fd<T: Deserialize>(fd: &mut UnixSeqpacket) -> Result<Option<T>> {
-    // Read the data and the passed file descriptors
-    let mut serialized: Vec<u8> = Vec::new();
-    let mut buffer_pos: usize = 0;
+// A message may span several packets, and the receiver may be polled in between, so the
+// incomplete part has to survive across polls. Keeping it in Receiver also makes recv cancel-safe
+#[derive(Default, Object)]
+struct PartialMessage {
+    serialized: Vec<u8>,
+    buffer_pos: usize,
+    received_fds: Vec<OwnedFd>,
+    bulk_len: Option<usize>,
+    original_len: Option<usize>,
+}
 
+fn poll_recv_on_fd<T: Deserialize>(
+    fd: &UnixSeqpacket,
+    partial: &mut PartialMessage,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Option<T>>> {
     let mut ancillary_buffer = [0; 253];
-    let mut received_fds: Vec<OwnedFd> = Vec::new();
 
+    // Read the data and the passed file descriptors
     loop {
-        serialized.resize(buffer_pos + MAX_PACKET_SIZE - 1, 0);
+        let buffer_pos = partial.buffer_pos;
+        let packet_size = if partial.bulk_len.is_some() {
+            BULK_PACKET_SIZE
+        } else {
+            MAX_PACKET_SIZE
+        };
+        partial.serialized.resize(buffer_pos + packet_size - 1, 0);
 
         let mut marker = [0];
         let mut ancillary = SocketAncillary::new(&mut ancillary_buffer[..]);
 
-        let n_read = fd
-            .recv_vectored_with_ancillary(
-                &mut [
-                    IoSliceMut::new(&mut marker),
-                    IoSliceMut::new(&mut serialized[buffer_pos..]),
-                ],
-                &mut ancillary,
-            )
-            .await?;
+        let n_read = ready!(fd.poll_recv_vectored_with_ancillary(
+            cx,
+            &mut [
+                IoSliceMut::new(&mut marker),
+                IoSliceMut::new(&mut partial.serialized[buffer_pos..]),
+            ],
+            &mut ancillary,
+        ))?;
 
         for cmsg in ancillary.messages() {
             if let Ok(AncillaryData::ScmRights(rights)) = cmsg {
                 for fd in rights {
-                    received_fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+                    partial
+                        .received_fds
+                        .push(unsafe { OwnedFd::from_raw_fd(fd) });
                 }
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Unexpected kind of cmsg on stream"),
-                ));
+                return Poll::Ready(Err(Error::FdPassing(
+                    "Unexpected kind of cmsg on stream".to_string(),
+                )));
             }
         }
 
         if ancillary.is_empty() && n_read == 0 {
-            if buffer_pos == 0 && received_fds.is_empty() {
-                return Ok(None);
+            if buffer_pos == 0 && partial.received_fds.is_empty() {
+                return Poll::Ready(Ok(None));
             } else {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Unterminated data on stream"),
-                ));
+                return Poll::Ready(Err(Error::Protocol(
+                    "Unterminated data on stream".to_string(),
+                )));
             }
         }
 
         if n_read == 0 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Unexpected empty message on stream"),
-            ));
+            return Poll::Ready(Err(Error::Protocol(
+                "Unexpected empty message on stream".to_string(),
+            )));
         }
 
-        buffer_pos += n_read - 1;
+        if marker[0] == COMPRESSED_MARKER {
+            partial.original_len = Some(parse_compression_header(
+                &partial.serialized[..n_read - 1],
+                buffer_pos,
+                partial.bulk_len,
+                partial.original_len,
+            )?);
+            continue;
+        }
+
+        if marker[0] == BULK_MARKER {
+            let len = parse_bulk_header(
+                &partial.serialized[..n_read - 1],
+                buffer_pos,
+                partial.bulk_len,
+            )?;
+            let additional = (len + BULK_PACKET_SIZE).saturating_sub(partial.serialized.len());
+            partial.serialized.reserve_exact(additional);
+            partial.bulk_len = Some(len);
+            continue;
+        }
+
+        partial.buffer_pos += n_read - 1;
         if marker[0] == 1 {
             break;
         }
     }
 
+    let PartialMessage {
+        mut serialized,
+        buffer_pos,
+        received_fds,
+        bulk_len,
+        original_len,
+    } = std::mem::take(partial);
+
+    if bulk_len.map_or(false, |len| len != buffer_pos) {
+        return Poll::Ready(Err(Error::Protocol(
+            "Bulk message length mismatch".to_string(),
+        )));
+    }
+
     serialized.truncate(buffer_pos);
+    let serialized = decompress(serialized, original_len)?;
 
     let mut d = Deserializer::from(serialized, received_fds);
-    Ok(Some(d.deserialize()))
+    Poll::Ready(Ok(Some(d.deserialize())))
+}
+
+async fn recv_on_fd<T: Deserialize>(
+    fd: &UnixSeqpacket,
+    partial: &mut PartialMessage,
+) -> Result<Option<T>> {
+    poll_fn(|cx| poll_recv_on_fd(fd, partial, cx)).await
 }
 
 impl<T: Serialize> Sender<T> {
     pub fn from_unix_seqpacket(fd: UnixSeqpacket) -> Self {
         Sender {
             fd,
+            compression_threshold: None,
             marker: PhantomData,
         }
     }
 
     pub async fn send(&mut self, value: &T) -> Result<()> {
-        send_on_fd(&mut self.fd, value).await
+        send_on_fd(&mut self.fd, value, self.compression_threshold).await
+    }
+
+    // See batched::BatchedSender. The batch is not flushed on drop, as that would require blocking
+    pub fn batched(self, max_bytes: usize, max_delay: Duration) -> BatchedSender<T> {
+        BatchedSender {
+            tx: Sender {
+                fd: self.fd,
+                compression_threshold: self.compression_threshold,
+                marker: PhantomData,
+            },
+            batcher: Batcher::new(max_bytes, max_delay),
+        }
+    }
+
+    // See ipc::Sender::set_compression_threshold
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
     }
 }
 
@@ -189,12 +324,40 @@ impl<T: Deserialize> Receiver<T> {
     pub fn from_unix_seqpacket(fd: UnixSeqpacket) -> Self {
         Receiver {
             fd,
+            partial: PartialMessage::default(),
+            failed: false,
             marker: PhantomData,
         }
     }
 
     pub async fn recv(&mut self) -> Result<Option<T>> {
-        recv_on_fd(&mut self.fd).await
+        recv_on_fd(&self.fd, &mut self.partial).await
+    }
+
+    pub fn batched(self) -> BatchedReceiver<T> {
+        BatchedReceiver {
+            rx: Receiver {
+                fd: self.fd,
+                partial: self.partial,
+                failed: self.failed,
+                marker: PhantomData,
+            },
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Deserialize> Stream for Receiver<T> {
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.failed {
+            return Poll::Ready(None);
+        }
+        let item = ready!(poll_recv_on_fd(&this.fd, &mut this.partial, cx)).transpose();
+        this.failed = matches!(item, Some(Err(_)));
+        Poll::Ready(item)
     }
 }
 
@@ -213,24 +376,111 @@ impl<T: Deserialize> FromRawFd for Receiver<T> {
     }
 }
 
+pub struct BatchedSender<T: Serialize> {
+    tx: Sender<Batch<T>>,
+    batcher: Batcher<T>,
+}
+
+impl<T: Serialize> BatchedSender<T> {
+    pub async fn send(&mut self, value: T) -> Result<()> {
+        if self.batcher.push(value) {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        match self.batcher.take() {
+            Some((s, _values)) => {
+                send_serialized_on_fd(&mut self.tx.fd, s, self.tx.compression_threshold).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Suitable for tokio::time::sleep_until in a select! next to whatever produces the messages
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.batcher.deadline()
+    }
+}
+
+pub struct BatchedReceiver<T: Deserialize> {
+    rx: Receiver<Batch<T>>,
+    pending: VecDeque<T>,
+}
+
+impl<T: Deserialize> BatchedReceiver<T> {
+    pub async fn recv(&mut self) -> Result<Option<T>> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<T>>> {
+        while self.pending.is_empty() {
+            match ready!(poll_recv_on_fd::<Batch<T>>(
+                &self.rx.fd,
+                &mut self.rx.partial,
+                cx
+            ))? {
+                Some(batch) => self.pending.extend(batch.values),
+                None => return Poll::Ready(Ok(None)),
+            }
+        }
+        Poll::Ready(Ok(self.pending.pop_front()))
+    }
+}
+
+// The messages are never pinned
+impl<T: Deserialize> Unpin for BatchedReceiver<T> {}
+
+impl<T: Deserialize> Stream for BatchedReceiver<T> {
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.rx.failed {
+            return Poll::Ready(None);
+        }
+        let item = ready!(this.poll_recv(cx)).transpose();
+        this.rx.failed = matches!(item, Some(Err(_)));
+        Poll::Ready(item)
+    }
+}
+
 impl<S: Serialize, R: Deserialize> Duplex<S, R> {
     pub fn from_unix_seqpacket(fd: UnixSeqpacket) -> Self {
         Duplex {
             fd,
+            partial: PartialMessage::default(),
+            compression_threshold: None,
             marker: PhantomData,
         }
     }
 
     pub async fn send(&mut self, value: &S) -> Result<()> {
-        send_on_fd(&mut self.fd, value).await
+        send_on_fd(&mut self.fd, value, self.compression_threshold).await
     }
 
     pub async fn recv(&mut self) -> Result<Option<R>> {
-        recv_on_fd(&mut self.fd).await
+        recv_on_fd(&self.fd, &mut self.partial).await
+    }
+
+    // See ipc::Sender::set_compression_threshold. Only applies to this side of the duplex
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
     }
 
     pub fn into_receiver(self) -> Receiver<R> {
-        Receiver::from_unix_seqpacket(self.fd)
+        Receiver {
+            fd: self.fd,
+            partial: self.partial,
+            failed: false,
+            marker: PhantomData,
+        }
+    }
+
+    // The handshake happens before the channel is used for R, see imp::layout_checksum
+    pub(crate) async fn recv_layout_checksum(&mut self) -> Result<Option<u64>> {
+        recv_on_fd(&self.fd, &mut self.partial).await
     }
 }
 
@@ -251,19 +501,62 @@ impl<S: Serialize, R: Deserialize> FromRawFd for Duplex<S, R> {
 
 pub struct Child<T: Deserialize> {
     proc_pid: nix::unistd::Pid,
+    pidfd: AsyncFd<OwnedFd>,
     output_rx: Receiver<T>,
+    exit_status: Option<ExitStatus>,
+    heartbeat: Option<heartbeat::Watcher>,
 }
 
 impl<T: Deserialize> Child<T> {
-    pub fn new(proc_pid: nix::unistd::Pid, output_rx: Receiver<T>) -> Child<T> {
-        Child {
+    pub fn new(proc_pid: nix::unistd::Pid, output_rx: Receiver<T>) -> Result<Child<T>> {
+        let pidfd = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, proc_pid.as_raw(), 0) };
+        if pidfd == -1 {
+            return Err(Error::last_os_error());
+        }
+        let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+        Ok(Child {
             proc_pid,
+            pidfd: AsyncFd::new(pidfd)?,
             output_rx,
-        }
+            exit_status: None,
+            heartbeat: None,
+        })
+    }
+
+    // False if the child has terminated or, if it was spawned with heartbeats, has not sent one for
+    // too long. Always checks the process only after watcher() is called.
+    pub fn is_alive(&mut self) -> Result<bool> {
+        Ok(self.try_wait()?.is_none()
+            && self
+                .heartbeat
+                .as_mut()
+                .map_or(true, |watcher| watcher.is_responsive()))
+    }
+
+    // Detaches the heartbeat watcher, so that it can be awaited concurrently with join(), e.g.
+    //     tokio::select! {
+    //         result = child.join() => ...,
+    //         _ = watcher.unresponsive() => child.kill()?,
+    //     }
+    // Returns None if the child was spawned without heartbeats or the watcher was already taken.
+    pub fn watcher(&mut self) -> Option<heartbeat::Watcher> {
+        self.heartbeat.take()
     }
 
     pub fn kill(&mut self) -> Result<()> {
-        nix::sys::signal::kill(self.proc_pid, nix::sys::signal::Signal::SIGKILL)?;
+        // Signalling via pidfd is safe against PID reuse
+        if unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_pidfd_send_signal,
+                self.pidfd.as_raw_fd(),
+                nix::libc::SIGKILL,
+                std::ptr::null::<nix::libc::siginfo_t>(),
+                0,
+            )
+        } == -1
+        {
+            return Err(Error::last_os_error());
+        }
         Ok(())
     }
 
@@ -271,39 +564,123 @@ impl<T: Deserialize> Child<T> {
         self.proc_pid.as_raw()
     }
 
+    pub fn pidfd(&self) -> BorrowedFd<'_> {
+        self.pidfd.get_ref().as_fd()
+    }
+
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.exit_status.is_none() {
+            let mut status = 0;
+            match unsafe {
+                nix::libc::waitpid(self.proc_pid.as_raw(), &mut status, nix::libc::WNOHANG)
+            } {
+                -1 => return Err(Error::last_os_error()),
+                0 => {}
+                _ => self.exit_status = Some(ExitStatus::from_raw(status)),
+            }
+        }
+        Ok(self.exit_status)
+    }
+
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            // pidfd becomes readable when the process terminates
+            self.pidfd.readable().await?.clear_ready();
+        }
+    }
+
     pub async fn join(&mut self) -> Result<T> {
         let value = self.output_rx.recv().await?;
-        // This is synchronous, but should be really fast
-        let status = nix::sys::wait::waitpid(self.proc_pid, None)?;
-        if let nix::sys::wait::WaitStatus::Exited(_, 0) = status {
+        let status = self.wait().await?;
+        if status.success() {
             value.ok_or_else(|| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "The subprocess terminated without returning a value",
-                )
+                Error::Child("The subprocess terminated without returning a value".to_string())
             })
         } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "The subprocess did not terminate successfully: {:?}",
-                    status
-                ),
-            ))
+            Err(Error::Child(format!(
+                "The subprocess did not terminate successfully: {:?}",
+                status
+            )))
+        }
+    }
+}
+
+// See subprocess::StreamingChild
+pub struct StreamingChild<T: Deserialize> {
+    child: Child<T>,
+}
+
+impl<T: Deserialize> StreamingChild<T> {
+    pub fn kill(&mut self) -> Result<()> {
+        self.child.kill()
+    }
+
+    pub fn id(&self) -> pid_t {
+        self.child.id()
+    }
+
+    pub fn pidfd(&self) -> BorrowedFd<'_> {
+        self.child.pidfd()
+    }
+
+    pub fn output(&mut self) -> &mut Receiver<T> {
+        &mut self.child.output_rx
+    }
+
+    pub async fn join(&mut self) -> Result<()> {
+        while self.child.output_rx.recv().await?.is_some() {}
+        let status = self.child.wait().await?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::Child(format!(
+                "The subprocess did not terminate successfully: {:?}",
+                status
+            )))
         }
     }
 }
 
+impl<T: Deserialize> From<Child<T>> for StreamingChild<T> {
+    fn from(child: Child<T>) -> Self {
+        StreamingChild { child }
+    }
+}
+
 pub async unsafe fn spawn<T: Object>(
     entry: Box<dyn FnOnce<(RawFd,), Output = i32>>,
-    flags: nix::libc::c_int,
+    options: &subprocess::SpawnOptions,
 ) -> Result<Child<T>> {
+    let (entry, channels) = subprocess::with_hidden_channels(entry, options)?;
+
     let (mut local, child) = duplex::<Box<dyn FnOnce<(RawFd,), Output = i32>>, T>()?;
 
     let child_fd = child.as_raw_fd();
 
-    let pid = subprocess::_spawn_child(child_fd, flags)?;
+    let pid = subprocess::_spawn_child(child_fd, options)?;
+    // Close our copy of the child's end so that we notice if the child dies before the handshake
+    drop(child);
+
+    if let Err(e) = imp::verify_layout_checksum(local.recv_layout_checksum().await?) {
+        let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+        // This is synchronous, but the process is dead by now
+        let _ = nix::sys::wait::waitpid(pid, None);
+        return Err(e);
+    }
 
     local.send(&entry).await?;
-    Ok(Child::new(pid, local.into_receiver()))
+    let mut child = Child::new(pid, local.into_receiver())?;
+    if let (Some(rx), Some(timeout)) = (channels.heartbeat_rx, options.heartbeat) {
+        child.heartbeat = Some(heartbeat::Watcher::new(
+            Receiver::from_raw_fd(rx.into_raw_fd()),
+            timeout,
+        ));
+    }
+    if let Some(rx) = channels.log_rx {
+        log::forward_tokio(pid.as_raw(), Receiver::from_raw_fd(rx.into_raw_fd()));
+    }
+    Ok(child)
 }
@@ -1,4 +1,8 @@
-use multiprocessing::{channel, duplex, Bind, Duplex, Object, Receiver, Sender, TraitObject};
+use multiprocessing::{
+    channel, duplex, Bind, Duplex, Object, Receiver, RemoteFn, RemoteFnMut, SchedPolicy, Sender,
+    SpawnOptions, TraitObject, Value,
+};
+use std::os::unix::io::AsRawFd;
 
 #[derive(Debug, PartialEq, Object)]
 struct SimplePair {
@@ -6,6 +10,27 @@ struct SimplePair {
     y: i32,
 }
 
+#[derive(Debug, PartialEq, Object)]
+struct WithAttributes {
+    x: i32,
+    #[object(skip)]
+    cache: Option<String>,
+    #[object(with = "negated")]
+    y: i32,
+}
+
+mod negated {
+    use multiprocessing::{Deserializer, Serializer};
+
+    pub fn serialize(value: &i32, s: &mut Serializer) {
+        s.serialize(&-*value);
+    }
+
+    pub fn deserialize(d: &mut Deserializer) -> i32 {
+        -d.deserialize::<i32>()
+    }
+}
+
 #[multiprocessing::entrypoint]
 fn simple() -> i64 {
     0x123456789abcdef
@@ -34,6 +59,11 @@ fn swap_complex_argument(pair: SimplePair) -> SimplePair {
     }
 }
 
+#[multiprocessing::entrypoint]
+fn with_attributes(value: WithAttributes) -> WithAttributes {
+    value
+}
+
 #[multiprocessing::entrypoint]
 fn inc_with_boxed(item: Box<i32>) -> Box<i32> {
     Box::new(*item + 1)
@@ -66,6 +96,30 @@ fn with_passed_trait(arg: Box<dyn Trait>) -> String {
     arg.say()
 }
 
+#[multiprocessing::portable_trait]
+trait PortableTrait {
+    fn say(&self) -> String;
+}
+
+#[multiprocessing::portable_trait]
+impl PortableTrait for ImplA {
+    fn say(&self) -> String {
+        format!("Portable ImplA says: {}", self.0)
+    }
+}
+
+#[multiprocessing::portable_trait]
+impl PortableTrait for ImplB {
+    fn say(&self) -> String {
+        format!("Portable ImplB says: {}", self.0)
+    }
+}
+
+#[multiprocessing::entrypoint]
+fn with_passed_portable_trait(arg: Vec<Box<dyn PortableTrait>>) -> Vec<String> {
+    arg.iter().map(|item| item.say()).collect()
+}
+
 #[multiprocessing::entrypoint]
 fn with_passed_fn(func: Box<dyn multiprocessing::FnOnce<(i32, i32), Output = i32>>) -> i32 {
     func(5, 7)
@@ -88,12 +142,60 @@ fn with_passed_rx(mut rx: Receiver<i32>) -> i32 {
     a - b
 }
 
+#[multiprocessing::entrypoint]
+fn with_passed_rx_iter(rx: Receiver<Vec<u8>>) -> usize {
+    rx.map(|chunk| chunk.unwrap().len()).sum()
+}
+
+#[multiprocessing::entrypoint]
+fn with_passed_rx_checksums(rx: Receiver<Vec<u8>>) -> Vec<u64> {
+    rx.map(|chunk| chunk.unwrap().into_iter().map(|x| x as u64).sum())
+        .collect()
+}
+
+#[multiprocessing::entrypoint]
+fn with_passed_rx_timeout(mut rx: Receiver<i32>) -> (bool, i32) {
+    let timed_out = rx
+        .recv_timeout(std::time::Duration::from_millis(50))
+        .unwrap_err()
+        .kind()
+        == std::io::ErrorKind::TimedOut;
+    let value = rx
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    (timed_out, value)
+}
+
 #[multiprocessing::entrypoint]
 fn with_passed_tx(mut tx: Sender<i32>) -> () {
     tx.send(&5).unwrap();
     tx.send(&7).unwrap();
 }
 
+#[multiprocessing::entrypoint]
+fn with_passed_batched_tx(tx: Sender<String>) -> () {
+    // Everything fits into a few batches, the last of which is sent on drop
+    let mut tx = tx.batched(4096, std::time::Duration::from_secs(3600));
+    for i in 0..1000 {
+        tx.send(i.to_string()).unwrap();
+    }
+}
+
+// Reads the parameters the caller is known to send and passes the rest through unchanged
+#[multiprocessing::entrypoint]
+fn inspect_value(value: Value) -> (Option<i64>, Value) {
+    (value.get("tests").and_then(Value::as_i64), value)
+}
+
+#[multiprocessing::entrypoint]
+fn echo_compressed(mut chan: Duplex<Vec<u8>, Vec<u8>>) -> () {
+    chan.set_compression_threshold(Some(1024));
+    while let Some(data) = chan.recv().unwrap() {
+        chan.send(&data).unwrap();
+    }
+}
+
 #[multiprocessing::entrypoint]
 fn with_passed_duplex(mut chan: Duplex<i32, (i32, i32)>) -> () {
     while let Some((x, y)) = chan.recv().unwrap() {
@@ -101,6 +203,115 @@ fn with_passed_duplex(mut chan: Duplex<i32, (i32, i32)>) -> () {
     }
 }
 
+#[multiprocessing::entrypoint]
+fn with_passed_tcp_stream(mut stream: std::net::TcpStream) -> () {
+    use std::io::Write;
+    stream.write_all(b"hello").unwrap();
+}
+
+#[multiprocessing::entrypoint]
+fn detached_report_sid(path: String) -> () {
+    let sid = unsafe { multiprocessing::libc::getsid(0) };
+    std::fs::write(path, sid.to_string()).unwrap();
+}
+
+#[multiprocessing::entrypoint]
+fn report_scheduling() -> ((i32, i32), Vec<usize>) {
+    unsafe {
+        let nice = multiprocessing::libc::getpriority(multiprocessing::libc::PRIO_PROCESS, 0);
+        let policy = multiprocessing::libc::sched_getscheduler(0);
+        ((nice, policy), get_affinity())
+    }
+}
+
+unsafe fn get_affinity() -> Vec<usize> {
+    let mut cpu_set: multiprocessing::libc::cpu_set_t = std::mem::zeroed();
+    assert_eq!(
+        multiprocessing::libc::sched_getaffinity(
+            0,
+            std::mem::size_of::<multiprocessing::libc::cpu_set_t>(),
+            &mut cpu_set
+        ),
+        0
+    );
+    (0..multiprocessing::libc::CPU_SETSIZE as usize)
+        .filter(|cpu| multiprocessing::libc::CPU_ISSET(*cpu, &cpu_set))
+        .collect()
+}
+
+#[multiprocessing::entrypoint]
+fn report_nofile_limit() -> u64 {
+    let mut rlimit = multiprocessing::libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    assert_eq!(
+        unsafe {
+            multiprocessing::libc::getrlimit(multiprocessing::libc::RLIMIT_NOFILE, &mut rlimit)
+        },
+        0
+    );
+    rlimit.rlim_cur
+}
+
+#[multiprocessing::entrypoint]
+fn exit_with_failure() -> i32 {
+    std::process::exit(3);
+}
+
+#[multiprocessing::entrypoint]
+fn next_call_number() -> u32 {
+    static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+    COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+#[multiprocessing::entrypoint(stream)]
+fn squares(n: u64) -> impl Iterator<Item = u64> {
+    (1..=n).map(|i| i * i)
+}
+
+#[multiprocessing::entrypoint]
+fn report_layout_checksum() -> u64 {
+    multiprocessing::imp::layout_checksum()
+}
+
+#[multiprocessing::entrypoint]
+fn beat_then_hang(beats: u32) {
+    for _ in 0..beats {
+        multiprocessing::heartbeat();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    std::thread::sleep(std::time::Duration::from_secs(1000));
+}
+
+#[multiprocessing::entrypoint]
+fn report_pid() -> i32 {
+    std::process::id() as i32
+}
+
+#[multiprocessing::entrypoint]
+fn log_something() {
+    log::info!("hello from child");
+    log::debug!("filtered out");
+}
+
+struct LogCollector(std::sync::Mutex<Vec<String>>);
+
+impl log::Log for LogCollector {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
 #[multiprocessing::main]
 fn main() {
     assert_eq!(
@@ -152,6 +363,24 @@ fn main() {
     );
     println!("swap_complex_argument OK");
 
+    assert_eq!(
+        with_attributes
+            .spawn(WithAttributes {
+                x: 5,
+                cache: Some("hello".to_string()),
+                y: 7,
+            })
+            .unwrap()
+            .join()
+            .expect("with_attributes failed"),
+        WithAttributes {
+            x: 5,
+            cache: None,
+            y: 7,
+        }
+    );
+    println!("with_attributes OK");
+
     assert_eq!(
         *inc_with_boxed
             .spawn(Box::new(7))
@@ -180,6 +409,19 @@ fn main() {
     );
     println!("with_passed_trait OK");
 
+    assert_eq!(
+        with_passed_portable_trait
+            .spawn(vec![
+                Box::new(ImplA("hello".to_string())),
+                Box::new(ImplB(5)),
+            ])
+            .unwrap()
+            .join()
+            .expect("with_passed_portable_trait failed"),
+        vec!["Portable ImplA says: hello", "Portable ImplB says: 5"]
+    );
+    println!("with_passed_portable_trait OK");
+
     assert_eq!(
         with_passed_fn
             .spawn(Box::new(add_with_arguments))
@@ -219,6 +461,46 @@ fn main() {
         println!("with_passed_rx OK");
     }
 
+    {
+        let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+        let mut child = with_passed_rx_iter.spawn(rx).unwrap();
+        tx.send(&vec![1; 5]).unwrap();
+        tx.send(&vec![2; 100000]).unwrap();
+        drop(tx);
+        assert_eq!(child.join().expect("with_passed_rx_iter failed"), 100005);
+        println!("with_passed_rx_iter OK");
+    }
+
+    {
+        // Large enough to be sent in bulk mode, interleaved with small messages
+        let big: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        let big_sum: u64 = big.iter().map(|x| *x as u64).sum();
+        let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+        let mut child = with_passed_rx_checksums.spawn(rx).unwrap();
+        tx.send(&vec![1; 5]).unwrap();
+        tx.send(&big).unwrap();
+        tx.send(&vec![2; 5]).unwrap();
+        tx.send(&big).unwrap();
+        drop(tx);
+        assert_eq!(
+            child.join().expect("with_passed_rx_checksums failed"),
+            vec![5, big_sum, 10, big_sum]
+        );
+        println!("with_passed_rx_checksums OK");
+    }
+
+    {
+        let (mut tx, rx) = channel::<i32>().unwrap();
+        let mut child = with_passed_rx_timeout.spawn(rx).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        tx.send(&5).unwrap();
+        assert_eq!(
+            child.join().expect("with_passed_rx_timeout failed"),
+            (true, 5)
+        );
+        println!("with_passed_rx_timeout OK");
+    }
+
     {
         let (tx, mut rx) = channel::<i32>().unwrap();
         let mut child = with_passed_tx.spawn(tx).unwrap();
@@ -230,6 +512,310 @@ fn main() {
         println!("with_passed_tx OK");
     }
 
+    {
+        let (tx, rx) = channel::<String>().unwrap();
+        let mut child = with_passed_batched_tx.spawn(tx).unwrap();
+        let received: Vec<String> = rx.batched().map(|value| value.unwrap()).collect();
+        child.join().unwrap();
+        assert_eq!(
+            received,
+            (0..1000).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+
+        // A zero delay makes every message due immediately
+        let (tx, rx) = channel::<i32>().unwrap();
+        let (mut tx, mut rx) = (
+            tx.batched(usize::MAX, std::time::Duration::ZERO),
+            rx.batched(),
+        );
+        tx.send(5).unwrap();
+        assert_eq!(rx.recv().unwrap(), Some(5));
+        assert_eq!(tx.deadline(), None);
+
+        let (tx, rx) = channel::<i32>().unwrap();
+        let (mut tx, mut rx) = (
+            tx.batched(usize::MAX, std::time::Duration::from_secs(3600)),
+            rx.batched(),
+        );
+        tx.send(5).unwrap();
+        tx.send(7).unwrap();
+        assert!(tx.deadline().is_some());
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+        tx.flush().unwrap();
+        assert_eq!(tx.deadline(), None);
+        assert_eq!(rx.recv().unwrap(), Some(5));
+        assert_eq!(rx.recv().unwrap(), Some(7));
+        println!("batched OK");
+    }
+
+    {
+        let mut state: u64 = 1;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        };
+        let text: Vec<u8> = (0..200_000)
+            .flat_map(|i| format!("test {} passed in {} ms\n", i % 1000, i % 37).into_bytes())
+            .collect();
+        let samples: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![1; 5],
+            vec![0; 2000],
+            (0..5000).map(|_| random()).collect(),
+            (0..300_000).map(|i| (i / 1000) as u8).collect(),
+            // Long enough to be sent in bulk mode even after compression
+            text,
+        ];
+
+        let (mut local, downstream) = duplex::<Vec<u8>, Vec<u8>>().unwrap();
+        local.set_compression_threshold(Some(1024));
+        let mut child = echo_compressed.spawn(downstream).unwrap();
+        for sample in &samples {
+            local.send(sample).unwrap();
+            assert!(local.recv().unwrap().unwrap() == *sample);
+        }
+        drop(local);
+        child.join().unwrap();
+        println!("compression OK");
+    }
+
+    {
+        // The receiver must neither trust the declared length nor read past the end of the data
+        let malformed: [(u64, &[u8]); 3] = [
+            // Far more than two bytes can expand to
+            (1 << 40, &[0x10, b'x']),
+            // Truncated: the token announces 15 literals
+            (100, &[0xf0]),
+            // A match that refers to data before the start of the output
+            (100, &[0x00, 0xff, 0xff]),
+        ];
+        for (original_len, data) in malformed {
+            let (tx, mut rx) = channel::<Vec<u8>>().unwrap();
+            let mut header = vec![3u8];
+            header.extend_from_slice(&original_len.to_le_bytes());
+            let mut packet = vec![1u8];
+            packet.extend_from_slice(data);
+            for packet in [header, packet] {
+                nix::sys::socket::send(
+                    tx.as_raw_fd(),
+                    &packet,
+                    nix::sys::socket::MsgFlags::empty(),
+                )
+                .unwrap();
+            }
+            assert!(matches!(
+                rx.recv(),
+                Err(multiprocessing::Error::Protocol(_))
+            ));
+        }
+        println!("malformed compression OK");
+    }
+
+    {
+        let value: Value = [
+            ("tests", Value::from(42)),
+            ("nothing", Value::Null),
+            ("enabled", Value::from(true)),
+            ("ratio", Value::from(0.5)),
+            ("name", Value::from("checker")),
+            ("blob", Value::from(vec![0u8, 255])),
+            (
+                "nested",
+                [
+                    Value::from(1),
+                    Value::from(Some("two")),
+                    Value::from(None::<i32>),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let (tests, echoed) = inspect_value
+            .spawn(value.clone())
+            .unwrap()
+            .join()
+            .expect("inspect_value failed");
+        assert_eq!(tests, Some(42));
+        assert_eq!(echoed, value);
+        assert_eq!(echoed.get("missing"), None);
+        assert_eq!(Value::from(1).get("tests"), None);
+        println!("value OK");
+    }
+
+    {
+        use std::io::Read;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut child = with_passed_tcp_stream.spawn(stream).unwrap();
+        child.join().expect("with_passed_tcp_stream failed");
+        let mut received = String::new();
+        peer.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello");
+        println!("with_passed_tcp_stream OK");
+    }
+
+    {
+        let path = format!("/tmp/multiprocessing-detached-{}", std::process::id());
+        let child = detached_report_sid.spawn_detached(path.clone()).unwrap();
+        // pidfd becomes readable when the process terminates
+        let mut pollfd = multiprocessing::libc::pollfd {
+            fd: child.pidfd().as_raw_fd(),
+            events: multiprocessing::libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(
+            unsafe { multiprocessing::libc::poll(&mut pollfd, 1, 5000) },
+            1
+        );
+        let sid: i32 = std::fs::read_to_string(&path).unwrap().parse().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(sid, unsafe { multiprocessing::libc::getsid(0) });
+        println!("detached_report_sid OK");
+    }
+
+    {
+        let cpu = unsafe { get_affinity() }[0];
+        let options = SpawnOptions::new()
+            .nice(19)
+            .sched_policy(SchedPolicy::Batch)
+            .cpu_affinity(vec![cpu]);
+        assert_eq!(
+            unsafe { report_scheduling.spawn_with_options(&options) }
+                .unwrap()
+                .join()
+                .expect("report_scheduling failed"),
+            ((19, multiprocessing::libc::SCHED_BATCH), vec![cpu])
+        );
+        println!("spawn_with_options OK");
+    }
+
+    {
+        let options = SpawnOptions::new().heartbeat(std::time::Duration::from_millis(200));
+        let mut child = unsafe { beat_then_hang.spawn_with_options(&options, 4) }.unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(child.is_alive().unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        assert!(!child.is_alive().unwrap());
+        child.kill().unwrap();
+        assert!(child.join().is_err());
+        println!("heartbeat OK");
+    }
+
+    {
+        let collector: &'static LogCollector =
+            Box::leak(Box::new(LogCollector(std::sync::Mutex::new(Vec::new()))));
+        log::set_logger(collector).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let options = SpawnOptions::new().forward_logs();
+        let mut child = unsafe { log_something.spawn_with_options(&options) }.unwrap();
+        let pid = child.id();
+        child.join().expect("log_something failed");
+
+        // Records are replayed on a background thread, so they may arrive after join() returns
+        for _ in 0..100 {
+            if !collector.0.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(
+            *collector.0.lock().unwrap(),
+            vec![format!("INFO [pid {pid}] hello from child")]
+        );
+        println!("forward_logs OK");
+    }
+
+    {
+        let options = unsafe {
+            SpawnOptions::new().pre_exec(|| {
+                let rlimit = multiprocessing::libc::rlimit {
+                    rlim_cur: 100,
+                    rlim_max: 100,
+                };
+                if multiprocessing::libc::setrlimit(multiprocessing::libc::RLIMIT_NOFILE, &rlimit)
+                    == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        assert_eq!(
+            unsafe { report_nofile_limit.spawn_with_options(&options) }
+                .unwrap()
+                .join()
+                .expect("report_nofile_limit failed"),
+            100
+        );
+        println!("pre_exec OK");
+    }
+
+    {
+        let options = SpawnOptions::new().vfork();
+        assert_eq!(
+            unsafe { simple.spawn_with_options(&options) }
+                .unwrap()
+                .join()
+                .expect("simple failed"),
+            0x123456789abcdef
+        );
+
+        // Hooks run in the child, so their effects don't leak into the parent
+        let options = unsafe {
+            SpawnOptions::new().vfork().pre_exec(|| {
+                let rlimit = multiprocessing::libc::rlimit {
+                    rlim_cur: 50,
+                    rlim_max: 50,
+                };
+                if multiprocessing::libc::setrlimit(multiprocessing::libc::RLIMIT_NOFILE, &rlimit)
+                    == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        assert_eq!(
+            unsafe { report_nofile_limit.spawn_with_options(&options) }
+                .unwrap()
+                .join()
+                .expect("report_nofile_limit failed"),
+            50
+        );
+        assert_ne!(report_nofile_limit(), 50);
+
+        // A failing hook makes the child abort before exec
+        let options = unsafe {
+            SpawnOptions::new()
+                .vfork()
+                .pre_exec(|| Err(std::io::Error::from_raw_os_error(1)))
+        };
+        assert!(unsafe { simple.spawn_with_options(&options) }.is_err());
+
+        // Namespace flags still apply. Creating a PID namespace requires root
+        if unsafe { multiprocessing::libc::geteuid() } == 0 {
+            let options = SpawnOptions::new()
+                .vfork()
+                .flags(multiprocessing::libc::CLONE_NEWPID);
+            assert_eq!(
+                unsafe { report_pid.spawn_with_options(&options) }
+                    .unwrap()
+                    .join()
+                    .expect("report_pid failed"),
+                1
+            );
+        }
+        println!("vfork OK");
+    }
+
     {
         let (mut local, downstream) = duplex::<(i32, i32), i32>().unwrap();
         let mut child = with_passed_duplex.spawn(downstream).unwrap();
@@ -241,4 +827,58 @@ fn main() {
         child.join().unwrap();
         println!("with_passed_duplex OK");
     }
+
+    {
+        let add = RemoteFn::new(add_with_arguments.bind(5)).unwrap();
+        assert_eq!(add.call((7,)).unwrap(), 12);
+        assert_eq!(add.call((-5,)).unwrap(), 0);
+        add.join().expect("remote add_with_arguments failed");
+        println!("remote_fn OK");
+    }
+
+    {
+        // The calls land in the same process, so the counter persists
+        let mut counter = RemoteFnMut::new(next_call_number).unwrap();
+        let numbers: Vec<u32> = (0..3).map(|_| counter.call(()).unwrap()).collect();
+        assert_eq!(numbers, vec![0, 1, 2]);
+        counter.join().expect("remote next_call_number failed");
+        println!("remote_fn_mut OK");
+    }
+
+    {
+        let mut child = squares.spawn(5).unwrap();
+        let values: Vec<u64> = child.output().map(|value| value.unwrap()).collect();
+        assert_eq!(values, vec![1, 4, 9, 16, 25]);
+        child.join().expect("squares failed");
+        println!("squares OK");
+    }
+
+    assert!(matches!(
+        exit_with_failure.spawn().unwrap().join(),
+        Err(multiprocessing::Error::Child(_))
+    ));
+    println!("exit_with_failure OK");
+
+    assert_eq!(
+        report_layout_checksum
+            .spawn()
+            .unwrap()
+            .join()
+            .expect("report_layout_checksum failed"),
+        multiprocessing::imp::layout_checksum()
+    );
+    println!("report_layout_checksum OK");
+
+    // The child inherits the variable and reports a checksum that differs from ours
+    std::env::set_var(multiprocessing::imp::LAYOUT_SALT_VAR, "1");
+    let result = report_layout_checksum.spawn();
+    std::env::remove_var(multiprocessing::imp::LAYOUT_SALT_VAR);
+    match result {
+        Err(multiprocessing::Error::Serialization(message)) => {
+            assert!(message.contains("Layout checksum mismatch"), "{message}")
+        }
+        Err(e) => panic!("report_layout_checksum failed with an unexpected error: {e}"),
+        Ok(_) => panic!("report_layout_checksum was started despite a layout mismatch"),
+    }
+    println!("report_layout_checksum mismatch OK");
 }
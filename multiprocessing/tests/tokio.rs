@@ -1,3 +1,4 @@
+use futures::StreamExt;
 use multiprocessing::tokio::{channel, duplex, Duplex, Receiver, Sender};
 use multiprocessing::Object;
 
@@ -36,6 +37,22 @@ async fn with_passed_rx(mut rx: Receiver<i32>) -> i32 {
     a - b
 }
 
+#[multiprocessing::entrypoint]
+#[tokio::main]
+async fn with_passed_rx_stream(rx: Receiver<Vec<u8>>) -> usize {
+    rx.map(|chunk| chunk.unwrap().len())
+        .fold(0, |acc, len| async move { acc + len })
+        .await
+}
+
+#[multiprocessing::entrypoint]
+#[tokio::main]
+async fn with_passed_rx_checksums(rx: Receiver<Vec<u8>>) -> Vec<u64> {
+    rx.map(|chunk| chunk.unwrap().into_iter().map(|x| x as u64).sum::<u64>())
+        .collect()
+        .await
+}
+
 #[multiprocessing::entrypoint]
 #[tokio::main]
 async fn with_passed_tx(mut tx: Sender<i32>) -> () {
@@ -43,6 +60,17 @@ async fn with_passed_tx(mut tx: Sender<i32>) -> () {
     tx.send(&7).await.unwrap();
 }
 
+#[multiprocessing::entrypoint]
+#[tokio::main]
+async fn with_passed_batched_tx(tx: Sender<String>) -> () {
+    let mut tx = tx.batched(4096, std::time::Duration::from_secs(3600));
+    for i in 0..1000 {
+        tx.send(i.to_string()).await.unwrap();
+    }
+    // Dropping a tokio sender loses the pending batch
+    tx.flush().await.unwrap();
+}
+
 #[multiprocessing::entrypoint]
 #[tokio::main]
 async fn with_passed_duplex(mut chan: Duplex<i32, (i32, i32)>) -> () {
@@ -51,6 +79,54 @@ async fn with_passed_duplex(mut chan: Duplex<i32, (i32, i32)>) -> () {
     }
 }
 
+#[multiprocessing::entrypoint(stream)]
+#[tokio::main]
+async fn with_passed_rx_running_sum(rx: Receiver<i32>) -> impl futures::Stream<Item = i32> {
+    rx.scan(0, |sum, value| {
+        *sum += value.unwrap();
+        futures::future::ready(Some(*sum))
+    })
+}
+
+#[multiprocessing::entrypoint]
+#[tokio::main]
+async fn exit_with_code(code: i32) -> () {
+    std::process::exit(code);
+}
+
+#[multiprocessing::entrypoint]
+#[tokio::main(flavor = "current_thread")]
+async fn beat_then_hang(beats: u32) {
+    for _ in 0..beats {
+        multiprocessing::heartbeat();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    std::thread::sleep(std::time::Duration::from_secs(1000));
+}
+
+#[multiprocessing::entrypoint]
+#[tokio::main(flavor = "current_thread")]
+async fn log_something() {
+    log::warn!("hello from child");
+}
+
+struct LogCollector(std::sync::Mutex<Vec<String>>);
+
+impl log::Log for LogCollector {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
 #[multiprocessing::main]
 #[tokio::main]
 async fn main() {
@@ -102,6 +178,49 @@ async fn main() {
         println!("with_passed_rx OK");
     }
 
+    {
+        let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+        let mut child = with_passed_rx_stream.spawn_tokio(rx).await.unwrap();
+        tx.send(&vec![1; 5]).await.unwrap();
+        tx.send(&vec![2; 100000]).await.unwrap();
+        drop(tx);
+        assert_eq!(
+            child.join().await.expect("with_passed_rx_stream failed"),
+            100005
+        );
+        println!("with_passed_rx_stream OK");
+    }
+
+    {
+        let big: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+        let big_sum: u64 = big.iter().map(|x| *x as u64).sum();
+        let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+        let mut child = with_passed_rx_checksums.spawn_tokio(rx).await.unwrap();
+        tx.send(&vec![1; 5]).await.unwrap();
+        tx.send(&big).await.unwrap();
+        tx.send(&vec![2; 5]).await.unwrap();
+        tx.send(&big).await.unwrap();
+        drop(tx);
+        assert_eq!(
+            child.join().await.expect("with_passed_rx_checksums failed"),
+            vec![5, big_sum, 10, big_sum]
+        );
+        println!("with_passed_rx_checksums OK");
+
+        let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+        tx.set_compression_threshold(Some(1024));
+        let mut child = with_passed_rx_checksums.spawn_tokio(rx).await.unwrap();
+        tx.send(&vec![1; 5]).await.unwrap();
+        tx.send(&big).await.unwrap();
+        tx.send(&vec![2; 5000]).await.unwrap();
+        drop(tx);
+        assert_eq!(
+            child.join().await.expect("with_passed_rx_checksums failed"),
+            vec![5, big_sum, 10000]
+        );
+        println!("compression OK");
+    }
+
     {
         let (tx, mut rx) = channel::<i32>().unwrap();
         let mut child = with_passed_tx.spawn_tokio(tx).await.unwrap();
@@ -113,6 +232,26 @@ async fn main() {
         println!("with_passed_tx OK");
     }
 
+    {
+        let (tx, rx) = channel::<String>().unwrap();
+        let mut child = with_passed_batched_tx.spawn_tokio(tx).await.unwrap();
+        let received: Vec<String> = rx.batched().map(|value| value.unwrap()).collect().await;
+        child.join().await.unwrap();
+        assert_eq!(
+            received,
+            (0..1000).map(|i| i.to_string()).collect::<Vec<_>>()
+        );
+
+        let (tx, rx) = channel::<i32>().unwrap();
+        let (mut tx, mut rx) = (
+            tx.batched(usize::MAX, std::time::Duration::ZERO),
+            rx.batched(),
+        );
+        tx.send(5).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Some(5));
+        println!("batched OK");
+    }
+
     {
         let (mut local, downstream) = duplex::<(i32, i32), i32>().unwrap();
         let mut child = with_passed_duplex.spawn_tokio(downstream).await.unwrap();
@@ -124,4 +263,80 @@ async fn main() {
         child.join().await.unwrap();
         println!("with_passed_duplex OK");
     }
+
+    {
+        let (mut tx, rx) = channel::<i32>().unwrap();
+        let mut child = with_passed_rx_running_sum.spawn_tokio(rx).await.unwrap();
+        for (value, sum) in [(5, 5), (7, 12), (-2, 10)] {
+            tx.send(&value).await.unwrap();
+            assert_eq!(child.output().recv().await.unwrap(), Some(sum));
+        }
+        drop(tx);
+        assert_eq!(child.output().recv().await.unwrap(), None);
+        child
+            .join()
+            .await
+            .expect("with_passed_rx_running_sum failed");
+        println!("with_passed_rx_running_sum OK");
+    }
+
+    {
+        let mut child = exit_with_code.spawn_tokio(3).await.unwrap();
+        assert_eq!(child.wait().await.unwrap().code(), Some(3));
+        assert_eq!(child.try_wait().unwrap().unwrap().code(), Some(3));
+        println!("wait OK");
+    }
+
+    {
+        let options =
+            multiprocessing::SpawnOptions::new().heartbeat(std::time::Duration::from_millis(200));
+
+        let mut child = unsafe { beat_then_hang.spawn_with_options_tokio(&options, 0) }
+            .await
+            .unwrap();
+        let mut watcher = child.watcher().unwrap();
+        let start = std::time::Instant::now();
+        watcher.unresponsive().await;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+        assert!(child.is_alive().unwrap());
+        child.kill().unwrap();
+        assert!(!child.wait().await.unwrap().success());
+
+        // A child that terminates normally does not trigger the watcher
+        let mut child = unsafe { exit_with_code.spawn_with_options_tokio(&options, 0) }
+            .await
+            .unwrap();
+        let mut watcher = child.watcher().unwrap();
+        tokio::select! {
+            _ = child.wait() => {}
+            _ = watcher.unresponsive() => panic!("The watcher fired for a terminated child"),
+        }
+        println!("heartbeat_watcher OK");
+    }
+
+    {
+        let collector: &'static LogCollector =
+            Box::leak(Box::new(LogCollector(std::sync::Mutex::new(Vec::new()))));
+        log::set_logger(collector).unwrap();
+        log::set_max_level(log::LevelFilter::Info);
+
+        let options = multiprocessing::SpawnOptions::new().forward_logs();
+        let mut child = unsafe { log_something.spawn_with_options_tokio(&options) }
+            .await
+            .unwrap();
+        let pid = child.id();
+        child.join().await.expect("log_something failed");
+
+        for _ in 0..100 {
+            if !collector.0.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *collector.0.lock().unwrap(),
+            vec![format!("WARN [pid {pid}] hello from child")]
+        );
+        println!("forward_logs_tokio OK");
+    }
 }
@@ -1,8 +1,66 @@
 use crate::{errors, errors::ToResult};
 use libc::pid_t;
 use std::collections::HashSet;
+use std::io::{Read, Seek};
 use std::path::Path;
 
+// Controllers the invoker relies on. All of them have to be available in the unified hierarchy: a
+// controller that is bound to a v1 hierarchy cannot be enabled in v2 at the same time.
+const REQUIRED_CONTROLLERS: [&str; 5] = ["cpu", "cpuset", "io", "memory", "pids"];
+
+// sunwalker only supports cgroups v2. Distributions that still default to the hybrid layout mount
+// v1 controllers at /sys/fs/cgroup and an empty v2 hierarchy at /sys/fs/cgroup/unified, which is of
+// no use, so that case gets a dedicated message.
+pub fn verify_unified_hierarchy() -> Result<(), errors::Error> {
+    let fs_type =
+        |path: &str| nix::sys::statfs::statfs(path).map(|stat| stat.filesystem_type().0 as i64);
+
+    match fs_type("/sys/fs/cgroup")
+        .context_invoker("cgroups are not available at /sys/fs/cgroup")?
+    {
+        libc::CGROUP2_SUPER_MAGIC => {}
+        libc::TMPFS_MAGIC => {
+            if fs_type("/sys/fs/cgroup/unified") == Ok(libc::CGROUP2_SUPER_MAGIC) {
+                return Err(errors::ConfigurationFailure(
+                    "cgroups are mounted in hybrid mode: v1 controllers at /sys/fs/cgroup and v2 \
+                     at /sys/fs/cgroup/unified. sunwalker requires the controllers to be \
+                     available in cgroups v2. Please boot with systemd.unified_cgroup_hierarchy=1"
+                        .to_string(),
+                ));
+            }
+            return Err(errors::ConfigurationFailure(
+                "cgroups v1 seems to be mounted at /sys/fs/cgroup. sunwalker requires cgroups v2. \
+                 Please configure your kernel and/or distribution to use cgroups v2"
+                    .to_string(),
+            ));
+        }
+        _ => {
+            return Err(errors::ConfigurationFailure(
+                "Unknown filesystem type at /sys/fs/cgroup. sunwalker requires cgroups v2. Please \
+                 configure your kernel and/or distribution to use cgroups v2"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let controllers = std::fs::read_to_string("/sys/fs/cgroup/cgroup.controllers")
+        .context_invoker("Failed to read /sys/fs/cgroup/cgroup.controllers")?;
+    let controllers: HashSet<&str> = controllers.split_whitespace().collect();
+    let missing: Vec<&str> = REQUIRED_CONTROLLERS
+        .into_iter()
+        .filter(|controller| !controllers.contains(controller))
+        .collect();
+    if !missing.is_empty() {
+        return Err(errors::ConfigurationFailure(format!(
+            "Controllers {missing:?} are not available in cgroups v2, most likely because they \
+             are bound to a v1 hierarchy or disabled. Please boot with cgroup_no_v1=all, and make \
+             sure the kernel is built with support for them"
+        )));
+    }
+
+    Ok(())
+}
+
 pub fn create_root_cpuset() -> Result<(), errors::Error> {
     std::fs::create_dir("/sys/fs/cgroup/sunwalker_root")
         .or_else(|e| {
@@ -16,13 +74,108 @@ pub fn create_root_cpuset() -> Result<(), errors::Error> {
 
     std::fs::write(
         "/sys/fs/cgroup/sunwalker_root/cgroup.subtree_control",
-        "+cpu +memory +pids +cpuset",
+        "+cpu +io +memory +pids +cpuset",
     )
     .context_invoker("Failed to enable cpuset controller")?;
 
     Ok(())
 }
 
+// cpu.weight ranges from 1 to 10000 and only matters when a core is oversubscribed, i.e. when a
+// compilation runs on the same core as another submission's tests. The tests then get nearly all the
+// CPU time they ask for, so that their timing is not affected, and the compiler uses whatever is
+// left.
+pub const TEST_CPU_WEIGHT: u64 = 10000;
+pub const COMPILATION_CPU_WEIGHT: u64 = 1;
+
+pub fn set_cpu_weight(dir: &str, weight: u64) -> Result<(), errors::Error> {
+    std::fs::write(format!("{dir}/cpu.weight"), format!("{weight}\n"))
+        .with_context_invoker(|| format!("Failed to write to {dir}/cpu.weight"))
+}
+
+// The memory limit itself is set per invocation, as it differs between blocks. Here, the cgroup is
+// configured so that the limit cannot be bypassed by swapping, and so that an OOM kill takes down
+// the whole cgroup rather than a single process, which could leave the rest of the solution running
+// in a broken state.
+pub fn configure_memory(dir: &str) -> Result<(), errors::Error> {
+    std::fs::write(format!("{dir}/memory.oom.group"), "1\n")
+        .with_context_invoker(|| format!("Failed to write to {dir}/memory.oom.group"))?;
+    disable_swap(dir)
+}
+
+pub fn disable_swap(dir: &str) -> Result<(), errors::Error> {
+    // memory.swap.max is missing if swap accounting is disabled, in which case there is nothing
+    // to limit
+    match std::fs::write(format!("{dir}/memory.swap.max"), "0\n") {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context_invoker(|| format!("Failed to write to {dir}/memory.swap.max"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The number of processes killed by the OOM killer since the cgroup was created, read from an open
+// memory.events. Like cpu.stat, this is not reset, so only the difference matters.
+pub fn oom_kills(memory_events: &mut std::fs::File) -> Result<u64, errors::Error> {
+    memory_events
+        .rewind()
+        .context_invoker("Failed to rewind memory.events")?;
+
+    let mut buf = String::new();
+    memory_events
+        .read_to_string(&mut buf)
+        .context_invoker("Failed to read memory.events")?;
+
+    for line in buf.lines() {
+        if let Some(count) = line.strip_prefix("oom_kill ") {
+            return count
+                .parse()
+                .context_invoker("Invalid memory.events format");
+        }
+    }
+    Err(errors::InvokerFailure(
+        "memory.events does not contain oom_kill".to_string(),
+    ))
+}
+
+pub fn set_max_pids(dir: &str, max_pids: u64) -> Result<(), errors::Error> {
+    std::fs::write(format!("{dir}/pids.max"), format!("{max_pids}\n"))
+        .with_context_invoker(|| format!("Failed to write to {dir}/pids.max"))
+}
+
+// Limits disk bandwidth of the cgroup on every disk of the machine, so that a submission reading
+// huge files cannot starve workers on other cores. None means no limit. io.max only accepts whole
+// disks, and some of them, e.g. ramdisks, cannot be throttled at all and are skipped.
+pub fn set_io_limits(
+    dir: &str,
+    read_bps: Option<u64>,
+    write_bps: Option<u64>,
+) -> Result<(), errors::Error> {
+    if read_bps.is_none() && write_bps.is_none() {
+        return Ok(());
+    }
+
+    let to_string = |bps: Option<u64>| bps.map_or("max".to_string(), |bps| bps.to_string());
+    let limits = format!("rbps={} wbps={}", to_string(read_bps), to_string(write_bps));
+
+    for entry in std::fs::read_dir("/sys/block").context_invoker("Failed to list /sys/block")? {
+        let entry = entry.context_invoker("Failed to list /sys/block")?;
+        let dev_path = entry.path().join("dev");
+        let dev = std::fs::read_to_string(&dev_path)
+            .with_context_invoker(|| format!("Failed to read {dev_path:?}"))?;
+        let dev = dev.trim();
+        if let Err(e) = std::fs::write(format!("{dir}/io.max"), format!("{dev} {limits}\n")) {
+            if e.raw_os_error() != Some(libc::ENODEV) && e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(e).with_context_invoker(|| {
+                    format!("Failed to write limits for device {dev} to {dir}/io.max")
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
 pub fn create_core_cpuset(core: u64) -> Result<(), errors::Error> {
     let dir = format!("/sys/fs/cgroup/sunwalker_root/cpu_{core}");
 
@@ -38,7 +191,7 @@ pub fn create_core_cpuset(core: u64) -> Result<(), errors::Error> {
 
     std::fs::write(
         format!("{dir}/cgroup.subtree_control"),
-        "+cpu +memory +pids",
+        "+cpu +io +memory +pids",
     )
     .with_context_invoker(|| format!("Failed to write to {dir}/cgroup.subtree_control"))?;
 
@@ -55,9 +208,77 @@ pub fn create_core_cpuset(core: u64) -> Result<(), errors::Error> {
         })
         .with_context_invoker(|| format!("Unable to create {dir}/invoker directory"))?;
 
+    std::fs::create_dir(format!("{dir}/compilation"))
+        .or_else(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+        .with_context_invoker(|| format!("Unable to create {dir}/compilation directory"))?;
+    set_cpu_weight(&format!("{dir}/compilation"), COMPILATION_CPU_WEIGHT)?;
+
+    // A cgroup with controllers enabled for its children cannot hold processes itself, so the
+    // workers that are building stay in compilation/worker, and each build gets a child of its own,
+    // see create_build_cgroup
+    std::fs::write(
+        format!("{dir}/compilation/cgroup.subtree_control"),
+        "+memory +pids",
+    )
+    .with_context_invoker(|| {
+        format!("Failed to write to {dir}/compilation/cgroup.subtree_control")
+    })?;
+
+    std::fs::create_dir(format!("{dir}/compilation/worker"))
+        .or_else(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+        .with_context_invoker(|| format!("Unable to create {dir}/compilation/worker directory"))?;
+
     Ok(())
 }
 
+// Several submissions may compile on the same core at once, so every build is limited and accounted
+// for in a cgroup of its own. memory is in bytes, 0 meaning no limit. Unlike for tests, an OOM kill
+// only takes down the offending process, so that the build can report what happened. max_pids stops
+// a fork bomb in a build script or a compiler plugin the same way it does in tests. Returns the path
+// to the cgroup, which the caller removes once the build is over.
+pub fn create_build_cgroup(
+    core: u64,
+    build_id: &str,
+    memory: u64,
+    max_pids: u64,
+) -> Result<String, errors::Error> {
+    let dir = format!("/sys/fs/cgroup/sunwalker_root/cpu_{core}/compilation/build-{build_id}");
+
+    std::fs::create_dir(&dir)
+        .or_else(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+        .with_context_invoker(|| format!("Unable to create {dir} directory"))?;
+
+    disable_swap(&dir)?;
+    let memory_max = if memory > 0 {
+        format!("{memory}\n")
+    } else {
+        "max\n".to_string()
+    };
+    std::fs::write(format!("{dir}/memory.max"), memory_max)
+        .with_context_invoker(|| format!("Failed to write to {dir}/memory.max"))?;
+    set_max_pids(&dir, max_pids)?;
+
+    Ok(dir)
+}
+
 pub fn move_process_to_cgroup(pid: pid_t, name: String) -> Result<(), errors::Error> {
     std::fs::write(
         format!("/sys/fs/cgroup/sunwalker_root/{name}/cgroup.procs"),
@@ -66,6 +287,63 @@ pub fn move_process_to_cgroup(pid: pid_t, name: String) -> Result<(), errors::Er
     .with_context_invoker(|| format!("Failed to move process {pid} to cgroup {name}"))
 }
 
+// SIGKILLs every process in the cgroup and its descendants but spare, until none is left.
+// cgroup.kill would be atomic, but it cannot spare the caller, which shares the compilation cgroup
+// with the compiler. A process forking concurrently is caught by the next pass, and pids.max bounds
+// how many passes it takes.
+pub fn kill_processes(dir: &str, spare: pid_t) -> Result<(), errors::Error> {
+    loop {
+        let procs = match std::fs::read_to_string(format!("{dir}/cgroup.procs")) {
+            Ok(procs) => procs,
+            // The cgroup is removed along with the strategy that created it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context_invoker(|| format!("Failed to read {dir}/cgroup.procs"))
+            }
+        };
+        let mut killed_any = false;
+        for pid in procs.lines() {
+            let pid: pid_t = pid
+                .parse()
+                .with_context_invoker(|| format!("Invalid PID {pid:?} in {dir}/cgroup.procs"))?;
+            // Processes outside our PID namespace are listed as 0
+            if pid == spare || pid == 0 {
+                continue;
+            }
+            if unsafe { libc::kill(pid, libc::SIGKILL) } == -1 {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(e).with_context_invoker(|| format!("Failed to kill process {pid}"));
+                }
+            }
+            killed_any = true;
+        }
+        if !killed_any {
+            break;
+        }
+    }
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context_invoker(|| format!("Failed to list {dir}")),
+    };
+    for entry in entries {
+        let entry = entry.with_context_invoker(|| format!("Failed to list {dir}"))?;
+        if entry
+            .file_type()
+            .with_context_invoker(|| format!("Failed to stat {:?}", entry.path()))?
+            .is_dir()
+        {
+            kill_processes(
+                &format!("{dir}/{}", entry.file_name().to_string_lossy()),
+                spare,
+            )?;
+        }
+    }
+    Ok(())
+}
+
 pub fn drop_existing_affine_cpusets() -> Result<(), errors::Error> {
     if Path::new("/sys/fs/cgroup/sunwalker_root").exists() {
         // Remove all the child cgroups
@@ -112,7 +390,7 @@ pub fn drop_existing_affine_cpusets() -> Result<(), errors::Error> {
     Ok(())
 }
 
-fn parse_cpuset_list(s: &str) -> Result<Vec<u64>, errors::Error> {
+pub fn parse_cpuset_list(s: &str) -> Result<Vec<u64>, errors::Error> {
     let mut result: Vec<u64> = Vec::new();
     for part in s.trim().split(',') {
         if part.contains('-') {
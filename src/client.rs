@@ -1,11 +1,12 @@
 use crate::{
-    cgroups, communicator, config, errors, image, init, message, problem, submission, system,
+    cgroups, communicator, config, errors, idle, image, init, isolation, message, problem,
+    report, signing, submission, supervisor, system, worker,
 };
 use anyhow::Context;
 use futures_util::StreamExt;
 use libc::CLONE_NEWNS;
 use ouroboros::self_referencing;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::{Mutex, MutexGuard, RwLock};
 
@@ -13,10 +14,20 @@ pub struct Client {
     config: config::Config,
     submissions: RwLock<HashMap<String, Arc<submission::Submission>>>,
     problem_store: problem::store::ProblemStore,
-    mounted_image: Arc<image::image::Image>,
+    // By name. An image is replaced when it is reloaded, but submissions hold on to the image they
+    // were added with
+    mounted_images: RwLock<HashMap<String, Arc<image::image::Image>>>,
+    image_mounter: Mutex<image::mount::ImageMounter>,
     ephemeral_disk_space: u64,
     communicator: Arc<communicator::Communicator>,
     core_locks: HashMap<u64, Mutex<()>>,
+    // Only used in oversubscription mode, where compilation doesn't lock the core exclusively
+    compilation_locks: HashMap<u64, Mutex<()>>,
+    host_id: String,
+    signer: Option<signing::Signer>,
+    idle: idle::IdleTracker,
+    program_cache: Option<Arc<image::program_cache::ProgramCache>>,
+    spare_workers: Option<Arc<worker::SparePool>>,
 }
 
 #[self_referencing]
@@ -39,10 +50,37 @@ impl CoreHandle {
 }
 
 impl Client {
+    fn fingerprint(&self, core: u64, image_version: &str) -> problem::verdict::MachineFingerprint {
+        problem::verdict::MachineFingerprint {
+            host_id: self.host_id.clone(),
+            invoker_name: self.config.invoker.name.clone(),
+            core,
+            image_version: image_version.to_string(),
+        }
+    }
+
     fn try_lock_core(self: &Arc<Self>, core: u64) -> Result<CoreHandle, errors::Error> {
+        self.try_lock_core_in(core, |client| &client.core_locks)
+    }
+
+    fn try_lock_core_for_compilation(
+        self: &Arc<Self>,
+        core: u64,
+    ) -> Result<CoreHandle, errors::Error> {
+        if self.config.environment.oversubscription {
+            self.try_lock_core_in(core, |client| &client.compilation_locks)
+        } else {
+            self.try_lock_core(core)
+        }
+    }
+
+    fn try_lock_core_in(
+        self: &Arc<Self>,
+        core: u64,
+        locks: fn(&Client) -> &HashMap<u64, Mutex<()>>,
+    ) -> Result<CoreHandle, errors::Error> {
         CoreHandle::try_new(core, self.clone(), |client| {
-            client
-                .core_locks
+            locks(client)
                 .get(&core)
                 .ok_or_else(|| {
                     errors::ConductorFailure(format!(
@@ -71,31 +109,92 @@ pub fn client_main(cli_args: init::CLIArgs) -> anyhow::Result<()> {
 async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
     let config = std::fs::read_to_string(&cli_args.config)
         .with_context(|| format!("Failed to read config from {}", cli_args.config))?;
-    let config: config::Config = toml::from_str(&config).with_context(|| "Config is invalid")?;
+    let mut config: config::Config =
+        toml::from_str(&config).with_context(|| "Config is invalid")?;
 
-    let image_cfg = std::fs::read_to_string(&config.image.config).with_context(|| {
-        format!(
-            "Failed to read file image.cfg from path {} (this path is from field image.config of \
-             the configuration file)",
-            config.image.config
-        )
-    })?;
-    let image_cfg = image::config::Config::load(&image_cfg).with_context(|| {
-        format!(
-            "Failed to load image.cfg as a script from path {} (this path is from field \
-             image.config of the configuration file)",
-            config.image.config
-        )
-    })?;
+    if config.images.contains_key(config::DEFAULT_IMAGE) {
+        anyhow::bail!(
+            "Field images.{} of the configuration file is not allowed, as this name refers to the \
+             image from field image",
+            config::DEFAULT_IMAGE
+        );
+    }
 
     let mut mnt = image::mount::ImageMounter::new();
-    let mounted_image = Arc::new(mnt.mount(&config.image.path, image_cfg).with_context(|| {
-        format!(
-            "Failed to mount image.sfs from path {} (this path is from field image.path of the \
-             configuration file)",
-            config.image.path
-        )
-    })?);
+    let mut mounted_images = HashMap::new();
+    let mut image_names: Vec<String> = config.images.keys().cloned().collect();
+    image_names.sort();
+    image_names.insert(0, config::DEFAULT_IMAGE.to_string());
+    for name in image_names {
+        let (image_config, field) = if name == config::DEFAULT_IMAGE {
+            (&config.image, "image".to_string())
+        } else {
+            (&config.images[&name], format!("images.{name}"))
+        };
+
+        let image_cfg = std::fs::read_to_string(&image_config.config).with_context(|| {
+            format!(
+                "Failed to read file image.cfg from path {} (this path is from field \
+                 {field}.config of the configuration file)",
+                image_config.config
+            )
+        })?;
+        let mut image_cfg = image::config::Config::load(&image_cfg).with_context(|| {
+            format!(
+                "Failed to load image.cfg as a script from path {} (this path is from field \
+                 {field}.config of the configuration file)",
+                image_config.config
+            )
+        })?;
+        image_cfg.extensions.extend(image_config.extensions.clone());
+
+        let manifest = match image_config.manifest {
+            Some(ref manifest) => Some(
+                image::integrity::Manifest::load(
+                    manifest,
+                    image_config.manifest_public_key.as_deref(),
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to load the manifest of the image (this path is from field \
+                         {field}.manifest of the configuration file)"
+                    )
+                })?,
+            ),
+            None if image_config.manifest_public_key.is_some() => {
+                anyhow::bail!(
+                    "Field {field}.manifest_public_key of the configuration file is set, but \
+                     {field}.manifest is not"
+                );
+            }
+            None => None,
+        };
+
+        let mounted_image = mnt
+            .mount(&image_config.path, image_cfg, manifest.as_ref())
+            .with_context(|| {
+                format!(
+                    "Failed to mount image.sfs from path {} (this path is from field \
+                     {field}.path of the configuration file)",
+                    image_config.path
+                )
+            })?;
+        mounted_images.insert(name, Arc::new(mounted_image));
+    }
+
+    // Workers get the resolved backend along with the rest of the sandbox config. All images are
+    // mounted the same way, so checking one of them is enough
+    if config.sandbox.rootfs_backend == config::RootfsBackend::Auto {
+        config.sandbox.rootfs_backend =
+            image::sandbox::detect_backend(&mounted_images[config::DEFAULT_IMAGE].mountpoint);
+    }
+    image::sandbox::set_backend(config.sandbox.rootfs_backend);
+
+    image::ids::validate_external_user_ids(config.sandbox.user_ids).with_context(|| {
+        "Field sandbox.user_ids of the configuration file is invalid, or conflicts with \
+         /etc/subuid or /etc/subgid"
+    })?;
+    image::ids::set_external_user_ids(config.sandbox.user_ids);
 
     cgroups::isolate_cores(&config.environment.cpu_cores).with_context(|| {
         format!(
@@ -110,8 +209,41 @@ async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
             .with_context(|| format!("Failed to create cpuset for core {core}"))?;
     }
 
+    config
+        .sandbox
+        .limits
+        .validate()
+        .with_context(|| "Field sandbox.limits of the configuration file is invalid")?;
+
+    for (name, mounted_image) in mounted_images.iter() {
+        image::image::Image::warm_up(mounted_image.clone(), &config.sandbox.limits)
+            .await
+            .with_context(|| format!("Failed to warm up caches of image {name}"))?;
+    }
+
+    if let Some(ref network) = config.sandbox.network {
+        image::network::setup_bridge(network)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to set up sandbox network on bridge {} (this bridge is from field \
+                 sandbox.network.bridge of the configuration file)",
+                    network.bridge
+                )
+            })?;
+    }
+
+    let core_isolation = isolation::verify_core_isolation(
+        &config.environment.cpu_cores,
+        config.environment.require_nohz_full,
+    )
+    .with_context(|| "Failed to verify isolation of CPU cores")?;
+    for issue in &core_isolation.issues {
+        println!("Judging cores are not fully isolated: {issue}");
+    }
+
     let communicator = Arc::new(
-        communicator::Communicator::connect(&config.conductor.address)
+        communicator::Communicator::connect(&config.conductor)
             .await
             .with_context(|| {
                 format!(
@@ -122,9 +254,12 @@ async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
             })?,
     );
 
+    let problem_source = problem::source::from_config(&config.problem_source, communicator.clone())
+        .with_context(|| "Failed to set up the source of problems from field problem_source")?;
+
     let problem_store = problem::store::ProblemStore::new(
         std::path::PathBuf::from(&config.cache.problems),
-        communicator.clone(),
+        problem_source,
     )
     .with_context(|| {
         format!(
@@ -134,6 +269,31 @@ async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
         )
     })?;
 
+    let program_cache = match config.cache.programs {
+        Some(ref path) => Some(Arc::new(
+            image::program_cache::ProgramCache::new(
+                std::path::PathBuf::from(path),
+                config.cache.max_programs,
+            )
+            .with_context(|| {
+                format!(
+                    "Failed to create program cache at {path} (this path is from field \
+                     cache.programs of the configuration file)"
+                )
+            })?,
+        )),
+        None => None,
+    };
+
+    let spare_workers = match config.prewarm {
+        Some(ref prewarm) => Some(Arc::new(worker::SparePool::new(
+            config.sandbox.clone(),
+            prewarm_language(&config, &mounted_images[config::DEFAULT_IMAGE])
+                .with_context(|| "Field prewarm.language of the configuration file is invalid")?,
+        ))),
+        None => None,
+    };
+
     let ephemeral_disk_space: u64 = config
         .environment
         .ephemeral_disk_space
@@ -146,35 +306,133 @@ async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
         config.conductor.address
     );
 
+    let host_id = get_host_id().with_context(|| "Failed to determine host ID")?;
+
+    let signer = match config.invoker.signing_key {
+        Some(ref path) => {
+            let signer = signing::Signer::load(path).with_context(|| {
+                format!(
+                    "Failed to load signing key from path {path} (this path is from field \
+                     invoker.signing_key of the configuration file)"
+                )
+            })?;
+            println!(
+                "Signing results with public key {}",
+                signer.public_key_hex()
+            );
+            Some(signer)
+        }
+        None => None,
+    };
+
     let core_locks = config
         .environment
         .cpu_cores
         .iter()
         .map(|core| (*core, Mutex::new(())))
         .collect();
+    let compilation_locks = config
+        .environment
+        .cpu_cores
+        .iter()
+        .map(|core| (*core, Mutex::new(())))
+        .collect();
 
     let client = Arc::new(Client {
         config,
         submissions: RwLock::new(HashMap::new()),
         problem_store,
-        mounted_image,
+        mounted_images: RwLock::new(mounted_images),
+        image_mounter: Mutex::new(mnt),
         ephemeral_disk_space,
         communicator,
         core_locks,
+        compilation_locks,
+        host_id,
+        signer,
+        idle: idle::IdleTracker::new(),
+        program_cache,
+        spare_workers,
     });
 
-    // Handshake
+    let idle_tick = client
+        .config
+        .idle
+        .as_ref()
+        .map_or(std::time::Duration::ZERO, |idle| {
+            std::time::Duration::from_secs(idle.tick)
+        });
+    tokio::spawn(errors::flush_throttled_logs_periodically(idle_tick));
+    if client.config.idle.is_some() {
+        tokio::spawn(reclaim_when_idle(client.clone()));
+    }
+    tokio::spawn(reload_image_on_sighup(client.clone()));
+    tokio::spawn(collect_garbage_periodically(client.clone()));
+    if let Some(ref spare_workers) = client.spare_workers {
+        for core in client.config.environment.cpu_cores.iter().copied() {
+            let spare_workers = spare_workers.clone();
+            tokio::spawn(async move {
+                if let Err(e) = spare_workers.fill(core).await {
+                    println!("Failed to spawn a spare worker on core {core}: {e:?}");
+                }
+            });
+        }
+    }
+
+    greet_conductor(&client, &core_isolation).await?;
+
+    loop {
+        {
+            let messages = client.communicator.messages();
+            futures::pin_mut!(messages);
+
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(message) => handle_message(message, &client).await,
+                    Err(e) => {
+                        println!("Connection to the conductor broke: {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Submissions keep being judged in the background, and their results are queued until the
+        // conductor is back
+        println!("Disconnected from the conductor, reconnecting");
+        client.communicator.disconnect().await;
+        loop {
+            client.communicator.reconnect().await;
+            match greet_conductor(&client, &core_isolation).await {
+                Ok(()) => break,
+                Err(e) => {
+                    println!("Failed to greet the conductor after reconnecting: {e:?}");
+                    client.communicator.disconnect().await;
+                }
+            }
+        }
+        println!("Reconnected to the conductor");
+    }
+}
+
+// Repeated on every connection. The handshake must come before the messages queued while the
+// conductor was unreachable, so both bypass the outbox.
+async fn greet_conductor(
+    client: &Client,
+    core_isolation: &isolation::IsolationReport,
+) -> Result<(), errors::Error> {
     client
         .communicator
-        .send_to_conductor(message::i2c::Message::Handshake(message::i2c::Handshake {
+        .send_immediately(message::i2c::Message::Handshake(message::i2c::Handshake {
             invoker_name: client.config.invoker.name.clone(),
+            core_isolation: core_isolation.clone(),
         }))
         .await?;
 
     // Initial mode
     client
         .communicator
-        .send_to_conductor(message::i2c::Message::UpdateMode(
+        .send_immediately(message::i2c::Message::UpdateMode(
             message::i2c::UpdateMode {
                 added_cores: client.config.environment.cpu_cores.clone(),
                 removed_cores: Vec::new(),
@@ -183,14 +441,7 @@ async fn client_main_async(cli_args: init::CLIArgs) -> anyhow::Result<()> {
         ))
         .await?;
 
-    let messages = client.communicator.messages();
-    futures::pin_mut!(messages);
-
-    while let Some(message) = messages.next().await {
-        handle_message(message?, &client).await;
-    }
-
-    Ok(())
+    client.communicator.flush_outbox().await
 }
 
 async fn handle_message(message: message::c2i::Message, client: &Arc<Client>) {
@@ -198,6 +449,10 @@ async fn handle_message(message: message::c2i::Message, client: &Arc<Client>) {
 
     println!("{:?}", message);
 
+    if client.idle.touch() {
+        println!("Leaving idle mode");
+    }
+
     match message {
         Message::AddSubmission(message) => add_submission(message, client).await,
         Message::PushToJudgementQueue(message) => push_to_judgment_queue(message, client).await,
@@ -205,34 +460,149 @@ async fn handle_message(message: message::c2i::Message, client: &Arc<Client>) {
             cancel_judgement_on_tests(message, &client).await
         }
         Message::FinalizeSubmission(message) => finalize_submission(message, &client).await,
+        Message::AbortSubmission(message) => abort_submission(message, &client).await,
         Message::SupplyFile(message) => supply_file(message, &client).await,
+        Message::CompareRevisions(message) => compare_revisions(message, client).await,
+        Message::ExportReport(message) => export_report(message, client).await,
+        Message::CompareSubmissions(message) => compare_submissions(message, client).await,
+        Message::ReloadImage(message) => reload_image(message, client).await,
+    }
+}
+
+async fn reclaim_when_idle(client: Arc<Client>) {
+    let config = client.config.idle.as_ref().unwrap();
+    loop {
+        client
+            .idle
+            .wait_until_idle(std::time::Duration::from_secs(config.after))
+            .await;
+
+        // A long test or a conductor that takes its time to finalize a submission is not idleness
+        if !client.submissions.read().await.is_empty() {
+            client.idle.touch();
+            continue;
+        }
+
+        println!("Idle for {} seconds, releasing resources", config.after);
+
+        if let Err(e) = image::sandbox::drain_pool() {
+            println!("Failed to remove pooled rootfs's: {e:?}");
+        }
+        client
+            .problem_store
+            .shrink(config.problem_cache_floor)
+            .await;
+        if let Err(e) = client.problem_store.release_page_cache() {
+            println!("Failed to release page cache of problems: {e:?}");
+        }
+    }
+}
+
+async fn collect_garbage_periodically(client: Arc<Client>) {
+    let interval = std::time::Duration::from_secs(client.config.invoker.gc_interval);
+    loop {
+        let mut live_submissions: HashSet<String> = HashSet::new();
+        for submission in client.submissions.read().await.values() {
+            live_submissions.extend(submission.residue_ids());
+        }
+        if let Some(ref spare_workers) = client.spare_workers {
+            live_submissions.extend(spare_workers.live_ids());
+        }
+        match image::gc::collect(&live_submissions) {
+            Ok(collected) => {
+                if !collected.is_empty() {
+                    println!("Reclaimed residue of dead submissions: {collected:?}");
+                }
+            }
+            Err(e) => println!("Failed to reclaim residue of dead submissions: {e:?}"),
+        }
+        tokio::time::sleep(interval).await;
     }
 }
 
 async fn add_submission(message: message::c2i::AddSubmission, client: &Arc<Client>) {
     match async {
-        let core = client.try_lock_core(message.compilation_core)?;
+        let core = client.try_lock_core_for_compilation(message.compilation_core)?;
 
-        if !client.mounted_image.has_language(&message.language) {
-            return Err(errors::ConductorFailure(format!(
-                "Language {} is not available",
-                message.language
-            )));
-        }
+        // Seeds for randomized checkers are derived from this, so that rejudges are reproducible
+        let seed_key = format!(
+            "{}\0{}\0{}",
+            message.submission_id, message.problem_id, message.revision_id
+        );
 
         let problem = client
             .problem_store
             .load_revision(message.problem_id, message.revision_id)
             .await?;
 
+        let image_name = match (&message.image, &problem.image) {
+            (Some(submission_image), Some(problem_image)) if submission_image != problem_image => {
+                return Err(errors::ConductorFailure(format!(
+                    "The submission asks for image {submission_image}, but the problem is judged \
+                     with image {problem_image}"
+                )));
+            }
+            (Some(image_name), _) | (None, Some(image_name)) => image_name.as_str(),
+            (None, None) => config::DEFAULT_IMAGE,
+        };
+        let mounted_image = client
+            .mounted_images
+            .read()
+            .await
+            .get(image_name)
+            .cloned()
+            .ok_or_else(|| {
+                errors::ConductorFailure(format!("Image {image_name} is not mounted"))
+            })?;
+
+        let language_name = match message.language {
+            Some(language_name) => {
+                if !mounted_image.has_language(&language_name) {
+                    return Err(errors::ConductorFailure(format!(
+                        "Language {language_name} is not available"
+                    )));
+                }
+                language_name
+            }
+            None => {
+                mounted_image.detect_language(message.files.keys().map(|name| name.as_str()))?
+            }
+        };
+
+        if let (Some(dialect), Some(allowed_dialects)) =
+            (&message.dialect, &message.allowed_dialects)
+        {
+            if !allowed_dialects.contains(dialect) {
+                return Err(errors::UserFailure(format!(
+                    "Dialect {dialect} of language {language_name} is not allowed in this contest"
+                )));
+            }
+        }
+
+        let compiler_flags = problem
+            .compiler_flags
+            .get(&language_name)
+            .cloned()
+            .unwrap_or_default();
+        let grader = problem.strategy_factory.grader_files(&language_name)?;
+        let language = image::image::Image::get_language(mounted_image, language_name)?
+            .with_dialect(message.dialect)?
+            .with_flags(compiler_flags)?
+            .with_diagnostic(message.diagnostic)?
+            .with_grader(grader)?;
+
         let mut submission = submission::Submission::new(
             message.submission_id.clone(),
             problem,
-            image::image::Image::get_language(
-                client.mounted_image.clone(),
-                message.language.clone(),
-            )?,
+            language,
             message.invocation_limits,
+            message.group_time_budgets,
+            seed_key,
+            client.config.retention.clone(),
+            client.config.sandbox.clone(),
+            message.output_only,
+            client.program_cache.clone(),
+            client.spare_workers.clone(),
         )?;
         for (name, content) in message.files.into_iter() {
             submission.add_source_file(&name, &content)?;
@@ -255,18 +625,42 @@ async fn add_submission(message: message::c2i::AddSubmission, client: &Arc<Clien
     .await
     {
         Ok((core, submission)) => {
+            if let Some(deadline) = submission.deadline() {
+                tokio::spawn(enforce_time_budget(
+                    client.clone(),
+                    Arc::downgrade(&submission),
+                    deadline,
+                ));
+            }
+
             let communicator = client.communicator.clone();
             tokio::spawn(async move {
+                let result = submission.compile_on_core(core).await;
+                if submission.is_aborted() {
+                    return;
+                }
+                // Compilation errors are reported as user failures containing the log
+                let diagnostics = match result {
+                    Ok(ref log) | Err(errors::UserFailure(ref log)) => {
+                        submission.parse_diagnostics(log)
+                    }
+                    Err(_) => Vec::new(),
+                };
                 if let Err(e) = communicator
                     .send_to_conductor(message::i2c::Message::NotifyCompilationStatus(
                         message::i2c::NotifyCompilationStatus {
                             submission_id: message.submission_id,
-                            result: submission.compile_on_core(core).await,
+                            language: Some(submission.language_name().to_string()),
+                            result,
+                            diagnostics,
                         },
                     ))
                     .await
                 {
-                    println!("Failed to send to conductor: {:?}", e);
+                    errors::log_throttled(
+                        "send to conductor",
+                        format!("Failed to send to conductor: {e:?}"),
+                    );
                 }
             });
         }
@@ -276,17 +670,72 @@ async fn add_submission(message: message::c2i::AddSubmission, client: &Arc<Clien
                 .send_to_conductor(message::i2c::Message::NotifyCompilationStatus(
                     message::i2c::NotifyCompilationStatus {
                         submission_id: message.submission_id,
+                        language: None,
                         result: Err(e),
+                        diagnostics: Vec::new(),
                     },
                 ))
                 .await
             {
-                println!("Failed to send to conductor: {:?}", e);
+                errors::log_throttled(
+                    "send to conductor",
+                    format!("Failed to send to conductor: {e:?}"),
+                );
             }
         }
     }
 }
 
+// Finalizes the submission once its judging time budget runs out, unless the conductor has
+// finalized or aborted it by then
+async fn enforce_time_budget(
+    client: Arc<Client>,
+    submission: std::sync::Weak<submission::Submission>,
+    deadline: std::time::Instant,
+) {
+    tokio::time::sleep_until(deadline.into()).await;
+
+    let submission = match submission.upgrade() {
+        Some(submission) => submission,
+        None => return,
+    };
+    {
+        let mut submissions = client.submissions.write().await;
+        match submissions.get(&submission.id) {
+            Some(current) if Arc::ptr_eq(current, &submission) => {
+                submissions.remove(&submission.id);
+            }
+            _ => return,
+        }
+    }
+
+    println!(
+        "Submission {} used up its judging time budget, finalizing",
+        submission.id
+    );
+
+    submission.exhaust_time_budget().await;
+    let message = match submission.finalize().await {
+        Ok(()) => message::i2c::Message::NotifyTimeBudgetExhausted(
+            message::i2c::NotifyTimeBudgetExhausted {
+                submission_id: submission.id.clone(),
+            },
+        ),
+        Err(e) => {
+            message::i2c::Message::NotifySubmissionError(message::i2c::NotifySubmissionError {
+                submission_id: submission.id.clone(),
+                error: e,
+            })
+        }
+    };
+    if let Err(e) = client.communicator.send_to_conductor(message).await {
+        errors::log_throttled(
+            "send to conductor",
+            format!("Failed to send to conductor: {e:?}"),
+        );
+    }
+}
+
 async fn push_to_judgment_queue(message: message::c2i::PushToJudgementQueue, client: &Arc<Client>) {
     if let Err(e) = try {
         let submissions = client.submissions.read().await;
@@ -298,27 +747,122 @@ async fn push_to_judgment_queue(message: message::c2i::PushToJudgementQueue, cli
             ))
         })?;
 
-        let core = client.try_lock_core(message.core)?;
-        let submission_id = submission.id.clone();
-        let communicator = client.communicator.clone();
+        let mut cores = vec![client.try_lock_core(message.core)?];
+        for core in message.extra_cores {
+            if core != message.core {
+                cores.push(client.try_lock_core(core)?);
+            }
+        }
+        let core_id = message.core;
+        let submission = submission.clone();
+        let client = client.clone();
+        let program_hash = submission.program_hash().await.unwrap_or_default();
 
-        let mut stream = submission.test_on_core(core, message.tests).await?;
+        let tests = submission.order_tests(submission.resolve_tests(message.tests)?);
+        let speculative_tests = submission.resolve_tests(message.speculative_tests)?;
+        let mut stream = submission
+            .test_on_cores(cores, tests, speculative_tests)
+            .await?;
 
         tokio::spawn(async move {
-            while let Some((test, judgement_result)) = stream.next().await {
-                if let Err(e) = communicator
-                    .send_to_conductor(message::i2c::Message::NotifyTestStatus(
-                        message::i2c::NotifyTestStatus {
-                            submission_id: submission_id.clone(),
-                            test,
-                            judgement_result,
-                        },
-                    ))
-                    .await
-                {
-                    println!("Failed to send to conductor: {:?}", e);
+            let report =
+                |test, core, mut judgement_result: problem::verdict::TestJudgementResult| {
+                    judgement_result.fingerprint =
+                        Some(client.fingerprint(core, submission.image_version()));
+                    judgement_result.test_name = submission.test_name(test);
+                    notify_test_status(&client, &submission, &program_hash, test, judgement_result)
+                };
+
+            let mut failed_tests = Vec::new();
+            let mut quarantined_cores = HashSet::new();
+            while let Some((test, core, judgement_result)) = stream.next().await {
+                match judgement_result {
+                    Ok(judgement_result) => {
+                        if let problem::verdict::TestVerdict::SecurityViolation(_) =
+                            judgement_result.verdict
+                        {
+                            quarantined_cores.insert(core);
+                        }
+                        report(test, core, judgement_result).await
+                    }
+                    Err(e) => {
+                        errors::log_throttled(
+                            "infrastructure error",
+                            format!("Test {test} failed because of an infrastructure error: {e:?}"),
+                        );
+                        failed_tests.push((test, e));
+                    }
+                }
+            }
+
+            // The worker refuses to run tests after a security violation, and its sandboxes are
+            // not to be trusted anyway
+            for core in quarantined_cores {
+                submission.replace_worker(core).await;
+            }
+
+            // The tests are retried after the stream is exhausted, because the worker only
+            // handles one command at a time
+            let mut retries_done = 0;
+            for retries in 1..=client.config.invoker.test_retries {
+                if failed_tests.is_empty() || submission.is_aborted() {
+                    return;
+                }
+
+                let tests: Vec<u64> = failed_tests.iter().map(|(test, _)| *test).collect();
+                let retry: Result<_, errors::Error> = try {
+                    if failed_tests
+                        .iter()
+                        .any(|(_, e)| matches!(e, errors::ProblemCacheFailure(_)))
+                    {
+                        client
+                            .problem_store
+                            .refetch_revision(submission.problem_revision())
+                            .await?;
+                    }
+                    let core = client.try_lock_core(core_id)?;
+                    // Whatever broke may have been left in the sandbox or the worker
+                    submission.replace_worker(core_id).await;
+                    submission.test_on_core(core, tests, Vec::new()).await?
+                };
+
+                let mut stream = match retry {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        for (_, error) in failed_tests.iter_mut() {
+                            *error = e.clone();
+                        }
+                        break;
+                    }
+                };
+
+                retries_done = retries;
+                failed_tests.clear();
+                while let Some((test, judgement_result)) = stream.next().await {
+                    match judgement_result {
+                        Ok(mut judgement_result) => {
+                            judgement_result.retries = retries;
+                            report(test, core_id, judgement_result).await;
+                        }
+                        Err(e) => {
+                            errors::log_throttled(
+                                "infrastructure error on retry",
+                                format!(
+                                    "Test {test} failed because of an infrastructure error on \
+                                     retry {retries}: {e:?}"
+                                ),
+                            );
+                            failed_tests.push((test, e));
+                        }
+                    }
                 }
             }
+
+            for (test, e) in failed_tests {
+                let mut judgement_result = submission::failed_evaluation(e);
+                judgement_result.retries = retries_done;
+                report(test, core_id, judgement_result).await;
+            }
         });
     } {
         if let Err(e) = client
@@ -331,9 +875,98 @@ async fn push_to_judgment_queue(message: message::c2i::PushToJudgementQueue, cli
             ))
             .await
         {
-            println!("Failed to send to conductor: {:?}", e);
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
+        }
+    }
+}
+
+async fn notify_test_status(
+    client: &Client,
+    submission: &submission::Submission,
+    program_hash: &str,
+    test: u64,
+    judgement_result: problem::verdict::TestJudgementResult,
+) {
+    if submission.is_aborted() {
+        return;
+    }
+
+    submission.record_result(test, &judgement_result);
+
+    let signature = match client.signer {
+        Some(ref signer) => match signer.sign_test_result(
+            &submission.id,
+            test,
+            submission.problem_revision_hash(),
+            program_hash,
+            &judgement_result,
+        ) {
+            Ok(signature) => Some(signature),
+            Err(e) => {
+                errors::log_throttled(
+                    "sign test result",
+                    format!("Failed to sign test result: {e:?}"),
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    if let Err(e) = client
+        .communicator
+        .send_to_conductor(message::i2c::Message::NotifyTestStatus(
+            message::i2c::NotifyTestStatus {
+                submission_id: submission.id.clone(),
+                test,
+                judgement_result,
+                signature,
+            },
+        ))
+        .await
+    {
+        errors::log_throttled(
+            "send to conductor",
+            format!("Failed to send to conductor: {e:?}"),
+        );
+    }
+
+    for (group, result) in submission.judge_groups_of(test) {
+        if let Err(e) = client
+            .communicator
+            .send_to_conductor(message::i2c::Message::NotifyGroupStatus(
+                message::i2c::NotifyGroupStatus {
+                    submission_id: submission.id.clone(),
+                    group,
+                    result,
+                },
+            ))
+            .await
+        {
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
         }
     }
+
+    if let Err(e) = client
+        .communicator
+        .send_to_conductor(message::i2c::Message::NotifySubmissionScore(
+            message::i2c::NotifySubmissionScore {
+                submission_id: submission.id.clone(),
+                score: submission.score(),
+            },
+        ))
+        .await
+    {
+        errors::log_throttled(
+            "send to conductor",
+            format!("Failed to send to conductor: {e:?}"),
+        );
+    }
 }
 
 async fn cancel_judgement_on_tests(message: message::c2i::CancelJudgementOnTests, client: &Client) {
@@ -359,7 +992,10 @@ async fn cancel_judgement_on_tests(message: message::c2i::CancelJudgementOnTests
             ))
             .await
         {
-            println!("Failed to send to conductor: {:?}", e);
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
         }
     }
 }
@@ -387,15 +1023,471 @@ async fn finalize_submission(message: message::c2i::FinalizeSubmission, client:
             ))
             .await
         {
-            println!("Failed to send to conductor: {:?}", e);
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
+        }
+    }
+}
+
+async fn abort_submission(message: message::c2i::AbortSubmission, client: &Client) {
+    if let Err(e) = try {
+        let mut submissions = client.submissions.write().await;
+
+        let submission = submissions.remove(&message.submission_id).ok_or_else(|| {
+            errors::ConductorFailure(format!(
+                "Cannot abort an unknown (or already finalized) submission {}",
+                message.submission_id
+            ))
+        })?;
+        drop(submissions);
+
+        submission.abort().await?
+    } {
+        if let Err(e) = client
+            .communicator
+            .send_to_conductor(message::i2c::Message::NotifySubmissionError(
+                message::i2c::NotifySubmissionError {
+                    submission_id: message.submission_id.clone(),
+                    error: e,
+                },
+            ))
+            .await
+        {
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
         }
     }
 }
 
+async fn export_report(message: message::c2i::ExportReport, client: &Client) {
+    let result = try {
+        let submission = client
+            .submissions
+            .read()
+            .await
+            .get(&message.submission_id)
+            .ok_or_else(|| {
+                errors::ConductorFailure(format!(
+                    "Submission {} does not exist or has already been finalized",
+                    message.submission_id
+                ))
+            })?
+            .clone();
+        let signer = client.signer.as_ref().ok_or_else(|| {
+            errors::ConfigurationFailure(
+                "Report bundles are signed, but no signing key is configured".to_string(),
+            )
+        })?;
+        report::make_bundle(&submission, signer).await?
+    };
+
+    if let Err(e) = client
+        .communicator
+        .send_to_conductor(message::i2c::Message::NotifyReportBundle(
+            message::i2c::NotifyReportBundle {
+                submission_id: message.submission_id,
+                result,
+            },
+        ))
+        .await
+    {
+        errors::log_throttled(
+            "send to conductor",
+            format!("Failed to send to conductor: {e:?}"),
+        );
+    }
+}
+
+async fn compare_revisions(message: message::c2i::CompareRevisions, client: &Arc<Client>) {
+    let res: Result<_, errors::Error> = try {
+        let submission = client
+            .submissions
+            .read()
+            .await
+            .get(&message.submission_id)
+            .ok_or_else(|| {
+                errors::ConductorFailure(format!(
+                    "Submission {} does not exist or has already been finalized",
+                    message.submission_id
+                ))
+            })?
+            .clone();
+
+        let core = client.try_lock_core(message.core)?;
+
+        let candidate = client
+            .problem_store
+            .load_revision(
+                submission.problem_revision().problem_id.clone(),
+                message.candidate_revision_id.clone(),
+            )
+            .await?;
+
+        // A test or a group may only exist in one of the revisions. The comparison reports such
+        // tests as missing on the other side, so only selectors valid in neither revision fail.
+        let mut tests = Vec::new();
+        for selector in message.tests {
+            let batch = match (
+                submission.resolve_tests(vec![selector.clone()]),
+                candidate.resolve_tests(vec![selector]),
+            ) {
+                (Err(_), Err(e)) => Err(e)?,
+                (baseline, candidate) => baseline
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(candidate.unwrap_or_default()),
+            };
+            for test in batch {
+                if !tests.contains(&test) {
+                    tests.push(test);
+                }
+            }
+        }
+
+        (core, submission, candidate, tests)
+    };
+
+    let communicator = client.communicator.clone();
+    let notify = async move |result| {
+        if let Err(e) = communicator
+            .send_to_conductor(message::i2c::Message::NotifyRevisionComparison(
+                message::i2c::NotifyRevisionComparison {
+                    submission_id: message.submission_id,
+                    candidate_revision_id: message.candidate_revision_id,
+                    result,
+                },
+            ))
+            .await
+        {
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
+        }
+    };
+
+    match res {
+        Ok((core, submission, candidate, tests)) => {
+            tokio::spawn(async move {
+                let result = submission
+                    .compare_on_core(core, &candidate, message.candidate_invocation_limits, tests)
+                    .await;
+                notify(result).await;
+            });
+        }
+        Err(e) => notify(Err(e)).await,
+    }
+}
+
+async fn compare_submissions(message: message::c2i::CompareSubmissions, client: &Arc<Client>) {
+    let res: Result<_, errors::Error> = try {
+        let submissions = client.submissions.read().await;
+        let get_submission = |id: &String| {
+            submissions
+                .get(id)
+                .ok_or_else(|| {
+                    errors::ConductorFailure(format!(
+                        "Submission {id} does not exist or has already been finalized"
+                    ))
+                })
+                .cloned()
+        };
+        let submission = get_submission(&message.submission_id)?;
+        let other_submission = get_submission(&message.other_submission_id)?;
+        drop(submissions);
+
+        if submission.problem_revision_hash() != other_submission.problem_revision_hash() {
+            Err(errors::ConductorFailure(format!(
+                "Submissions {} and {} were added with different problem revisions",
+                message.submission_id, message.other_submission_id
+            )))?;
+        }
+
+        let core = client.try_lock_core(message.core)?;
+        let tests = submission.resolve_tests(message.tests)?;
+
+        (core, submission, other_submission, tests)
+    };
+
+    let communicator = client.communicator.clone();
+    let notify = async move |result| {
+        if let Err(e) = communicator
+            .send_to_conductor(message::i2c::Message::NotifySubmissionComparison(
+                message::i2c::NotifySubmissionComparison {
+                    submission_id: message.submission_id,
+                    other_submission_id: message.other_submission_id,
+                    result,
+                },
+            ))
+            .await
+        {
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
+        }
+    };
+
+    match res {
+        Ok((core, submission, other_submission, tests)) => {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let result: Result<_, errors::Error> = try {
+                    // Both submissions are judged with the seeds of the first one, so that
+                    // randomized tests don't diverge by themselves
+                    let seed_key = submission.seed_key().to_string();
+                    let results = submission
+                        .rejudge_on_core(core, tests.clone(), seed_key.clone())
+                        .await?;
+                    // The core is only held while a command is being started, so it has to be
+                    // locked again for the other submission
+                    let core = client.try_lock_core(message.core)?;
+                    let other_results = other_submission
+                        .rejudge_on_core(core, tests, seed_key)
+                        .await?;
+                    problem::verdict::SubmissionDiff::new(results, other_results)
+                };
+                notify(result).await;
+            });
+        }
+        Err(e) => notify(Err(e)).await,
+    }
+}
+
+async fn reload_image(message: message::c2i::ReloadImage, client: &Arc<Client>) {
+    let client = client.clone();
+    // Warming up the new image takes a while, and messages must keep being handled meanwhile
+    tokio::spawn(async move {
+        let name = message.name;
+        let result = swap_image(
+            &client,
+            &name,
+            message.path,
+            message.config,
+            message.manifest,
+        )
+        .await;
+        match result {
+            Ok(ref version) => println!("Switched image {name} to version {version}"),
+            Err(ref e) => println!("Failed to reload image {name}: {e:?}"),
+        }
+        if let Err(e) = client
+            .communicator
+            .send_to_conductor(message::i2c::Message::NotifyImageReload(
+                message::i2c::NotifyImageReload { name, result },
+            ))
+            .await
+        {
+            errors::log_throttled(
+                "send to conductor",
+                format!("Failed to send to conductor: {e:?}"),
+            );
+        }
+    });
+}
+
+// A local way to reload the images from the configured paths, e.g. after image.sfs is replaced
+async fn reload_image_on_sighup(client: Arc<Client>) {
+    let mut hangups =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                println!(
+                    "Failed to handle SIGHUP, the image can only be reloaded by the conductor: \
+                     {e:?}"
+                );
+                return;
+            }
+        };
+    while hangups.recv().await.is_some() {
+        println!("Received SIGHUP, reloading the images");
+        // Images added by the conductor at runtime have no configured paths and are left alone
+        let mut names: Vec<String> = client.config.images.keys().cloned().collect();
+        names.sort();
+        names.insert(0, config::DEFAULT_IMAGE.to_string());
+        for name in names {
+            reload_image(
+                message::c2i::ReloadImage {
+                    name,
+                    path: None,
+                    config: None,
+                    manifest: None,
+                },
+                &client,
+            )
+            .await;
+        }
+    }
+}
+
+// Mounts and warms up the image, then switches new submissions to it. Returns the new version
+async fn swap_image(
+    client: &Client,
+    name: &str,
+    path: Option<String>,
+    config: Option<String>,
+    manifest: Option<String>,
+) -> Result<String, errors::Error> {
+    let image_config = client.config.image_config(name);
+    if image_config.is_none() && client.config.requires_signed_images() {
+        return Err(errors::ConfigurationFailure(format!(
+            "Image {name} is not configured, so it cannot be checked for a signature, which other \
+             images on this invoker require"
+        )));
+    }
+
+    // The key comes from the configuration file only, so that whoever can send ReloadImage cannot
+    // make the invoker use an unsigned image where a signed one is required
+    let public_key =
+        image_config.and_then(|image_config| image_config.manifest_public_key.as_deref());
+    let manifest =
+        manifest.or_else(|| image_config.and_then(|image_config| image_config.manifest.clone()));
+    let manifest = match manifest {
+        Some(manifest) => Some(
+            image::integrity::Manifest::load(&manifest, public_key).map_err(|e| {
+                errors::ConfigurationFailure(format!(
+                    "Failed to load the manifest of the image from path {manifest}: {e:?}"
+                ))
+            })?,
+        ),
+        None if public_key.is_some() => {
+            return Err(errors::ConfigurationFailure(format!(
+                "Image {name} must be signed, but no manifest is given"
+            )));
+        }
+        None => None,
+    };
+
+    let (path, config) = match (path, config, image_config) {
+        (Some(path), Some(config), _) => (path, config),
+        (path, config, Some(image_config)) => (
+            path.unwrap_or_else(|| image_config.path.clone()),
+            config.unwrap_or_else(|| image_config.config.clone()),
+        ),
+        (_, _, None) => {
+            return Err(errors::ConfigurationFailure(format!(
+                "Image {name} is not configured, so both its path and config must be given"
+            )));
+        }
+    };
+
+    // Held until the switch, so that concurrent reloads take effect in order
+    let mut mounter = client.image_mounter.lock().await;
+
+    let image_cfg = std::fs::read_to_string(&config).map_err(|e| {
+        errors::ConfigurationFailure(format!("Failed to read image.cfg from path {config}: {e:?}"))
+    })?;
+    let mut image_cfg = image::config::Config::load(&image_cfg).map_err(|e| {
+        errors::ConfigurationFailure(format!(
+            "Failed to load image.cfg as a script from path {config}: {e:?}"
+        ))
+    })?;
+    if let Some(image_config) = image_config {
+        image_cfg.extensions.extend(image_config.extensions.clone());
+    }
+    // Verifying the image reads all of it, which should not stall other tasks
+    let new_image =
+        tokio::task::block_in_place(|| mounter.mount(&path, image_cfg, manifest.as_ref()))
+            .map_err(|e| {
+                errors::ConfigurationFailure(format!(
+                    "Failed to mount image from path {path}: {e:?}"
+                ))
+            })?;
+    let new_image = Arc::new(new_image);
+
+    if let Err(e) =
+        image::image::Image::warm_up(new_image.clone(), &client.config.sandbox.limits).await
+    {
+        // Nobody has seen the image yet, so it can be unmounted right away
+        if let Ok(new_image) = Arc::try_unwrap(new_image) {
+            if let Err(e) = image::mount::unmount(new_image) {
+                println!("Failed to unmount the image that failed to warm up: {e:?}");
+            }
+        }
+        return Err(e);
+    }
+
+    let version = new_image.version.clone();
+    let spare_language = match client.spare_workers {
+        Some(_) if name == config::DEFAULT_IMAGE => {
+            Some(prewarm_language(&client.config, &new_image)?)
+        }
+        _ => None,
+    };
+    let old_image = client
+        .mounted_images
+        .write()
+        .await
+        .insert(name.to_string(), new_image);
+    if let Some(old_image) = old_image {
+        tokio::spawn(unmount_when_unused(old_image));
+    }
+    // Spares made from the old image would keep it mounted
+    if let (Some(spare_workers), Some(language)) = (&client.spare_workers, spare_language) {
+        spare_workers.set_language(language).await;
+    }
+    Ok(version)
+}
+
+fn prewarm_language(
+    config: &config::Config,
+    image: &Arc<image::image::Image>,
+) -> Result<Option<image::language::Language>, errors::Error> {
+    config
+        .prewarm
+        .as_ref()
+        .and_then(|prewarm| prewarm.language.clone())
+        .map(|name| image::image::Image::get_language(image.clone(), name))
+        .transpose()
+}
+
+async fn unmount_when_unused(mut old_image: Arc<image::image::Image>) {
+    // Submissions drop their references to the image when they are finalized. There is no
+    // notification for that, but reloads are rare, so polling is good enough
+    let old_image = loop {
+        match Arc::try_unwrap(old_image) {
+            Ok(old_image) => break old_image,
+            Err(still_used) => {
+                old_image = still_used;
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            }
+        }
+    };
+
+    // Pooled rootfs's made from the old image keep it busy
+    if let Err(e) = image::sandbox::drain_pool() {
+        println!("Failed to remove pooled rootfs's: {e:?}");
+    }
+
+    let mountpoint = old_image.mountpoint.clone();
+    match image::mount::unmount(old_image) {
+        Ok(()) => println!("Unmounted old image at {mountpoint:?}"),
+        Err(e) => println!("Failed to unmount old image at {mountpoint:?}: {e:?}"),
+    }
+}
+
 async fn supply_file(message: message::c2i::SupplyFile, client: &Client) {
     client.communicator.supply_file(message).await;
 }
 
+fn get_host_id() -> anyhow::Result<String> {
+    // machine-id is the most stable identifier, but it is not present on all systems, e.g. in some
+    // containers
+    match std::fs::read_to_string("/etc/machine-id") {
+        Ok(machine_id) => Ok(machine_id.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(nix::unistd::gethostname(&mut [0u8; 256])?
+                .to_string_lossy()
+                .into_owned())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 fn enter_sandbox() -> anyhow::Result<()> {
     // Various sanity checks
     let suid_dumpable = std::fs::read_to_string("/proc/sys/fs/suid_dumpable")?;
@@ -405,25 +1497,6 @@ fn enter_sandbox() -> anyhow::Result<()> {
         anyhow::bail!("suid_dumpable is not set to zero, unable to continue safely");
     }
 
-    let fstype = nix::sys::statfs::statfs("/sys/fs/cgroup")
-        .context("cgroups are not available at /sys/fs/cgroup")?
-        .filesystem_type();
-    match fstype.0 as i64 {
-        libc::CGROUP2_SUPER_MAGIC => {}
-        libc::TMPFS_MAGIC => {
-            anyhow::bail!(
-                "cgroups v1 seems to be mounted at /sys/fs/cgroup. sunwalker requires cgroups v2. \
-                 Please configure your kernel and/or distribution to use cgroups v2"
-            );
-        }
-        _ => {
-            anyhow::bail!(
-                "Unknown filesystem type at /sys/fs/cgroup. sunwalker requires cgroups v2. Please \
-                 configure your kernel and/or distribution to use cgroups v2"
-            );
-        }
-    }
-
     // Unshare namespaces
     if unsafe { libc::unshare(CLONE_NEWNS) } != 0 {
         anyhow::bail!("Initial unshare() failed, unable to continue safely");
@@ -433,8 +1506,12 @@ fn enter_sandbox() -> anyhow::Result<()> {
     system::change_propagation("/", system::MS_PRIVATE | system::MS_REC)
         .with_context(|| "Setting propagation of / to private recursively failed")?;
 
+    // The supervisor has to share the mount namespace we have just created. All the mounts below go
+    // through it too
+    supervisor::start().with_context(|| "Failed to start the mount supervisor")?;
+
     // Mount tmpfs
-    system::mount("none", "/tmp/sunwalker_invoker", "tmpfs", 0, None)
+    supervisor::mount("none", "/tmp/sunwalker_invoker", "tmpfs", 0, None)
         .with_context(|| "Mounting tmpfs on /tmp/sunwalker_invoker failed")?;
 
     // Make various temporary directories
@@ -450,6 +1527,10 @@ fn enter_sandbox() -> anyhow::Result<()> {
         .with_context(|| "Creating /tmp/sunwalker_invoker/artifacts failed")?;
     std::fs::create_dir("/tmp/sunwalker_invoker/emptydir")
         .with_context(|| "Creating /tmp/sunwalker_invoker/emptydir failed")?;
+    std::fs::create_dir("/tmp/sunwalker_invoker/warm")
+        .with_context(|| "Creating /tmp/sunwalker_invoker/warm failed")?;
+    std::fs::create_dir("/tmp/sunwalker_invoker/ids")
+        .with_context(|| "Creating /tmp/sunwalker_invoker/ids failed")?;
 
     // Prepare a copy of /dev
     std::fs::create_dir("/tmp/sunwalker_invoker/dev")
@@ -472,7 +1553,7 @@ fn enter_sandbox() -> anyhow::Result<()> {
         } else {
             std::fs::File::create(&target).with_context(|| format!("Cannot touch {target:?}"))?;
         }
-        system::bind_mount(&source, &target)
+        supervisor::bind_mount(&source, &target)
             .with_context(|| format!("Bind-mounting {source} to {target} failed"))?;
     }
 
@@ -1,82 +1,250 @@
-use crate::{errors, errors::ToResult, message};
+use crate::{config, errors, errors::ToResult, message};
 use async_stream::try_stream;
 use futures::stream::{SplitSink, SplitStream, Stream};
 use futures_util::SinkExt;
 use futures_util::StreamExt;
-use std::collections::HashMap;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::atomic;
 use tokio::sync::{oneshot, Mutex};
 use tokio_tungstenite::tungstenite;
 
+type WebSocket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
 pub struct Communicator {
-    conductor_read: Mutex<
-        SplitStream<
-            tokio_tungstenite::WebSocketStream<
-                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-            >,
-        >,
-    >,
-    conductor_write: Mutex<
-        SplitSink<
-            tokio_tungstenite::WebSocketStream<
-                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-            >,
-            tungstenite::Message,
-        >,
-    >,
+    address: String,
+    reconnect_interval: std::time::Duration,
+    // None while disconnected
+    conductor_read: Mutex<Option<SplitStream<WebSocket>>>,
+    conductor_write: Mutex<Option<SplitSink<WebSocket, tungstenite::Message>>>,
     next_request_id: atomic::AtomicU64,
     requests: Mutex<HashMap<u64, oneshot::Sender<Result<Vec<u8>, errors::Error>>>>,
+    outbox: Mutex<Outbox>,
 }
 
-impl Communicator {
-    pub async fn connect(conductor_address: &str) -> Result<Communicator, errors::Error> {
-        let (conductor_ws, _) = tokio_tungstenite::connect_async(conductor_address)
-            .await
-            .context_invoker("Failed to connect to the conductor via a websocket")?;
+// Messages that could not be delivered because the conductor was unreachable. Judging goes on while
+// the invoker is offline, and the messages are sent in their original order once it is back.
+struct Outbox {
+    // Set once the handshake is done and the queue is flushed. Until then, messages are queued so
+    // that they are not reordered
+    online: bool,
+    queue: VecDeque<(u64, Vec<u8>)>,
+    next_seq: u64,
+    // If set, the queue is mirrored to this directory, one file per message, so that it survives
+    // a restart of the invoker
+    path: Option<PathBuf>,
+}
+
+impl Outbox {
+    fn load(path: Option<PathBuf>) -> Result<Outbox, errors::Error> {
+        let mut queue = VecDeque::new();
+        if let Some(ref path) = path {
+            std::fs::create_dir_all(path)
+                .with_context_invoker(|| format!("Failed to create outbox at {path:?}"))?;
+            let mut seqs = Vec::new();
+            for entry in std::fs::read_dir(path)
+                .with_context_invoker(|| format!("Failed to list outbox at {path:?}"))?
+            {
+                let entry = entry
+                    .with_context_invoker(|| format!("Failed to list outbox at {path:?}"))?;
+                // Leftovers of interrupted writes have a .tmp suffix and are skipped
+                if let Some(seq) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.parse::<u64>().ok())
+                {
+                    seqs.push(seq);
+                }
+            }
+            seqs.sort();
+            for seq in seqs {
+                let message_path = path.join(seq.to_string());
+                let data = std::fs::read(&message_path).with_context_invoker(|| {
+                    format!("Failed to read queued message at {message_path:?}")
+                })?;
+                queue.push_back((seq, data));
+            }
+        }
+        if !queue.is_empty() {
+            println!(
+                "{} messages to the conductor were left over from the previous run",
+                queue.len()
+            );
+        }
+        Ok(Outbox {
+            online: false,
+            next_seq: queue.back().map(|(seq, _)| seq + 1).unwrap_or(0),
+            queue,
+            path,
+        })
+    }
 
-        let (conductor_write, conductor_read) = conductor_ws.split();
+    fn push(&mut self, data: Vec<u8>) -> Result<(), errors::Error> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(ref path) = self.path {
+            let tmp_path = path.join(format!("{seq}.tmp"));
+            std::fs::write(&tmp_path, &data).with_context_invoker(|| {
+                format!("Failed to write queued message to {tmp_path:?}")
+            })?;
+            std::fs::rename(&tmp_path, path.join(seq.to_string())).with_context_invoker(|| {
+                format!("Failed to commit queued message at {tmp_path:?}")
+            })?;
+        }
+        self.queue.push_back((seq, data));
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<(), errors::Error> {
+        if let Some((seq, _)) = self.queue.pop_front() {
+            if let Some(ref path) = self.path {
+                let message_path = path.join(seq.to_string());
+                std::fs::remove_file(&message_path).with_context_invoker(|| {
+                    format!("Failed to remove delivered message at {message_path:?}")
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Communicator {
+    pub async fn connect(config: &config::ConductorConfig) -> Result<Communicator, errors::Error> {
+        let (conductor_write, conductor_read) = Self::open(&config.address).await?;
 
         Ok(Communicator {
-            conductor_read: Mutex::new(conductor_read),
-            conductor_write: Mutex::new(conductor_write),
+            address: config.address.clone(),
+            reconnect_interval: std::time::Duration::from_millis(config.reconnect_interval),
+            conductor_read: Mutex::new(Some(conductor_read)),
+            conductor_write: Mutex::new(Some(conductor_write)),
             next_request_id: atomic::AtomicU64::new(0),
             requests: Mutex::new(HashMap::new()),
+            outbox: Mutex::new(Outbox::load(config.outbox.as_ref().map(PathBuf::from))?),
         })
     }
 
-    pub async fn send_to_conductor(
-        &self,
-        message: message::i2c::Message,
-    ) -> Result<(), errors::Error> {
+    async fn open(
+        address: &str,
+    ) -> Result<(SplitSink<WebSocket, tungstenite::Message>, SplitStream<WebSocket>), errors::Error>
+    {
+        let (conductor_ws, _) = tokio_tungstenite::connect_async(address)
+            .await
+            .context_invoker("Failed to connect to the conductor via a websocket")?;
+        Ok(conductor_ws.split())
+    }
+
+    // Marks the connection as lost. Messages sent from now on are queued, and file requests in
+    // flight fail, as the conductor will not answer them on a new connection.
+    pub async fn disconnect(&self) {
+        self.outbox.lock().await.online = false;
+        *self.conductor_write.lock().await = None;
+        *self.conductor_read.lock().await = None;
+        for (_, tx) in self.requests.lock().await.drain() {
+            let _ = tx.send(Err(errors::CommunicationError(
+                "Lost connection to the conductor while waiting for a file".to_string(),
+            )));
+        }
+    }
+
+    // Retries until the conductor is reachable again. The caller is expected to repeat the
+    // handshake and then flush the outbox.
+    pub async fn reconnect(&self) {
+        loop {
+            match Self::open(&self.address).await {
+                Ok((conductor_write, conductor_read)) => {
+                    *self.conductor_write.lock().await = Some(conductor_write);
+                    *self.conductor_read.lock().await = Some(conductor_read);
+                    return;
+                }
+                Err(e) => errors::log_throttled(
+                    "reconnect to conductor",
+                    format!("Failed to reconnect to the conductor: {e:?}"),
+                ),
+            }
+            tokio::time::sleep(self.reconnect_interval).await;
+        }
+    }
+
+    fn serialize(message: &message::i2c::Message) -> Result<Vec<u8>, errors::Error> {
+        rmp_serde::to_vec(message).map_err(|e| {
+            errors::CommunicationError(format!("Failed to serialize a message to conductor: {e:?}"))
+        })
+    }
+
+    async fn send_bytes(&self, data: Vec<u8>) -> Result<(), errors::Error> {
         self.conductor_write
             .lock()
             .await
-            .send(tungstenite::Message::Binary(
-                rmp_serde::to_vec(&message).map_err(|e| {
-                    errors::CommunicationError(format!(
-                        "Failed to serialize a message to conductor: {e:?}"
-                    ))
-                })?,
-            ))
+            .as_mut()
+            .ok_or_else(|| {
+                errors::CommunicationError("Not connected to the conductor".to_string())
+            })?
+            .send(tungstenite::Message::Binary(data))
             .await
             .map_err(|e| {
                 errors::CommunicationError(format!(
                     "Failed to send a message to conductor via websocket: {e:?}"
                 ))
-            })?;
+            })
+    }
+
+    // Bypasses the outbox. Only meant for the handshake and for requests that make no sense to
+    // deliver late
+    pub async fn send_immediately(
+        &self,
+        message: message::i2c::Message,
+    ) -> Result<(), errors::Error> {
+        self.send_bytes(Self::serialize(&message)?).await
+    }
+
+    // Succeeds if the message was either delivered or queued for delivery after reconnecting
+    pub async fn send_to_conductor(
+        &self,
+        message: message::i2c::Message,
+    ) -> Result<(), errors::Error> {
+        let data = Self::serialize(&message)?;
+        let mut outbox = self.outbox.lock().await;
+        if outbox.online {
+            match self.send_bytes(data.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    errors::log_throttled(
+                        "send to conductor",
+                        format!("Queueing messages to the conductor until it is back: {e:?}"),
+                    );
+                    outbox.online = false;
+                }
+            }
+        }
+        outbox.push(data)
+    }
 
+    // Delivers the queued messages in order and goes back online. On failure, the undelivered
+    // messages stay queued.
+    pub async fn flush_outbox(&self) -> Result<(), errors::Error> {
+        let mut outbox = self.outbox.lock().await;
+        let n_queued = outbox.queue.len();
+        while let Some((_, data)) = outbox.queue.front() {
+            self.send_bytes(data.clone()).await?;
+            outbox.pop()?;
+        }
+        if n_queued > 0 {
+            println!("Delivered {n_queued} messages queued while the conductor was unreachable");
+        }
+        outbox.online = true;
         Ok(())
     }
 
-    async fn request_file(&self, hash: &str) -> Result<Vec<u8>, errors::Error> {
+    pub async fn request_file(&self, hash: &str) -> Result<Vec<u8>, errors::Error> {
         let request_id = self.next_request_id.fetch_add(1, atomic::Ordering::Relaxed);
 
         let (tx, rx) = oneshot::channel();
         self.requests.lock().await.insert(request_id, tx);
 
-        self.send_to_conductor(message::i2c::Message::RequestFile(
+        // A request queued until reconnecting would stall the download, and the response would
+        // arrive on a different connection anyway, so it fails right away instead
+        self.send_immediately(message::i2c::Message::RequestFile(
             message::i2c::RequestFile {
                 request_id,
                 hash: hash.to_string(),
@@ -88,87 +256,14 @@ impl Communicator {
             .context_invoker("Did not receive response to request of file")?
     }
 
-    pub async fn download_archive(
-        &self,
-        topic: &str,
-        target_path: &Path,
-    ) -> Result<(), errors::Error> {
-        if let Err(e) = std::fs::remove_dir_all(target_path) {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                return Err(e).context_invoker("Failed to delete target directory");
-            }
-        }
-        std::fs::create_dir_all(target_path)
-            .context_invoker("Failed to create target directory")?;
-
-        let manifest = self
-            .request_file(&format!("manifest/{topic}"))
-            .await
-            .context_invoker("Failed to load manifest")?;
-
-        let manifest = std::str::from_utf8(&manifest).map_err(|e| {
-            errors::ConfigurationFailure(format!("Invalid manifest for topic {topic}: {e:?}"))
-        })?;
-
-        for mut line in manifest.lines() {
-            if line.ends_with('/') {
-                // Directory
-                let dir_path = target_path.join(&line);
-                std::fs::create_dir(&dir_path)
-                    .with_context_invoker(|| format!("Failed to create {dir_path:?}"))?;
-            } else {
-                // File
-                let mut executable = false;
-                if line.starts_with("+x ") {
-                    executable = true;
-                    line = &line[3..];
-                }
-
-                // TODO: deduplication
-                let (hash, file) = line
-                    .split_once(' ')
-                    .context_invoker("Invalid manifest: invalid line format")?;
-
-                // TODO: stream directly to file without loading to RAM
-                let data = self
-                    .request_file(hash)
-                    .await
-                    .with_context_invoker(|| format!("Failed to download file {file}"))?;
-
-                let file_path = target_path.join(&file);
-                std::fs::write(&file_path, data)
-                    .with_context_invoker(|| format!("Failed to write to {file_path:?}"))?;
-
-                if executable {
-                    let mut permissions = file_path
-                        .metadata()
-                        .with_context_invoker(|| {
-                            format!("Failed to get metadata of {file_path:?}")
-                        })?
-                        .permissions();
-
-                    // Whoever can read can also execute
-                    permissions.set_mode(permissions.mode() | ((permissions.mode() & 0o444) >> 2));
-
-                    std::fs::set_permissions(&file_path, permissions).with_context_invoker(
-                        || format!("Failed to make {file_path:?} executable"),
-                    )?
-                }
-            }
-        }
-
-        let ready_path = target_path.join(".ready");
-        std::fs::write(&ready_path, b"")
-            .with_context_invoker(|| format!("Failed to write to {ready_path:?}"))?;
-
-        Ok(())
-    }
-
     pub fn messages<'a>(
         &'a self,
     ) -> impl Stream<Item = Result<message::c2i::Message, errors::Error>> + 'a {
         try_stream! {
             let mut conductor_read = self.conductor_read.lock().await;
+            let conductor_read = conductor_read.as_mut().ok_or_else(|| {
+                errors::CommunicationError("Not connected to the conductor".to_string())
+            })?;
 
             while let Some(message) = conductor_read.next().await {
                 let message = message.map_err(|e| {
@@ -1,23 +1,92 @@
-use serde::Deserialize;
+use crate::{errors, outputs};
+use multiprocessing::Object;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub invoker: InvokerConfig,
     pub image: ImageConfig,
+    // More images, e.g. with beta toolchains, mounted alongside the default one. Problems and
+    // submissions choose an image by name; the one from field image is called "default"
+    #[serde(default)]
+    pub images: HashMap<String, ImageConfig>,
     pub environment: EnvironmentConfig,
     pub conductor: ConductorConfig,
     pub cache: CacheConfig,
+    #[serde(default)]
+    pub problem_source: ProblemSourceConfig,
+    #[serde(default)]
+    pub retention: outputs::RetentionPolicy,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    // Without this, the invoker holds on to its caches and pools indefinitely
+    #[serde(default)]
+    pub idle: Option<IdleConfig>,
+    // Without this, workers are spawned when a submission first needs a core
+    #[serde(default)]
+    pub prewarm: Option<PrewarmConfig>,
 }
 
 #[derive(Deserialize)]
 pub struct InvokerConfig {
     pub name: String,
+    // Path to a PKCS#8 Ed25519 key. If set, test results are signed
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    // How many times a test that failed because of an infrastructure error is rerun before it is
+    // reported as Bug. This includes re-fetching damaged problems, so 0 disables that too.
+    #[serde(default = "default_test_retries")]
+    pub test_retries: u32,
+    // How often, in seconds, residue of crashed and finalized submissions is reclaimed, see
+    // image/gc.rs
+    #[serde(default = "default_gc_interval")]
+    pub gc_interval: u64,
 }
 
-#[derive(Deserialize)]
+fn default_test_retries() -> u32 {
+    2
+}
+
+fn default_gc_interval() -> u64 {
+    300
+}
+
+#[derive(Clone, Deserialize)]
 pub struct ImageConfig {
     pub path: String,
     pub config: String,
+    // A list of SHA-256 hashes of the packages, see image/integrity.rs. If set, the image is only
+    // used if all its packages match
+    #[serde(default)]
+    pub manifest: Option<String>,
+    // Hex-encoded Ed25519 public key the manifest must be signed with. Requires manifest
+    #[serde(default)]
+    pub manifest_public_key: Option<String>,
+    // Overrides the extensions from image.cfg, e.g. to make .cpp mean a different compiler on
+    // this installation
+    #[serde(default)]
+    pub extensions: HashMap<String, String>,
+}
+
+pub const DEFAULT_IMAGE: &str = "default";
+
+impl Config {
+    pub fn image_config(&self, name: &str) -> Option<&ImageConfig> {
+        if name == DEFAULT_IMAGE {
+            Some(&self.image)
+        } else {
+            self.images.get(name)
+        }
+    }
+
+    // If any image must be signed, images that are not configured, and thus have no key to check
+    // their signature against, must not be used either
+    pub fn requires_signed_images(&self) -> bool {
+        std::iter::once(&self.image)
+            .chain(self.images.values())
+            .any(|image_config| image_config.manifest_public_key.is_some())
+    }
 }
 
 #[derive(Deserialize)]
@@ -25,16 +94,335 @@ pub struct EnvironmentConfig {
     pub cpu_cores: Vec<u64>,
     pub ephemeral_disk_space: Space,
     pub ephemeral_inodes: u64,
+    // If set, a core may be used for compiling one submission while another submission is judged on
+    // it. Compilation then runs with the lowest CPU priority. Otherwise, cores are locked
+    // exclusively, which is more predictable but leaves them idle during long compilations.
+    #[serde(default)]
+    pub oversubscription: bool,
+    // If set, judging cores that are not in the kernel's nohz_full list are reported as not
+    // isolated. Timer ticks are a minor source of noise, so this is off by default.
+    #[serde(default)]
+    pub require_nohz_full: bool,
+    // If set, IRQs are moved away from the judging cores while the invoker is running
+    #[serde(default)]
+    pub manage_irq_affinity: bool,
+}
+
+// Limits applied to every block of a strategy on top of the per-test limits the conductor sends
+#[derive(Clone, Debug, Deserialize, Object)]
+pub struct SandboxConfig {
+    // pids.max of the block cgroup and of the cgroup of a build. This counts threads too, so
+    // multithreaded runtimes and compilers need some headroom, but a fork bomb is stopped long
+    // before it exhausts the process table of the machine
+    #[serde(default = "default_max_pids")]
+    pub max_pids: u64,
+    // Disk bandwidth limits in bytes per second, applied to every disk. Reads served from the page
+    // cache are not counted.
+    #[serde(default)]
+    pub io_read_bps: Option<u64>,
+    #[serde(default)]
+    pub io_write_bps: Option<u64>,
+    // Without this, sandboxes have no network at all
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub rootfs_backend: RootfsBackend,
+    #[serde(default)]
+    pub log_limits: LogLimits,
+    #[serde(default)]
+    pub user_ids: UserIdRange,
+}
+
+// How the read-only root of a sandbox is assembled from the image. Overlayfs is cheaper, but is not
+// available in some containers and kernels, e.g. on top of another overlayfs. Auto is resolved at
+// startup by trying to mount an overlay.
+#[derive(Clone, Copy, Debug, Deserialize, Object, PartialEq, Eq)]
+pub enum RootfsBackend {
+    Auto,
+    Overlay,
+    // A read-only tmpfs with the top-level entries of the package bind-mounted into it
+    Bind,
+}
+
+impl Default for RootfsBackend {
+    fn default() -> Self {
+        RootfsBackend::Auto
+    }
+}
+
+fn default_max_pids() -> u64 {
+    256
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            max_pids: default_max_pids(),
+            io_read_bps: None,
+            io_write_bps: None,
+            network: None,
+            limits: LimitsConfig::default(),
+            rootfs_backend: RootfsBackend::default(),
+            log_limits: LogLimits::default(),
+            user_ids: UserIdRange::default(),
+        }
+    }
+}
+
+// External UIDs and GIDs leased to the sandboxed users, see image/ids.rs. The range must not be
+// used by anything else on the machine, in particular by the subordinate IDs of other users
+#[derive(Clone, Copy, Debug, Deserialize, Object)]
+pub struct UserIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+impl Default for UserIdRange {
+    fn default() -> Self {
+        UserIdRange {
+            start: 100000,
+            count: 65536,
+        }
+    }
+}
+
+// How many bytes of each log the worker sends to the invoker, and thus to the conductor. Longer logs
+// keep their beginning and their end, see outputs::cap_log. 0 means no limit. The compilation log is
+// also limited by the image, which keeps only its beginning
+#[derive(Clone, Debug, Deserialize, Object)]
+pub struct LogLimits {
+    #[serde(default = "default_compilation_log_size")]
+    pub compilation_log_size: u64,
+    // Applies to each entry of TestJudgementResult::logs separately, e.g. checker comments
+    #[serde(default = "default_test_log_size")]
+    pub test_log_size: u64,
+}
+
+fn default_compilation_log_size() -> u64 {
+    256 * 1024
+}
+
+fn default_test_log_size() -> u64 {
+    1024 * 1024
+}
+
+impl Default for LogLimits {
+    fn default() -> Self {
+        LogLimits {
+            compilation_log_size: default_compilation_log_size(),
+            test_log_size: default_test_log_size(),
+        }
+    }
+}
+
+// Sizes of /space in the sandboxes, in bytes and inodes. Problems may override the run quotas, e.g.
+// when a solution is expected to write large temporary files.
+//
+// The ancillary buffers used to pass file descriptors over channels are deliberately not
+// configurable. Both ends of a channel must agree on their size, so it is part of the protocol of
+// the multiprocessing crate, and the kernel caps a message at SCM_MAX_FD (253) descriptors anyway.
+#[derive(Clone, Debug, Deserialize, Object)]
+pub struct LimitsConfig {
+    #[serde(default = "default_build_space")]
+    pub build_space: u64,
+    #[serde(default = "default_build_inodes")]
+    pub build_inodes: u64,
+    #[serde(default = "default_run_space")]
+    pub run_space: u64,
+    #[serde(default = "default_run_inodes")]
+    pub run_inodes: u64,
+}
+
+// The part of LimitsConfig a problem may override in judging.msgpack
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LimitsOverride {
+    #[serde(default)]
+    pub run_space: Option<u64>,
+    #[serde(default)]
+    pub run_inodes: Option<u64>,
+}
+
+// /space is a tmpfs, so its size is charged to RAM. Anything beyond these bounds is most likely a
+// unit mistake, e.g. megabytes instead of bytes
+const MIN_SPACE: u64 = 1024 * 1024;
+const MAX_SPACE: u64 = 16 * 1024 * 1024 * 1024;
+const MIN_INODES: u64 = 16;
+const MAX_INODES: u64 = 1024 * 1024;
+
+fn default_build_space() -> u64 {
+    32 * 1024 * 1024
+}
+
+fn default_build_inodes() -> u64 {
+    1024
+}
+
+fn default_run_space() -> u64 {
+    32 * 1024 * 1024
+}
+
+fn default_run_inodes() -> u64 {
+    1024
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            build_space: default_build_space(),
+            build_inodes: default_build_inodes(),
+            run_space: default_run_space(),
+            run_inodes: default_run_inodes(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn with_override(&self, limits_override: &LimitsOverride) -> LimitsConfig {
+        LimitsConfig {
+            build_space: self.build_space,
+            build_inodes: self.build_inodes,
+            run_space: limits_override.run_space.unwrap_or(self.run_space),
+            run_inodes: limits_override.run_inodes.unwrap_or(self.run_inodes),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        for (name, value, min, max) in [
+            ("build_space", self.build_space, MIN_SPACE, MAX_SPACE),
+            ("build_inodes", self.build_inodes, MIN_INODES, MAX_INODES),
+            ("run_space", self.run_space, MIN_SPACE, MAX_SPACE),
+            ("run_inodes", self.run_inodes, MIN_INODES, MAX_INODES),
+        ] {
+            if !(min..=max).contains(&value) {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Limit {name} is {value}, which is out of range {min}..={max}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Network access for the programs run on tests. Compilers never get network access, whatever is
+// configured here.
+#[derive(Clone, Debug, Deserialize, Object)]
+pub struct NetworkConfig {
+    // The bridge the sandboxes are connected to. It is created at startup, replacing an existing
+    // interface with the same name
+    #[serde(default = "default_bridge")]
+    pub bridge: String,
+    // IPv4 subnet in CIDR notation. The bridge takes the first host address, sandboxes the rest
+    #[serde(default = "default_subnet")]
+    pub subnet: String,
+    // Addresses and CIDR ranges the sandboxes may connect to, in nftables syntax. Nothing else is
+    // reachable: not the host, not other sandboxes, not even a DNS server unless it is listed here
+    #[serde(default)]
+    pub allowed_destinations: Vec<String>,
+    // If not empty, only these TCP and UDP ports of the allowed destinations are reachable
+    #[serde(default)]
+    pub allowed_ports: Vec<u16>,
+}
+
+fn default_bridge() -> String {
+    "sunwalker0".to_string()
+}
+
+fn default_subnet() -> String {
+    "10.87.0.0/16".to_string()
 }
 
 #[derive(Deserialize)]
 pub struct ConductorConfig {
     pub address: String,
+    // Directory where messages to the conductor are kept while it is unreachable, so that results
+    // judged offline survive a restart of the invoker. Without it, they are only kept in memory
+    #[serde(default)]
+    pub outbox: Option<String>,
+    // Delay between attempts to reconnect to the conductor, in milliseconds
+    #[serde(default = "default_reconnect_interval")]
+    pub reconnect_interval: u64,
+}
+
+fn default_reconnect_interval() -> u64 {
+    1000
+}
+
+// What the invoker does when it has had nothing to do for a while, see idle.rs
+#[derive(Deserialize)]
+pub struct IdleConfig {
+    // How long, in seconds, the invoker must go without submissions or messages from the conductor
+    pub after: u64,
+    // How many problem revisions are kept loaded in memory. The rest are reloaded from the disk
+    // cache when needed
+    #[serde(default = "default_problem_cache_floor")]
+    pub problem_cache_floor: usize,
+    // How often, in seconds, periodic tasks wake up while idle
+    #[serde(default = "default_idle_tick")]
+    pub tick: u64,
+}
+
+fn default_problem_cache_floor() -> usize {
+    4
+}
+
+fn default_idle_tick() -> u64 {
+    600
+}
+
+// Keeps a spare worker on every core, so that the first test of a submission on the core does not
+// wait for the worker to start, see worker::SparePool
+#[derive(Deserialize)]
+pub struct PrewarmConfig {
+    // Language of the default image whose package is mounted in advance, e.g. the most common one.
+    // Only the worker itself is started if unset
+    #[serde(default)]
+    pub language: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct CacheConfig {
     pub problems: String,
+    // Where built programs are kept, see image/program_cache.rs. Programs are built every time if
+    // unset
+    #[serde(default)]
+    pub programs: Option<String>,
+    #[serde(default = "default_max_cached_programs")]
+    pub max_programs: usize,
+}
+
+fn default_max_cached_programs() -> usize {
+    1024
+}
+
+// Where problem revisions are downloaded from, see problem/source.rs. The layout is the same
+// everywhere: manifest/problems/<problem>/<revision> and files named by their hashes.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProblemSourceConfig {
+    Conductor,
+    Local {
+        path: String,
+    },
+    Http {
+        url: String,
+    },
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        #[serde(default)]
+        prefix: String,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+impl Default for ProblemSourceConfig {
+    fn default() -> Self {
+        ProblemSourceConfig::Conductor
+    }
 }
 
 #[derive(Clone, Deserialize)]
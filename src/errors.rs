@@ -1,5 +1,10 @@
+use crate::idle;
+use lazy_static::lazy_static;
 use multiprocessing::Object;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, Deserialize, Serialize, Object)]
 pub enum Error {
@@ -8,10 +13,52 @@ pub enum Error {
     ConfigurationFailure(String),
     CommunicationError(String),
     UserFailure(String),
+    // Files of a cached problem are missing or unreadable, so the problem has to be re-fetched
+    ProblemCacheFailure(String),
+    // A subprocess or a channel to it failed. The kind of the failure is kept, so that e.g. a
+    // worker that crashed can be told from one that runs a different executable
+    MultiprocessingFailure(MultiprocessingFailureKind, String),
 }
 
 pub use Error::*;
 
+// Mirrors multiprocessing::Error, which cannot be cloned or serialized itself
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, Object)]
+pub enum MultiprocessingFailureKind {
+    Io,
+    Protocol,
+    FdPassing,
+    Serialization,
+    Child,
+}
+
+impl MultiprocessingFailureKind {
+    fn of(e: &multiprocessing::Error) -> Self {
+        match e {
+            multiprocessing::Error::Io(_) => MultiprocessingFailureKind::Io,
+            multiprocessing::Error::Protocol(_) => MultiprocessingFailureKind::Protocol,
+            multiprocessing::Error::FdPassing(_) => MultiprocessingFailureKind::FdPassing,
+            multiprocessing::Error::Serialization(_) => MultiprocessingFailureKind::Serialization,
+            multiprocessing::Error::Child(_) => MultiprocessingFailureKind::Child,
+            multiprocessing::Error::Context(_, e) => Self::of(e),
+        }
+    }
+}
+
+impl Error {
+    // Failures that are not caused by the submission or the problem, e.g. a failed mount, a crashed
+    // worker or a broken channel. Tests that fail this way are worth rerunning in a fresh sandbox.
+    pub fn is_infrastructure_failure(&self) -> bool {
+        matches!(
+            self,
+            InvokerFailure(_)
+                | CommunicationError(_)
+                | ProblemCacheFailure(_)
+                | MultiprocessingFailure(..)
+        )
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -56,3 +103,104 @@ impl<T> ToResult<T> for Option<T> {
         self.ok_or_else(|| Error::InvokerFailure(f()))
     }
 }
+
+// multiprocessing errors are converted with context_multiprocessing instead of context_invoker,
+// which keeps their kind, see MultiprocessingFailure
+pub trait ToMultiprocessingResult<T> {
+    fn context_multiprocessing(self, message: &str) -> Result<T, Error>;
+}
+
+impl<T> ToMultiprocessingResult<T> for Result<T, multiprocessing::Error> {
+    fn context_multiprocessing(self, message: &str) -> Result<T, Error> {
+        self.map_err(|e| {
+            MultiprocessingFailure(
+                MultiprocessingFailureKind::of(&e),
+                format!("{message}: {e}"),
+            )
+        })
+    }
+}
+
+// A systemic failure, e.g. a broken mount or a lost connection during a contest, makes every test of
+// every submission fail in the same way, and logging each failure buries whatever happened first
+// under gigabytes of identical lines. Such errors are logged through log_throttled, which prints a
+// message at most once per THROTTLE_WINDOW and counts the rest. The count is printed along with the
+// last suppressed message when the window expires, e.g. "Mount failed: ... (x412 in last minute)".
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+
+struct ThrottledMessage {
+    window_start: Instant,
+    suppressed: u64,
+    last_message: String,
+}
+
+lazy_static! {
+    static ref THROTTLED_MESSAGES: Mutex<HashMap<String, ThrottledMessage>> =
+        Mutex::new(HashMap::new());
+}
+
+fn print_suppressed(entry: &ThrottledMessage) {
+    if entry.suppressed > 0 {
+        println!(
+            "{} (x{} in last minute)",
+            entry.last_message,
+            entry.suppressed + 1
+        );
+    }
+}
+
+// Messages are deduplicated by key, which is the message itself minus the details that differ
+// between otherwise identical failures, such as test numbers
+pub fn log_throttled(key: &str, message: String) {
+    let mut messages = THROTTLED_MESSAGES
+        .lock()
+        .expect("Failed to acquire throttled messages");
+    let now = Instant::now();
+    if let Some(entry) = messages.get_mut(key) {
+        if now.duration_since(entry.window_start) < THROTTLE_WINDOW {
+            entry.suppressed += 1;
+            entry.last_message = message;
+            return;
+        }
+        print_suppressed(entry);
+    }
+    println!("{message}");
+    messages.insert(
+        key.to_string(),
+        ThrottledMessage {
+            window_start: now,
+            suppressed: 0,
+            last_message: message,
+        },
+    );
+}
+
+// Prints the counts of suppressed messages whose windows have expired. Without this, the count
+// would only be printed when the failure happens again, which might be never
+pub fn flush_throttled_logs() {
+    let mut messages = THROTTLED_MESSAGES
+        .lock()
+        .expect("Failed to acquire throttled messages");
+    let now = Instant::now();
+    messages.retain(|_, entry| {
+        if now.duration_since(entry.window_start) < THROTTLE_WINDOW {
+            true
+        } else {
+            print_suppressed(entry);
+            false
+        }
+    });
+}
+
+// While the invoker is idle, nothing is logged, so waking up every window is pointless
+pub async fn flush_throttled_logs_periodically(idle_tick: Duration) {
+    loop {
+        tokio::time::sleep(if idle::is_idle() {
+            idle_tick.max(THROTTLE_WINDOW)
+        } else {
+            THROTTLE_WINDOW
+        })
+        .await;
+        flush_throttled_logs();
+    }
+}
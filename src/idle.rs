@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Judging hosts are often shared with other services, and an invoker that sits on pooled sandboxes,
+// cached problems and test data in the page cache for hours without a submission in sight is a bad
+// neighbour. After a period without activity, the invoker releases what it can rebuild cheaply and
+// slows down its periodic tasks. Nothing is done to warm back up eagerly: pools refill and problems
+// are reloaded from the disk cache as the next submissions need them.
+
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+// Whether the invoker is in idle mode. Periodic tasks use this to wake up less often
+pub fn is_idle() -> bool {
+    IDLE.load(Ordering::Relaxed)
+}
+
+pub struct IdleTracker {
+    last_activity: Mutex<Instant>,
+    activity: Notify,
+}
+
+impl IdleTracker {
+    pub fn new() -> IdleTracker {
+        IdleTracker {
+            last_activity: Mutex::new(Instant::now()),
+            activity: Notify::new(),
+        }
+    }
+
+    // Returns true if this ends idle mode
+    pub fn touch(&self) -> bool {
+        *self
+            .last_activity
+            .lock()
+            .expect("Failed to acquire last activity time") = Instant::now();
+        self.activity.notify_one();
+        IDLE.swap(false, Ordering::Relaxed)
+    }
+
+    // Resolves once the invoker has seen no activity for the given period. Calling this again
+    // resolves only after the next period of inactivity, i.e. after some activity
+    pub async fn wait_until_idle(&self, period: Duration) {
+        loop {
+            if is_idle() {
+                self.activity.notified().await;
+                continue;
+            }
+            let last_activity = *self
+                .last_activity
+                .lock()
+                .expect("Failed to acquire last activity time");
+            let deadline = last_activity + period;
+            if Instant::now() >= deadline {
+                IDLE.store(true, Ordering::Relaxed);
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::errors;
+use std::path::Path;
+
+// execve fails with E2BIG in two cases: when a single argument or environment string is longer than
+// MAX_ARG_STRLEN, and when argv and envp together, counting the pointers, don't fit into a quarter
+// of the stack rlimit, which is what sysconf(_SC_ARG_MAX) reports. Inside the sandbox, E2BIG is
+// reported as a failure to start the program, which says nothing about which of the arguments is at
+// fault, so the sizes are checked beforehand.
+//
+// Many compilers can read arguments from a "response file" passed as @path instead, which avoids
+// both limits. Whether a program supports this is up to whoever configures it.
+
+// 32 pages, including the null terminator. The kernel does not export this
+const MAX_ARG_STRLEN: usize = 32 * 4096;
+
+// Used when sysconf fails, which corresponds to the default 8 MiB stack limit
+const DEFAULT_ARG_MAX: usize = 2 * 1024 * 1024;
+
+fn arg_max() -> usize {
+    match nix::unistd::sysconf(nix::unistd::SysconfVar::ARG_MAX) {
+        Ok(Some(value)) if value > 0 => value as usize,
+        _ => DEFAULT_ARG_MAX,
+    }
+}
+
+fn strings_size(strings: impl Iterator<Item = usize>) -> usize {
+    // Each string takes its length, a null terminator and a pointer, plus the null pointer at the
+    // end of the array
+    strings
+        .map(|len| len + 1 + std::mem::size_of::<usize>())
+        .sum::<usize>()
+        + std::mem::size_of::<usize>()
+}
+
+fn describe(s: &str) -> String {
+    if s.len() <= 64 {
+        format!("{s:?}")
+    } else {
+        let mut end = 64;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{:?}... ({} bytes)", &s[..end], s.len())
+    }
+}
+
+// Returns a descriptive error if execve would fail with E2BIG
+pub fn check(argv: &[String], env: &[(String, String)]) -> Result<(), errors::Error> {
+    for (i, arg) in argv.iter().enumerate() {
+        if arg.len() + 1 > MAX_ARG_STRLEN {
+            return Err(errors::ConfigurationFailure(format!(
+                "Argument #{i} {} is longer than the kernel allows for a single argument ({} \
+                 bytes)",
+                describe(arg),
+                MAX_ARG_STRLEN - 1
+            )));
+        }
+    }
+    for (name, value) in env {
+        if name.len() + 1 + value.len() + 1 > MAX_ARG_STRLEN {
+            return Err(errors::ConfigurationFailure(format!(
+                "Environment variable {name} {} is longer than the kernel allows for a single \
+                 variable ({} bytes)",
+                describe(value),
+                MAX_ARG_STRLEN - 1
+            )));
+        }
+    }
+
+    let argv_size = strings_size(argv.iter().map(|arg| arg.len()));
+    let env_size = strings_size(env.iter().map(|(name, value)| name.len() + 1 + value.len()));
+    let limit = arg_max();
+    if argv_size + env_size > limit {
+        return Err(errors::ConfigurationFailure(format!(
+            "Arguments ({} of them, {argv_size} bytes) and environment ({} variables, {env_size} \
+             bytes) exceed the kernel limit of {limit} bytes",
+            argv.len(),
+            env.len()
+        )));
+    }
+
+    Ok(())
+}
+
+// Quotes an argument the way GCC, Clang, rustc, javac and others parse response files: whitespace,
+// quotes and backslashes are escaped with a backslash
+fn quote_for_response_file(arg: &str) -> String {
+    let mut quoted = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        if c.is_whitespace() || c == '\'' || c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted
+}
+
+// Makes argv fit into the kernel limits. If it does not fit as is and response_file is set, the
+// arguments after argv[0] are written to the file at the outer path, and the program is passed
+// @<inner path> instead. Otherwise, a descriptive error is returned.
+pub fn fit(
+    argv: Vec<String>,
+    env: &[(String, String)],
+    response_file: Option<(&Path, &str)>,
+) -> Result<Vec<String>, errors::Error> {
+    let e = match check(&argv, env) {
+        Ok(()) => return Ok(argv),
+        Err(e) => e,
+    };
+    let (outer_path, inner_path) = match response_file {
+        Some(response_file) => response_file,
+        None => return Err(e),
+    };
+    // Not all parsers treat a backslash-escaped newline as part of the argument
+    if argv.is_empty() || argv.iter().any(|arg| arg.contains('\n')) {
+        return Err(e);
+    }
+
+    let contents: String = argv[1..]
+        .iter()
+        .map(|arg| quote_for_response_file(arg) + "\n")
+        .collect();
+    std::fs::write(outer_path, contents).map_err(|e| {
+        errors::InvokerFailure(format!("Failed to write response file {outer_path:?}: {e:?}"))
+    })?;
+
+    let argv = vec![argv[0].clone(), format!("@{inner_path}")];
+    check(&argv, env)?;
+    Ok(argv)
+}
@@ -0,0 +1,206 @@
+use crate::{
+    errors,
+    errors::ToResult,
+    image::{program, sandbox},
+    signing, system,
+};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// A solution that escaped the sandbox is unlikely to announce it, but it is likely to leave traces
+// that a well-behaved program cannot: files shared between sandboxes modified, mounts appearing
+// next to the rootfs, processes surviving the PID namespace. The state of these things is recorded
+// when a strategy is created and compared with the actual state after every test.
+
+// The shared /dev of all sandboxes, bind-mounted read-only into each rootfs
+const SANDBOX_DEV: &str = "/tmp/sunwalker_invoker/dev";
+
+// Processes of a terminated PID namespace are killed asynchronously, so they may linger in the
+// cgroup for a short while
+const CGROUP_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Hashing every shared file after each test is too slow for problems with many short tests, so
+// regular files are only rehashed if their metadata changes, and unconditionally once every this
+// many tests. Writing to a file always updates its ctime, which cannot be set from userspace.
+const FULL_CHECK_INTERVAL: u64 = 64;
+
+#[derive(PartialEq)]
+struct FileState {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    // Only regular files are hashed
+    hash: Option<String>,
+}
+
+// Cheap to obtain, and changes whenever the contents of a file do
+#[derive(PartialEq)]
+struct Stamp {
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl FileState {
+    fn read(path: &Path, metadata: &std::fs::Metadata) -> Result<Self, errors::Error> {
+        let hash = if metadata.is_file() {
+            let content =
+                std::fs::read(path).with_context_invoker(|| format!("Failed to read {path:?}"))?;
+            Some(signing::sha256_hex(&content))
+        } else {
+            None
+        };
+        Ok(FileState {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: metadata.rdev(),
+            hash,
+        })
+    }
+}
+
+impl Stamp {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        Stamp {
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+struct Canary {
+    path: PathBuf,
+    state: FileState,
+    stamp: Stamp,
+}
+
+impl Canary {
+    fn read(path: PathBuf) -> Result<Self, errors::Error> {
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context_invoker(|| format!("Failed to stat {path:?}"))?;
+        Ok(Canary {
+            state: FileState::read(&path, &metadata)?,
+            stamp: Stamp::of(&metadata),
+            path,
+        })
+    }
+
+    fn is_intact(&self, full: bool) -> Result<bool, errors::Error> {
+        let metadata = std::fs::symlink_metadata(&self.path)
+            .with_context_invoker(|| format!("Failed to stat {:?}", self.path))?;
+        if !full && metadata.is_file() && Stamp::of(&metadata) == self.stamp {
+            return Ok(metadata.mode() == self.state.mode
+                && metadata.uid() == self.state.uid
+                && metadata.gid() == self.state.gid);
+        }
+        Ok(FileState::read(&self.path, &metadata)? == self.state)
+    }
+}
+
+pub struct Canaries {
+    files: Vec<Canary>,
+    verifications: u64,
+}
+
+impl Canaries {
+    pub fn snapshot(programs: &[program::InvocableProgram]) -> Result<Canaries, errors::Error> {
+        let mut paths = vec![PathBuf::from(SANDBOX_DEV)];
+        for entry in std::fs::read_dir(SANDBOX_DEV)
+            .with_context_invoker(|| format!("Failed to list {SANDBOX_DEV}"))?
+        {
+            paths.push(
+                entry
+                    .with_context_invoker(|| format!("Failed to list {SANDBOX_DEV}"))?
+                    .path(),
+            );
+        }
+        // Artifacts of the programs are shared by all tests
+        for program in programs {
+            for prerequisite in &program.program.prerequisites {
+                paths.push(program.program.artifacts_path.join(prerequisite));
+            }
+        }
+
+        let files = paths
+            .into_iter()
+            .map(Canary::read)
+            .collect::<Result<_, errors::Error>>()?;
+        Ok(Canaries {
+            files,
+            verifications: 0,
+        })
+    }
+
+    // Returns human-readable descriptions of the violations, empty if everything is in order
+    pub async fn verify(
+        &mut self,
+        programs: &[program::InvocableProgram],
+        cgroups: &[String],
+    ) -> Result<Vec<String>, errors::Error> {
+        let mut issues = Vec::new();
+
+        self.verifications += 1;
+        let full = self.verifications % FULL_CHECK_INTERVAL == 0;
+        for canary in &self.files {
+            match canary.is_intact(full) {
+                Ok(true) => {}
+                Ok(false) => issues.push(format!("{:?} was modified", canary.path)),
+                Err(_) => issues.push(format!("{:?} is missing", canary.path)),
+            }
+        }
+
+        let mounts =
+            system::get_mountinfo().context_invoker("Failed to read /proc/self/mountinfo")?;
+        for program in programs {
+            check_mounts(&program.rootfs, &mounts, &mut issues);
+        }
+
+        for cgroup in cgroups {
+            check_cgroup_empty(cgroup, &mut issues).await?;
+        }
+
+        Ok(issues)
+    }
+}
+
+fn check_mounts(rootfs: &sandbox::RootFS, mounts: &[system::MountInfo], issues: &mut Vec<String>) {
+    let prefix = PathBuf::from(format!("/tmp/sunwalker_invoker/rootfs/{}", rootfs.id));
+    let overlay = PathBuf::from(rootfs.overlay());
+    let expected = rootfs.expected_mounts();
+
+    for mount in mounts {
+        let path = &mount.mount_point;
+        if path.starts_with(&prefix)
+            && !expected.contains(path)
+            && !path.starts_with(overlay.join("space"))
+        {
+            issues.push(format!("Unexpected mount at {path:?}"));
+        }
+    }
+}
+
+async fn check_cgroup_empty(cgroup: &str, issues: &mut Vec<String>) -> Result<(), errors::Error> {
+    let path = format!("{cgroup}/cgroup.procs");
+    let deadline = std::time::Instant::now() + CGROUP_DRAIN_TIMEOUT;
+    loop {
+        let procs = std::fs::read_to_string(&path)
+            .with_context_invoker(|| format!("Failed to read {path}"))?;
+        if procs.trim().is_empty() {
+            return Ok(());
+        }
+        if std::time::Instant::now() >= deadline {
+            let pids: Vec<&str> = procs.split_whitespace().collect();
+            issues.push(format!(
+                "Processes {} survived the test in {cgroup}",
+                pids.join(", ")
+            ));
+            return Ok(());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
@@ -8,6 +8,12 @@ use std::collections::HashMap;
 #[lisp(name = "config")]
 pub struct Config {
     pub packages: HashMap<String, Package>,
+    // Source file extensions, e.g. ".cpp", mapped to the languages submissions with such files are
+    // built with when no language is specified. Extensions matched by the input pattern of exactly
+    // one single-file language need not be listed; this is for choosing among several candidates,
+    // e.g. the newest version of a compiler.
+    #[lisp(default)]
+    pub extensions: HashMap<String, String>,
 }
 
 #[derive(Clone, Debug, LispType, Object)]
@@ -21,9 +27,174 @@ pub struct Package {
 pub struct Language {
     pub identify: Term,
     pub base_rule: Term,
+    // Patterns of the input files, e.g. %.cpp. A language without input patterns builds projects,
+    // e.g. with CMake, Cargo or Maven: any number of files is accepted, and they are placed into
+    // /space under the names they were submitted with, directories included. build then typically
+    // runs a build script or several steps, see the steps function, and run.prerequisites lists the
+    // files to keep, which may be in subdirectories too.
     pub inputs: Vec<String>,
     pub build: Term,
     pub run: RunStatement,
+    // Alternative build rules selectable by submissions, e.g. to compile with a different
+    // language standard. When no dialect is requested, build is used.
+    #[lisp(default)]
+    pub dialects: HashMap<String, Term>,
+    // Names of the syscalls the compiled program may use. Anything else kills it with SIGSYS. When
+    // empty, no seccomp filter is installed.
+    #[lisp(default)]
+    pub syscalls: Vec<String>,
+    // Steps run once when the image is mounted, e.g. to precompile the standard library. They run
+    // like build, but with /warm writable; the rest of the time, /warm is read-only and shared by
+    // all sandboxes of the package.
+    #[lisp(default)]
+    pub warm_cache: Vec<Term>,
+    // How much more time and memory programs in this language get than the limits say
+    #[lisp(default)]
+    pub multipliers: Multipliers,
+    // Flags problems may add to the build, available to it as $flags. A % matches anything, e.g.
+    // -D% allows any macro definition. Flags are appended, so with most compilers they override
+    // the ones the build passes itself, e.g. -O0 after -O2
+    #[lisp(default)]
+    pub allowed_flags: Vec<String>,
+    // Limits of the build, as opposed to the limits of the tests, which are set by the problem
+    #[lisp(default)]
+    pub compilation_limits: CompilationLimits,
+    // The build profile for diagnostic rejudging, see Diagnostic. Languages without one don't
+    // support it
+    #[lisp(default)]
+    pub diagnostic: Diagnostic,
+    // How a virtual machine the language runs on manages memory, see MemoryModel
+    #[lisp(default)]
+    pub memory_model: MemoryModel,
+    // Whether the language supports problems with a grader, i.e. sources supplied by the problem
+    // that are built together with the submission, which then provides functions rather than
+    // main. The grader files are placed into /space under their own names and are available to
+    // build, run.prerequisites and run.argv as $grader; the build must use them
+    #[lisp(default)]
+    pub grader: bool,
+    // A fork server the program is run through, see Runtime. Without one, the program is started
+    // afresh on every test
+    #[lisp(default)]
+    pub runtime: Runtime,
+}
+
+// (runtime argv), e.g. for Java:
+//     (runtime (list "/usr/lib/sunwalker/jvm-zygote"))
+// Runtimes like the JVM or .NET take a noticeable share of a short test just to start. With a
+// runtime, argv followed by the argv of the program, heap flags included, is started once per
+// worker and block, in a sandbox of its own. It loads the program and then forks a copy of it for
+// every test, talking to the invoker over a unix socket at file descriptor 3:
+// - once the program is loaded, the runtime writes "ready\n";
+// - for each test, the invoker sends a single byte with the stdin, stdout and stderr of the test
+//   attached as SCM_RIGHTS. The runtime forks and closes its own copies of them, and the child
+//   makes them its standard streams, closes descriptor 3 and runs the program from the loaded
+//   state;
+// - when the child terminates, the runtime writes its wait status in decimal followed by "\n".
+// The copy is measured and limited like a program started the usual way, in a cgroup of its own,
+// except that the memory it shares with the runtime is not counted, and that the syscall filter of
+// the language is installed in the runtime itself. Between tests, leftover processes are killed and
+// the files written to /space and /dev/mqueue are removed; if a test modified or removed a file the
+// runtime started with, the runtime is started again. Still, the runtime is shared by the tests of
+// a worker, so it must keep no state of its own, and the syscall filter should deny what could be
+// used to reach it, e.g. ptrace, kill and System V IPC. A runtime that fails to get ready is given
+// up on, and the program is started afresh on every test. So are blocks that need more than their
+// standard streams, i.e. with arguments, bindings or artifacts, and runs that are traced.
+#[derive(Clone, Debug, Default, LispType, Object)]
+#[lisp(name = "runtime")]
+pub struct Runtime {
+    pub argv: Vec<String>,
+}
+
+// (memory_model heap_flags heap_percent [oom_exit_codes]), e.g. for Java:
+//     (memory_model (list "-Xmx%m" "-XX:+ExitOnOutOfMemoryError") 75 (list 3))
+// Languages running on a VM with a heap of its own, like the JVM or V8, don't grow past the heap
+// size they are started with, so the heap has to be sized from the memory limit, and when the heap
+// is full, the VM reports an error of its own instead of being killed by the cgroup.
+// - heap_flags are inserted into argv right after the program, with % replaced by heap_percent
+//   percent of the memory limit in MiB. The rest of the limit is left to the VM itself.
+// - oom_exit_codes are the exit codes of the VM running out of heap, with a negative number
+//   meaning a signal, e.g. -6 for SIGABRT. They are judged as MemoryLimitExceeded.
+#[derive(Clone, Debug, LispType, Object)]
+#[lisp(name = "memory_model")]
+pub struct MemoryModel {
+    pub heap_flags: Vec<String>,
+    pub heap_percent: i64,
+    #[lisp(default)]
+    pub oom_exit_codes: Vec<i64>,
+}
+
+impl Default for MemoryModel {
+    fn default() -> Self {
+        Self {
+            heap_flags: Vec::new(),
+            heap_percent: 100,
+            oom_exit_codes: Vec::new(),
+        }
+    }
+}
+
+// (diagnostic flags time_percent memory_percent [syscalls]), e.g.
+//     (diagnostic (list "-g" "-fsanitize=address,undefined") 300 400)
+// A submission built in diagnostic mode gets the flags appended to $flags, the syscalls added to
+// the allowed ones, and its limits multiplied on top of the multipliers of the language, as
+// sanitizers are slow and use a lot of memory. Sanitizer reports are collected into the logs of
+// the tests, see strategy.rs
+#[derive(Clone, Debug, Default, LispType, Object)]
+#[lisp(name = "diagnostic")]
+pub struct Diagnostic {
+    pub flags: Vec<String>,
+    pub time_percent: i64,
+    pub memory_percent: i64,
+    #[lisp(default)]
+    pub syscalls: Vec<String>,
+}
+
+// (compilation_limits cpu_time_ms memory output_size log_size), with sizes in bytes and 0 meaning
+// no limit:
+// - cpu_time_ms is the CPU time of all processes of the build together.
+// - memory is the memory.max of the cgroup the build runs in, i.e. the memory all processes of the
+//   build actually use together. A process that exceeds it is killed by the OOM killer.
+// - output_size is the largest file a compiler may write.
+// - log_size is how much of the compilation log is kept; the rest is cut off, and the output of
+//   each process is not even read beyond that.
+// A build that exceeds these fails with a compilation error. A compiler that crashes by itself is
+// reported as a bug in the image.
+#[derive(Clone, Debug, LispType, Object)]
+#[lisp(name = "compilation_limits")]
+pub struct CompilationLimits {
+    pub cpu_time_ms: i64,
+    pub memory: i64,
+    pub output_size: i64,
+    pub log_size: i64,
+}
+
+impl Default for CompilationLimits {
+    fn default() -> Self {
+        Self {
+            cpu_time_ms: 30000,
+            memory: 0,
+            output_size: 0,
+            log_size: 1024 * 1024,
+        }
+    }
+}
+
+// In percent, as the configuration language has no fractions: (multipliers 200 150) means twice
+// the time and one and a half times the memory
+#[derive(Clone, Debug, LispType, Object)]
+#[lisp(name = "multipliers")]
+pub struct Multipliers {
+    pub time_percent: i64,
+    pub memory_percent: i64,
+}
+
+impl Default for Multipliers {
+    fn default() -> Self {
+        Self {
+            time_percent: 100,
+            memory_percent: 100,
+        }
+    }
 }
 
 #[derive(Clone, Debug, LispType, Object)]
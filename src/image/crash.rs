@@ -0,0 +1,298 @@
+use crate::image::trace;
+use nix::sys::{ptrace, signal::Signal};
+use nix::unistd::Pid;
+use std::fmt::Write;
+use std::os::unix::fs::FileExt;
+
+// When a program dies by a signal, the verdict only says which one. Core dumps would tell more, but
+// core_pattern is global and a dump of a program with a 1 GiB heap is not something to attach to a
+// test result. Instead, the tracer stops the program when the fatal signal is about to be delivered
+// and writes a short summary: the signal, the faulting address, the instruction pointer and a guess
+// at the call stack. Addresses are symbolized with the ELF symbol tables of the mapped files when
+// these are not stripped.
+//
+// There is no unwinder here. Frame pointers are usually omitted, so the "stack" is just the words
+// near the stack pointer that point into executable mappings. Some of them are stale, but the top
+// few are usually right, which is what one needs to tell a null pointer dereference in main from a
+// stack overflow in a recursive function.
+
+// How much of the stack to scan for return addresses
+const STACK_SCAN_SIZE: usize = 4096;
+const MAX_FRAMES: usize = 16;
+
+// Symbol tables larger than this are not loaded, as the tracer shares the memory limit with the
+// program
+const MAX_SYMTAB_SIZE: u64 = 16 * 1024 * 1024;
+
+// Whether the program has installed a handler for the signal, in which case it is not fatal yet
+pub fn is_signal_caught(pid: Pid, signal: Signal) -> bool {
+    let status = match std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigCgt:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .map_or(false, |mask| mask & (1 << (signal as i32 - 1)) != 0)
+}
+
+struct Mapping {
+    start: u64,
+    end: u64,
+    executable: bool,
+    offset: u64,
+    path: Option<String>,
+}
+
+fn read_mappings(pid: Pid) -> Vec<Mapping> {
+    let maps = match std::fs::read_to_string(format!("/proc/{pid}/maps")) {
+        Ok(maps) => maps,
+        Err(_) => return Vec::new(),
+    };
+    maps.lines()
+        .filter_map(|line| {
+            // start-end perms offset dev inode [path]
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?;
+            let offset = fields.next()?;
+            let path = fields.nth(2).filter(|path| path.starts_with('/'));
+            Some(Mapping {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                executable: perms.as_bytes().get(2) == Some(&b'x'),
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                path: path.map(|path| path.to_string()),
+            })
+        })
+        .collect()
+}
+
+struct Symbol {
+    name: String,
+    value: u64,
+    size: u64,
+}
+
+// The parts of an ELF file needed to map a file offset to a function name
+struct ElfSymbols {
+    // (p_offset, p_vaddr, p_filesz) of PT_LOAD segments
+    segments: Vec<(u64, u64, u64)>,
+    // Sorted by value
+    functions: Vec<Symbol>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn read_at(file: &std::fs::File, offset: u64, size: u64) -> Option<Vec<u8>> {
+    if size > MAX_SYMTAB_SIZE {
+        return None;
+    }
+    let mut data = vec![0; size as usize];
+    file.read_exact_at(&mut data, offset).ok()?;
+    Some(data)
+}
+
+// Only 64-bit little-endian ELF files are supported, which covers everything the invoker runs on
+fn load_elf_symbols(path: &str) -> Option<ElfSymbols> {
+    let file = std::fs::File::open(path).ok()?;
+    let header = read_at(&file, 0, 64)?;
+    if &header[..4] != b"\x7fELF" || header[4] != 2 || header[5] != 1 {
+        return None;
+    }
+
+    let phoff = read_u64(&header, 0x20)?;
+    let shoff = read_u64(&header, 0x28)?;
+    let phentsize = read_u16(&header, 0x36)? as u64;
+    let phnum = read_u16(&header, 0x38)? as u64;
+    let shentsize = read_u16(&header, 0x3a)? as u64;
+    let shnum = read_u16(&header, 0x3c)? as u64;
+
+    let program_headers = read_at(&file, phoff, phentsize * phnum)?;
+    let segments = (0..phnum as usize)
+        .filter_map(|i| {
+            let phdr = &program_headers[i * phentsize as usize..];
+            // PT_LOAD
+            if read_u32(phdr, 0)? != 1 {
+                return None;
+            }
+            Some((read_u64(phdr, 8)?, read_u64(phdr, 16)?, read_u64(phdr, 32)?))
+        })
+        .collect();
+
+    let section_headers = read_at(&file, shoff, shentsize * shnum)?;
+    let section = |i: usize| section_headers.get(i * shentsize as usize..);
+
+    // Prefer the full symbol table, falling back to the dynamic one of stripped libraries
+    let mut symtab = None;
+    for wanted_type in [2, 11] {
+        symtab = (0..shnum as usize).find_map(|i| {
+            let shdr = section(i)?;
+            (read_u32(shdr, 4)? == wanted_type).then_some(shdr)
+        });
+        if symtab.is_some() {
+            break;
+        }
+    }
+    let symtab = symtab?;
+    let strtab = section(read_u32(symtab, 40)? as usize)?;
+
+    let symbols = read_at(&file, read_u64(symtab, 24)?, read_u64(symtab, 32)?)?;
+    let strings = read_at(&file, read_u64(strtab, 24)?, read_u64(strtab, 32)?)?;
+
+    let mut functions: Vec<Symbol> = symbols
+        .chunks_exact(24)
+        .filter_map(|sym| {
+            // STT_FUNC, defined in this file
+            if sym[4] & 0xf != 2 || read_u16(sym, 6)? == 0 {
+                return None;
+            }
+            let name_offset = read_u32(sym, 0)? as usize;
+            let name = strings.get(name_offset..)?;
+            let name = &name[..name.iter().position(|&c| c == 0)?];
+            Some(Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                value: read_u64(sym, 8)?,
+                size: read_u64(sym, 16)?,
+            })
+        })
+        .collect();
+    functions.sort_by_key(|symbol| symbol.value);
+
+    Some(ElfSymbols {
+        segments,
+        functions,
+    })
+}
+
+impl ElfSymbols {
+    fn lookup(&self, file_offset: u64) -> Option<String> {
+        let vaddr = self
+            .segments
+            .iter()
+            .find(|(offset, _, size)| (*offset..offset + size).contains(&file_offset))
+            .map(|(offset, vaddr, _)| file_offset - offset + vaddr)?;
+        let index = self
+            .functions
+            .partition_point(|symbol| symbol.value <= vaddr)
+            .checked_sub(1)?;
+        let symbol = &self.functions[index];
+        // Symbols of size 0 are typically hand-written assembly, so anything up to the next symbol
+        // is attributed to them
+        if symbol.size != 0 && vaddr >= symbol.value + symbol.size {
+            return None;
+        }
+        Some(format!("{}+{:#x}", symbol.name, vaddr - symbol.value))
+    }
+}
+
+struct Symbolizer {
+    mappings: Vec<Mapping>,
+    elves: std::collections::HashMap<String, Option<ElfSymbols>>,
+}
+
+impl Symbolizer {
+    fn mapping(&self, address: u64) -> Option<&Mapping> {
+        self.mappings
+            .iter()
+            .find(|mapping| (mapping.start..mapping.end).contains(&address))
+    }
+
+    fn describe(&mut self, address: u64) -> String {
+        let mapping = match self.mapping(address) {
+            Some(mapping) => mapping,
+            None => return format!("{address:#018x}"),
+        };
+        let path = match mapping.path {
+            Some(ref path) => path.clone(),
+            None => return format!("{address:#018x}"),
+        };
+        let file_offset = address - mapping.start + mapping.offset;
+        let module = path.rsplit('/').next().unwrap_or(&path).to_string();
+        let function = self
+            .elves
+            .entry(path.clone())
+            .or_insert_with(|| load_elf_symbols(&path))
+            .as_ref()
+            .and_then(|elf| elf.lookup(file_offset));
+        match function {
+            Some(function) => format!("{address:#018x} {module}+{file_offset:#x} ({function})"),
+            None => format!("{address:#018x} {module}+{file_offset:#x}"),
+        }
+    }
+}
+
+// Must be called while the program is stopped on delivery of the signal
+pub fn summarize(pid: Pid, signal: Signal) -> String {
+    let mut summary = String::new();
+    let mut symbolizer = Symbolizer {
+        mappings: read_mappings(pid),
+        elves: std::collections::HashMap::new(),
+    };
+
+    match ptrace::getsiginfo(pid) {
+        Ok(siginfo) => {
+            let _ = write!(summary, "Killed by {signal:?} (si_code {})", siginfo.si_code);
+            if matches!(
+                signal,
+                Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGFPE | Signal::SIGILL
+            ) {
+                let address = unsafe { siginfo.si_addr() } as usize;
+                let _ = write!(summary, " at address {address:#x}");
+            }
+            let _ = writeln!(summary);
+        }
+        Err(_) => {
+            let _ = writeln!(summary, "Killed by {signal:?}");
+        }
+    }
+
+    // PTRACE_GET_SYSCALL_INFO fills the instruction and stack pointers at any stop, and unlike
+    // PTRACE_GETREGS works the same on all architectures
+    let (ip, sp) = match trace::syscall_info(pid) {
+        Ok(info) => (info.instruction_pointer, info.stack_pointer),
+        Err(_) => {
+            let _ = writeln!(summary, "Failed to read registers");
+            return summary;
+        }
+    };
+    let _ = writeln!(summary, "Instruction pointer: {}", symbolizer.describe(ip));
+    let _ = writeln!(summary, "Stack pointer: {sp:#018x}");
+
+    // The read stops short at the top of the stack
+    let mut stack = vec![0; STACK_SCAN_SIZE];
+    let n_read = std::fs::File::open(format!("/proc/{pid}/mem"))
+        .and_then(|mem| mem.read_at(&mut stack, sp))
+        .unwrap_or(0);
+    let frames: Vec<u64> = stack[..n_read]
+        .chunks_exact(8)
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+        .filter(|&word| {
+            symbolizer
+                .mapping(word)
+                .map_or(false, |mapping| mapping.executable)
+        })
+        .take(MAX_FRAMES)
+        .collect();
+    if frames.is_empty() {
+        let _ = writeln!(summary, "No return addresses found on the stack");
+    } else {
+        let _ = writeln!(summary, "Possible return addresses on the stack:");
+        for frame in frames {
+            let _ = writeln!(summary, "    {}", symbolizer.describe(frame));
+        }
+    }
+
+    summary
+}
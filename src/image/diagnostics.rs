@@ -0,0 +1,202 @@
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: u64,
+    pub column: Option<u64>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+// Compilers don't agree on a common format, but most of them follow one of two conventions. GCC,
+// clang, javac, fpc and many others print
+//     file:line:column: severity: message
+// (javac omits the column). rustc prints
+//     severity[code]: message
+//       --> file:line:column
+// Lines that don't fit either are ignored, so the raw log is still the only source of truth for an
+// unknown compiler.
+pub fn parse(log: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut lines = log.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if let Some(diagnostic) = parse_gnu_style(line) {
+            diagnostics.push(diagnostic);
+        } else if let Some((severity, message)) = parse_rustc_header(line) {
+            // The location immediately follows the header
+            if let Some(location) = lines
+                .peek()
+                .and_then(|line| line.trim_start().strip_prefix("--> "))
+            {
+                if let Some((file, line, column)) = parse_location(location) {
+                    lines.next();
+                    diagnostics.push(Diagnostic {
+                        file,
+                        line,
+                        column,
+                        severity,
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn parse_severity(s: &str) -> Option<Severity> {
+    match s {
+        "error" | "fatal error" => Some(Severity::Error),
+        "warning" => Some(Severity::Warning),
+        "note" => Some(Severity::Note),
+        _ => None,
+    }
+}
+
+fn parse_gnu_style(line: &str) -> Option<Diagnostic> {
+    for marker in [": fatal error: ", ": error: ", ": warning: ", ": note: "] {
+        if let Some((location, message)) = line.split_once(marker) {
+            let (file, line, column) = parse_location(location)?;
+            return Some(Diagnostic {
+                file,
+                line,
+                column,
+                severity: parse_severity(&marker[2..marker.len() - 2])?,
+                message: message.to_string(),
+            });
+        }
+    }
+    None
+}
+
+fn parse_rustc_header(line: &str) -> Option<(Severity, String)> {
+    let (header, message) = line.split_once(": ")?;
+    // Strip error code, e.g. error[E0308]
+    let severity = match header.split_once('[') {
+        Some((severity, code)) if code.ends_with(']') => severity,
+        Some(_) => return None,
+        None => header,
+    };
+    Some((parse_severity(severity)?, message.to_string()))
+}
+
+// Parses file:line or file:line:column
+fn parse_location(location: &str) -> Option<(String, u64, Option<u64>)> {
+    let (rest, last) = location.rsplit_once(':')?;
+    let last: u64 = last.parse().ok()?;
+    if let Some((file, line)) = rest.rsplit_once(':') {
+        if let Ok(line) = line.parse() {
+            return (!file.is_empty()).then(|| (file.to_string(), line, Some(last)));
+        }
+    }
+    (!rest.is_empty()).then(|| (rest.to_string(), last, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders diagnostics in GCC style, which is easier to compare against
+    fn render(log: &str) -> Vec<String> {
+        parse(log)
+            .into_iter()
+            .map(|d| {
+                let column = d
+                    .column
+                    .map(|column| format!(":{column}"))
+                    .unwrap_or_default();
+                format!(
+                    "{}:{}{column}: {:?}: {}",
+                    d.file, d.line, d.severity, d.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_gnu_style_lines() {
+        let log = "\
+a.cpp:3:5: error: expected ';' before '}' token
+main.c:10:2: warning: unused variable 'x' [-Wunused-variable]
+a.cpp:1:10: fatal error: foo.h: No such file or directory
+Main.java:5: error: cannot find symbol
+";
+        assert_eq!(
+            render(log),
+            [
+                "a.cpp:3:5: Error: expected ';' before '}' token",
+                "main.c:10:2: Warning: unused variable 'x' [-Wunused-variable]",
+                "a.cpp:1:10: Error: foo.h: No such file or directory",
+                "Main.java:5: Error: cannot find symbol",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_context_of_multiline_diagnostics() {
+        let log = "\
+In file included from a.cpp:1:
+a.cpp: In function 'int main()':
+a.cpp:4:7: error: 'foo' was not declared in this scope
+    4 |     foo();
+      |     ^~~
+a.cpp:2:6: note: 'bar' declared here
+    2 | void bar();
+      |      ^~~
+";
+        assert_eq!(
+            render(log),
+            [
+                "a.cpp:4:7: Error: 'foo' was not declared in this scope",
+                "a.cpp:2:6: Note: 'bar' declared here",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_rustc_style_diagnostics() {
+        let log = "\
+error[E0308]: mismatched types
+ --> src/main.rs:2:18
+  |
+2 |     let x: i32 = \"a\";
+  |            ---   ^^^ expected `i32`, found `&str`
+  = note: expected type annotation
+warning: unused variable: `y`
+ --> src/main.rs:3:9
+";
+        assert_eq!(
+            render(log),
+            [
+                "src/main.rs:2:18: Error: mismatched types",
+                "src/main.rs:3:9: Warning: unused variable: `y`",
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_lines() {
+        let log = "\
+error: linker `cc` not found
+:3:5: error: no file name
+a.cpp:abc: error: no line number
+a.cpp:3:5: remark: unknown severity
+error[E0308: unterminated code
+ --> src/main.rs:2:18
+warning: location without a line number
+ --> src/main.rs
+";
+        assert!(render(log).is_empty());
+        assert!(render("").is_empty());
+    }
+}
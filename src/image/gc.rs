@@ -0,0 +1,179 @@
+use crate::{
+    errors,
+    errors::ToResult,
+    image::{ids, network, sandbox},
+};
+use std::collections::HashSet;
+use std::path::Path;
+
+// Submissions clean up after themselves when they are finalized, but a worker that crashes or is
+// killed leaves its rootfs's, mounts, leases and temporary directories behind, and the directory of
+// a submission is never removed at all. The collector reclaims what no live submission can use.
+//
+// Everything under /tmp/sunwalker_invoker that belongs to a submission is named after it:
+//     - submissions/<id>,
+//     - artifacts/judge-<id>, the built program,
+//     - aux/<kind>-<worker> and rootfs/<kind>-<worker>[-block-<i>|-runtime-<i>], where the worker
+//       ID starts with the submission ID and kind is run, build or candidate.
+// Rootfs's and auxiliary directories of the images themselves, e.g. for warm-up, are left alone.
+//
+// A directory is created a moment before the submission is registered, so entries younger than
+// GRACE_PERIOD are never touched. The whole of /tmp/sunwalker_invoker is a tmpfs mounted at
+// startup, so there is nothing to reclaim from a previous run, but a pass is made anyway to start
+// from a known state.
+const GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+const WORKER_KINDS: [&str; 3] = ["run-", "build-", "candidate-"];
+
+#[derive(Debug, Default)]
+pub struct Collected {
+    pub submissions: usize,
+    pub artifacts: usize,
+    pub aux: usize,
+    pub rootfs: usize,
+    pub leases: usize,
+}
+
+impl Collected {
+    pub fn is_empty(&self) -> bool {
+        self.submissions + self.artifacts + self.aux + self.rootfs + self.leases == 0
+    }
+}
+
+// Whether the name contains one of the IDs as a whole, dash-separated. IDs may contain dashes
+// themselves, so this errs on the side of keeping things
+fn is_live(name: &str, live_submissions: &HashSet<String>) -> bool {
+    let name = format!("-{name}-");
+    live_submissions
+        .iter()
+        .any(|id| name.contains(&format!("-{id}-")))
+}
+
+fn is_settled(path: &Path) -> Result<bool, errors::Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        // Removed concurrently, e.g. by a worker that is being finalized
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.with_context_invoker(|| format!("Failed to stat {path:?}"))),
+    };
+    let modified = metadata
+        .modified()
+        .with_context_invoker(|| format!("Failed to get mtime of {path:?}"))?;
+    Ok(modified.elapsed().map_or(false, |age| age >= GRACE_PERIOD))
+}
+
+// Lists the entries of the directory that are old enough to be collected and satisfy the predicate
+fn stale_entries(
+    dir: &str,
+    is_orphaned: impl Fn(&str) -> bool,
+) -> Result<Vec<String>, errors::Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // The network leases directory only exists if networking is enabled
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.with_context_invoker(|| format!("Failed to list {dir}"))),
+    };
+    let mut stale = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context_invoker(|| format!("Failed to list {dir}"))?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if is_orphaned(&name) && is_settled(&entry.path())? {
+            stale.push(name);
+        }
+    }
+    Ok(stale)
+}
+
+fn remove_dir(path: &str) -> Result<(), errors::Error> {
+    match std::fs::remove_dir_all(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.with_context_invoker(|| format!("Failed to remove {path} recursively"))),
+    }
+}
+
+// Leases contain the prefix of the rootfs that holds them. A lease whose rootfs is gone is leaked
+fn collect_leases(dir: &str) -> Result<usize, errors::Error> {
+    let mut count = 0;
+    for name in stale_entries(dir, |_| true)? {
+        let path = format!("{dir}/{name}");
+        let owner = match std::fs::read_to_string(&path) {
+            Ok(owner) => owner,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.with_context_invoker(|| format!("Failed to read {path}"))),
+        };
+        if owner.is_empty() || Path::new(&owner).exists() {
+            continue;
+        }
+        std::fs::remove_file(&path).with_context_invoker(|| format!("Failed to remove {path}"))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Entries are independent, so a failure to reclaim one, e.g. because a concurrent finalize got to
+// it first, is logged and the pass goes on with the rest
+fn reclaimed(path: &str, result: Result<(), errors::Error>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            errors::log_throttled(
+                "collect garbage",
+                format!("Failed to collect {path}, leaving it for the next pass: {e:?}"),
+            );
+            false
+        }
+    }
+}
+
+pub fn collect(live_submissions: &HashSet<String>) -> Result<Collected, errors::Error> {
+    let mut collected = Collected::default();
+
+    for id in stale_entries("/tmp/sunwalker_invoker/submissions", |name| {
+        !live_submissions.contains(name)
+    })? {
+        let path = format!("/tmp/sunwalker_invoker/submissions/{id}");
+        if reclaimed(&path, remove_dir(&path)) {
+            collected.submissions += 1;
+        }
+    }
+
+    for name in stale_entries("/tmp/sunwalker_invoker/artifacts", |name| {
+        name.starts_with("judge-") && !is_live(name, live_submissions)
+    })? {
+        let path = format!("/tmp/sunwalker_invoker/artifacts/{name}");
+        if reclaimed(&path, remove_dir(&path)) {
+            collected.artifacts += 1;
+        }
+    }
+
+    let is_orphaned_worker = |name: &str| {
+        WORKER_KINDS.iter().any(|kind| name.starts_with(kind)) && !is_live(name, live_submissions)
+    };
+
+    for name in stale_entries("/tmp/sunwalker_invoker/aux", is_orphaned_worker)? {
+        let path = format!("/tmp/sunwalker_invoker/aux/{name}");
+        if reclaimed(&path, remove_dir(&path)) {
+            collected.aux += 1;
+        }
+    }
+
+    // The mounts are unmounted first, or removing the directory would descend into the image
+    for name in stale_entries("/tmp/sunwalker_invoker/rootfs", is_orphaned_worker)? {
+        let prefix = format!("/tmp/sunwalker_invoker/rootfs/{name}");
+        let result =
+            sandbox::unmount_recursively(&prefix, false).and_then(|()| remove_dir(&prefix));
+        if reclaimed(&prefix, result) {
+            collected.rootfs += 1;
+        }
+    }
+
+    // Only after the rootfs's are gone, so that their leases are reclaimed in the same pass
+    collected.leases += collect_leases(ids::LEASES_DIR)?;
+    collected.leases += collect_leases(network::LEASES_DIR)?;
+
+    Ok(collected)
+}
@@ -1,4 +1,8 @@
+use crate::{config, errors, errors::ToResult};
+use lazy_static::lazy_static;
 use libc::{gid_t, uid_t};
+use std::io::Write;
+use std::sync::Mutex;
 
 // Not mapping external uid 0 to anything ensures it's impossible to do anything real root can from
 // inside the sandbox
@@ -9,9 +13,8 @@ pub const EXTERNAL_ROOT_UID: uid_t = 1;
 pub const INTERNAL_ROOT_UID: uid_t = 0;
 
 // Sandboxed user:
-// The external UID, which we use upon creating files and pipes
-pub const EXTERNAL_USER_UID: uid_t = 2;
-// The UID the sandboxed program sees
+// The external UID is leased per sandbox, see Lease. The UID the sandboxed program sees is the same
+// everywhere
 pub const INTERNAL_USER_UID: uid_t = 1000;
 
 pub const NOBODY_UID: uid_t = 65534;
@@ -20,7 +23,130 @@ pub const NOBODY_UID: uid_t = 65534;
 pub const EXTERNAL_ROOT_GID: gid_t = 1;
 pub const INTERNAL_ROOT_GID: gid_t = 0;
 
-pub const EXTERNAL_USER_GID: gid_t = 2;
 pub const INTERNAL_USER_GID: gid_t = 1000;
 
 pub const NOGRP_GID: gid_t = 65534;
+
+// If all sandboxes mapped the sandboxed user to the same external UID, two programs running at once
+// on different cores would share whatever the kernel accounts per user: RLIMIT_NPROC, POSIX message
+// queue bytes, inotify instances, keyrings, and the right to send signals to each other's processes
+// if they ever become visible. So each sandbox leases an external UID of its own, with the GID equal
+// to it, for as long as it exists, including while it sits in the pool. The sandboxed root stays
+// shared, as it owns the files of the image.
+//
+// Sandboxes are made by workers, which are separate processes, so leases are files in LEASES_DIR,
+// created exclusively. A lease contains the prefix of the rootfs that holds it, so that gc.rs can
+// reclaim it if the worker dies without removing the rootfs.
+pub const LEASES_DIR: &str = "/tmp/sunwalker_invoker/ids";
+
+lazy_static! {
+    // Set once per process, before any sandbox is made
+    static ref EXTERNAL_USER_IDS: Mutex<config::UserIdRange> =
+        Mutex::new(config::UserIdRange::default());
+}
+
+pub fn set_external_user_ids(range: config::UserIdRange) {
+    *EXTERNAL_USER_IDS
+        .lock()
+        .expect("Failed to acquire the sandbox user ID range") = range;
+}
+
+// The range must not contain the IDs mapped into the sandbox as they are, and must not overlap the
+// subordinate IDs of any user, or a user namespace of theirs could act as a sandboxed user
+pub fn validate_external_user_ids(range: config::UserIdRange) -> Result<(), errors::Error> {
+    let end = range.start.checked_add(range.count).ok_or_else(|| {
+        errors::ConfigurationFailure(format!(
+            "Sandbox user ID range {}+{} overflows",
+            range.start, range.count
+        ))
+    })?;
+    if range.count == 0 {
+        return Err(errors::ConfigurationFailure(
+            "Sandbox user ID range is empty".to_string(),
+        ));
+    }
+    for id in [0, EXTERNAL_ROOT_UID, NOBODY_UID] {
+        if (range.start..end).contains(&id) {
+            return Err(errors::ConfigurationFailure(format!(
+                "Sandbox user ID range {}..{end} contains reserved ID {id}",
+                range.start
+            )));
+        }
+    }
+
+    for path in ["/etc/subuid", "/etc/subgid"] {
+        let list = match std::fs::read_to_string(path) {
+            Ok(list) => list,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context_invoker(|| format!("Failed to read {path}")),
+        };
+        for line in list.lines() {
+            // name:start:count
+            let mut fields = line.split(':');
+            let (name, start, count) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(start), Some(count)) => (name, start, count),
+                _ => continue,
+            };
+            let (start, end_of_user) =
+                match (start.trim().parse::<u64>(), count.trim().parse::<u64>()) {
+                    (Ok(start), Ok(count)) => (start, start.saturating_add(count)),
+                    _ => continue,
+                };
+            if start < u64::from(end) && u64::from(range.start) < end_of_user {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Sandbox user ID range {}..{end} overlaps IDs {start}..{end_of_user} of \
+                     {name} in {path}",
+                    range.start
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct Lease {
+    index: u32,
+    start: u32,
+}
+
+impl Lease {
+    pub fn acquire(owner: &str) -> Result<Lease, errors::Error> {
+        let range = *EXTERNAL_USER_IDS
+            .lock()
+            .expect("Failed to acquire the sandbox user ID range");
+        for index in 0..range.count {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(format!("{LEASES_DIR}/{index}"))
+            {
+                Ok(mut file) => {
+                    file.write_all(owner.as_bytes())
+                        .context_invoker("Failed to record the owner of a sandbox user ID")?;
+                    return Ok(Lease {
+                        index,
+                        start: range.start,
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.context_invoker("Failed to lease a sandbox user ID")),
+            }
+        }
+        Err(errors::InvokerFailure(
+            "All sandbox user IDs are in use".to_string(),
+        ))
+    }
+
+    pub fn uid(&self) -> uid_t {
+        self.start + self.index
+    }
+
+    pub fn gid(&self) -> gid_t {
+        self.start + self.index
+    }
+
+    pub fn release(self) -> Result<(), errors::Error> {
+        std::fs::remove_file(format!("{LEASES_DIR}/{}", self.index))
+            .with_context_invoker(|| format!("Failed to release sandbox user ID {}", self.uid()))
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     errors,
+    errors::ToResult,
     image::{config, language, package},
 };
 use multiprocessing::Object;
@@ -11,6 +12,10 @@ pub struct Image {
     pub mountpoint: std::path::PathBuf,
     pub config: config::Config,
     pub language_to_package_name: HashMap<String, String>,
+    // Extensions, with the leading dot, to languages. See config::Config::extensions
+    pub extension_to_language: HashMap<String, String>,
+    // Identifies the image file the image was mounted from, for fingerprints
+    pub version: String,
 }
 
 impl Image {
@@ -22,6 +27,43 @@ impl Image {
         self.language_to_package_name.contains_key(name)
     }
 
+    // Picks the language for the files by their extensions. Files with unknown extensions, e.g.
+    // headers, are ignored, but the rest must agree on the language. The longest known extension of
+    // a file is used, so that .tar.gz takes precedence over .gz
+    pub fn detect_language<'a>(
+        &self,
+        file_names: impl Iterator<Item = &'a str>,
+    ) -> Result<String, errors::Error> {
+        let mut detected: Option<(&str, &str)> = None;
+        for file_name in file_names {
+            let base_name = file_name.rsplit('/').next().unwrap_or(file_name);
+            let language = base_name
+                .match_indices('.')
+                .find_map(|(i, _)| self.extension_to_language.get(&base_name[i..]));
+            let language = match language {
+                Some(language) => language.as_str(),
+                None => continue,
+            };
+            match detected {
+                Some((other_file_name, other_language)) if other_language != language => {
+                    return Err(errors::UserFailure(format!(
+                        "Cannot detect the language: {other_file_name} is in {other_language}, \
+                         but {file_name} is in {language}"
+                    )));
+                }
+                Some(_) => {}
+                None => detected = Some((file_name, language)),
+            }
+        }
+        detected
+            .map(|(_, language)| language.to_string())
+            .ok_or_else(|| {
+                errors::UserFailure(
+                    "Cannot detect the language: no file has a known extension".to_string(),
+                )
+            })
+    }
+
     pub fn get_language(
         image: Arc<Image>,
         name: String,
@@ -35,6 +77,24 @@ impl Image {
             .clone();
         package::Package::new(image, package_name)?.get_language(&name)
     }
+
+    // Runs the warm-up steps of all languages. This happens once, right after the image is mounted
+    // and before any submissions are accepted, so all sandboxes see the same caches
+    pub async fn warm_up(
+        image: Arc<Image>,
+        limits: &crate::config::LimitsConfig,
+    ) -> Result<(), errors::Error> {
+        let mut names: Vec<&String> = image.language_to_package_name.keys().collect();
+        names.sort();
+        for (i, name) in names.into_iter().enumerate() {
+            let language = Image::get_language(image.clone(), name.clone())?;
+            language
+                .warm_up(i.to_string(), limits)
+                .await
+                .with_context_invoker(|| format!("Failed to warm up language {name}"))?;
+        }
+        Ok(())
+    }
 }
 
 // Leaks? Who cares? At least it doesn't crash magnificently.
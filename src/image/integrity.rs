@@ -0,0 +1,167 @@
+use crate::signing;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+// A corrupted image (a flipped bit on a disk, a truncated download) or one replaced by someone who
+// should not have been able to fails in the least helpful way: a compiler crashes once in a while,
+// or, worse, produces wrong code and submissions get wrong verdicts. To rule this out, the image may
+// come with a manifest listing a SHA-256 hash of every package, in the format of sha256sum:
+//     # comment
+//     <hex hash>  <package name>
+// The manifest itself may be signed with Ed25519, the raw 64-byte signature of the file contents
+// being stored next to it at <manifest path>.sig. If a public key is configured, unsigned manifests
+// are refused, so that replacing the image and the manifest together is not enough.
+//
+// The hash of a package is the SHA-256 of a serialization of its directory tree. Entries are visited
+// depth-first in the order of their byte-wise sorted names, and each contributes its path relative
+// to the package directory, a null byte, and then:
+//     for a directory: 'd', permission bits as u32 LE
+//     for a regular file: 'f', permission bits as u32 LE, size as u64 LE, contents
+//     for a symlink: 'l', target, a null byte
+// The package directory itself is not included. Other file types are not allowed in packages.
+
+pub struct Manifest {
+    packages: HashMap<String, String>,
+}
+
+impl Manifest {
+    pub fn load(path: &str, public_key: Option<&str>) -> Result<Manifest> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("Failed to read manifest from {path}"))?;
+
+        if let Some(public_key) = public_key {
+            let public_key = signing::from_hex(public_key)
+                .with_context(|| format!("Public key {public_key:?} is not valid hex"))?;
+            let signature_path = format!("{path}.sig");
+            let signature = std::fs::read(&signature_path).with_context(|| {
+                format!("Failed to read manifest signature from {signature_path}")
+            })?;
+            if ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+                .verify(&contents, &signature)
+                .is_err()
+            {
+                bail!("Signature {signature_path} does not match manifest {path}");
+            }
+        }
+
+        let contents = String::from_utf8(contents)
+            .with_context(|| format!("Manifest {path} is not valid UTF-8"))?;
+        let mut packages = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (hash, package) = match line.split_once(char::is_whitespace) {
+                Some((hash, package)) => (hash, package.trim_start()),
+                None => bail!(
+                    "Manifest {path}, line {}: expected a hash and a name",
+                    i + 1
+                ),
+            };
+            if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                bail!(
+                    "Manifest {path}, line {}: {hash:?} is not a SHA-256 hash",
+                    i + 1
+                );
+            }
+            if packages
+                .insert(package.to_string(), hash.to_ascii_lowercase())
+                .is_some()
+            {
+                bail!(
+                    "Manifest {path}, line {}: package {package} is listed twice",
+                    i + 1
+                );
+            }
+        }
+
+        Ok(Manifest { packages })
+    }
+
+    // Checks that the image at the mountpoint provides the given packages exactly as listed in the
+    // manifest. A package missing from the manifest is an error too, as it cannot be trusted.
+    pub fn verify<'a>(
+        &self,
+        mountpoint: &Path,
+        packages: impl Iterator<Item = &'a String>,
+    ) -> Result<()> {
+        for package in packages {
+            let expected = match self.packages.get(package) {
+                Some(expected) => expected,
+                None => bail!("Package {package} is not listed in the manifest"),
+            };
+            let actual = hash_package(&mountpoint.join(package))
+                .with_context(|| format!("Failed to hash package {package}"))?;
+            if actual != *expected {
+                bail!(
+                    "Package {package} is corrupted or has been tampered with: its hash is \
+                     {actual}, but the manifest says {expected}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn hash_package(path: &Path) -> Result<String> {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    hash_directory(path, Path::new(""), &mut context)?;
+    Ok(signing::to_hex(context.finish().as_ref()))
+}
+
+fn hash_directory(root: &Path, relative: &Path, context: &mut ring::digest::Context) -> Result<()> {
+    let directory = root.join(relative);
+    let mut names = std::fs::read_dir(&directory)
+        .with_context(|| format!("Failed to read directory {directory:?}"))?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("Failed to read directory {directory:?}"))?;
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+    for name in names {
+        let relative = relative.join(name);
+        let path = root.join(&relative);
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("Failed to get metadata of {path:?}"))?;
+        let mode = metadata.permissions().mode() & 0o7777;
+
+        context.update(relative.as_os_str().as_bytes());
+        context.update(b"\0");
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            context.update(b"d");
+            context.update(&mode.to_le_bytes());
+            hash_directory(root, &relative, context)?;
+        } else if file_type.is_file() {
+            context.update(b"f");
+            context.update(&mode.to_le_bytes());
+            context.update(&metadata.size().to_le_bytes());
+            let mut file =
+                std::fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+            let mut buffer = vec![0; 1 << 16];
+            loop {
+                let n = std::io::Read::read(&mut file, &mut buffer)
+                    .with_context(|| format!("Failed to read {path:?}"))?;
+                if n == 0 {
+                    break;
+                }
+                context.update(&buffer[..n]);
+            }
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)
+                .with_context(|| format!("Failed to read symlink {path:?}"))?;
+            context.update(b"l");
+            context.update(target.as_os_str().as_bytes());
+            context.update(b"\0");
+        } else {
+            bail!("{path:?} is neither a directory, nor a regular file, nor a symlink");
+        }
+    }
+
+    Ok(())
+}
@@ -1,15 +1,31 @@
 use crate::{
-    errors,
+    cgroups, errors,
     errors::{ToError, ToResult},
-    image::{config, ids, package, program, sandbox},
-    system,
+    image::{args, config, diagnostics, ids, image, package, program, sandbox, seccomp},
+    problem::verdict,
+    supervisor, system,
 };
+use lazy_static::lazy_static;
 use multiprocessing::{Bind, Deserialize, DeserializeBoxed, Deserializer, Serialize, Serializer};
 use ouroboros::self_referencing;
 use rand::{thread_rng, Rng};
-use std::os::unix::process::CommandExt;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    // Set by the build entrypoint for the processes exec starts. The entrypoint runs in a process
+    // of its own, so this doesn't leak into identify and warm-up, which are not limited
+    static ref COMPILATION_LIMITS: Mutex<Option<config::CompilationLimits>> = Mutex::new(None);
+    // memory.events of the cgroup of the build, to tell the processes killed for exceeding the
+    // memory limit from those that crashed by themselves
+    static ref COMPILATION_MEMORY_EVENTS: Mutex<Option<std::fs::File>> = Mutex::new(None);
+}
 
 #[self_referencing(pub_extras)]
 pub struct LanguageImpl {
@@ -17,6 +33,13 @@ pub struct LanguageImpl {
     #[borrows(package)]
     config: &'this config::Language,
     name: String,
+    dialect: Option<String>,
+    // Set by the problem, see config::Language::allowed_flags
+    flags: Vec<String>,
+    // Whether to build with config::Language::diagnostic
+    diagnostic: bool,
+    // Set by the problem, see config::Language::grader
+    grader: Vec<PathBuf>,
 }
 
 impl LanguageImpl {
@@ -45,6 +68,7 @@ impl LanguageImpl {
         let identification = sandbox::run_isolated(
             Box::new(identify.bind(self.borrow_config().identify.clone())),
             &rootfs,
+            None,
         )
         .await?;
 
@@ -54,16 +78,73 @@ impl LanguageImpl {
             ));
         }
 
-        rootfs.remove().context_invoker("Failed to remove rootfs")?;
+        rootfs
+            .recycle()
+            .context_invoker("Failed to recycle rootfs")?;
 
         Ok(identification)
     }
 
-    pub async fn build(
+    pub async fn warm_up(
         &self,
-        mut input_files: Vec<&str>,
         build_id: String,
-    ) -> Result<(program::Program, String), errors::Error> {
+        limits: &crate::config::LimitsConfig,
+    ) -> Result<(), errors::Error> {
+        let package = self.borrow_package();
+        let config = self.borrow_config();
+
+        if config.warm_cache.is_empty() {
+            return Ok(());
+        }
+
+        // Make sandbox
+        let rootfs = sandbox::make_rootfs(
+            package,
+            Vec::new(),
+            sandbox::DiskQuotas {
+                space: limits.build_space,
+                max_inodes: limits.build_inodes,
+            },
+            format!("warm-{build_id}"),
+        )
+        .await
+        .context_invoker("Failed to make sandbox for warm-up")?;
+
+        rootfs
+            .reset()
+            .context_invoker("Failed to reset rootfs for warm-up")?;
+
+        // The steps run as the sandbox user. Afterwards, the cache is handed over to the sandboxed
+        // root, as other sandboxes have users of their own, see ids::Lease
+        let warm_cache = package.warm_cache_path();
+        std::os::unix::fs::chown(
+            &warm_cache,
+            Some(rootfs.external_user_uid()),
+            Some(rootfs.external_user_gid()),
+        )
+        .with_context_invoker(|| format!("Failed to chown {warm_cache:?}"))?;
+        rootfs.mount_warm_cache_writable(&warm_cache)?;
+
+        // Enter the sandbox in another process
+        let result = sandbox::run_isolated(
+            Box::new(warm_up.bind(config.warm_cache.clone())),
+            &rootfs,
+            None,
+        )
+        .await;
+
+        rootfs.remove().context_invoker("Failed to remove rootfs")?;
+
+        result?;
+        share_warm_cache(&warm_cache)
+    }
+
+    // Matches input files to the input patterns of the language. The result is sorted by suffix
+    // length, so that more specific patterns go first
+    fn map_input_files<'a>(
+        &'a self,
+        mut input_files: Vec<&'a str>,
+    ) -> Result<Vec<(&'a str, &'a str)>, errors::Error> {
         let package = self.borrow_package();
         let config = self.borrow_config();
         let name = self.borrow_name();
@@ -121,18 +202,136 @@ impl LanguageImpl {
             }
         }
 
+        Ok(files_and_patterns)
+    }
+
+    // Input files of a project keep their names relative to the source root
+    fn map_project_files<'a>(
+        &self,
+        input_files: Vec<&'a str>,
+        source_root: &Path,
+    ) -> Result<Vec<(&'a str, String)>, errors::Error> {
+        if input_files.is_empty() {
+            return Err(errors::UserFailure(
+                "No files were provided to build the project from".to_string(),
+            ));
+        }
+        let mut files_and_names = Vec::new();
+        for input_file in input_files {
+            let name = Path::new(input_file)
+                .strip_prefix(source_root)
+                .ok()
+                .and_then(|name| name.to_str())
+                .with_context_invoker(|| {
+                    format!("Input file {input_file} is not under {source_root:?}")
+                })?;
+            // These are used by the sandbox
+            let first_component = name.split('/').next().unwrap_or(name);
+            if ["artifacts", ".args", ".shm"].contains(&first_component) {
+                return Err(errors::UserFailure(format!(
+                    "File {name} cannot be a part of the project, as {first_component} is a \
+                     reserved name"
+                )));
+            }
+            files_and_names.push((input_file, name.to_string()));
+        }
+        Ok(files_and_names)
+    }
+
+    pub async fn build(
+        &self,
+        input_files: Vec<&str>,
+        source_root: &Path,
+        build_id: String,
+        core: u64,
+        sandbox_config: &crate::config::SandboxConfig,
+    ) -> Result<(program::Program, String), errors::Error> {
+        let package = self.borrow_package();
+        let mut config = (*self.borrow_config()).clone();
+
+        // The dialect was validated when it was chosen
+        if let Some(dialect) = self.borrow_dialect() {
+            config.build = config.dialects[dialect].clone();
+        }
+
+        // The profile was validated when it was chosen
+        let mut flags = self.borrow_flags().clone();
+        let multipliers = verdict::LimitMultipliers {
+            time: config.multipliers.time_percent as f64 / 100.0,
+            memory: config.multipliers.memory_percent as f64 / 100.0,
+        };
+        let mut diagnostic_multipliers = verdict::LimitMultipliers::default();
+        if *self.borrow_diagnostic() {
+            flags.extend(config.diagnostic.flags.iter().cloned());
+            // An empty list means no filter at all, which should stay so
+            if !config.syscalls.is_empty() {
+                config
+                    .syscalls
+                    .extend(config.diagnostic.syscalls.iter().cloned());
+            }
+            diagnostic_multipliers.time = config.diagnostic.time_percent as f64 / 100.0;
+            diagnostic_multipliers.memory = config.diagnostic.memory_percent as f64 / 100.0;
+        }
+
         // Set pattern arbitrarily
         let mut pre_pattern = [0i8; 8];
         thread_rng().fill(&mut pre_pattern[..]);
         let pre_pattern = pre_pattern.map(|x| format!("{x:02x}")).join("");
 
-        // Mount input files into sandbox
+        // Mount input files into sandbox. Files of a project are not renamed
         let mut bound_files = Vec::new();
-        for (input_file, input_pattern) in &files_and_patterns {
-            bound_files.push((
-                input_file.into(),
-                "/space/".to_string() + &input_pattern.replace("%", &pre_pattern),
-            ));
+        let mut patterns = Vec::new();
+        if config.inputs.is_empty() {
+            for (input_file, name) in self.map_project_files(input_files, source_root)? {
+                bound_files.push((input_file.into(), format!("/space/{name}")));
+            }
+        } else {
+            for (input_file, input_pattern) in self.map_input_files(input_files)? {
+                bound_files.push((
+                    input_file.into(),
+                    "/space/".to_string() + &input_pattern.replace("%", &pre_pattern),
+                ));
+                patterns.push(input_pattern.to_string());
+            }
+        }
+
+        // Grader files keep their names. A submission cannot replace them
+        let mut grader = Vec::new();
+        for path in self.borrow_grader() {
+            // Validated by with_grader
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+            let target = format!("/space/{name}");
+            if bound_files.iter().any(|(_, bound)| *bound == target) {
+                return Err(errors::UserFailure(format!(
+                    "File {name} is provided by the problem and cannot be submitted"
+                )));
+            }
+            bound_files.push((path.clone(), target));
+            grader.push(name);
+        }
+
+        // Better to fail before spending time on compilation
+        seccomp::validate(&config.syscalls)?;
+        let compilation_limits = &config.compilation_limits;
+        if [
+            compilation_limits.cpu_time_ms,
+            compilation_limits.memory,
+            compilation_limits.output_size,
+            compilation_limits.log_size,
+        ]
+        .iter()
+        .any(|limit| *limit < 0)
+        {
+            return Err(errors::ConfigurationFailure(format!(
+                "Compilation limits of language {} must not be negative",
+                self.borrow_name()
+            )));
+        }
+        if !(1..=100).contains(&config.memory_model.heap_percent) {
+            return Err(errors::ConfigurationFailure(format!(
+                "Heap size of language {} must be between 1% and 100% of the memory limit",
+                self.borrow_name()
+            )));
         }
 
         // Make sandbox
@@ -140,8 +339,8 @@ impl LanguageImpl {
             package,
             bound_files,
             sandbox::DiskQuotas {
-                space: 32 * 1024 * 1024, // TODO: make this configurable
-                max_inodes: 1024,
+                space: sandbox_config.limits.build_space,
+                max_inodes: sandbox_config.limits.build_inodes,
             },
             format!("build-{build_id}"),
         )
@@ -159,36 +358,83 @@ impl LanguageImpl {
             .with_context_invoker(|| format!("Failed to create {artifacts_path:?}"))?;
         std::fs::create_dir(&overlay_artifacts_path)
             .with_context_invoker(|| format!("Failed to create {overlay_artifacts_path}"))?;
-        system::bind_mount(&artifacts_path, &overlay_artifacts_path)
+        supervisor::bind_mount(&artifacts_path, &overlay_artifacts_path)
             .with_context_invoker(|| format!("Failed to bind-mount {artifacts_path:?}"))?;
 
         // Allow the sandbox user to access data
         std::os::unix::fs::chown(
             &overlay_artifacts_path,
-            Some(ids::EXTERNAL_USER_UID),
-            Some(ids::EXTERNAL_USER_GID),
+            Some(rootfs.external_user_uid()),
+            Some(rootfs.external_user_gid()),
         )
         .with_context_invoker(|| format!("Failed to chown {overlay_artifacts_path}"))?;
 
+        // The build process moves itself into the cgroup of the build, as it cannot see the
+        // cgroup hierarchy from inside the sandbox
+        let cgroup = cgroups::create_build_cgroup(
+            core,
+            &build_id,
+            config.compilation_limits.memory as u64,
+            sandbox_config.max_pids,
+        )?;
+        let cgroup_procs = std::fs::File::options()
+            .write(true)
+            .open(format!("{cgroup}/cgroup.procs"))
+            .context_invoker("Failed to open cgroup.procs of the build")?;
+        let open_memory_events = || {
+            std::fs::File::open(format!("{cgroup}/memory.events"))
+                .context_invoker("Failed to open memory.events of the build")
+        };
+        let mut memory_events = open_memory_events()?;
+        let oom_kills_before = cgroups::oom_kills(&mut memory_events)?;
+
         // Enter the sandbox in another process
-        let (pattern, log) = sandbox::run_isolated(
+        let result = sandbox::run_isolated(
             Box::new(
-                build.bind((*config).clone()).bind(pre_pattern).bind(
-                    files_and_patterns
-                        .into_iter()
-                        .map(|(_, pattern)| pattern.to_string())
-                        .collect(),
-                ),
+                build
+                    .bind(config.clone())
+                    .bind(pre_pattern)
+                    .bind(patterns)
+                    .bind(flags)
+                    .bind(grader.clone())
+                    .bind(cgroup_procs)
+                    .bind(open_memory_events()?),
             ),
             &rootfs,
+            None,
         )
-        .await?;
+        .await;
 
-        rootfs.remove().context_invoker("Failed to remove rootfs")?;
+        // The OOM killer may have picked the build process itself rather than the compiler, in
+        // which case there is no log to report
+        let result = match result {
+            Err(e)
+                if !matches!(e, errors::UserFailure(_))
+                    && cgroups::oom_kills(&mut memory_events)? > oom_kills_before =>
+            {
+                Err(errors::UserFailure(format!(
+                    "The build exceeded the compilation memory limit: {e:?}"
+                )))
+            }
+            result => result,
+        };
+        if let Err(e) = std::fs::remove_dir(&cgroup) {
+            errors::log_throttled(
+                "remove build cgroup",
+                format!("Failed to remove {cgroup}: {e:?}"),
+            );
+        }
+        let (pattern, log) = result?;
+
+        rootfs
+            .recycle()
+            .context_invoker("Failed to recycle rootfs")?;
 
         let prerequisites: Vec<String> = lisp::evaluate(
             config.run.prerequisites.clone(),
-            &lisp::State::new().var("$base".to_string(), pattern.clone()),
+            &lisp::State::new()
+                .var("$base".to_string(), pattern.clone())
+                .var("$grader".to_string(), grader_term(&grader)),
         )
         .map_err(|e| {
             errors::ConfigurationFailure(format!("Failed to evaluate run.prerequisites: {e:?}"))
@@ -202,7 +448,9 @@ impl LanguageImpl {
 
         let argv: Vec<String> = lisp::evaluate(
             config.run.argv.clone(),
-            &lisp::State::new().var("$base".to_string(), pattern.clone()),
+            &lisp::State::new()
+                .var("$base".to_string(), pattern.clone())
+                .var("$grader".to_string(), grader_term(&grader)),
         )
         .map_err(|e| errors::ConfigurationFailure(format!("Failed to evaluate run.argv: {e:?}")))?
         .to_native()
@@ -218,10 +466,125 @@ impl LanguageImpl {
                 prerequisites,
                 argv,
                 artifacts_path,
+                syscalls: config.syscalls,
+                language: self.borrow_name().clone(),
+                limit_multipliers: multipliers,
+                diagnostic_multipliers,
+                output_only: false,
+                diagnostic: *self.borrow_diagnostic(),
+                memory_model: config.memory_model,
+                runtime: config.runtime.argv,
+            },
+            log,
+        ))
+    }
+
+    // The counterpart of build for output-only submissions: the files are answers to the tests and
+    // are taken as they are. The package still provides the sandbox the answers are checked in
+    pub fn collect_answers(
+        &self,
+        input_files: Vec<&str>,
+        build_id: String,
+    ) -> Result<(program::Program, String), errors::Error> {
+        let artifacts_path = PathBuf::from(format!("/tmp/sunwalker_invoker/artifacts/{build_id}"));
+        std::fs::create_dir(&artifacts_path)
+            .with_context_invoker(|| format!("Failed to create {artifacts_path:?}"))?;
+
+        let mut prerequisites: Vec<String> = Vec::new();
+        for input_file in input_files {
+            let name = Path::new(input_file)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .with_context_invoker(|| format!("Invalid file name {input_file:?}"))?
+                .to_string();
+            let test = program::answer_test(&name).ok_or_else(|| {
+                errors::UserFailure(format!(
+                    "Answer file {name} is not named after a test, e.g. 1 or 1.out"
+                ))
+            })?;
+            if let Some(other) = prerequisites
+                .iter()
+                .find(|other| program::answer_test(other) == Some(test))
+            {
+                return Err(errors::UserFailure(format!(
+                    "Answer files {other} and {name} are both for test {test}"
+                )));
+            }
+            let path = artifacts_path.join(&name);
+            std::fs::copy(input_file, &path)
+                .with_context_invoker(|| format!("Failed to copy {input_file} to {path:?}"))?;
+            prerequisites.push(name);
+        }
+        prerequisites.sort_by_key(|name| program::answer_test(name));
+
+        let log = format!("Received answers to {} tests", prerequisites.len());
+        Ok((
+            program::Program {
+                package: self.borrow_package().clone(),
+                prerequisites,
+                argv: Vec::new(),
+                artifacts_path,
+                syscalls: Vec::new(),
+                language: self.borrow_name().clone(),
+                limit_multipliers: verdict::LimitMultipliers::default(),
+                diagnostic_multipliers: verdict::LimitMultipliers::default(),
+                output_only: true,
+                diagnostic: false,
+                memory_model: config::MemoryModel::default(),
+                runtime: Vec::new(),
             },
             log,
         ))
     }
+
+    // Inside the sandbox, input files are renamed according to the input patterns, so the
+    // diagnostics have to be mapped back to the names the user knows
+    pub fn parse_diagnostics(
+        &self,
+        log: &str,
+        input_files: Vec<&str>,
+    ) -> Vec<diagnostics::Diagnostic> {
+        // Files of a project are not renamed, so paths only need to be made relative
+        let project = self.borrow_config().inputs.is_empty();
+
+        let files_and_patterns = if project {
+            Vec::new()
+        } else {
+            match self.map_input_files(input_files) {
+                Ok(files_and_patterns) => files_and_patterns,
+                Err(_) => return Vec::new(),
+            }
+        };
+
+        diagnostics::parse(log)
+            .into_iter()
+            .map(|mut diagnostic| {
+                let path = diagnostic.file.trim_start_matches("/space/");
+                let path = path.trim_start_matches("./");
+                if project {
+                    diagnostic.file = path.to_string();
+                    return diagnostic;
+                }
+                let matches = |pattern: &str| match pattern.split_once('%') {
+                    Some((prefix, suffix)) => {
+                        path.len() >= prefix.len() + suffix.len()
+                            && path.starts_with(prefix)
+                            && path.ends_with(suffix)
+                    }
+                    None => path == pattern,
+                };
+                if let Some((input_file, _)) = files_and_patterns
+                    .iter()
+                    .find(|(_, input_pattern)| matches(input_pattern))
+                {
+                    if let Some(file_name) = Path::new(input_file).file_name() {
+                        diagnostic.file = file_name.to_string_lossy().into_owned();
+                    }
+                }
+                diagnostic
+            })
+            .collect()
+    }
 }
 
 pub struct Language {
@@ -257,10 +620,126 @@ impl Language {
                         .unwrap()
                 },
                 name.to_string(),
+                None,
+                Vec::new(),
+                false,
+                Vec::new(),
             ),
         })
     }
 
+    pub fn name(&self) -> &str {
+        self.nested.borrow_name()
+    }
+
+    pub fn dialect(&self) -> Option<&str> {
+        self.nested.borrow_dialect().as_deref()
+    }
+
+    pub fn flags(&self) -> &[String] {
+        self.nested.borrow_flags()
+    }
+
+    pub fn diagnostic(&self) -> bool {
+        *self.nested.borrow_diagnostic()
+    }
+
+    pub fn grader(&self) -> &[PathBuf] {
+        self.nested.borrow_grader()
+    }
+
+    pub fn memory_model(&self) -> &config::MemoryModel {
+        &self.nested.borrow_config().memory_model
+    }
+
+    pub fn runtime(&self) -> &[String] {
+        &self.nested.borrow_config().runtime.argv
+    }
+
+    pub fn package(&self) -> &package::Package {
+        self.nested.borrow_package()
+    }
+
+    pub fn image(&self) -> &image::Image {
+        &self.nested.borrow_package().image
+    }
+
+    pub fn with_dialect(mut self, dialect: Option<String>) -> Result<Language, errors::Error> {
+        if let Some(ref dialect) = dialect {
+            let dialects = &self.nested.borrow_config().dialects;
+            if !dialects.contains_key(dialect) {
+                let mut available: Vec<&String> = dialects.keys().collect();
+                available.sort();
+                return Err(errors::UserFailure(format!(
+                    "Language {} does not support dialect {dialect}, available dialects are: \
+                     {available:?}",
+                    self.nested.borrow_name()
+                )));
+            }
+        }
+        self.nested.with_dialect_mut(|field| *field = dialect);
+        Ok(self)
+    }
+
+    pub fn with_flags(mut self, flags: Vec<String>) -> Result<Language, errors::Error> {
+        let allowed_flags = &self.nested.borrow_config().allowed_flags;
+        for flag in &flags {
+            let allowed = allowed_flags
+                .iter()
+                .any(|pattern| match pattern.split_once('%') {
+                    Some((prefix, suffix)) => {
+                        flag.len() >= prefix.len() + suffix.len()
+                            && flag.starts_with(prefix)
+                            && flag.ends_with(suffix)
+                    }
+                    None => flag == pattern,
+                });
+            if !allowed {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Compiler flag {flag} is not allowed for language {}, allowed flags are: \
+                     {allowed_flags:?}",
+                    self.nested.borrow_name()
+                )));
+            }
+        }
+        self.nested.with_flags_mut(|field| *field = flags);
+        Ok(self)
+    }
+
+    pub fn with_diagnostic(mut self, diagnostic: bool) -> Result<Language, errors::Error> {
+        if diagnostic && self.nested.borrow_config().diagnostic.flags.is_empty() {
+            return Err(errors::UserFailure(format!(
+                "Language {} does not support diagnostic builds",
+                self.nested.borrow_name()
+            )));
+        }
+        self.nested.with_diagnostic_mut(|field| *field = diagnostic);
+        Ok(self)
+    }
+
+    pub fn with_grader(mut self, grader: Vec<PathBuf>) -> Result<Language, errors::Error> {
+        if !grader.is_empty() && !self.nested.borrow_config().grader {
+            return Err(errors::ConfigurationFailure(format!(
+                "Language {} does not support problems with a grader",
+                self.nested.borrow_name()
+            )));
+        }
+        let mut names = HashSet::new();
+        for path in &grader {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .with_context_invoker(|| format!("Grader file {path:?} has no valid name"))?;
+            if ["artifacts", ".args", ".shm"].contains(&name) || !names.insert(name) {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Grader file name {name} is reserved or used more than once"
+                )));
+            }
+        }
+        self.nested.with_grader_mut(|field| *field = grader);
+        Ok(self)
+    }
+
     pub async fn identify(&self, build_id: String) -> Result<String, errors::Error> {
         self.nested.identify(build_id).await
     }
@@ -268,9 +747,30 @@ impl Language {
     pub async fn build(
         &self,
         input_files: Vec<&str>,
+        source_root: &Path,
         build_id: String,
+        core: u64,
+        sandbox_config: &crate::config::SandboxConfig,
     ) -> Result<(program::Program, String), errors::Error> {
-        self.nested.build(input_files, build_id).await
+        self.nested
+            .build(input_files, source_root, build_id, core, sandbox_config)
+            .await
+    }
+
+    pub async fn warm_up(
+        &self,
+        build_id: String,
+        limits: &crate::config::LimitsConfig,
+    ) -> Result<(), errors::Error> {
+        self.nested.warm_up(build_id, limits).await
+    }
+
+    pub fn parse_diagnostics(
+        &self,
+        log: &str,
+        input_files: Vec<&str>,
+    ) -> Vec<diagnostics::Diagnostic> {
+        self.nested.parse_diagnostics(log, input_files)
     }
 }
 
@@ -280,6 +780,10 @@ impl Clone for Language {
             self.nested.borrow_package().clone(),
             self.nested.borrow_name(),
         )
+        .and_then(|language| language.with_dialect(self.nested.borrow_dialect().clone()))
+        .and_then(|language| language.with_flags(self.nested.borrow_flags().clone()))
+        .and_then(|language| language.with_diagnostic(*self.nested.borrow_diagnostic()))
+        .and_then(|language| language.with_grader(self.nested.borrow_grader().clone()))
         .expect("Failed to clone a language")
     }
 }
@@ -288,13 +792,26 @@ impl Serialize for Language {
     fn serialize_self(&self, s: &mut Serializer) {
         s.serialize(self.nested.borrow_package());
         s.serialize(self.nested.borrow_name());
+        s.serialize(self.nested.borrow_dialect());
+        s.serialize(self.nested.borrow_flags());
+        s.serialize(self.nested.borrow_diagnostic());
+        s.serialize(self.nested.borrow_grader());
     }
 }
 impl Deserialize for Language {
     fn deserialize_self(d: &mut Deserializer) -> Self {
         let package = d.deserialize();
         let name: String = d.deserialize();
-        Language::new(package, &name).expect("Failed to deserialize a language")
+        let dialect = d.deserialize();
+        let flags = d.deserialize();
+        let diagnostic = d.deserialize();
+        let grader = d.deserialize();
+        Language::new(package, &name)
+            .and_then(|language| language.with_dialect(dialect))
+            .and_then(|language| language.with_flags(flags))
+            .and_then(|language| language.with_diagnostic(diagnostic))
+            .and_then(|language| language.with_grader(grader))
+            .expect("Failed to deserialize a language")
     }
 }
 impl<'a> DeserializeBoxed<'a> for Language {
@@ -306,37 +823,275 @@ impl<'a> DeserializeBoxed<'a> for Language {
     }
 }
 
+// Makes the files the warm-up steps have written owned by the sandboxed root and readable, and
+// executable where they were executable by the owner, by everyone
+fn share_warm_cache(path: &Path) -> Result<(), errors::Error> {
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context_invoker(|| format!("Failed to stat {path:?}"))?;
+    std::os::unix::fs::lchown(
+        path,
+        Some(ids::EXTERNAL_ROOT_UID),
+        Some(ids::EXTERNAL_ROOT_GID),
+    )
+    .with_context_invoker(|| format!("Failed to chown {path:?}"))?;
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    let mut mode = metadata.permissions().mode() | 0o444;
+    if metadata.is_dir() || mode & 0o100 != 0 {
+        mode |= 0o111;
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context_invoker(|| format!("Failed to chmod {path:?}"))?;
+
+    if metadata.is_dir() {
+        for entry in
+            std::fs::read_dir(path).with_context_invoker(|| format!("Failed to list {path:?}"))?
+        {
+            let entry = entry.with_context_invoker(|| format!("Failed to list {path:?}"))?;
+            share_warm_cache(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
 #[lisp::function]
 fn exec(call: lisp::CallTerm, state: &lisp::State) -> Result<lisp::TypedRef, lisp::Error> {
     let argv: Vec<String> = lisp::evaluate(lisp::builtins::as_item1(call)?, state)?.to_native()?;
-    let output = unsafe {
+    run_command(argv, false)
+}
+
+// Like exec, but if the arguments don't fit into the kernel limits, passes them to the program via
+// a response file, i.e. as @file. Only for programs that support this, such as most compilers
+#[lisp::function]
+fn exec_with_response_file(
+    call: lisp::CallTerm,
+    state: &lisp::State,
+) -> Result<lisp::TypedRef, lisp::Error> {
+    let argv: Vec<String> = lisp::evaluate(lisp::builtins::as_item1(call)?, state)?.to_native()?;
+    run_command(argv, true)
+}
+
+// The CPU time of the processes the build has started and waited for, their children included
+fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage);
+    }
+    let to_duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+fn run_command(argv: Vec<String>, response_file: bool) -> Result<lisp::TypedRef, lisp::Error> {
+    const RESPONSE_FILE_PATH: &str = "/space/.args";
+
+    let limits = COMPILATION_LIMITS
+        .lock()
+        .expect("Failed to acquire compilation limits")
+        .clone();
+    let time_limit = limits
+        .as_ref()
+        .filter(|limits| limits.cpu_time_ms > 0)
+        .map(|limits| Duration::from_millis(limits.cpu_time_ms as u64));
+    let log_size = limits.as_ref().map_or(0, |limits| limits.log_size).max(0) as usize;
+    let time_limit_exceeded =
+        || time_limit.map_or(false, |time_limit| children_cpu_time() >= time_limit);
+
+    // RLIMIT_CPU applies to each process separately, so every process gets what is left of the
+    // time limit of the whole build
+    if time_limit_exceeded() {
+        return Err(lisp::Error {
+            message: format!("Process failed: {argv:?}: compilation time limit exceeded"),
+        });
+    }
+    let mut rlimits = Vec::new();
+    if let Some(time_limit) = time_limit {
+        let seconds_left = time_limit
+            .saturating_sub(children_cpu_time())
+            .as_secs_f64()
+            .ceil() as u64;
+        // SIGXCPU at the soft limit, SIGKILL a second later if the process ignores it
+        rlimits.push((libc::RLIMIT_CPU, seconds_left, seconds_left + 1));
+    }
+    if let Some(output_size) = limits.as_ref().map(|limits| limits.output_size) {
+        if output_size > 0 {
+            rlimits.push((libc::RLIMIT_FSIZE, output_size as u64, output_size as u64));
+        }
+    }
+
+    let env: Vec<(String, String)> = std::env::vars().collect();
+    let argv = args::fit(
+        argv,
+        &env,
+        response_file.then_some((Path::new(RESPONSE_FILE_PATH), RESPONSE_FILE_PATH)),
+    )
+    .map_err(|e| lisp::Error {
+        message: format!("Cannot start process: {e}"),
+    })?;
+
+    let oom_kills = || match COMPILATION_MEMORY_EVENTS
+        .lock()
+        .expect("Failed to acquire compilation memory events")
+        .as_mut()
+    {
+        Some(memory_events) => cgroups::oom_kills(memory_events).map_err(|e| lisp::Error {
+            message: format!("Failed to read memory events of the build: {e:?}"),
+        }),
+        None => Ok(0),
+    };
+    let oom_kills_before = oom_kills()?;
+
+    let child = unsafe {
         Command::new(argv[0].clone())
             .args(argv.iter().skip(1))
             .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .current_dir("/space")
-            .pre_exec(sandbox::drop_privileges)
+            .pre_exec(move || {
+                for (resource, soft, hard) in &rlimits {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: *soft,
+                        rlim_max: *hard,
+                    };
+                    if libc::setrlimit(*resource, &rlimit) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                sandbox::drop_privileges()
+            })
     }
-    .output()
-    .map_err(|e| lisp::Error {
-        message: format!("Failed to start process {argv:?}: {e}"),
+    .spawn();
+    let output = child.and_then(|mut child| {
+        // Both pipes are drained at once, lest the compiler block on the one not being read
+        let stderr = child.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || read_bounded(stderr, log_size));
+        let stdout = read_bounded(child.stdout.take().unwrap(), log_size);
+        let stderr = stderr.join().expect("Failed to join the stderr reader");
+        let status = child.wait()?;
+        Ok((status, stdout?, stderr?))
+    });
+    if argv.len() == 2 && argv[1] == format!("@{RESPONSE_FILE_PATH}") {
+        let _ = std::fs::remove_file(RESPONSE_FILE_PATH);
+    }
+    let (status, stdout, stderr) = output.map_err(|e| lisp::Error {
+        message: format!("Failed to run process {argv:?}: {e}"),
     })?;
-    if output.status.success() {
+
+    // A compiler killed by the limits is the fault of the submission, e.g. a template bomb, but a
+    // compiler that crashes by itself is the fault of the image
+    if time_limit_exceeded() {
+        return Err(lisp::Error {
+            message: format!(
+                "Process failed: {argv:?}: compilation time limit exceeded\n\n{stdout}\n\n{stderr}"
+            ),
+        });
+    }
+    if oom_kills()? > oom_kills_before {
+        return Err(lisp::Error {
+            message: format!(
+                "Process failed: {argv:?}: memory limit exceeded\n\n{stdout}\n\n{stderr}"
+            ),
+        });
+    }
+    match status.signal() {
+        Some(libc::SIGXFSZ) => {
+            return Err(lisp::Error {
+                message: format!(
+                    "Process failed: {argv:?}: output size limit exceeded\n\n{stdout}\n\n{stderr}"
+                ),
+            });
+        }
+        Some(signal) => {
+            return Err(lisp::Error {
+                message: format!(
+                    "Compiler crashed: {argv:?}: killed by signal {signal}\n\n{stdout}\n\n{stderr}"
+                ),
+            });
+        }
+        None => {}
+    }
+
+    if status.success() {
         Ok(lisp::TypedRef::new(
-            String::from_utf8_lossy(&output.stdout).into_owned()
-                + &String::from_utf8_lossy(&output.stderr), // TODO: interleave
+            stdout + &stderr, // TODO: interleave
         ))
     } else {
         Err(lisp::Error {
-            message: format!(
-                "Process failed: {argv:?}: {}\n\n{}\n\n{}",
-                output.status,
-                String::from_utf8_lossy(&output.stdout).into_owned(),
-                String::from_utf8_lossy(&output.stderr).into_owned()
-            ),
+            message: format!("Process failed: {argv:?}: {status}\n\n{stdout}\n\n{stderr}"),
         })
     }
 }
 
+// Reads the stream to the end, but only keeps the first limit bytes, 0 meaning no limit. The build
+// process is in the cgroup of the build, so a compiler flooding its output would otherwise run it out
+// of memory
+fn read_bounded(mut stream: impl Read, limit: usize) -> std::io::Result<String> {
+    let mut kept = Vec::new();
+    let mut dropped = 0;
+    let mut buf = [0u8; 65536];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let keep = if limit == 0 {
+            n
+        } else {
+            n.min(limit.saturating_sub(kept.len()))
+        };
+        kept.extend_from_slice(&buf[..keep]);
+        dropped += n - keep;
+    }
+    let mut output = String::from_utf8_lossy(&kept).into_owned();
+    if dropped > 0 {
+        output += &format!("\n\n({dropped} more bytes of output are cut off)");
+    }
+    Ok(output)
+}
+
+// Keeps the beginning of the log, where the first and usually most relevant errors are
+fn truncate_log(log: String, log_size: i64) -> String {
+    if log_size <= 0 || log.len() <= log_size as usize {
+        return log;
+    }
+    let mut end = log_size as usize;
+    while !log.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}\n\n(The log is truncated to {log_size} bytes out of {})",
+        &log[..end],
+        log.len()
+    )
+}
+
+// Runs the build steps in order and concatenates their logs. Unlike concat, keeps the logs of the
+// steps that succeeded when a later one fails, e.g. to show the output of cmake when make fails
+#[lisp::function]
+fn steps(call: lisp::CallTerm, state: &lisp::State) -> Result<lisp::TypedRef, lisp::Error> {
+    let mut log = String::new();
+    for step in call.params.into_iter() {
+        match lisp::evaluate(step, state).and_then(|output| output.to_native::<String>()) {
+            Ok(output) => log += &output,
+            Err(e) => {
+                return Err(match e.message.strip_prefix("Process failed: ") {
+                    Some(failure) if !log.is_empty() => lisp::Error {
+                        message: format!("Process failed: {failure}\n\nPrevious steps:\n{log}"),
+                    },
+                    _ => e,
+                });
+            }
+        }
+    }
+    Ok(lisp::TypedRef::new(log))
+}
+
 #[lisp::function]
 fn mv(call: lisp::CallTerm, state: &lisp::State) -> Result<lisp::TypedRef, lisp::Error> {
     let argv = lisp::builtins::as_tuple2(call)?;
@@ -358,12 +1113,40 @@ fn identify(term: lisp::Term) -> Result<String, errors::Error> {
         .context_invoker("Failed to interpret identify result as a string")
 }
 
+#[multiprocessing::entrypoint]
+fn warm_up(steps: Vec<lisp::Term>) -> Result<(), errors::Error> {
+    for step in steps {
+        lisp::evaluate(step, &lisp::State::new()).context_invoker("Warm-up step failed")?;
+    }
+    Ok(())
+}
+
+fn grader_term(grader: &[String]) -> Vec<lisp::TypedRef> {
+    grader.iter().cloned().map(lisp::TypedRef::new).collect()
+}
+
 #[multiprocessing::entrypoint]
 fn build(
     config: config::Language,
     pre_pattern: String,
     patterns: Vec<String>,
+    flags: Vec<String>,
+    grader: Vec<String>,
+    mut cgroup_procs: std::fs::File,
+    memory_events: std::fs::File,
 ) -> Result<(String, String), errors::Error> {
+    cgroup_procs
+        .write_all(b"0\n")
+        .context_invoker("Failed to move the build to its cgroup")?;
+    drop(cgroup_procs);
+
+    let log_size = config.compilation_limits.log_size;
+    *COMPILATION_LIMITS
+        .lock()
+        .expect("Failed to acquire compilation limits") = Some(config.compilation_limits.clone());
+    *COMPILATION_MEMORY_EVENTS
+        .lock()
+        .expect("Failed to acquire compilation memory events") = Some(memory_events);
     // Evaluate correct pattern
     let pattern: String = lisp::evaluate(
         config.base_rule.clone(),
@@ -377,7 +1160,14 @@ fn build(
         // Rename files according to new pattern
         for pattern in patterns {
             let old_path = Path::new("/space").join(pattern.replace("%", &pre_pattern));
-            let new_path = Path::new("/space").join(pattern.replace("%", &pattern));
+            let new_name = pattern.replace("%", &pattern);
+            if grader.contains(&new_name) {
+                return Err(errors::UserFailure(format!(
+                    "The submission is built as {new_name}, which is a grader file provided by \
+                     the problem"
+                )));
+            }
+            let new_path = Path::new("/space").join(new_name);
             std::fs::write(&new_path, "").with_context_invoker(|| {
                 format!("Failed to create file {new_path:?} on overlay")
             })?;
@@ -391,17 +1181,29 @@ fn build(
     }
 
     // Run build process
-    let state = lisp::State::new().var("$base".to_string(), pattern.clone());
+    let state = lisp::State::new()
+        .var("$base".to_string(), pattern.clone())
+        .var(
+            "$flags".to_string(),
+            flags
+                .into_iter()
+                .map(lisp::TypedRef::new)
+                .collect::<Vec<_>>(),
+        )
+        .var("$grader".to_string(), grader_term(&grader));
     let log: String = lisp::evaluate(config.build.clone(), &state)
         .map_err(|e| {
             if e.message.starts_with("Process failed: ") {
-                errors::UserFailure(e.message)
+                errors::UserFailure(truncate_log(e.message, log_size))
+            } else if e.message.starts_with("Compiler crashed: ") {
+                errors::ConfigurationFailure(truncate_log(e.message, log_size))
             } else {
                 e.context_invoker("Failed to build the program")
             }
         })?
         .to_native()
         .context_invoker("Failed to parse compilation log as a string")?;
+    let log = truncate_log(log, log_size);
 
     let run_prerequisites: Vec<String> = lisp::evaluate(config.run.prerequisites.clone(), &state)
         .context_invoker("Failed to evaluate run.prerequisites")?
@@ -417,6 +1219,11 @@ fn build(
     for rel_path in run_prerequisites.into_iter() {
         let from = Path::new("/space").join(&rel_path);
         let to = Path::new("/space/artifacts").join(&rel_path);
+        // Projects may keep their outputs in subdirectories, e.g. target/release
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context_invoker(|| format!("Failed to create directory {parent:?}"))?;
+        }
         std::fs::copy(&from, &to).with_context_invoker(|| {
             format!("Failed to copy artifact {rel_path} from {from:?} to {to:?}")
         })?;
@@ -1,6 +1,6 @@
 use crate::{
-    image::{config, image},
-    system,
+    image::{config, image, integrity},
+    supervisor, system,
 };
 use anyhow::{bail, Context, Result};
 use libc::{MS_RDONLY, MS_REC};
@@ -24,6 +24,7 @@ impl ImageMounter {
         &mut self,
         source_path: P,
         config: config::Config,
+        manifest: Option<&integrity::Manifest>,
     ) -> Result<image::Image> {
         let attr = std::fs::metadata(&source_path).with_context(|| {
             format!(
@@ -32,6 +33,9 @@ impl ImageMounter {
             )
         })?;
 
+        let version = get_image_version(source_path.as_ref())
+            .with_context(|| "Failed to determine the version of the image")?;
+
         let mountpoint = self.get_mountpoint();
         std::fs::create_dir(&mountpoint)
             .with_context(|| format!("Unable to create temporary mountpoint at {mountpoint:?}"))?;
@@ -40,7 +44,7 @@ impl ImageMounter {
 
         if file_type.is_dir() {
             // Bind-mount
-            system::bind_mount_opt(source_path, &mountpoint, MS_RDONLY | MS_REC)
+            supervisor::bind_mount_opt(source_path, &mountpoint, MS_RDONLY | MS_REC)
                 .with_context(|| "Bind-mounting image failed")?;
         } else if file_type.is_file() {
             // Mount as squashfs image
@@ -99,10 +103,79 @@ impl ImageMounter {
             }
         }
 
+        // Extensions matched by exactly one single-file language map to it, unless overridden
+        let mut candidates: HashMap<String, Vec<&String>> = HashMap::new();
+        for package in config.packages.values() {
+            for (lang_name, language) in &package.languages {
+                if let [pattern] = language.inputs.as_slice() {
+                    if let Some(extension) = pattern.strip_prefix('%') {
+                        if extension.starts_with('.') && !extension.contains('%') {
+                            candidates
+                                .entry(extension.to_string())
+                                .or_default()
+                                .push(lang_name);
+                        }
+                    }
+                }
+            }
+        }
+        let mut extension_to_language: HashMap<String, String> = candidates
+            .into_iter()
+            .filter(|(_, lang_names)| lang_names.len() == 1)
+            .map(|(extension, lang_names)| (extension, lang_names[0].clone()))
+            .collect();
+        for (extension, lang_name) in &config.extensions {
+            if !extension.starts_with('.') {
+                bail!("Extension {extension} must start with a dot");
+            }
+            if !language_to_package_name.contains_key(lang_name) {
+                bail!(
+                    "Extension {extension} is mapped to language {lang_name}, which is not \
+                     provided by any package"
+                );
+            }
+            extension_to_language.insert(extension.clone(), lang_name.clone());
+        }
+
+        // Nothing from the image is executed before this point
+        if let Some(manifest) = manifest {
+            if let Err(e) = manifest.verify(&mountpoint, config.packages.keys()) {
+                let _ = supervisor::umount_opt(&mountpoint, system::MNT_DETACH);
+                let _ = std::fs::remove_dir(&mountpoint);
+                return Err(e.context("Integrity check of the image failed"));
+            }
+        }
+
         Ok(image::Image {
             mountpoint,
             config,
             language_to_package_name,
+            extension_to_language,
+            version,
         })
     }
 }
+
+fn get_image_version(path: &std::path::Path) -> Result<String> {
+    // Hashing the whole image would take too long, and images are replaced rather than modified in
+    // place, so size and modification time are a good enough approximation
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)?;
+    Ok(format!("{}-{}", metadata.size(), metadata.mtime()))
+}
+
+// Unmounts an image that is no longer used by anyone, along with the caches made for it
+pub fn unmount(image: image::Image) -> Result<()> {
+    supervisor::umount_opt(&image.mountpoint, system::MNT_DETACH)
+        .with_context(|| format!("Failed to unmount image at {:?}", image.mountpoint))?;
+    std::fs::remove_dir(&image.mountpoint)
+        .with_context(|| format!("Failed to remove mountpoint {:?}", image.mountpoint))?;
+    if let Some(name) = image.mountpoint.file_name() {
+        let warm = std::path::Path::new("/tmp/sunwalker_invoker/warm").join(name);
+        if warm.exists() {
+            std::fs::remove_dir_all(&warm)
+                .with_context(|| format!("Failed to remove warm caches at {warm:?}"))?;
+        }
+    }
+    Ok(())
+}
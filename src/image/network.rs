@@ -0,0 +1,393 @@
+use crate::{
+    config, errors,
+    errors::{ToError, ToMultiprocessingResult, ToResult},
+};
+use futures_util::TryStreamExt;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::AsRawFd;
+use std::process::{Command, Stdio};
+
+// By default, a sandbox has a network namespace of its own with nothing but a disabled lo in it,
+// see make_ns. Some problems need more than that, e.g. a checker that talks to a remote service.
+// With networking enabled in the sandbox config, the rootfs of each test program also gets one end
+// of a veth pair, the other end of which is plugged into a bridge on the host. The bridge is the
+// default gateway of the sandboxes, and an nftables policy decides what passes through it:
+//     - the host itself and other sandboxes are unreachable,
+//     - traffic to the allowed destinations is masqueraded and forwarded,
+//     - everything else is dropped.
+//
+// The veth pair lives as long as the rootfs, so unlike with lo, its counters are not reset between
+// tests. This leak is the price of opting in.
+
+// A file per leased address, so that workers in different processes don't hand out the same one.
+// The file contains the prefix of the rootfs the address is leased to, see gc.rs
+pub const LEASES_DIR: &str = "/tmp/sunwalker_invoker/network";
+
+struct Subnet {
+    network: u32,
+    prefix_length: u8,
+    // Not counting the network address, the bridge and the broadcast address
+    hosts: u32,
+}
+
+impl Subnet {
+    fn parse(subnet: &str) -> Result<Subnet, errors::Error> {
+        let invalid = || {
+            errors::ConfigurationFailure(format!(
+                "Invalid sandbox subnet {subnet:?}, expected IPv4 CIDR notation such as \
+                 10.87.0.0/16"
+            ))
+        };
+        let (address, prefix_length) = subnet.split_once('/').ok_or_else(invalid)?;
+        let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+        let prefix_length: u8 = prefix_length.parse().map_err(|_| invalid())?;
+        if !(1..=30).contains(&prefix_length) {
+            return Err(invalid());
+        }
+        let mask = u32::MAX << (32 - prefix_length);
+        Ok(Subnet {
+            network: u32::from(address) & mask,
+            prefix_length,
+            hosts: (1 << (32 - prefix_length)) - 3,
+        })
+    }
+
+    fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1)
+    }
+
+    fn host(&self, index: u32) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 2 + index)
+    }
+}
+
+pub struct Lease {
+    index: u32,
+}
+
+impl Lease {
+    fn acquire(subnet: &Subnet, owner: &str) -> Result<Lease, errors::Error> {
+        for index in 0..subnet.hosts {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(format!("{LEASES_DIR}/{index}"))
+            {
+                Ok(mut file) => {
+                    file.write_all(owner.as_bytes())
+                        .context_invoker("Failed to record the owner of a sandbox address")?;
+                    return Ok(Lease { index });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.context_invoker("Failed to lease a sandbox address")),
+            }
+        }
+        Err(errors::InvokerFailure(
+            "All addresses of the sandbox subnet are in use".to_string(),
+        ))
+    }
+
+    pub fn release(self) -> Result<(), errors::Error> {
+        std::fs::remove_file(format!("{LEASES_DIR}/{}", self.index))
+            .with_context_invoker(|| format!("Failed to release sandbox address #{}", self.index))
+    }
+}
+
+async fn find_link(handle: &rtnetlink::Handle, name: &str) -> Result<Option<u32>, errors::Error> {
+    let link = handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .try_next()
+        .await;
+    match link {
+        Ok(link) => Ok(link.map(|link| link.header.index)),
+        // The kernel reports a missing interface as ENODEV rather than an empty list
+        Err(rtnetlink::Error::NetlinkError(e)) if e.code == -libc::ENODEV => Ok(None),
+        Err(e) => Err(e.with_context_invoker(|| format!("Failed to find link {name}"))),
+    }
+}
+
+// Creates the bridge and installs the nftables policy. Called once at startup, in the network
+// namespace of the host
+pub async fn setup_bridge(network: &config::NetworkConfig) -> Result<(), errors::Error> {
+    let subnet = Subnet::parse(&network.subnet)?;
+    if network.bridge.is_empty() || network.bridge.len() > 15 {
+        return Err(errors::ConfigurationFailure(format!(
+            "Invalid bridge name {:?}, expected 1 to 15 characters",
+            network.bridge
+        )));
+    }
+
+    std::fs::create_dir(LEASES_DIR)
+        .with_context_invoker(|| format!("Failed to create {LEASES_DIR}"))?;
+
+    std::fs::write("/proc/sys/net/ipv4/ip_forward", "1")
+        .context_invoker("Failed to enable IPv4 forwarding")?;
+
+    let (connection, handle, _) =
+        rtnetlink::new_connection().context_invoker("Failed to connect to rtnetlink")?;
+    tokio::spawn(connection);
+
+    // The bridge may be left over from a previous run, with stale ports attached
+    if let Some(index) = find_link(&handle, &network.bridge).await? {
+        handle
+            .link()
+            .del(index)
+            .execute()
+            .await
+            .with_context_invoker(|| format!("Failed to delete stale bridge {}", network.bridge))?;
+    }
+
+    handle
+        .link()
+        .add()
+        .bridge(network.bridge.clone())
+        .execute()
+        .await
+        .with_context_invoker(|| format!("Failed to create bridge {}", network.bridge))?;
+    let index = find_link(&handle, &network.bridge)
+        .await?
+        .with_context_invoker(|| format!("Bridge {} disappeared", network.bridge))?;
+    handle
+        .address()
+        .add(index, IpAddr::V4(subnet.gateway()), subnet.prefix_length)
+        .execute()
+        .await
+        .with_context_invoker(|| format!("Failed to assign an address to {}", network.bridge))?;
+    handle
+        .link()
+        .set(index)
+        .up()
+        .execute()
+        .await
+        .with_context_invoker(|| format!("Failed to bring {} up", network.bridge))?;
+
+    apply_ruleset(&make_ruleset(network))
+}
+
+fn make_ruleset(network: &config::NetworkConfig) -> String {
+    let bridge = &network.bridge;
+    let subnet = &network.subnet;
+
+    let allow = if network.allowed_destinations.is_empty() {
+        String::new()
+    } else {
+        let destinations = network.allowed_destinations.join(", ");
+        let ports = if network.allowed_ports.is_empty() {
+            String::new()
+        } else {
+            let ports: Vec<String> = network
+                .allowed_ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect();
+            format!(
+                " meta l4proto {{ tcp, udp }} th dport {{ {} }}",
+                ports.join(", ")
+            )
+        };
+        format!("iifname \"{bridge}\" ip daddr {{ {destinations} }}{ports} accept")
+    };
+
+    // Declaring a table and deleting it right away makes the file idempotent: the deletion does not
+    // fail on the first run, and the rules of a previous run are replaced rather than duplicated.
+    //
+    // Traffic between two ports of the bridge never reaches the inet family, so it is dropped by a
+    // separate bridge table.
+    format!(
+        "table inet sunwalker
+delete table inet sunwalker
+table inet sunwalker {{
+    chain input {{
+        type filter hook input priority 0; policy accept;
+        iifname \"{bridge}\" drop
+    }}
+    chain forward {{
+        type filter hook forward priority 0; policy accept;
+        oifname \"{bridge}\" ct state established,related accept
+        oifname \"{bridge}\" drop
+        {allow}
+        iifname \"{bridge}\" drop
+    }}
+    chain postrouting {{
+        type nat hook postrouting priority 100; policy accept;
+        ip saddr {subnet} oifname != \"{bridge}\" masquerade
+    }}
+}}
+table bridge sunwalker
+delete table bridge sunwalker
+table bridge sunwalker {{
+    chain forward {{
+        type filter hook forward priority 0; policy accept;
+        meta ibrname \"{bridge}\" drop
+    }}
+}}
+"
+    )
+}
+
+fn apply_ruleset(ruleset: &str) -> Result<(), errors::Error> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context_invoker("Failed to start nft")?;
+    child
+        .stdin
+        .take()
+        .context_invoker("nft has no stdin")?
+        .write_all(ruleset.as_bytes())
+        .context_invoker("Failed to pass the ruleset to nft")?;
+    let output = child
+        .wait_with_output()
+        .context_invoker("Failed to wait for nft")?;
+    if !output.status.success() {
+        // Most likely a malformed entry in allowed_destinations
+        return Err(errors::ConfigurationFailure(format!(
+            "nft rejected the sandbox network policy: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+// Connects the network namespace saved at <prefix>/ns/net to the bridge
+pub async fn attach(prefix: &str, network: &config::NetworkConfig) -> Result<Lease, errors::Error> {
+    let subnet = Subnet::parse(&network.subnet)?;
+    let lease = Lease::acquire(&subnet, prefix)?;
+
+    // Interface names are limited to 15 characters, so the rootfs id can't be used here
+    let host_name = format!("swv{}", lease.index);
+    let peer_name = format!("swp{}", lease.index);
+
+    let (connection, handle, _) =
+        rtnetlink::new_connection().context_invoker("Failed to connect to rtnetlink")?;
+    tokio::spawn(connection);
+
+    let result: Result<(), errors::Error> = try {
+        handle
+            .link()
+            .add()
+            .veth(host_name.clone(), peer_name.clone())
+            .execute()
+            .await
+            .with_context_invoker(|| format!("Failed to create veth pair {host_name}"))?;
+
+        let netns_path = format!("{prefix}/ns/net");
+        let netns = std::fs::File::open(&netns_path)
+            .with_context_invoker(|| format!("Failed to open {netns_path}"))?;
+        let peer = find_link(&handle, &peer_name)
+            .await?
+            .with_context_invoker(|| format!("veth peer {peer_name} disappeared"))?;
+        handle
+            .link()
+            .set(peer)
+            .setns_by_fd(netns.as_raw_fd())
+            .execute()
+            .await
+            .with_context_invoker(|| format!("Failed to move {peer_name} to the sandbox"))?;
+
+        let bridge = find_link(&handle, &network.bridge)
+            .await?
+            .with_context_invoker(|| format!("Bridge {} does not exist", network.bridge))?;
+        let host = find_link(&handle, &host_name)
+            .await?
+            .with_context_invoker(|| format!("veth {host_name} disappeared"))?;
+        handle
+            .link()
+            .set(host)
+            .master(bridge)
+            .up()
+            .execute()
+            .await
+            .with_context_invoker(|| format!("Failed to attach {host_name} to the bridge"))?;
+
+        configure_guest
+            .spawn_tokio(
+                netns_path,
+                peer_name.clone(),
+                u32::from(subnet.host(lease.index)),
+                subnet.prefix_length,
+                u32::from(subnet.gateway()),
+            )
+            .await
+            .context_multiprocessing(
+                "Failed to start a subprocess in the sandbox network namespace",
+            )?
+            .join()
+            .await
+            .context_multiprocessing(
+                "Sandbox network configuration did not terminate gracefully",
+            )??;
+    };
+
+    if let Err(e) = result {
+        // Once the peer is in the sandbox, deleting the host end removes both
+        if let Ok(Some(host)) = find_link(&handle, &host_name).await {
+            let _ = handle.link().del(host).execute().await;
+        }
+        if let Err(e) = lease.release() {
+            println!("Failed to release sandbox address after unsuccessful attach: {e:?}");
+        }
+        return Err(e);
+    }
+
+    Ok(lease)
+}
+
+#[multiprocessing::entrypoint]
+#[tokio::main(flavor = "current_thread")] // setns requires a single thread
+async fn configure_guest(
+    netns_path: String,
+    peer_name: String,
+    address: u32,
+    prefix_length: u8,
+    gateway: u32,
+) -> Result<(), errors::Error> {
+    let netns = std::fs::File::open(&netns_path)
+        .with_context_invoker(|| format!("Failed to open {netns_path}"))?;
+    nix::sched::setns(netns.as_raw_fd(), nix::sched::CloneFlags::CLONE_NEWNET)
+        .with_context_invoker(|| format!("Failed to setns {netns_path}"))?;
+
+    let (connection, handle, _) =
+        rtnetlink::new_connection().context_invoker("Failed to connect to rtnetlink")?;
+    tokio::spawn(connection);
+
+    let index = find_link(&handle, &peer_name)
+        .await?
+        .with_context_invoker(|| format!("{peer_name} is missing in the sandbox"))?;
+    handle
+        .link()
+        .set(index)
+        .name("eth0".to_string())
+        .execute()
+        .await
+        .context_invoker("Failed to rename the sandbox interface to eth0")?;
+    handle
+        .address()
+        .add(index, IpAddr::V4(Ipv4Addr::from(address)), prefix_length)
+        .execute()
+        .await
+        .context_invoker("Failed to assign an address to eth0")?;
+    handle
+        .link()
+        .set(index)
+        .up()
+        .execute()
+        .await
+        .context_invoker("Failed to bring eth0 up")?;
+    handle
+        .route()
+        .add()
+        .v4()
+        .gateway(Ipv4Addr::from(gateway))
+        .execute()
+        .await
+        .context_invoker("Failed to add the default route")?;
+
+    Ok(())
+}
@@ -3,27 +3,169 @@ use crate::{
     image::{image, language},
 };
 use multiprocessing::Object;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 #[derive(Clone, Debug, Object)]
 pub struct Package {
     pub image: Arc<image::Image>,
     pub name: String,
+    // Parsed from /.sunwalker/env of the package, in order of appearance
+    pub env: Vec<(String, String)>,
 }
 
 impl Package {
     pub fn new(image: Arc<image::Image>, name: String) -> Result<Package, errors::Error> {
-        if image.has_package(name.as_ref()) {
-            Ok(Package { image, name })
-        } else {
-            Err(errors::ConfigurationFailure(format!(
+        if !image.has_package(name.as_ref()) {
+            return Err(errors::ConfigurationFailure(format!(
                 "Image {:?} does not contain package {name}",
                 image.mountpoint
-            )))
+            )));
         }
+
+        // The file is parsed here rather than in the sandbox, so that a broken package is reported
+        // as such when it is loaded instead of failing every run
+        let env_path = image.mountpoint.join(&name).join(".sunwalker/env");
+        let env = std::fs::read_to_string(&env_path).map_err(|e| {
+            errors::ConfigurationFailure(format!("Failed to read {env_path:?}: {e:?}"))
+        })?;
+        let env = parse_env(&env).map_err(|(line, message)| {
+            errors::ConfigurationFailure(format!("{env_path:?}, line {line}: {message}"))
+        })?;
+
+        Ok(Package { image, name, env })
     }
 
     pub fn get_language(&self, language_name: &str) -> Result<language::Language, errors::Error> {
         language::Language::new(self.clone(), language_name)
     }
+
+    // Where the outputs of the warm-up steps of the languages of the package are kept
+    pub fn warm_cache_path(&self) -> PathBuf {
+        let image_name = self
+            .image
+            .mountpoint
+            .file_name()
+            .map_or("image".into(), |name| name.to_string_lossy());
+        PathBuf::from(format!("/tmp/sunwalker_invoker/warm/{image_name}/{}", self.name))
+    }
+}
+
+// The format is a small subset of what shells accept, so that the file can be generated from a
+// Dockerfile or sourced for debugging:
+//     # comment
+//     NAME=value with spaces
+//     NAME='literal, no escapes'
+//     NAME="escapes: \" \\ \n \t"
+//     NAME=continued \
+//          on the next line
+// Quoted parts may span lines and be mixed with unquoted ones. Unlike in a shell, an unquoted value
+// extends to the end of the line, so old files with unquoted spaces keep working.
+fn parse_env(text: &str) -> Result<Vec<(String, String)>, (usize, String)> {
+    let mut env = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start_line = line;
+
+        // Skip blank lines and comments
+        match chars.peek() {
+            Some('\n') => {
+                chars.next();
+                line += 1;
+                continue;
+            }
+            Some('#') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut name = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some('\n') | None => {
+                    return Err((start_line, format!("'=' not found after {name:?}")));
+                }
+                Some(c) => name.push(c),
+            }
+        }
+        let valid_name = name.chars().next().map_or(false, |c| !c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err((start_line, format!("Invalid variable name {name:?}")));
+        }
+
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                None => break,
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some('\\') => match chars.next() {
+                    Some('\n') => line += 1,
+                    Some(c) => value.push(c),
+                    None => return Err((line, "Trailing backslash".to_string())),
+                },
+                Some('\'') => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            value.push(c);
+                        }
+                        None => {
+                            return Err((start_line, "Unterminated single quote".to_string()));
+                        }
+                    }
+                },
+                Some('"') => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('\n') => line += 1,
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(c @ ('"' | '\\' | '$' | '`')) => value.push(c),
+                            Some(c) => {
+                                return Err((line, format!("Unknown escape sequence \\{c}")));
+                            }
+                            None => {
+                                return Err((start_line, "Unterminated double quote".to_string()));
+                            }
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            value.push(c);
+                        }
+                        None => {
+                            return Err((start_line, "Unterminated double quote".to_string()));
+                        }
+                    }
+                },
+                Some(c) => value.push(c),
+            }
+        }
+        if value.contains('\0') {
+            return Err((start_line, format!("Value of {name} contains a null character")));
+        }
+
+        env.push((name, value));
+    }
+
+    Ok(env)
 }
@@ -1,7 +1,9 @@
 use crate::{
-    errors,
+    config, errors,
     errors::ToResult,
-    image::{image, package, sandbox},
+    image::{image, package, sandbox, seccomp},
+    problem::verdict,
+    signing,
 };
 use multiprocessing::Object;
 use serde::{Deserialize, Serialize};
@@ -14,6 +16,24 @@ pub struct Program {
     pub prerequisites: Vec<String>,
     pub argv: Vec<String>,
     pub artifacts_path: PathBuf,
+    pub syscalls: Vec<String>,
+    // The language of a submission, empty for problem-supplied programs
+    pub language: String,
+    // From the configuration of the language. The problem may override them, see StrategyFactory
+    pub limit_multipliers: verdict::LimitMultipliers,
+    // Of the diagnostic profile, if the program was built with it. These apply on top of
+    // limit_multipliers, whether the problem overrides them or not
+    pub diagnostic_multipliers: verdict::LimitMultipliers,
+    // Set for output-only submissions. The prerequisites are then the answers to the tests, named
+    // after them, and the program is never run: its output on a test is the answer
+    pub output_only: bool,
+    // Built with the diagnostic profile of the language, so sanitizer reports are collected
+    pub diagnostic: bool,
+    // Of the language; the default, i.e. no VM, for problem-supplied programs
+    pub memory_model: crate::image::config::MemoryModel,
+    // The fork server of the language, see Runtime in image/config.rs. Empty for problem-supplied
+    // programs and for languages without one
+    pub runtime: Vec<String>,
 }
 
 pub struct InvocableProgram {
@@ -26,6 +46,9 @@ pub struct CachedProgram {
     pub package: String,
     pub prerequisites: Vec<String>,
     pub argv: Vec<String>,
+    // Problem-supplied programs are trusted and run unfiltered unless they opt in
+    #[serde(default)]
+    pub syscalls: Vec<String>,
 }
 
 impl Program {
@@ -49,6 +72,7 @@ impl Program {
                 )));
             }
         }
+        seccomp::validate(&program.syscalls)?;
         Ok(Self {
             package: package::Package::new(image, program.package.clone()).map_err(|e| {
                 errors::ConfigurationFailure(format!(
@@ -60,10 +84,22 @@ impl Program {
             prerequisites: program.prerequisites,
             argv: program.argv,
             artifacts_path: path.join("artifacts"),
+            syscalls: program.syscalls,
+            language: String::new(),
+            limit_multipliers: verdict::LimitMultipliers::default(),
+            diagnostic_multipliers: verdict::LimitMultipliers::default(),
+            output_only: false,
+            diagnostic: false,
+            memory_model: crate::image::config::MemoryModel::default(),
+            runtime: Vec::new(),
         })
     }
 
-    pub async fn into_invocable(self, id: String) -> Result<InvocableProgram, errors::Error> {
+    pub async fn into_invocable(
+        self,
+        id: String,
+        limits: &config::LimitsConfig,
+    ) -> Result<InvocableProgram, errors::Error> {
         let mut bound_files = Vec::new();
         for prerequisite in &self.prerequisites {
             bound_files.push((
@@ -75,8 +111,8 @@ impl Program {
             &self.package,
             bound_files,
             sandbox::DiskQuotas {
-                space: 32 * 1024 * 1024, // TODO: make this configurable
-                max_inodes: 1024,
+                space: limits.run_space,
+                max_inodes: limits.run_inodes,
             },
             id.clone(),
         )
@@ -89,6 +125,30 @@ impl Program {
         })
     }
 
+    // The answer of an output-only submission to the test, if there is one. Answers match tests by
+    // the name without the extension, so 7, 7.out and 07.txt all answer test 7
+    pub fn answer_to(&self, test: u64) -> Option<PathBuf> {
+        self.prerequisites
+            .iter()
+            .find(|name| answer_test(name) == Some(test))
+            .map(|name| self.artifacts_path.join(name))
+    }
+
+    // Covers the names and contents of the artifacts in the order of prerequisites
+    pub fn hash(&self) -> Result<String, errors::Error> {
+        let mut data = Vec::new();
+        for prerequisite in &self.prerequisites {
+            let path = self.artifacts_path.join(prerequisite);
+            let content = std::fs::read(&path)
+                .with_context_invoker(|| format!("Failed to read program artifact at {path:?}"))?;
+            data.extend_from_slice(&(prerequisite.len() as u64).to_le_bytes());
+            data.extend_from_slice(prerequisite.as_bytes());
+            data.extend_from_slice(&(content.len() as u64).to_le_bytes());
+            data.extend_from_slice(&content);
+        }
+        Ok(signing::sha256_hex(&data))
+    }
+
     pub fn remove(self) -> Result<(), errors::Error> {
         std::fs::remove_dir_all(&self.artifacts_path).with_context_invoker(|| {
             format!(
@@ -98,3 +158,7 @@ impl Program {
         })
     }
 }
+
+pub fn answer_test(name: &str) -> Option<u64> {
+    Path::new(name).file_stem()?.to_str()?.parse().ok()
+}
@@ -0,0 +1,287 @@
+use crate::{
+    errors,
+    errors::ToResult,
+    image::{language, program},
+    problem::verdict,
+    signing,
+};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Rejudges and identical re-submissions (the same solution sent to several problems of a contest,
+// or resent after a verdict the author disagrees with) would otherwise be compiled again, which
+// often takes longer than judging. Successfully built programs are kept on disk, keyed by what the
+// build depends on: the image, the language with its dialect and flags, and the source files.
+//
+// Layout: <key>/entry.msgpack and <key>/artifacts/. Entries are written to <key>.<random>.tmp
+// first and renamed, so a half-written entry is never used.
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    program: program::CachedProgram,
+    language: String,
+    limit_multipliers: verdict::LimitMultipliers,
+    log: String,
+    // Entries written before this was stored separately have it folded into limit_multipliers
+    #[serde(default)]
+    diagnostic_multipliers: verdict::LimitMultipliers,
+}
+
+pub struct ProgramCache {
+    path: PathBuf,
+    capacity: usize,
+    last_used: Mutex<HashMap<String, Instant>>,
+}
+
+impl ProgramCache {
+    pub fn new(path: PathBuf, capacity: usize) -> anyhow::Result<ProgramCache> {
+        if !path.exists() {
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create program cache directory {path:?}"))?;
+        }
+        if !std::fs::metadata(&path)?.is_dir() {
+            bail!("Program cache directory is not a directory");
+        }
+
+        // The order of use is not persisted, so entries that survive a restart are equally old
+        let now = Instant::now();
+        let mut last_used = HashMap::new();
+        for entry in std::fs::read_dir(&path)
+            .with_context(|| format!("Failed to list program cache directory {path:?}"))?
+        {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                std::fs::remove_dir_all(entry.path()).with_context(|| {
+                    format!("Failed to remove incomplete cache entry {:?}", entry.path())
+                })?;
+            } else {
+                last_used.insert(name, now);
+            }
+        }
+
+        Ok(ProgramCache {
+            path,
+            capacity,
+            last_used: Mutex::new(last_used),
+        })
+    }
+
+    pub fn key(
+        language: &language::Language,
+        source_root: &str,
+        source_files: &[String],
+    ) -> Result<String, errors::Error> {
+        let mut sources = Vec::with_capacity(source_files.len());
+        for path in source_files {
+            let name = Path::new(path)
+                .strip_prefix(source_root)
+                .ok()
+                .and_then(|name| name.to_str())
+                .with_context_invoker(|| {
+                    format!("Source file {path} is not under {source_root}")
+                })?;
+            sources.push((name, path));
+        }
+        sources.sort();
+
+        let mut data = Vec::new();
+        let mut push = |bytes: &[u8]| {
+            data.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            data.extend_from_slice(bytes);
+        };
+        push(language.image().version.as_bytes());
+        push(language.package().name.as_bytes());
+        push(language.name().as_bytes());
+        push(language.dialect().unwrap_or("").as_bytes());
+        for flag in language.flags() {
+            push(flag.as_bytes());
+        }
+        push(&[language.diagnostic() as u8]);
+        for path in language.grader() {
+            let content = std::fs::read(path)
+                .with_context_invoker(|| format!("Failed to read grader file {path:?}"))?;
+            // Validated by Language::with_grader
+            push(path.file_name().unwrap().to_str().unwrap().as_bytes());
+            push(&content);
+        }
+        for (name, path) in sources {
+            let content = std::fs::read(path)
+                .with_context_invoker(|| format!("Failed to read source file {path}"))?;
+            push(name.as_bytes());
+            push(&content);
+        }
+        Ok(signing::sha256_hex(&data))
+    }
+
+    // Copies the artifacts of a cached program to the place a fresh build would put them
+    pub fn load(
+        &self,
+        key: &str,
+        language: &language::Language,
+        build_id: &str,
+    ) -> Result<Option<(program::Program, String)>, errors::Error> {
+        match self
+            .last_used
+            .lock()
+            .expect("Failed to acquire the program cache")
+            .get_mut(key)
+        {
+            Some(last_used) => *last_used = Instant::now(),
+            None => return Ok(None),
+        }
+
+        let entry_path = self.path.join(key);
+        let entry = std::fs::read(entry_path.join("entry.msgpack"))
+            .with_context_invoker(|| format!("Failed to read cache entry {entry_path:?}"))?;
+        let entry: Entry = rmp_serde::from_slice(&entry)
+            .with_context_invoker(|| format!("Failed to parse cache entry {entry_path:?}"))?;
+
+        let artifacts_path = PathBuf::from(format!("/tmp/sunwalker_invoker/artifacts/{build_id}"));
+        std::fs::create_dir(&artifacts_path)
+            .with_context_invoker(|| format!("Failed to create {artifacts_path:?}"))?;
+        let copied: Result<(), errors::Error> = try {
+            for prerequisite in &entry.program.prerequisites {
+                let from = entry_path.join("artifacts").join(prerequisite);
+                let to = artifacts_path.join(prerequisite);
+                if let Some(parent) = to.parent() {
+                    std::fs::create_dir_all(parent).with_context_invoker(|| {
+                        format!("Failed to create directory {parent:?}")
+                    })?;
+                }
+                std::fs::copy(&from, &to).with_context_invoker(|| {
+                    format!("Failed to copy cached artifact {from:?} to {to:?}")
+                })?;
+            }
+        };
+        if let Err(e) = copied {
+            // Leave the place free for a fresh build
+            let _ = std::fs::remove_dir_all(&artifacts_path);
+            return Err(e);
+        }
+
+        Ok(Some((
+            program::Program {
+                package: language.package().clone(),
+                prerequisites: entry.program.prerequisites,
+                argv: entry.program.argv,
+                artifacts_path,
+                syscalls: entry.program.syscalls,
+                language: entry.language,
+                limit_multipliers: entry.limit_multipliers,
+                diagnostic_multipliers: entry.diagnostic_multipliers,
+                output_only: false,
+                diagnostic: language.diagnostic(),
+                memory_model: language.memory_model().clone(),
+                runtime: language.runtime().to_vec(),
+            },
+            entry.log,
+        )))
+    }
+
+    pub fn store(
+        &self,
+        key: &str,
+        program: &program::Program,
+        log: &str,
+    ) -> Result<(), errors::Error> {
+        if self
+            .last_used
+            .lock()
+            .expect("Failed to acquire the program cache")
+            .contains_key(key)
+        {
+            return Ok(());
+        }
+
+        // Several submissions with the same key may be built at once, each writing its own copy
+        let tmp_path = self
+            .path
+            .join(format!("{key}.{}.tmp", rand::random::<u64>()));
+        let result: Result<(), errors::Error> = try {
+            let entry = Entry {
+                program: program::CachedProgram {
+                    package: program.package.name.clone(),
+                    prerequisites: program.prerequisites.clone(),
+                    argv: program.argv.clone(),
+                    syscalls: program.syscalls.clone(),
+                },
+                language: program.language.clone(),
+                limit_multipliers: program.limit_multipliers,
+                log: log.to_string(),
+                diagnostic_multipliers: program.diagnostic_multipliers,
+            };
+            std::fs::create_dir_all(tmp_path.join("artifacts"))
+                .with_context_invoker(|| format!("Failed to create {tmp_path:?}"))?;
+            for prerequisite in &program.prerequisites {
+                let from = program.artifacts_path.join(prerequisite);
+                let to = tmp_path.join("artifacts").join(prerequisite);
+                if let Some(parent) = to.parent() {
+                    std::fs::create_dir_all(parent).with_context_invoker(|| {
+                        format!("Failed to create directory {parent:?}")
+                    })?;
+                }
+                std::fs::copy(&from, &to).with_context_invoker(|| {
+                    format!("Failed to copy artifact {from:?} to {to:?}")
+                })?;
+            }
+            let entry =
+                rmp_serde::to_vec(&entry).context_invoker("Failed to serialize the cache entry")?;
+            std::fs::write(tmp_path.join("entry.msgpack"), entry)
+                .with_context_invoker(|| format!("Failed to write cache entry {tmp_path:?}"))?;
+            std::fs::rename(&tmp_path, self.path.join(key))
+                .with_context_invoker(|| format!("Failed to move cache entry {tmp_path:?}"))?;
+        };
+        if result.is_err() {
+            // Most likely, another copy has been renamed into place first
+            let _ = std::fs::remove_dir_all(&tmp_path);
+            if self.path.join(key).exists() {
+                return Ok(());
+            }
+            return result;
+        }
+
+        let mut last_used = self
+            .last_used
+            .lock()
+            .expect("Failed to acquire the program cache");
+        last_used.insert(key.to_string(), Instant::now());
+        self.evict(&mut last_used)
+    }
+
+    // Removes an entry that turned out to be broken, so that the program is built anew next time
+    pub fn forget(&self, key: &str) -> Result<(), errors::Error> {
+        self.last_used
+            .lock()
+            .expect("Failed to acquire the program cache")
+            .remove(key);
+        let entry_path = self.path.join(key);
+        if entry_path.exists() {
+            std::fs::remove_dir_all(&entry_path)
+                .with_context_invoker(|| format!("Failed to remove cache entry {entry_path:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn evict(&self, last_used: &mut HashMap<String, Instant>) -> Result<(), errors::Error> {
+        if last_used.len() <= self.capacity {
+            return Ok(());
+        }
+        let mut by_last_use: Vec<(Instant, String)> = last_used
+            .iter()
+            .map(|(key, instant)| (*instant, key.clone()))
+            .collect();
+        by_last_use.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, key) in by_last_use.into_iter().skip(self.capacity) {
+            last_used.remove(&key);
+            let entry_path = self.path.join(&key);
+            std::fs::remove_dir_all(&entry_path)
+                .with_context_invoker(|| format!("Failed to remove cache entry {entry_path:?}"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+use crate::{
+    cgroups, config, errors,
+    errors::ToResult,
+    image::{args, program, sandbox, seccomp},
+    problem::verdict,
+};
+use multiprocessing::Bind;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// How long loading the program may take. A runtime that takes longer is given up on
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// How long the runtime may take to report the status of a copy that has terminated or was killed
+const STATUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How long the runtime may take to fork a copy once asked to
+const FORK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// The runtime talks to the invoker and forks the copies under the syscall filter of the language,
+// see Runtime in image/config.rs, so these are allowed on top of it. The copies inherit them along
+// with the rest of the filter
+const PROTOCOL_SYSCALLS: &[&str] = &[
+    "read",
+    "write",
+    "recvmsg",
+    "sendmsg",
+    "close",
+    "dup3",
+    "clone",
+    "wait4",
+    "waitid",
+    "exit_group",
+    #[cfg(target_arch = "x86_64")]
+    "dup2",
+    #[cfg(target_arch = "x86_64")]
+    "fork",
+];
+
+// A fork server started once per worker and block, see Runtime in image/config.rs
+pub struct Runtime {
+    // The rootfs is the runtime's own, so that the rootfs of the block is reset between tests as
+    // usual, while /space of the runtime keeps the files the program was loaded from
+    rootfs: Arc<sandbox::RootFS>,
+    control: UnixStream,
+    // The runtime lives in <cgroup>/runtime and the copies in <cgroup>/copy, so that the memory the
+    // runtime has loaded the program into is not counted towards the limit of the test. Pages a
+    // copy shares with the runtime stay charged to the runtime, so only what the copy allocates or
+    // modifies counts
+    cgroup: String,
+    // The runtime process itself, as seen by the invoker. Everything else in the cgroups is a copy
+    // of the program or was started by one
+    pid: libc::pid_t,
+    // What /space contained when the runtime got ready. Anything else is removed between tests
+    pristine: HashMap<PathBuf, EntryState>,
+    terminated: Arc<AtomicBool>,
+    // Runs the sandbox of the runtime, and finishes once the runtime is dead
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+// The runtime may have loaded the program lazily, so a file it started with that a test modifies
+// would leak into later tests. Such changes can't be undone, so they are detected instead. ctime
+// catches a write even if the test restores the mtime. Directories only change by having entries
+// added or removed, which is handled separately
+#[derive(PartialEq, Eq)]
+struct EntryState {
+    ino: u64,
+    mode: u32,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl EntryState {
+    fn of(metadata: &std::fs::Metadata) -> Self {
+        if metadata.is_dir() {
+            EntryState {
+                ino: metadata.ino(),
+                mode: metadata.mode(),
+                size: 0,
+                mtime: (0, 0),
+                ctime: (0, 0),
+            }
+        } else {
+            EntryState {
+                ino: metadata.ino(),
+                mode: metadata.mode(),
+                size: metadata.size(),
+                mtime: (metadata.mtime(), metadata.mtime_nsec()),
+                ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            }
+        }
+    }
+}
+
+// The invoker's end of the runtime for a single test
+pub struct Connection {
+    control: UnixStream,
+    cgroup: String,
+    pid: libc::pid_t,
+    terminated: Arc<AtomicBool>,
+}
+
+impl Runtime {
+    // argv is what the program would be started with without a runtime. Fails if the runtime does
+    // not get ready, e.g. because it could not load the program
+    pub async fn start(
+        program: &program::Program,
+        argv: Vec<String>,
+        invocation_limit: &verdict::InvocationLimit,
+        id: String,
+        core: u64,
+        sandbox_config: &config::SandboxConfig,
+    ) -> Result<Runtime, errors::Error> {
+        let rootfs = program
+            .clone()
+            .into_invocable(id.clone(), &sandbox_config.limits)
+            .await?
+            .rootfs;
+        rootfs
+            .reset()
+            .context_invoker("Failed to reset rootfs for the runtime")?;
+        let rootfs = Arc::new(rootfs);
+
+        let cgroup = format!("/sys/fs/cgroup/sunwalker_root/cpu_{core}/{id}");
+        create_cgroup(&cgroup)?;
+        cgroups::set_cpu_weight(&cgroup, cgroups::TEST_CPU_WEIGHT)?;
+        cgroups::set_max_pids(&cgroup, sandbox_config.max_pids)?;
+        cgroups::set_io_limits(
+            &cgroup,
+            sandbox_config.io_read_bps,
+            sandbox_config.io_write_bps,
+        )?;
+        std::fs::write(format!("{cgroup}/cgroup.subtree_control"), "+io +memory\n")
+            .with_context_invoker(|| {
+                format!("Failed to write to {cgroup}/cgroup.subtree_control")
+            })?;
+        create_cgroup(&format!("{cgroup}/runtime"))?;
+        create_cgroup(&format!("{cgroup}/copy"))?;
+        cgroups::configure_memory(&format!("{cgroup}/copy"))?;
+        let cgroup_procs = std::fs::File::options()
+            .write(true)
+            .open(format!("{cgroup}/runtime/cgroup.procs"))
+            .context_invoker("Failed to open cgroup.procs of the runtime")?;
+
+        let (control, theirs) =
+            UnixStream::pair().context_invoker("Failed to create a socket pair")?;
+        let argv = program
+            .runtime
+            .iter()
+            .cloned()
+            .chain(argv)
+            .collect::<Vec<String>>();
+        let entry = Box::new(
+            serve
+                .bind(argv)
+                .bind(runtime_syscalls(&program.syscalls))
+                .bind(std::fs::File::from(OwnedFd::from(theirs)))
+                .bind(cgroup_procs)
+                .bind(invocation_limit.output_size)
+                .bind(invocation_limit.thp),
+        );
+        let terminated = Arc::new(AtomicBool::new(false));
+        let task = {
+            let rootfs = rootfs.clone();
+            let terminated = terminated.clone();
+            tokio::spawn(async move {
+                let result = sandbox::run_isolated(entry, &rootfs, None).await;
+                // Runtimes that are dropped are killed, which is not worth reporting
+                if !terminated.swap(true, Ordering::Relaxed) {
+                    errors::log_throttled(
+                        "runtime terminated",
+                        format!("Runtime {} terminated unexpectedly: {result:?}", rootfs.id),
+                    );
+                }
+            })
+        };
+
+        // From now on, dropping the runtime kills it
+        let mut runtime = Runtime {
+            rootfs,
+            control,
+            cgroup,
+            pid: 0,
+            pristine: HashMap::new(),
+            terminated,
+            task: Some(task),
+        };
+
+        let mut control = runtime
+            .control
+            .try_clone()
+            .context_invoker("Failed to dup(2) the control socket")?;
+        let ready = tokio::task::spawn_blocking(move || -> Result<_, errors::Error> {
+            control
+                .set_read_timeout(Some(STARTUP_TIMEOUT))
+                .context_invoker("Failed to set a timeout on the control socket")?;
+            read_line(&mut control).context_invoker("Failed to wait for the runtime to get ready")
+        })
+        .await
+        .context_invoker("Failed to wait for the runtime to get ready")??;
+        match ready.as_deref() {
+            Some("ready") => {}
+            Some(line) => {
+                return Err(errors::ConfigurationFailure(format!(
+                    "The runtime wrote {line:?} instead of getting ready"
+                )));
+            }
+            None => {
+                return Err(errors::ConfigurationFailure(
+                    "The runtime terminated before getting ready".to_string(),
+                ));
+            }
+        }
+
+        let procs = std::fs::read_to_string(format!("{}/runtime/cgroup.procs", runtime.cgroup))
+            .context_invoker("Failed to read cgroup.procs of the runtime")?;
+        let pids: Vec<&str> = procs.lines().collect();
+        runtime.pid = match pids[..] {
+            [pid] => pid
+                .parse()
+                .with_context_invoker(|| format!("Invalid PID {pid:?} in cgroup.procs"))?,
+            _ => {
+                return Err(errors::ConfigurationFailure(format!(
+                    "The runtime must be a single process when it gets ready, but {} processes \
+                     were found",
+                    pids.len()
+                )));
+            }
+        };
+
+        let space = runtime.space();
+        list_tree(&space, &mut runtime.pristine)?;
+
+        Ok(runtime)
+    }
+
+    pub fn has_terminated(&self) -> bool {
+        self.terminated.load(Ordering::Relaxed)
+    }
+
+    // The cgroup the copies run in, which is where they are measured and limited
+    pub fn copies_cgroup(&self) -> String {
+        format!("{}/copy", self.cgroup)
+    }
+
+    // Brings the runtime back to the state it was in when it got ready: the copies left over from
+    // the previous test are killed, and the files they made are removed. Returns false if that is
+    // not enough because the files the runtime started with were modified or removed, in which
+    // case the runtime has to be started again
+    pub fn reset(&self) -> Result<bool, errors::Error> {
+        cgroups::kill_processes(&self.cgroup, self.pid)?;
+        let mut n_pristine = 0;
+        if !remove_new_entries(&self.space(), &self.pristine, &mut n_pristine)?
+            || n_pristine != self.pristine.len()
+        {
+            return Ok(false);
+        }
+
+        let mqueue = format!("{}/dev/mqueue", self.rootfs.overlay());
+        for entry in std::fs::read_dir(&mqueue)
+            .with_context_invoker(|| format!("Failed to list {mqueue}"))?
+        {
+            let entry = entry.with_context_invoker(|| format!("Failed to list {mqueue}"))?;
+            std::fs::remove_file(entry.path())
+                .with_context_invoker(|| format!("Failed to delete {:?}", entry.path()))?;
+        }
+        Ok(true)
+    }
+
+    // Kills the runtime and waits until its sandbox is torn down, so that a new runtime can be
+    // started with the same ID
+    pub async fn stop(mut self) {
+        self.terminated.store(true, Ordering::Relaxed);
+        if let Err(e) = cgroups::kill_processes(&self.cgroup, 0) {
+            errors::log_throttled(
+                "kill runtime",
+                format!("Failed to kill runtime {}: {e:?}", self.rootfs.id),
+            );
+            return;
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    pub fn connect(&self) -> Result<Connection, errors::Error> {
+        Ok(Connection {
+            control: self
+                .control
+                .try_clone()
+                .context_invoker("Failed to dup(2) the control socket")?,
+            cgroup: self.cgroup.clone(),
+            pid: self.pid,
+            terminated: self.terminated.clone(),
+        })
+    }
+
+    fn space(&self) -> PathBuf {
+        PathBuf::from(format!("{}/space", self.rootfs.overlay()))
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        self.terminated.store(true, Ordering::Relaxed);
+        // No process is listed as PID 0, so nothing is spared
+        if let Err(e) = cgroups::kill_processes(&self.cgroup, 0) {
+            errors::log_throttled(
+                "kill runtime",
+                format!("Failed to kill runtime {} in drop(): {e:?}", self.rootfs.id),
+            );
+            return;
+        }
+        for dir in [
+            format!("{}/copy", self.cgroup),
+            format!("{}/runtime", self.cgroup),
+            self.cgroup.clone(),
+        ] {
+            if let Err(e) = std::fs::remove_dir(&dir) {
+                errors::log_throttled(
+                    "kill runtime",
+                    format!("Failed to remove cgroup {dir} in drop(): {e:?}"),
+                );
+                return;
+            }
+        }
+    }
+}
+
+impl Connection {
+    // Asks the runtime for a copy of the program with the given standard streams. A child is forked
+    // into the cgroup of its parent, so the runtime is moved to the cgroup of the copies until the
+    // copy shows up there
+    pub fn fork(&self, streams: [&std::fs::File; 3]) -> Result<(), errors::Error> {
+        use nix::sys::socket::*;
+        self.move_runtime_to("copy")?;
+        let fds = streams.map(|stream| stream.as_raw_fd());
+        let result = sendmsg::<UnixAddr>(
+            self.control.as_raw_fd(),
+            &[std::io::IoSlice::new(b"\n")],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .context_invoker("Failed to send a request to the runtime")
+        .and_then(|_| self.wait_for_copy());
+        if result.is_err() {
+            // The runtime is in an unknown state and may have been left in the wrong cgroup
+            self.terminated.store(true, Ordering::Relaxed);
+        }
+        result?;
+        self.move_runtime_to("runtime")
+    }
+
+    fn move_runtime_to(&self, name: &str) -> Result<(), errors::Error> {
+        std::fs::write(
+            format!("{}/{name}/cgroup.procs", self.cgroup),
+            format!("{}\n", self.pid),
+        )
+        .with_context_invoker(|| format!("Failed to move the runtime to cgroup {name}"))
+    }
+
+    // Returns once the copy is in its cgroup, or has already terminated, in which case the runtime
+    // is about to report its status
+    fn wait_for_copy(&self) -> Result<(), errors::Error> {
+        use nix::poll::*;
+        let copies = format!("{}/copy/cgroup.procs", self.cgroup);
+        let runtime_pid = self.pid.to_string();
+        let start = std::time::Instant::now();
+        loop {
+            let procs = std::fs::read_to_string(&copies)
+                .with_context_invoker(|| format!("Failed to read {copies}"))?;
+            if procs.lines().any(|pid| pid != runtime_pid) {
+                return Ok(());
+            }
+            let mut fds = [PollFd::new(self.control.as_raw_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, 1).context_invoker("Failed to poll the control socket")? > 0 {
+                return Ok(());
+            }
+            if start.elapsed() > FORK_TIMEOUT {
+                return Err(errors::ConfigurationFailure(
+                    "The runtime did not fork a copy of the program in time".to_string(),
+                ));
+            }
+        }
+    }
+
+    // Becomes readable when the copy terminates or the runtime dies
+    pub fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.control.as_raw_fd()
+    }
+
+    // The wait status of the copy, or None if the runtime has died
+    pub fn read_status(&mut self) -> Result<Option<i32>, errors::Error> {
+        self.control
+            .set_read_timeout(Some(STATUS_TIMEOUT))
+            .context_invoker("Failed to set a timeout on the control socket")?;
+        let line = match read_line(&mut self.control) {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                self.terminated.store(true, Ordering::Relaxed);
+                return Ok(None);
+            }
+            Err(e) => {
+                // The protocol is out of sync, so the runtime is of no more use
+                self.terminated.store(true, Ordering::Relaxed);
+                return Err(e.context_invoker("Failed to read the status of the copy"));
+            }
+        };
+        line.parse().map(Some).map_err(|_| {
+            self.terminated.store(true, Ordering::Relaxed);
+            errors::ConfigurationFailure(format!(
+                "The runtime reported {line:?} instead of a wait status"
+            ))
+        })
+    }
+
+    // Kills the copy and whatever it has started, but not the runtime
+    pub fn kill_copies(&self) -> Result<(), errors::Error> {
+        cgroups::kill_processes(&self.cgroup, self.pid)
+    }
+}
+
+// An empty allow-list means no filter at all, which stays so
+fn runtime_syscalls(syscalls: &[String]) -> Vec<String> {
+    let mut syscalls = syscalls.to_vec();
+    if !syscalls.is_empty() {
+        for name in PROTOCOL_SYSCALLS {
+            if !syscalls.iter().any(|syscall| syscall == name) {
+                syscalls.push(name.to_string());
+            }
+        }
+    }
+    syscalls
+}
+
+fn create_cgroup(dir: &str) -> Result<(), errors::Error> {
+    std::fs::create_dir(dir)
+        .or_else(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })
+        .with_context_invoker(|| format!("Unable to create {dir} directory"))
+}
+
+fn read_line(control: &mut UnixStream) -> Result<Option<String>, std::io::Error> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        if control.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'\n' {
+            return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+        }
+        // Nothing the runtime says is long
+        if line.len() >= 64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The line is too long",
+            ));
+        }
+        line.push(byte[0]);
+    }
+}
+
+fn list_tree(dir: &Path, entries: &mut HashMap<PathBuf, EntryState>) -> Result<(), errors::Error> {
+    for entry in
+        std::fs::read_dir(dir).with_context_invoker(|| format!("Failed to list {dir:?}"))?
+    {
+        let entry = entry.with_context_invoker(|| format!("Failed to list {dir:?}"))?;
+        // Not followed if it is a symlink
+        let metadata = entry
+            .metadata()
+            .with_context_invoker(|| format!("Failed to stat {:?}", entry.path()))?;
+        if metadata.is_dir() {
+            list_tree(&entry.path(), entries)?;
+        }
+        entries.insert(entry.path(), EntryState::of(&metadata));
+    }
+    Ok(())
+}
+
+// Returns whether the pristine entries that are still there are unchanged, and counts them
+fn remove_new_entries(
+    dir: &Path,
+    pristine: &HashMap<PathBuf, EntryState>,
+    n_pristine: &mut usize,
+) -> Result<bool, errors::Error> {
+    let mut unchanged = true;
+    for entry in
+        std::fs::read_dir(dir).with_context_invoker(|| format!("Failed to list {dir:?}"))?
+    {
+        let entry = entry.with_context_invoker(|| format!("Failed to list {dir:?}"))?;
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .with_context_invoker(|| format!("Failed to stat {path:?}"))?;
+        let file_type = metadata.file_type();
+        if let Some(state) = pristine.get(&path) {
+            *n_pristine += 1;
+            if *state != EntryState::of(&metadata) {
+                unchanged = false;
+            }
+            if file_type.is_dir() {
+                unchanged &= remove_new_entries(&path, pristine, n_pristine)?;
+            }
+        } else if file_type.is_dir() {
+            std::fs::remove_dir_all(&path)
+                .with_context_invoker(|| format!("Failed to remove {path:?}"))?;
+        } else {
+            std::fs::remove_file(&path)
+                .with_context_invoker(|| format!("Failed to remove {path:?}"))?;
+        }
+    }
+    Ok(unchanged)
+}
+
+#[multiprocessing::entrypoint]
+fn serve(
+    argv: Vec<String>,
+    syscalls: Vec<String>,
+    control: std::fs::File,
+    mut cgroup_procs: std::fs::File,
+    output_size: Option<u64>,
+    thp: verdict::ThpPolicy,
+) -> Result<(), errors::Error> {
+    // The invoker moves the runtime to the cgroup of the copies when it asks for one, see
+    // Connection::fork
+    cgroup_procs
+        .write(b"0\n")
+        .context_invoker("Failed to move the runtime to its cgroup")?;
+    drop(cgroup_procs);
+
+    sandbox::drop_privileges().context_invoker("Failed to drop privileges")?;
+
+    std::env::set_current_dir("/space").context_invoker("Failed to chdir to /space")?;
+
+    // Both are inherited by the copies. The limits of a block don't change between tests, except
+    // for time, which is enforced by the invoker anyway
+    if thp == verdict::ThpPolicy::Disabled
+        && unsafe { libc::prctl(libc::PR_SET_THP_DISABLE, 1, 0, 0, 0) } == -1
+    {
+        Err(std::io::Error::last_os_error()).context_invoker("Failed to disable THP")?;
+    }
+    if let Some(output_size) = output_size {
+        let limit = libc::rlimit {
+            rlim_cur: output_size,
+            rlim_max: output_size,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_FSIZE, &limit) } == -1 {
+            Err(std::io::Error::last_os_error()).context_invoker("Failed to set RLIMIT_FSIZE")?;
+        }
+    }
+
+    // The runtime talks to the invoker over descriptor 3 only, and its own output goes nowhere.
+    // The socket is moved out of the way of the descriptors that are about to be replaced first
+    let control = nix::fcntl::fcntl(
+        control.as_raw_fd(),
+        nix::fcntl::FcntlArg::F_DUPFD_CLOEXEC(10),
+    )
+    .context_invoker("Failed to dup(2) the control socket")?;
+    let null = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context_invoker("Failed to open /dev/null")?;
+    for fd in [
+        nix::libc::STDIN_FILENO,
+        nix::libc::STDOUT_FILENO,
+        nix::libc::STDERR_FILENO,
+    ] {
+        nix::unistd::dup2(null.as_raw_fd(), fd).context_invoker("dup2 for a stream failed")?;
+    }
+    nix::unistd::dup2(control, 3).context_invoker("dup2 for the control socket failed")?;
+
+    let env: Vec<(String, String)> = std::env::vars().collect();
+    args::check(&argv, &env)?;
+
+    let mut args = Vec::with_capacity(argv.len());
+    for arg in argv {
+        args.push(
+            CString::new(arg.into_bytes()).context_invoker("Argument contains null character")?,
+        );
+    }
+
+    seccomp::apply(&syscalls)?;
+
+    nix::unistd::execv(&args[0], &args).context_invoker("execve failed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_filter_keeps_the_protocol_working() {
+        let language: Vec<String> = ["read", "write", "mmap", "exit_group"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(!seccomp::allows(&language, libc::SYS_recvmsg));
+
+        let syscalls = runtime_syscalls(&language);
+        for nr in [
+            libc::SYS_recvmsg,
+            libc::SYS_sendmsg,
+            libc::SYS_clone,
+            libc::SYS_wait4,
+            libc::SYS_mmap,
+        ] {
+            assert!(seccomp::allows(&syscalls, nr), "syscall {nr} is denied");
+        }
+        assert!(!seccomp::allows(&syscalls, libc::SYS_ptrace));
+        assert_eq!(syscalls.iter().filter(|name| *name == "read").count(), 1);
+    }
+
+    #[test]
+    fn no_filter_stays_no_filter() {
+        assert!(runtime_syscalls(&[]).is_empty());
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
-    cgroups, errors,
-    errors::{ToError, ToResult},
-    image::{ids, package},
-    system,
+    cgroups, config, errors,
+    errors::{ToError, ToMultiprocessingResult, ToResult},
+    image::{ids, network, package},
+    supervisor, system,
 };
 use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
 use libc::{
     c_char, CLONE_NEWIPC, CLONE_NEWNET, CLONE_NEWNS, CLONE_NEWPID, CLONE_NEWUSER, CLONE_NEWUTS,
     CLONE_SYSVSEM,
@@ -15,8 +16,10 @@ use std::os::unix::{
     fs::{MetadataExt, PermissionsExt},
     io::AsRawFd,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
+#[derive(Clone, Copy)]
 pub struct DiskQuotas {
     pub space: u64,
     pub max_inodes: u64,
@@ -25,33 +28,153 @@ pub struct DiskQuotas {
 pub struct RootFS {
     removed: bool,
     pub id: String,
+    // The lowerdir of the overlay, i.e. the package the rootfs is made of
+    lowerdir: String,
+    // The environment of the package, set inside the sandbox
+    env: Vec<(String, String)>,
     bound_files: Vec<(PathBuf, String)>,
     quotas: DiskQuotas,
+    // Only set if the sandbox is connected to the network
+    network_lease: Option<network::Lease>,
+    // Set if the rootfs must not be reused, e.g. because a test run in it failed an integrity check
+    tainted: bool,
+    backend: config::RootfsBackend,
+    // With the bind backend, the entries of the package mounted into the root
+    root_binds: Vec<PathBuf>,
+    // The external IDs of the sandboxed user, fixed by the user namespace. Released when the rootfs
+    // is removed
+    user_ids: Option<ids::Lease>,
 }
 
-// Unmount everything beneath prefix recursively. Does not unmount prefix itself.
-fn unmount_recursively(prefix: &str, inclusive: bool) -> Result<(), errors::Error> {
-    let prefix_slash = format!("{prefix}/");
+// Making a rootfs takes a dozen mounts and a subprocess to create the namespaces, which is a
+// noticeable share of the time spent on a short test, and compilation, each revision comparison and
+// each replaced worker make new ones. So rootfs's that are no longer needed are not torn down but
+// vacated, checked for stray mounts, and kept in a pool to be handed out again for the same package.
+// The next user resets the rootfs before running anything in it, exactly as it happens between two
+// tests.
+//
+// The pool is per-process: the mounts of a rootfs belong to the supervisor connection of the
+// process that made it. Workers drain their pools when they are finalized.
+const POOL_CAPACITY: usize = 8;
+
+// PID namespaces are made ahead of time, see run_isolated. Also per-process
+const PID_NAMESPACE_POOL_CAPACITY: usize = 4;
+
+lazy_static! {
+    static ref POOL: Mutex<Vec<RootFS>> = Mutex::new(Vec::new());
+    static ref PID_NAMESPACES: Mutex<Vec<PidNamespace>> = Mutex::new(Vec::new());
+    // Set once per process, before any rootfs is made
+    static ref BACKEND: Mutex<config::RootfsBackend> = Mutex::new(config::RootfsBackend::Overlay);
+}
+
+// Entries of the root that are mounted over by the invoker, so they are not taken from the package
+const ROOT_MOUNTPOINTS: [&str; 4] = ["space", "dev", "proc", "warm"];
 
-    let file = std::fs::File::open("/proc/self/mounts")
-        .context_invoker("Failed to open /proc/self/mounts for reading")?;
+pub fn set_backend(backend: config::RootfsBackend) {
+    *BACKEND.lock().expect("Failed to acquire the rootfs backend") = backend;
+}
 
-    let mut vec = Vec::new();
-    for line in std::io::BufReader::new(file).lines() {
-        let line = line.context_invoker("Failed to read /proc/self/mounts")?;
-        let mut it = line.split(" ");
-        it.next()
-            .context_invoker("Invalid format of /proc/self/mounts")?;
-        let target_path = it
-            .next()
-            .context_invoker("Invalid format of /proc/self/mounts")?;
-        if target_path.starts_with(&prefix_slash) || (inclusive && target_path == prefix) {
-            vec.push(target_path.to_string());
+// Tries to mount an overlay the way make_rootfs does, i.e. with the image as a lowerdir
+pub fn detect_backend(image_mountpoint: &std::path::Path) -> config::RootfsBackend {
+    let probe = "/tmp/sunwalker_invoker/aux/overlay-probe";
+    let res: Result<(), errors::Error> = try {
+        std::fs::create_dir_all(format!("{probe}/lower"))
+            .with_context_invoker(|| format!("Failed to create {probe}/lower"))?;
+        std::fs::create_dir_all(format!("{probe}/root"))
+            .with_context_invoker(|| format!("Failed to create {probe}/root"))?;
+        supervisor::mount(
+            "overlay",
+            format!("{probe}/root"),
+            "overlay",
+            0,
+            Some(&format!(
+                "lowerdir={}:{probe}/lower",
+                image_mountpoint.display()
+            )),
+        )
+        .context_invoker("Failed to mount overlay")?;
+        supervisor::umount(format!("{probe}/root"))
+            .context_invoker("Failed to unmount overlay")?;
+    };
+    if let Err(e) = std::fs::remove_dir_all(probe) {
+        println!("Failed to remove {probe}: {e:?}");
+    }
+    match res {
+        Ok(()) => config::RootfsBackend::Overlay,
+        Err(e) => {
+            println!("Overlayfs is unavailable, falling back to bind-mounted rootfs: {e:?}");
+            config::RootfsBackend::Bind
         }
     }
+}
 
-    for path in vec.into_iter().rev() {
-        system::umount(&path).with_context_invoker(|| format!("Failed to unmount {path}"))?;
+// Assembles a read-only root from the entries of the package without overlayfs. Returns the
+// mountpoints.
+fn mount_bind_root(lowerdir: &str, root: &str) -> Result<Vec<PathBuf>, errors::Error> {
+    supervisor::mount("none", root, "tmpfs", 0, Some("mode=755"))
+        .with_context_invoker(|| format!("Failed to mount tmpfs on {root}"))?;
+
+    let mut binds = Vec::new();
+    for entry in std::fs::read_dir(lowerdir)
+        .with_context_invoker(|| format!("Failed to list package at {lowerdir}"))?
+    {
+        let entry =
+            entry.with_context_invoker(|| format!("Failed to list package at {lowerdir}"))?;
+        let name = entry.file_name();
+        if ROOT_MOUNTPOINTS.iter().any(|mountpoint| name == *mountpoint) {
+            continue;
+        }
+        let source = entry.path();
+        let target = PathBuf::from(root).join(&name);
+        let file_type = entry
+            .file_type()
+            .with_context_invoker(|| format!("Failed to stat {source:?}"))?;
+        if file_type.is_symlink() {
+            // Symlinks such as /bin -> usr/bin can't be bind-mounted, but can be copied
+            let link = std::fs::read_link(&source)
+                .with_context_invoker(|| format!("Failed to readlink {source:?}"))?;
+            std::os::unix::fs::symlink(&link, &target)
+                .with_context_invoker(|| format!("Failed to create symlink {target:?}"))?;
+            continue;
+        }
+        if file_type.is_dir() {
+            std::fs::create_dir(&target)
+                .with_context_invoker(|| format!("Failed to create {target:?}"))?;
+        } else {
+            std::fs::write(&target, "")
+                .with_context_invoker(|| format!("Failed to create {target:?}"))?;
+        }
+        // The image is a squashfs, so the bind mounts are read-only anyway
+        supervisor::bind_mount(&source, &target)
+            .with_context_invoker(|| format!("Failed to bind-mount {source:?} to {target:?}"))?;
+        binds.push(target);
+    }
+
+    for mountpoint in ROOT_MOUNTPOINTS {
+        let path = format!("{root}/{mountpoint}");
+        std::fs::create_dir(&path).with_context_invoker(|| format!("Failed to create {path}"))?;
+    }
+
+    // Without an overlay upperdir, the root is read-only in the overlay backend too
+    supervisor::mount(
+        "none",
+        root,
+        "tmpfs",
+        system::MS_REMOUNT | system::MS_RDONLY,
+        None,
+    )
+    .with_context_invoker(|| format!("Failed to remount {root} read-only"))?;
+
+    Ok(binds)
+}
+
+// Unmount everything beneath prefix recursively. Does not unmount prefix itself unless inclusive is
+// set.
+pub fn unmount_recursively(prefix: &str, inclusive: bool) -> Result<(), errors::Error> {
+    let mounts = system::get_mountinfo().context_invoker("Failed to read /proc/self/mountinfo")?;
+
+    for path in system::mounts_to_unmount(&mounts, prefix.as_ref(), inclusive) {
+        supervisor::umount(&path).with_context_invoker(|| format!("Failed to unmount {path:?}"))?;
     }
 
     Ok(())
@@ -67,12 +190,52 @@ pub fn enter_worker_space(core: u64) -> Result<(), errors::Error> {
     Ok(())
 }
 
+// Moves the worker to the low-priority cgroup of the core, so that the processes it spawns for
+// compilation don't steal CPU time from tests if the core is shared. Call enter_worker_space to
+// switch back.
+pub fn enter_compilation_space(core: u64) -> Result<(), errors::Error> {
+    let pid = unsafe { libc::getpid() };
+    cgroups::move_process_to_cgroup(pid, format!("cpu_{core}/compilation/worker"))
+        .with_context_invoker(|| {
+            format!(
+                "Failed to move current process (PID {pid}) to compilation cgroup of core {core}"
+            )
+        })?;
+
+    Ok(())
+}
+
 pub async fn make_rootfs(
     package: &package::Package,
     bound_files: Vec<(PathBuf, String)>,
     quotas: DiskQuotas,
     id: String,
 ) -> Result<RootFS, errors::Error> {
+    let lowerdir = format!(
+        "{}/{}",
+        package
+            .image
+            .mountpoint
+            .to_str()
+            .context_invoker("Mountpoint must be a string")?,
+        package.name
+    );
+
+    {
+        let mut pool = POOL.lock().expect("Failed to acquire the rootfs pool");
+        if let Some(index) = pool.iter().position(|rootfs| rootfs.lowerdir == lowerdir) {
+            let mut rootfs = pool.swap_remove(index);
+            rootfs.env = package.env.clone();
+            rootfs.bound_files = bound_files;
+            rootfs.quotas = quotas;
+            return Ok(rootfs);
+        }
+        // A pooled rootfs keeps the ID it was made with, which the caller may want to use again
+        if let Some(index) = pool.iter().position(|rootfs| rootfs.id == id) {
+            pool.swap_remove(index).remove()?;
+        }
+    }
+
     // There are two (obvious) ways to mount an image in a writable way.
     //
     // First, we can mount a tmpfs that would store the ephemeral data, and then mount an overlayfs
@@ -99,80 +262,112 @@ pub async fn make_rootfs(
     // /space and /dev are in the *second* lowerdir, so that the tmpfs doesn't have to handle all
     // the accesses to the permanent files just to return ENOENT.
 
+    //
+    // Where overlayfs is unavailable, the root is a read-only tmpfs with the entries of the image
+    // bind-mounted into it and empty directories for /space and /dev. This takes more mounts, but
+    // the rootfs is reset the same way.
+
     let prefix = format!("/tmp/sunwalker_invoker/rootfs/{id}");
+    let backend = *BACKEND.lock().expect("Failed to acquire the rootfs backend");
+    let mut root_binds = Vec::new();
 
     std::fs::create_dir(&prefix).context_invoker("Failed to create directory <prefix>")?;
 
+    let user_ids = match ids::Lease::acquire(&prefix) {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            if let Err(e) = std::fs::remove_dir(&prefix) {
+                println!("Failed to remove {prefix} after unsuccessful initialization: {e:?}");
+            }
+            return Err(e);
+        }
+    };
+
     if let Err(e) = try {
-        std::fs::create_dir(format!("{prefix}/ephemeral"))
-            .context_invoker("Failed to create directory <prefix>/ephemeral")?;
         std::fs::create_dir(format!("{prefix}/overlay"))
             .context_invoker("Failed to create directory <prefix>/overlay")?;
         std::fs::create_dir(format!("{prefix}/overlay/root"))
             .context_invoker("Failed to create directory <prefix>/overlay/root")?;
 
-        // Create a lowerdir for /space and /dev
-        system::mount("none", format!("{prefix}/ephemeral"), "tmpfs", 0, None)
-            .context_invoker("Failed to mount tmpfs on <prefix>/ephemeral")?;
-        std::fs::create_dir(format!("{prefix}/ephemeral/space"))
-            .context_invoker("Failed to create <prefix>/ephemeral/space")?;
-        std::fs::create_dir(format!("{prefix}/ephemeral/dev"))
-            .context_invoker("Failed to create <prefix>/ephemeral/dev")?;
-        std::fs::create_dir(format!("{prefix}/ephemeral/proc"))
-            .context_invoker("Failed to create <prefix>/ephemeral/proc")?;
-
-        // Mount overlay
-        let fs_options = format!(
-            "lowerdir={}/{}:{prefix}/ephemeral",
-            package
-                .image
-                .mountpoint
-                .to_str()
-                .context_invoker("Mountpoint must be a string")?,
-            package.name
-        );
-        system::mount(
-            "overlay",
-            format!("{prefix}/overlay/root"),
-            "overlay",
-            0,
-            Some(&fs_options),
-        )
-        .context_invoker("Failed to mount overlay on <prefix>/overlay/root")?;
+        if backend == config::RootfsBackend::Bind {
+            root_binds = mount_bind_root(&lowerdir, &format!("{prefix}/overlay/root"))?;
+        } else {
+            std::fs::create_dir(format!("{prefix}/ephemeral"))
+                .context_invoker("Failed to create directory <prefix>/ephemeral")?;
+
+            // Create a lowerdir for /space and /dev
+            supervisor::mount("none", format!("{prefix}/ephemeral"), "tmpfs", 0, None)
+                .context_invoker("Failed to mount tmpfs on <prefix>/ephemeral")?;
+            for mountpoint in ROOT_MOUNTPOINTS {
+                std::fs::create_dir(format!("{prefix}/ephemeral/{mountpoint}"))
+                    .with_context_invoker(|| {
+                        format!("Failed to create <prefix>/ephemeral/{mountpoint}")
+                    })?;
+            }
+
+            // Mount overlay
+            let fs_options = format!("lowerdir={lowerdir}:{prefix}/ephemeral");
+            supervisor::mount(
+                "overlay",
+                format!("{prefix}/overlay/root"),
+                "overlay",
+                0,
+                Some(&fs_options),
+            )
+            .context_invoker("Failed to mount overlay on <prefix>/overlay/root")?;
+        }
 
         // Don't mount /space, because RootFS::reset() will remount it anyway
 
         // Mount /dev on overlay
-        system::bind_mount_opt(
+        supervisor::bind_mount_opt(
             "/tmp/sunwalker_invoker/dev",
             format!("{prefix}/overlay/root/dev"),
             system::MS_RDONLY,
         )
         .context_invoker("Failed to mount /dev on <prefix>/overlay/root")?;
 
+        // Mount warm caches on overlay. The directory exists even if the languages of the package
+        // have nothing to warm up, so that all rootfs's look the same
+        let warm_cache = package.warm_cache_path();
+        std::fs::create_dir_all(&warm_cache)
+            .with_context_invoker(|| format!("Failed to create {warm_cache:?}"))?;
+        supervisor::bind_mount(&warm_cache, format!("{prefix}/overlay/root/warm"))
+            .context_invoker("Failed to mount /warm on <prefix>/overlay/root")?;
+        // MS_RDONLY is ignored when a bind mount is created, so it only takes effect on a remount
+        supervisor::mount(
+            "none",
+            format!("{prefix}/overlay/root/warm"),
+            "none",
+            system::MS_REMOUNT | system::MS_BIND | system::MS_RDONLY,
+            None,
+        )
+        .context_invoker("Failed to remount /warm on <prefix>/overlay/root read-only")?;
+
         // By now, rootfs generation is mostly finished
 
         // Start a subprocess which will create the appropriate namespaces
         let (mut upstream, downstream) = multiprocessing::tokio::duplex::<(), ()>()
-            .context_invoker("Failed to create duplex connection to an isolated subprocess")?;
+            .context_multiprocessing(
+                "Failed to create duplex connection to an isolated subprocess",
+            )?;
 
         let mut child = make_ns
             .spawn_tokio(downstream, prefix.clone())
             .await
-            .context_invoker("Failed to start an isolated subprocess")?;
+            .context_multiprocessing("Failed to start an isolated subprocess")?;
 
         // The subprocess will now mount /dev/mqueue
 
         // Wait for confirmation from the subprocess
-        let res = upstream
-            .recv()
-            .await
-            .context_invoker("Failed to read start confirmation from the isolated subprocess")?;
+        let res = upstream.recv().await.context_multiprocessing(
+            "Failed to read start confirmation from the isolated subprocess",
+        )?;
         if res.is_none() {
             let res = child
                 .join()
                 .await
-                .context_invoker("Isolated process didn't terminate gracefully")?;
+                .context_multiprocessing("Isolated process didn't terminate gracefully")?;
             let err = res
                 .err()
                 .unwrap_or_else(|| errors::InvokerFailure("(no error reported)".to_string()));
@@ -183,11 +378,13 @@ pub async fn make_rootfs(
         // Fill uid/gid maps
         {
             use ids::*;
+            let external_user_uid = user_ids.uid();
+            let external_user_gid = user_ids.gid();
             std::fs::write(
                 format!("/proc/{}/uid_map", child.id()),
                 format!(
                     "{INTERNAL_ROOT_UID} {EXTERNAL_ROOT_UID} 1\n{INTERNAL_USER_UID} \
-                     {EXTERNAL_USER_UID} 1\n{NOBODY_UID} {NOBODY_UID} 1\n"
+                     {external_user_uid} 1\n{NOBODY_UID} {NOBODY_UID} 1\n"
                 ),
             )
             .context_invoker("Failed to create uid_map for the isolated subprocess")?;
@@ -199,7 +396,7 @@ pub async fn make_rootfs(
                 format!("/proc/{}/gid_map", child.id()),
                 format!(
                     "{INTERNAL_ROOT_GID} {EXTERNAL_ROOT_GID} 1\n{INTERNAL_USER_GID} \
-                     {EXTERNAL_USER_GID} 1\n{NOGRP_GID} {NOGRP_GID} 1\n"
+                     {external_user_gid} 1\n{NOGRP_GID} {NOGRP_GID} 1\n"
                 ),
             )
             .context_invoker("Failed to create gid_map for the isolated subprocess")?;
@@ -219,7 +416,7 @@ pub async fn make_rootfs(
             };
             std::fs::write(&path, "")
                 .with_context_invoker(|| format!("Failed to create <prefix>/ns/{name}"))?;
-            system::bind_mount(&orig_path, &path).with_context_invoker(|| {
+            supervisor::bind_mount(&orig_path, &path).with_context_invoker(|| {
                 format!("Failed to bind-mount {orig_path} to <prefix>/ns/{name}")
             })?;
         }
@@ -227,12 +424,12 @@ pub async fn make_rootfs(
         upstream
             .send(&())
             .await
-            .context_invoker("Failed to tell the isolated subprocess to terminate")?;
+            .context_multiprocessing("Failed to tell the isolated subprocess to terminate")?;
 
         child
             .join()
             .await
-            .context_invoker("Isolated process didn't terminate gracefully")?
+            .context_multiprocessing("Isolated process didn't terminate gracefully")?
     } {
         // Rollback
         if let Err(e) = unmount_recursively(&prefix, false) {
@@ -243,18 +440,178 @@ pub async fn make_rootfs(
         if let Err(e) = std::fs::remove_dir_all(&prefix) {
             println!("Failed to rm -r {prefix} after unsuccessful initialization: {e:?}");
         }
+        if let Err(e) = user_ids.release() {
+            println!("Failed to release user IDs after unsuccessful initialization: {e:?}");
+        }
         return Err(e);
     }
 
     Ok(RootFS {
         removed: false,
         id,
+        lowerdir,
+        env: package.env.clone(),
         bound_files,
         quotas,
+        network_lease: None,
+        tainted: false,
+        backend,
+        root_binds,
+        user_ids: Some(user_ids),
     })
 }
 
+// Makes a rootfs of the package and a few PID namespaces and leaves them in the pools, so that the
+// first invocation in a fresh worker doesn't have to wait for them
+pub async fn prewarm(
+    package: &package::Package,
+    quotas: DiskQuotas,
+    id: String,
+) -> Result<(), errors::Error> {
+    make_rootfs(package, Vec::new(), quotas, id)
+        .await?
+        .recycle()?;
+    PidNamespace::refill().await
+}
+
+// Removes the rootfs's and PID namespaces kept for reuse
+pub fn drain_pool() -> Result<(), errors::Error> {
+    PID_NAMESPACES
+        .lock()
+        .expect("Failed to acquire the PID namespace pool")
+        .clear();
+    let pooled: Vec<RootFS> = POOL
+        .lock()
+        .expect("Failed to acquire the rootfs pool")
+        .drain(..)
+        .collect();
+    for rootfs in pooled {
+        rootfs.remove()?;
+    }
+    Ok(())
+}
+
+// A fresh PID namespace, kept alive by a process that sits at PID 1 in it and reaps orphans. The
+// holder is killed when the namespace is dropped, which takes down everything left inside
+struct PidNamespace {
+    holder: multiprocessing::tokio::Child<()>,
+    ns: std::fs::File,
+}
+
+impl PidNamespace {
+    async fn new() -> Result<PidNamespace, errors::Error> {
+        let holder = unsafe { hold_pid_namespace.spawn_with_flags_tokio(CLONE_NEWPID) }
+            .await
+            .context_multiprocessing("Failed to start a PID namespace holder")?;
+        let path = format!("/proc/{}/ns/pid", holder.id());
+        let ns =
+            std::fs::File::open(&path).with_context_invoker(|| format!("Failed to open {path}"))?;
+        Ok(PidNamespace { holder, ns })
+    }
+
+    async fn checkout() -> Result<PidNamespace, errors::Error> {
+        let pooled = PID_NAMESPACES
+            .lock()
+            .expect("Failed to acquire the PID namespace pool")
+            .pop();
+        match pooled {
+            Some(pid_namespace) => Ok(pid_namespace),
+            None => PidNamespace::new().await,
+        }
+    }
+
+    async fn refill() -> Result<(), errors::Error> {
+        while PID_NAMESPACES
+            .lock()
+            .expect("Failed to acquire the PID namespace pool")
+            .len()
+            < PID_NAMESPACE_POOL_CAPACITY
+        {
+            let pid_namespace = PidNamespace::new().await?;
+            let mut pool = PID_NAMESPACES
+                .lock()
+                .expect("Failed to acquire the PID namespace pool");
+            // Concurrent invocations may have filled the pool in the meantime
+            if pool.len() < PID_NAMESPACE_POOL_CAPACITY {
+                pool.push(pid_namespace);
+            }
+        }
+        Ok(())
+    }
+
+    // Children forked by the calling thread from now on are born in this namespace
+    fn enter_for_children(&self) -> Result<(), errors::Error> {
+        nix::sched::setns(self.ns.as_raw_fd(), nix::sched::CloneFlags::CLONE_NEWPID)
+            .context_invoker("Failed to setns to a pooled PID namespace")
+    }
+
+    fn leave_for_children() -> Result<(), errors::Error> {
+        let own = std::fs::File::open("/proc/self/ns/pid")
+            .context_invoker("Failed to open /proc/self/ns/pid")?;
+        nix::sched::setns(own.as_raw_fd(), nix::sched::CloneFlags::CLONE_NEWPID)
+            .context_invoker("Failed to setns back to the own PID namespace")
+    }
+}
+
+impl Drop for PidNamespace {
+    fn drop(&mut self) {
+        if let Err(e) = self.holder.kill() {
+            errors::log_throttled(
+                "kill pid namespace holder",
+                format!("Failed to kill the holder of a PID namespace: {e:?}"),
+            );
+            return;
+        }
+        // The holder is dead by now, so this doesn't block for long
+        let pid = nix::unistd::Pid::from_raw(self.holder.id());
+        if let Err(e) = nix::sys::wait::waitpid(pid, None) {
+            errors::log_throttled(
+                "kill pid namespace holder",
+                format!("Failed to reap the holder of a PID namespace: {e:?}"),
+            );
+        }
+    }
+}
+
+#[multiprocessing::entrypoint]
+fn hold_pid_namespace() {
+    // If the worker dies without dropping the namespace, the holder would otherwise keep it, and
+    // whatever was left inside, alive forever
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+    }
+    // SIGCHLD is ignored by default, so it has to be blocked to be waited for
+    let mut sigchld = nix::sys::signal::SigSet::empty();
+    sigchld.add(nix::sys::signal::Signal::SIGCHLD);
+    let _ = sigchld.thread_block();
+    loop {
+        while let Ok(status) =
+            nix::sys::wait::waitpid(None, Some(nix::sys::wait::WaitPidFlag::WNOHANG))
+        {
+            if status == nix::sys::wait::WaitStatus::StillAlive {
+                break;
+            }
+        }
+        let _ = sigchld.wait();
+    }
+}
+
 impl RootFS {
+    // What the sandboxed user is outside the sandbox, for files it must be able to write
+    pub fn external_user_uid(&self) -> libc::uid_t {
+        self.user_ids
+            .as_ref()
+            .expect("The rootfs has been removed")
+            .uid()
+    }
+
+    pub fn external_user_gid(&self) -> libc::gid_t {
+        self.user_ids
+            .as_ref()
+            .expect("The rootfs has been removed")
+            .gid()
+    }
+
     pub fn reset(&self) -> Result<(), errors::Error> {
         let space = format!("{}/space", self.overlay());
 
@@ -262,7 +619,7 @@ impl RootFS {
         unmount_recursively(&space, true)?;
 
         // Remount /space
-        system::mount(
+        supervisor::mount(
             "none",
             &space,
             "tmpfs",
@@ -279,8 +636,8 @@ impl RootFS {
 
         std::os::unix::fs::chown(
             &space,
-            Some(ids::EXTERNAL_USER_UID),
-            Some(ids::EXTERNAL_USER_GID),
+            Some(self.external_user_uid()),
+            Some(self.external_user_gid()),
         )
         .with_context_invoker(|| format!("Failed to chown {space}"))?;
 
@@ -289,7 +646,7 @@ impl RootFS {
         let dev_shm = format!("{}/dev/shm", self.overlay());
         std::fs::create_dir(&space_shm)
             .with_context_invoker(|| format!("Failed to create directory at {space_shm}"))?;
-        if let Err(e) = system::umount(&dev_shm) {
+        if let Err(e) = supervisor::umount(&dev_shm) {
             if let std::io::ErrorKind::InvalidInput = e.kind() {
                 // This means /dev/shm is not a mountpoint, which is fine the first time we run
                 // reset()
@@ -297,7 +654,7 @@ impl RootFS {
                 return Err(e.with_context_invoker(|| format!("Failed to unmount {dev_shm}")));
             }
         }
-        system::bind_mount(&space_shm, &dev_shm).with_context_invoker(|| {
+        supervisor::bind_mount(&space_shm, &dev_shm).with_context_invoker(|| {
             format!(
                 "Failed to bind-mount {space_shm} to {}/dev/shm",
                 self.overlay()
@@ -307,14 +664,145 @@ impl RootFS {
         let overlay = self.overlay();
         for (from, to) in self.bound_files.iter() {
             let to = format!("{overlay}{to}");
+            // Files of projects and their artifacts may be in subdirectories. The directories
+            // belong to the sandbox user, so that build tools can write next to the sources
+            if let Some(parent) = Path::new(&to).parent() {
+                if !parent.exists() {
+                    std::fs::create_dir_all(parent)
+                        .with_context_invoker(|| format!("Failed to create {parent:?}"))?;
+                    for dir in parent.ancestors().take_while(|dir| *dir != Path::new(&space)) {
+                        std::os::unix::fs::chown(
+                            dir,
+                            Some(self.external_user_uid()),
+                            Some(self.external_user_gid()),
+                        )
+                        .with_context_invoker(|| format!("Failed to chown {dir:?}"))?;
+                    }
+                }
+            }
             std::fs::write(&to, "").with_context_invoker(|| format!("Failed to create {to}"))?;
-            system::bind_mount_opt(from, &to, system::MS_RDONLY)
+            supervisor::bind_mount_opt(from, &to, system::MS_RDONLY)
                 .with_context_invoker(|| format!("Failed to bind-mount {from:?} to {to}"))?;
         }
 
         Ok(())
     }
 
+    // Plugs the sandbox into the bridge. The address is leased until the rootfs is removed
+    pub async fn connect_network(
+        &mut self,
+        network: &config::NetworkConfig,
+    ) -> Result<(), errors::Error> {
+        let prefix = format!("/tmp/sunwalker_invoker/rootfs/{}", self.id);
+        self.network_lease = Some(network::attach(&prefix, network).await?);
+        Ok(())
+    }
+
+    pub fn taint(&mut self) {
+        self.tainted = true;
+    }
+
+    // Everything the invoker mounts beneath the prefix itself, see make_rootfs and reset. /space is
+    // remounted before every use and contains whatever the user binds there.
+    pub fn expected_mounts(&self) -> Vec<PathBuf> {
+        let prefix = PathBuf::from(format!("/tmp/sunwalker_invoker/rootfs/{}", self.id));
+        let overlay = PathBuf::from(self.overlay());
+        let mut mounts = vec![
+            overlay.clone(),
+            overlay.join("dev"),
+            overlay.join("dev/mqueue"),
+            overlay.join("dev/shm"),
+            overlay.join("warm"),
+            prefix.join("overlay/userns"),
+            prefix.join("ns/ipc"),
+            prefix.join("ns/uts"),
+            prefix.join("ns/net"),
+        ];
+        if self.backend == config::RootfsBackend::Bind {
+            mounts.extend(self.root_binds.iter().cloned());
+        } else {
+            mounts.push(prefix.join("ephemeral"));
+        }
+        mounts
+    }
+
+    // Unmounts /space and /dev/shm and verifies that nothing else is left
+    fn vacate(&self) -> Result<Vec<PathBuf>, errors::Error> {
+        let overlay = self.overlay();
+        unmount_recursively(&format!("{overlay}/space"), true)?;
+        let dev_shm = format!("{overlay}/dev/shm");
+        if let Err(e) = supervisor::umount(&dev_shm) {
+            // Not mounted if the rootfs was never reset
+            if e.kind() != std::io::ErrorKind::InvalidInput {
+                return Err(e.with_context_invoker(|| format!("Failed to unmount {dev_shm}")));
+            }
+        }
+
+        let prefix = PathBuf::from(format!("/tmp/sunwalker_invoker/rootfs/{}", self.id));
+        let mut expected = self.expected_mounts();
+        expected.retain(|path| !path.ends_with("dev/shm"));
+        let mounts =
+            system::get_mountinfo().context_invoker("Failed to read /proc/self/mountinfo")?;
+        Ok(mounts
+            .into_iter()
+            .map(|mount| mount.mount_point)
+            .filter(|path| path.starts_with(&prefix) && !expected.contains(path))
+            .collect())
+    }
+
+    // Puts the rootfs into the pool if it can be reused, removes it otherwise
+    fn _recycle(&mut self) -> Result<(), errors::Error> {
+        if self.removed {
+            return Ok(());
+        }
+
+        // The veth pair carries counters from the previous user, and compilers must not get
+        // network access anyway
+        if self.tainted || self.network_lease.is_some() {
+            return self._remove();
+        }
+
+        let mut pool = POOL.lock().expect("Failed to acquire the rootfs pool");
+        if pool.len() >= POOL_CAPACITY {
+            drop(pool);
+            return self._remove();
+        }
+
+        let stray_mounts = match self.vacate() {
+            Ok(stray_mounts) => stray_mounts,
+            Err(e) => {
+                drop(pool);
+                self._remove()?;
+                return Err(e);
+            }
+        };
+        if !stray_mounts.is_empty() {
+            drop(pool);
+            println!(
+                "Not reusing rootfs {} because of unexpected mounts: {stray_mounts:?}",
+                self.id
+            );
+            return self._remove();
+        }
+
+        pool.push(RootFS {
+            removed: false,
+            id: std::mem::take(&mut self.id),
+            lowerdir: std::mem::take(&mut self.lowerdir),
+            env: std::mem::take(&mut self.env),
+            bound_files: Vec::new(),
+            quotas: self.quotas,
+            network_lease: None,
+            tainted: false,
+            backend: self.backend,
+            root_binds: std::mem::take(&mut self.root_binds),
+            user_ids: self.user_ids.take(),
+        });
+        self.removed = true;
+
+        Ok(())
+    }
+
     fn _remove(&mut self) -> Result<(), errors::Error> {
         if self.removed {
             return Ok(());
@@ -327,9 +815,32 @@ impl RootFS {
         std::fs::remove_dir_all(&prefix)
             .with_context_invoker(|| format!("Failed to remove {prefix} recursively"))?;
 
+        // The veth pair is destroyed along with the network namespace, so only the address is left
+        if let Some(lease) = self.network_lease.take() {
+            lease.release()?;
+        }
+        if let Some(lease) = self.user_ids.take() {
+            lease.release()?;
+        }
+
         Ok(())
     }
 
+    // Mounts the warm cache writable over the read-only one, for the warm-up steps. The extra mount
+    // keeps the rootfs from being reused
+    pub fn mount_warm_cache_writable(
+        &self,
+        warm_cache: &std::path::Path,
+    ) -> Result<(), errors::Error> {
+        let target = format!("{}/warm", self.overlay());
+        supervisor::bind_mount(warm_cache, &target)
+            .with_context_invoker(|| format!("Failed to bind-mount {warm_cache:?} to {target}"))
+    }
+
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
     pub fn overlay(&self) -> String {
         format!("/tmp/sunwalker_invoker/rootfs/{}/overlay/root", self.id)
     }
@@ -359,33 +870,106 @@ impl RootFS {
     pub fn remove(mut self) -> Result<(), errors::Error> {
         self._remove()
     }
+
+    pub fn recycle(mut self) -> Result<(), errors::Error> {
+        self._recycle()
+    }
 }
 
 impl Drop for RootFS {
     fn drop(&mut self) {
-        if let Err(e) = self._remove() {
-            println!("Failed to remove RootFS in drop(): {e:?}");
+        if let Err(e) = self._recycle() {
+            errors::log_throttled(
+                "recycle rootfs",
+                format!("Failed to recycle RootFS in drop(): {e:?}"),
+            );
         }
     }
 }
 
+// If heartbeat_timeout is set, f must call multiprocessing::heartbeat() regularly. The isolated
+// process is then killed if it stops doing so, which tells a wedged helper from a slow program.
 pub async fn run_isolated<T: Object + 'static>(
     f: Box<dyn multiprocessing::FnOnce<(), Output = Result<T, errors::Error>> + Send + Sync>,
     rootfs: &RootFS,
+    heartbeat_timeout: Option<std::time::Duration>,
 ) -> Result<T, errors::Error> {
     // A PID namespace is not usable after the process with PID 1 dies, so we can't create the
     // namespace once and reuse it later. We also can't unshare pidns inside isolated_entry, because
     // that would only affect the pidns of its children, and we would be unable to mount /proc
     // correctly.
-    let mut child =
-        unsafe { isolated_entry.spawn_with_flags_tokio(CLONE_NEWPID, f, rootfs.id.clone()) }
-            .await
-            .context_invoker("Failed to start an isolated subprocess")?;
+    //
+    // Creating a PID namespace is not free though, so a few are made ahead of time, see
+    // PidNamespace, and the pool is refilled after the isolated process terminates, so that forking
+    // holders does not compete with the program while it is being timed. The mount namespace
+    // still has to be unshared every time, see isolated_entry, while the rest are made once per
+    // rootfs and pooled along with it.
+    let pid_namespace = PidNamespace::checkout().await?;
+    let result = run_in_pid_namespace(f, rootfs, heartbeat_timeout, &pid_namespace).await;
+    if let Err(e) = PidNamespace::refill().await {
+        errors::log_throttled(
+            "refill pid namespaces",
+            format!("Failed to refill the PID namespace pool: {e:?}"),
+        );
+    }
+    result
+}
+
+async fn run_in_pid_namespace<T: Object + 'static>(
+    f: Box<dyn multiprocessing::FnOnce<(), Output = Result<T, errors::Error>> + Send + Sync>,
+    rootfs: &RootFS,
+    heartbeat_timeout: Option<std::time::Duration>,
+    pid_namespace: &PidNamespace,
+) -> Result<T, errors::Error> {
+    // A process is started per block per test, so the cheaper vfork path is used
+    let mut options = multiprocessing::SpawnOptions::new().vfork();
+    if let Some(timeout) = heartbeat_timeout {
+        options = options.heartbeat(timeout);
+    }
+
+    // The child is cloned synchronously during the first poll. Switching the namespace back right
+    // after it, without yielding in between, ensures nothing else is spawned into the namespace,
+    // and that both setns calls happen on the same thread even on a multi-threaded runtime
+    let mut spawning = Box::pin(unsafe {
+        isolated_entry.spawn_with_options_tokio(&options, f, rootfs.id.clone(), rootfs.env.clone())
+    });
+    pid_namespace.enter_for_children()?;
+    let first_poll = futures::poll!(&mut spawning);
+    PidNamespace::leave_for_children()?;
+    let mut child = match first_poll {
+        std::task::Poll::Ready(child) => child,
+        std::task::Poll::Pending => spawning.await,
+    }
+    .context_multiprocessing("Failed to start an isolated subprocess")?;
+
+    let mut watcher = match child.watcher() {
+        Some(watcher) => watcher,
+        None => {
+            return child
+                .join()
+                .await
+                .context_multiprocessing("Isolated process didn't terminate gracefully")?;
+        }
+    };
 
+    tokio::select! {
+        result = child.join() => {
+            return result.context_multiprocessing("Isolated process didn't terminate gracefully")?;
+        }
+        () = watcher.unresponsive() => {}
+    }
+
+    child
+        .kill()
+        .context_multiprocessing("Failed to kill unresponsive isolated process")?;
     child
-        .join()
+        .wait()
         .await
-        .context_invoker("Isolated process didn't terminate gracefully")?
+        .context_multiprocessing("Failed to wait for unresponsive isolated process")?;
+    Err(errors::InvokerFailure(format!(
+        "Isolated process stopped sending heartbeats for {:?}",
+        heartbeat_timeout.unwrap()
+    )))
 }
 
 #[multiprocessing::entrypoint]
@@ -460,7 +1044,8 @@ async fn make_ns(
     }
 
     // Mount /dev/mqueue. This has to happen inside the IPC namespace, because mqueuefs is attached
-    // to the namespace of the process that mounted it.
+    // to the namespace of the process that mounted it. This bypasses the mount supervisor, but the
+    // mount lies beneath the overlay, so the supervisor would still clean it up after a crash.
     let dev_mqueue = format!("{prefix}/overlay/root/dev/mqueue");
     system::mount("mqueue", &dev_mqueue, "mqueue", 0, None)
         .context_invoker("Failed to mount <prefix>/overlay/root/dev/mqueue")?;
@@ -479,11 +1064,11 @@ async fn make_ns(
     duplex
         .send(&())
         .await
-        .context_invoker("Failed to read notify the parent about successful unshare")?;
+        .context_multiprocessing("Failed to read notify the parent about successful unshare")?;
     duplex
         .recv()
         .await
-        .context_invoker("Failed to get stop signal from parent")?
+        .context_multiprocessing("Failed to get stop signal from parent")?
         .context_invoker("Parent died before sending stop signal to the isolated process")?;
 
     Ok(())
@@ -494,6 +1079,7 @@ async fn make_ns(
 async fn isolated_entry<T: Object + 'static>(
     f: Box<dyn multiprocessing::FnOnce<(), Output = Result<T, errors::Error>> + Send + Sync>,
     rootfs_id: String,
+    env: Vec<(String, String)>,
 ) -> Result<T, errors::Error> {
     let overlay = format!("/tmp/sunwalker_invoker/rootfs/{rootfs_id}/overlay");
 
@@ -857,21 +1443,8 @@ async fn isolated_entry<T: Object + 'static>(
     std::env::set_var("LC_NUMERIC", "en_US.UTF-8");
     std::env::set_var("LANG", "en_US.UTF-8");
 
-    // Use environment from the package
-    let file = std::fs::File::open("/.sunwalker/env").map_err(|e| {
-        errors::ConfigurationFailure(format!("Failed to open /.sunwalker/env for reading: {e:?}"))
-    })?;
-    for line in std::io::BufReader::new(file).lines() {
-        let line = line.map_err(|e| {
-            errors::ConfigurationFailure(format!("Failed to read from /.sunwalker/env: {e:?}"))
-        })?;
-        let idx = line.find('=').ok_or_else(|| {
-            errors::ConfigurationFailure(format!(
-                "'=' not found in a line of /.sunwalker/env: {line}"
-            ))
-        })?;
-        let (name, value) = line.split_at(idx);
-        let value = &value[1..];
+    // Use environment from the package, validated when the package was loaded
+    for (name, value) in env {
         std::env::set_var(name, value);
     }
 
@@ -0,0 +1,297 @@
+use crate::errors;
+use libc::{
+    c_long, sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET,
+    BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_KILL_PROCESS,
+};
+
+// Namespaces hide the rest of the system from the solution, but every syscall is still reachable,
+// and that is a lot of kernel code for an untrusted program to poke at. Languages may list the
+// syscalls their runtime needs in the image config, and anything else kills the process with
+// SIGSYS. The filter is installed right before execve, so it applies to the solution and everything
+// it spawns, but not to the invoker.
+//
+// Syscalls are listed by name, as numbers differ between architectures. Only the syscalls below
+// are known; a name missing from the table is a configuration error rather than silently ignored.
+
+const SYSCALLS: &[(&str, c_long)] = &[
+    ("read", libc::SYS_read),
+    ("write", libc::SYS_write),
+    ("readv", libc::SYS_readv),
+    ("writev", libc::SYS_writev),
+    ("pread64", libc::SYS_pread64),
+    ("pwrite64", libc::SYS_pwrite64),
+    ("openat", libc::SYS_openat),
+    ("close", libc::SYS_close),
+    ("fstat", libc::SYS_fstat),
+    ("newfstatat", libc::SYS_newfstatat),
+    ("statx", libc::SYS_statx),
+    ("lseek", libc::SYS_lseek),
+    ("mmap", libc::SYS_mmap),
+    ("mprotect", libc::SYS_mprotect),
+    ("munmap", libc::SYS_munmap),
+    ("mremap", libc::SYS_mremap),
+    ("madvise", libc::SYS_madvise),
+    ("brk", libc::SYS_brk),
+    ("rt_sigaction", libc::SYS_rt_sigaction),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn),
+    ("sigaltstack", libc::SYS_sigaltstack),
+    ("ioctl", libc::SYS_ioctl),
+    ("fcntl", libc::SYS_fcntl),
+    ("dup", libc::SYS_dup),
+    ("dup3", libc::SYS_dup3),
+    ("pipe2", libc::SYS_pipe2),
+    ("recvmsg", libc::SYS_recvmsg),
+    ("sendmsg", libc::SYS_sendmsg),
+    ("getcwd", libc::SYS_getcwd),
+    ("chdir", libc::SYS_chdir),
+    ("getdents64", libc::SYS_getdents64),
+    ("faccessat", libc::SYS_faccessat),
+    ("readlinkat", libc::SYS_readlinkat),
+    ("unlinkat", libc::SYS_unlinkat),
+    ("mkdirat", libc::SYS_mkdirat),
+    ("renameat", libc::SYS_renameat),
+    ("ftruncate", libc::SYS_ftruncate),
+    ("fsync", libc::SYS_fsync),
+    ("execve", libc::SYS_execve),
+    ("exit", libc::SYS_exit),
+    ("exit_group", libc::SYS_exit_group),
+    ("wait4", libc::SYS_wait4),
+    ("waitid", libc::SYS_waitid),
+    ("clone", libc::SYS_clone),
+    ("clone3", libc::SYS_clone3),
+    ("kill", libc::SYS_kill),
+    ("tgkill", libc::SYS_tgkill),
+    ("getpid", libc::SYS_getpid),
+    ("gettid", libc::SYS_gettid),
+    ("getppid", libc::SYS_getppid),
+    ("getuid", libc::SYS_getuid),
+    ("geteuid", libc::SYS_geteuid),
+    ("getgid", libc::SYS_getgid),
+    ("getegid", libc::SYS_getegid),
+    ("getrandom", libc::SYS_getrandom),
+    ("getrlimit", libc::SYS_getrlimit),
+    ("setrlimit", libc::SYS_setrlimit),
+    ("prlimit64", libc::SYS_prlimit64),
+    ("getrusage", libc::SYS_getrusage),
+    ("uname", libc::SYS_uname),
+    ("sysinfo", libc::SYS_sysinfo),
+    ("prctl", libc::SYS_prctl),
+    ("futex", libc::SYS_futex),
+    ("set_robust_list", libc::SYS_set_robust_list),
+    ("get_robust_list", libc::SYS_get_robust_list),
+    ("set_tid_address", libc::SYS_set_tid_address),
+    ("rseq", libc::SYS_rseq),
+    ("sched_yield", libc::SYS_sched_yield),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity),
+    ("sched_setaffinity", libc::SYS_sched_setaffinity),
+    ("clock_gettime", libc::SYS_clock_gettime),
+    ("clock_getres", libc::SYS_clock_getres),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep),
+    ("nanosleep", libc::SYS_nanosleep),
+    ("gettimeofday", libc::SYS_gettimeofday),
+    ("membarrier", libc::SYS_membarrier),
+    ("ppoll", libc::SYS_ppoll),
+    ("pselect6", libc::SYS_pselect6),
+    ("epoll_create1", libc::SYS_epoll_create1),
+    ("epoll_ctl", libc::SYS_epoll_ctl),
+    ("epoll_pwait", libc::SYS_epoll_pwait),
+    ("eventfd2", libc::SYS_eventfd2),
+    ("memfd_create", libc::SYS_memfd_create),
+    #[cfg(target_arch = "x86_64")]
+    ("arch_prctl", libc::SYS_arch_prctl),
+    #[cfg(target_arch = "x86_64")]
+    ("open", libc::SYS_open),
+    #[cfg(target_arch = "x86_64")]
+    ("stat", libc::SYS_stat),
+    #[cfg(target_arch = "x86_64")]
+    ("lstat", libc::SYS_lstat),
+    #[cfg(target_arch = "x86_64")]
+    ("access", libc::SYS_access),
+    #[cfg(target_arch = "x86_64")]
+    ("readlink", libc::SYS_readlink),
+    #[cfg(target_arch = "x86_64")]
+    ("unlink", libc::SYS_unlink),
+    #[cfg(target_arch = "x86_64")]
+    ("mkdir", libc::SYS_mkdir),
+    #[cfg(target_arch = "x86_64")]
+    ("rename", libc::SYS_rename),
+    #[cfg(target_arch = "x86_64")]
+    ("dup2", libc::SYS_dup2),
+    #[cfg(target_arch = "x86_64")]
+    ("pipe", libc::SYS_pipe),
+    #[cfg(target_arch = "x86_64")]
+    ("getdents", libc::SYS_getdents),
+    #[cfg(target_arch = "x86_64")]
+    ("fork", libc::SYS_fork),
+    #[cfg(target_arch = "x86_64")]
+    ("vfork", libc::SYS_vfork),
+    #[cfg(target_arch = "x86_64")]
+    ("poll", libc::SYS_poll),
+    #[cfg(target_arch = "x86_64")]
+    ("select", libc::SYS_select),
+    #[cfg(target_arch = "x86_64")]
+    ("epoll_create", libc::SYS_epoll_create),
+    #[cfg(target_arch = "x86_64")]
+    ("epoll_wait", libc::SYS_epoll_wait),
+    #[cfg(target_arch = "x86_64")]
+    ("time", libc::SYS_time),
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+
+// x32 syscalls are x86_64 syscalls with this bit set, and would bypass a filter on numbers alone
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+// Offsets into struct seccomp_data
+const OFFSET_NR: u32 = 0;
+const OFFSET_ARCH: u32 = 4;
+
+fn statement(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+pub fn syscall_number(name: &str) -> Option<c_long> {
+    SYSCALLS
+        .iter()
+        .find(|(known_name, _)| *known_name == name)
+        .map(|(_, nr)| *nr)
+}
+
+pub fn syscall_name(nr: c_long) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|(_, known_nr)| *known_nr == nr)
+        .map(|(name, _)| *name)
+}
+
+// Checks the allow-list without building the filter, so that typos in the image config are
+// reported when the program is built rather than when it is run
+pub fn validate(syscalls: &[String]) -> Result<(), errors::Error> {
+    for name in syscalls {
+        if syscall_number(name).is_none() {
+            return Err(errors::ConfigurationFailure(format!(
+                "Unknown syscall {name} in seccomp allow-list"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn build_filter(syscalls: &[String]) -> Result<Vec<sock_filter>, errors::Error> {
+    validate(syscalls)?;
+
+    let mut filter = vec![
+        statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+    ]);
+
+    // execve has to be allowed, as the filter is installed before the program is started
+    let numbers = syscalls
+        .iter()
+        .filter_map(|name| syscall_number(name))
+        .chain([libc::SYS_execve]);
+    for nr in numbers {
+        filter.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+        ]);
+    }
+    filter.push(statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+    Ok(filter)
+}
+
+// An empty allow-list means that the language has not opted in, and no filter is installed
+pub fn apply(syscalls: &[String]) -> Result<(), errors::Error> {
+    if syscalls.is_empty() {
+        return Ok(());
+    }
+
+    let mut filter = build_filter(syscalls)?;
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Unprivileged processes may only install filters with no_new_privs set. The user is dropped
+    // by now, and setuid binaries are not supposed to work in the sandbox anyway
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+        return Err(errors::InvokerFailure(format!(
+            "Failed to set no_new_privs: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    if unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &program as *const sock_fprog,
+        )
+    } == -1
+    {
+        return Err(errors::InvokerFailure(format!(
+            "Failed to install seccomp filter: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+// Runs the filter on a syscall the way the kernel would
+#[cfg(test)]
+pub fn allows(syscalls: &[String], nr: c_long) -> bool {
+    let filter = build_filter(syscalls).unwrap();
+    // struct seccomp_data starts with the syscall number and the architecture
+    let data = [nr as u32, AUDIT_ARCH];
+    let mut accumulator = 0;
+    let mut pc = 0;
+    loop {
+        let instruction = filter[pc];
+        let code = instruction.code as u32;
+        pc += 1;
+        if code == BPF_LD | BPF_W | BPF_ABS {
+            accumulator = data[instruction.k as usize / 4];
+        } else if code == BPF_JMP | BPF_JEQ | BPF_K || code == BPF_JMP | BPF_JGE | BPF_K {
+            let taken = if code & 0xf0 == BPF_JEQ {
+                accumulator == instruction.k
+            } else {
+                accumulator >= instruction.k
+            };
+            let offset = if taken {
+                instruction.jt
+            } else {
+                instruction.jf
+            };
+            pc += offset as usize;
+        } else if code == BPF_RET | BPF_K {
+            return instruction.k == SECCOMP_RET_ALLOW;
+        } else {
+            panic!("Unexpected instruction {code:#x}");
+        }
+    }
+}
@@ -1,26 +1,68 @@
 use crate::{
-    errors,
-    errors::{ToError, ToResult},
-    image::{ids, program, sandbox},
+    cgroups, config, errors,
+    errors::{ToError, ToMultiprocessingResult, ToResult},
+    image::{args, canary, program, runtime, sandbox, seccomp, trace},
     problem::verdict,
-    system,
+    signing, supervisor, system,
 };
+use futures::FutureExt;
 use multiprocessing::{Bind, Object};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::io::{Read, Seek, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+// The isolated process that runs a strategy pings the worker at least once per interval. If it
+// stays silent for much longer than that, it is considered wedged, which is reported as an invoker
+// failure rather than as a verdict for the submission.
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const HEARTBEAT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// A program is considered idle if it used less than this share of the wall-clock time as CPU time.
+// Waiting for I/O is never completely free, so the share is not zero.
+const IDLENESS_CPU_SHARE: u32 = 100;
+
+// A user program that talks to an interactor is killed with IdlenessLimitExceeded after being idle
+// for this long, unless the problem sets a limit itself. If both sides wait for each other, this is
+// what breaks the deadlock; the interactor then sees EOF and exits too.
+const INTERACTIVE_IDLENESS_LIMIT: std::time::Duration = std::time::Duration::from_secs(2);
 
 #[derive(Clone, Object, Deserialize, Serialize)]
 pub struct StrategyFactory {
     files: HashMap<String, FileType>,
     blocks: Vec<Block>,
     programs: HashMap<String, program::CachedProgram>,
+    // Override the multipliers from the configuration of the languages, by language name
+    #[serde(default)]
+    language_multipliers: HashMap<String, verdict::LimitMultipliers>,
+    // How testlib and interactor blocks report verdicts
+    #[serde(default)]
+    exit_codes: verdict::ExitCodeConvention,
+    // Limits of testlib blocks. If set, they override the limits sent by the conductor, which then
+    // need not send any for these blocks
+    #[serde(default)]
+    checker_limits: Option<verdict::CheckerLimits>,
+    // Files under grader/ built together with the submission, by language name. The submission then
+    // implements functions the grader calls rather than main, see config::Language::grader
+    #[serde(default)]
+    grader: HashMap<String, Vec<String>>,
+    // A shorthand for common flows, lowered into files and blocks when the problem is loaded. Stages
+    // may be freely mixed with hand-written blocks
+    #[serde(default)]
+    pipeline: Vec<Stage>,
     pub root: PathBuf,
 }
 
+impl std::fmt::Debug for StrategyFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StrategyFactory")
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
 pub struct Strategy {
     files: HashMap<String, FileType>,
     blocks: Vec<Block>,
@@ -29,8 +71,28 @@ pub struct Strategy {
     writer_by_file: HashMap<String, usize>,
     written_files_by_block: Vec<Vec<String>>,
     invocation_limits: HashMap<String, verdict::InvocationLimit>,
+    exit_codes: verdict::ExitCodeConvention,
+    // User blocks that run concurrently with an interactor
+    interactive_blocks: HashSet<usize>,
     core: u64,
     build_id: String,
+    // /sys is not available inside the sandbox, so this is read beforehand
+    system_thp: Option<verdict::ThpMode>,
+    canaries: canary::Canaries,
+    // Set when the canaries detect a violation. The sandbox is not trusted after that, so no more
+    // tests are run in it
+    quarantined: bool,
+    // By block, see Runtime in image/config.rs
+    runtimes: Vec<RuntimeSlot>,
+    sandbox_config: config::SandboxConfig,
+}
+
+enum RuntimeSlot {
+    // The program is started afresh on every test
+    None,
+    // Started before the next test
+    Pending,
+    Running(runtime::Runtime),
 }
 
 #[derive(Clone, Object, Deserialize, Serialize)]
@@ -43,12 +105,38 @@ struct Block {
     stdin: Option<Pattern>,
     stdout: Option<Pattern>,
     stderr: Option<Pattern>,
+    // Artifacts produced by this block: artifact name -> name of the file in /space the program
+    // writes it to. The worker stages an artifact after the block terminates, so that later blocks
+    // can consume it as @name regardless of where the producer put it.
+    #[serde(default)]
+    outputs: HashMap<String, String>,
+    // Whether the program accepts arguments from a response file passed as @path. If so, arguments
+    // that don't fit into the kernel limits are passed this way instead of failing the test
+    #[serde(default)]
+    response_file: bool,
 }
 
 #[derive(Clone, Object, Deserialize, Serialize)]
 enum Tactic {
+    // The submission. A strategy may run it several times per test, e.g. as an encoder and then as
+    // a decoder, by chaining user blocks via artifacts, possibly with a filter in between that
+    // shuffles the data
     User,
     Testlib,
+    // A problem-supplied program that post-processes data (typically the output of the user
+    // program) before it reaches the checker
+    Filter,
+    // A problem-supplied program that runs concurrently with the user program, talking to it via
+    // pipes, and reports the verdict with testlib exit codes
+    Interactor,
+    // A problem-supplied program that checks the input of the test and exits with a non-zero code
+    // if it is malformed. Validators run before the user program, and a failure is reported as
+    // InvalidTest rather than blamed on the submission
+    Validator,
+    // Like Testlib, but runs strictly after all testlib blocks and interactors, and only if they
+    // accepted the test. Its verdict is final, which lets it award partial points for data the
+    // checks accepted
+    Scorer,
 }
 
 #[derive(Clone, Copy, Debug, Object, Deserialize, Serialize)]
@@ -68,24 +156,280 @@ struct Binding {
 enum Pattern {
     File(String),
     VariableText(String),
+    Artifact(String),
+}
+
+// A step of a pipeline. Stages are connected by named files: a stage reads its inputs and writes its
+// stdout to the file called `output`, which later stages may list as their input. The order is
+// derived from these dependencies, just like for blocks.
+#[derive(Clone, Object, Deserialize, Serialize)]
+struct Stage {
+    name: String,
+    kind: StageKind,
+    // Ignored for Run stages, which always invoke the submission
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    argv: Vec<Pattern>,
+    #[serde(default)]
+    inputs: Vec<Pattern>,
+    #[serde(default)]
+    output: Option<String>,
+}
+
+#[derive(Clone, Copy, Object, Deserialize, Serialize)]
+enum StageKind {
+    // Produces data from the seed and the test files, e.g. a generator for tests that are too large
+    // to store. Lowered to a filter: the first input is passed as stdin, the rest as arguments
+    Generate,
+    // Runs the submission with the first input as stdin
+    Run,
+    // Post-processes data between stages. Lowered to a filter, like Generate
+    Transform,
+    // Checks the data with testlib conventions: inputs are passed as arguments and the comments are
+    // collected from stderr
+    Check,
+    // Like Check, but runs after all checks and interactors, and only if they accepted the test.
+    // Lowered to a scorer, whose verdict, e.g. partial points, is final
+    Score,
+    // Validates the test before the submission is run. The first input is passed as stdin, the
+    // rest as arguments, and the comments are collected from stderr
+    Validate,
 }
 
 struct StrategyRun<'a> {
     strategy: &'a mut Strategy,
     aux: String,
     test_path: PathBuf,
+    user_time_budget: UserTimeBudget,
+    seed: u64,
     removed: bool,
 }
 
+// The CPU time the submission has left. When it is run several times per test, e.g. as an encoder
+// and then as a decoder, the runs share the time limit of the test, so that a later run gets what
+// is left. The limit of the test is the largest one among the user blocks, which is what a problem
+// that gives every run the same limit expects. The group time budget, if any, is shared the same
+// way across the tests of the group. Each run is still bound by its own limit, i.e. it gets the
+// smallest of its limit and what is left of the shared ones.
+#[derive(Clone, Copy, Debug)]
+struct UserTimeBudget {
+    test: std::time::Duration,
+    group: Option<std::time::Duration>,
+}
+
+impl UserTimeBudget {
+    fn new(
+        user_limits: impl Iterator<Item = std::time::Duration>,
+        group: Option<std::time::Duration>,
+    ) -> Self {
+        Self {
+            test: user_limits.max().unwrap_or(std::time::Duration::MAX),
+            group,
+        }
+    }
+
+    fn limit(&self, tactic: &Tactic, cpu_time: std::time::Duration) -> std::time::Duration {
+        match tactic {
+            Tactic::User => cpu_time.min(self.left()),
+            _ => cpu_time,
+        }
+    }
+
+    fn left(&self) -> std::time::Duration {
+        self.test
+            .min(self.group.unwrap_or(std::time::Duration::MAX))
+    }
+
+    fn charge(&mut self, tactic: &Tactic, cpu_time: std::time::Duration) {
+        if let Tactic::User = tactic {
+            self.test = self.test.saturating_sub(cpu_time);
+            self.group = self.group.map(|group| group.saturating_sub(cpu_time));
+        }
+    }
+}
+
 impl StrategyFactory {
+    pub fn lower_pipeline(&mut self) -> Result<(), errors::Error> {
+        for stage in std::mem::take(&mut self.pipeline) {
+            if stage.name.is_empty() {
+                return Err(errors::ConfigurationFailure(
+                    "Pipeline stage has an empty name".to_string(),
+                ));
+            }
+            // Limits are sent by block name, so names must be unique among hand-written blocks too
+            if self.blocks.iter().any(|block| block.name == stage.name) {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Pipeline stage {} has the same name as another stage or block",
+                    stage.name
+                )));
+            }
+
+            let tactic = match stage.kind {
+                StageKind::Generate | StageKind::Transform => Tactic::Filter,
+                StageKind::Run => Tactic::User,
+                StageKind::Check => Tactic::Testlib,
+                StageKind::Score => Tactic::Scorer,
+                StageKind::Validate => Tactic::Validator,
+            };
+            let command = match stage.kind {
+                StageKind::Run => "user".to_string(),
+                _ => {
+                    if stage.command.is_empty() {
+                        return Err(errors::ConfigurationFailure(format!(
+                            "Pipeline stage {} does not specify a command",
+                            stage.name
+                        )));
+                    }
+                    stage.command
+                }
+            };
+
+            let mut argv = stage.argv;
+            let mut stdin = None;
+            let mut stderr = None;
+            match stage.kind {
+                StageKind::Generate | StageKind::Run | StageKind::Transform => {
+                    let mut inputs = stage.inputs.into_iter();
+                    stdin = inputs.next();
+                    if let StageKind::Run = stage.kind {
+                        if inputs.len() > 0 {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "Pipeline stage {} runs the submission, so it takes at most one \
+                                 input",
+                                stage.name
+                            )));
+                        }
+                    }
+                    argv.extend(inputs);
+                }
+                StageKind::Check | StageKind::Score => {
+                    argv.extend(stage.inputs);
+                    let comments = format!("{}.comments", stage.name);
+                    self.add_pipeline_file(&comments)?;
+                    stderr = Some(Pattern::File(comments));
+                }
+                StageKind::Validate => {
+                    let mut inputs = stage.inputs.into_iter();
+                    stdin = inputs.next();
+                    argv.extend(inputs);
+                    let comments = format!("{}.comments", stage.name);
+                    self.add_pipeline_file(&comments)?;
+                    stderr = Some(Pattern::File(comments));
+                }
+            }
+
+            let stdout = match stage.output {
+                Some(output) => {
+                    self.add_pipeline_file(&output)?;
+                    Some(Pattern::File(output))
+                }
+                None => None,
+            };
+
+            self.blocks.push(Block {
+                name: stage.name,
+                tactic,
+                bindings: HashMap::new(),
+                command,
+                argv,
+                stdin,
+                stdout,
+                stderr,
+                outputs: HashMap::new(),
+                response_file: false,
+            });
+        }
+        Ok(())
+    }
+
+    fn add_pipeline_file(&mut self, name: &str) -> Result<(), errors::Error> {
+        if self.files.contains_key(name) {
+            return Err(errors::ConfigurationFailure(format!(
+                "Pipeline file {name} is declared more than once"
+            )));
+        }
+        self.files.insert(name.to_string(), FileType::Regular);
+        Ok(())
+    }
+
+    pub fn grader_files(&self, language: &str) -> Result<Vec<PathBuf>, errors::Error> {
+        let mut paths = Vec::new();
+        for name in self.grader.get(language).into_iter().flatten() {
+            let path = self.root.join("grader").join(name);
+            if !path.is_file() {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Grader file {name} for language {language} is not a regular file at {path:?}"
+                )));
+            }
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
     pub async fn make<'a>(
         &'a self,
         user_program: &'a program::Program,
-        invocation_limits: HashMap<String, verdict::InvocationLimit>,
+        mut invocation_limits: HashMap<String, verdict::InvocationLimit>,
         core: u64,
         build_id: String,
+        sandbox_config: &config::SandboxConfig,
     ) -> Result<Strategy, errors::Error> {
+        // The conductor sends the same limits for all languages. The problem may override the
+        // multipliers of the language, but not those of the diagnostic profile
+        let multipliers = self
+            .language_multipliers
+            .get(&user_program.language)
+            .copied()
+            .unwrap_or(user_program.limit_multipliers)
+            .then(&user_program.diagnostic_multipliers);
+        multipliers.validate()?;
+        for block in self.blocks.iter() {
+            if let Tactic::User = block.tactic {
+                if let Some(limit) = invocation_limits.get_mut(&block.name) {
+                    multipliers.apply(limit);
+                }
+            }
+            if let (Tactic::Testlib | Tactic::Scorer, Some(checker_limits)) =
+                (&block.tactic, &self.checker_limits)
+            {
+                let limit = checker_limits.apply(invocation_limits.remove(&block.name));
+                invocation_limits.insert(block.name.clone(), limit);
+            }
+        }
+
         // Sanity checks
+        let mut producer_by_artifact: HashMap<&'a str, usize> = HashMap::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            for (name, path) in block.outputs.iter() {
+                // Artifacts are logged alongside regular files
+                if self.files.contains_key(name) {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Artifact @{name} has the same name as file %{name}; this is not allowed"
+                    )));
+                }
+                if producer_by_artifact.insert(name, i).is_some() {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Artifact @{name} is output by multiple blocks; this is not allowed"
+                    )));
+                }
+                // Names starting with a dot are reserved for the files the invoker creates itself
+                if path.is_empty() || path.contains('/') || path.starts_with('.') {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Artifact @{name} is output to {path:?}, which is not a plain file name; \
+                         this is not allowed"
+                    )));
+                }
+                if block.bindings.contains_key(path) {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Artifact @{name} is output to {path}, which is bound by block '{}'; this \
+                         is not allowed",
+                        block.name
+                    )));
+                }
+            }
+        }
+
         let mut seen_block_names = HashSet::new();
         for block in self.blocks.iter() {
             if !seen_block_names.insert(block.name.clone()) {
@@ -95,11 +439,18 @@ impl StrategyFactory {
                 )));
             }
 
-            if !invocation_limits.contains_key(&block.name) {
-                return Err(errors::ConfigurationFailure(format!(
-                    "Invocation limit missing for block '{}'",
-                    block.name
-                )));
+            match invocation_limits.get(&block.name) {
+                None => {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Invocation limit missing for block '{}'",
+                        block.name
+                    )));
+                }
+                Some(limit) => {
+                    if let Some(ref filter) = limit.trace {
+                        trace::validate(filter)?;
+                    }
+                }
             }
 
             for (_, binding) in block.bindings.iter() {
@@ -118,6 +469,20 @@ impl StrategyFactory {
                             )));
                         }
                     },
+                    Pattern::Artifact(ref name) => {
+                        if !producer_by_artifact.contains_key(name.as_str()) {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "Use of undeclared artifact @{name}"
+                            )));
+                        }
+                        // Only the producer may write to an artifact
+                        if binding.writable {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "Artifact @{name} is written to by block '{}'; this is not allowed",
+                                block.name
+                            )));
+                        }
+                    }
                     Pattern::VariableText(ref text) => {
                         // Binding from the sandbox into the sandbox is not supported
                         if !text.contains('\0') {
@@ -139,8 +504,8 @@ impl StrategyFactory {
             }
 
             for arg in block.argv.iter() {
-                if let Pattern::File(ref filename) = arg {
-                    match self.files.get(filename) {
+                match arg {
+                    Pattern::File(ref filename) => match self.files.get(filename) {
                         Some(file_type) => {
                             if let FileType::Pipe = file_type {
                                 return Err(errors::ConfigurationFailure(format!(
@@ -153,7 +518,15 @@ impl StrategyFactory {
                                 "Use of undeclared file %{filename}"
                             )));
                         }
+                    },
+                    Pattern::Artifact(ref name) => {
+                        if !producer_by_artifact.contains_key(name.as_str()) {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "Use of undeclared artifact @{name}"
+                            )));
+                        }
                     }
+                    Pattern::VariableText(_) => {}
                 }
             }
 
@@ -175,6 +548,19 @@ impl StrategyFactory {
                             )));
                         }
                     }
+                    Some(Pattern::Artifact(ref artifact)) => {
+                        if *writable {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "{name} is redirected to artifact @{artifact}; this is not \
+                                 allowed, artifacts are written via outputs"
+                            )));
+                        }
+                        if !producer_by_artifact.contains_key(artifact.as_str()) {
+                            return Err(errors::ConfigurationFailure(format!(
+                                "Use of undeclared artifact @{artifact}"
+                            )));
+                        }
+                    }
                     Some(Pattern::VariableText(ref text)) => {
                         if text.contains('\0') {
                             if *writable {
@@ -184,6 +570,9 @@ impl StrategyFactory {
                                     format_vars(text)
                                 )));
                             }
+                        } else if block.outputs.values().any(|path| path == text) {
+                            // The output file of an artifact is created by the invoker and can be
+                            // used as a stream, e.g. to save stdout of a generator
                         } else {
                             let binding = block.bindings.get(text).ok_or_else(|| {
                                 errors::ConfigurationFailure(format!(
@@ -208,8 +597,46 @@ impl StrategyFactory {
                 }
             }
 
+            // Filters are provided by the problem, running the user program as a filter would let
+            // it affect its own verdict
+            if let Tactic::Filter = block.tactic {
+                if block.command == "user" {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' uses the user program as a filter; this is not allowed",
+                        block.name
+                    )));
+                }
+            }
+
+            if let Tactic::Interactor = block.tactic {
+                if block.command == "user" {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' uses the user program as an interactor; this is not allowed",
+                        block.name
+                    )));
+                }
+            }
+
+            if let Tactic::Validator = block.tactic {
+                if block.command == "user" {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' uses the user program as a validator; this is not allowed",
+                        block.name
+                    )));
+                }
+            }
+
+            if let Tactic::Scorer = block.tactic {
+                if block.command == "user" {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' uses the user program as a scorer; this is not allowed",
+                        block.name
+                    )));
+                }
+            }
+
             // stderr of testlib must be redirected to a regular file
-            if let Tactic::Testlib = block.tactic {
+            if let Tactic::Testlib | Tactic::Interactor | Tactic::Scorer = block.tactic {
                 match block.stderr {
                     None => {
                         return Err(errors::ConfigurationFailure(
@@ -219,16 +646,31 @@ impl StrategyFactory {
                     Some(ref stderr) => {
                         let filename = match stderr {
                             Pattern::File(ref filename) => filename,
-                            Pattern::VariableText(ref text) => match block.bindings[text].source {
-                                Pattern::File(ref filename) => filename,
-                                Pattern::VariableText(_) => {
-                                    return Err(errors::InvokerFailure(
-                                        "The impossible happened: stderr is bound to a file that \
-                                         is mapped to an external file"
-                                            .to_string(),
-                                    ))
+                            Pattern::VariableText(ref text) => {
+                                match block.bindings.get(text).map(|binding| &binding.source) {
+                                    Some(Pattern::File(ref filename)) => filename,
+                                    None => {
+                                        return Err(errors::ConfigurationFailure(
+                                            "stderr of testlib must be redirected to a regular \
+                                             file, not to an artifact"
+                                                .to_string(),
+                                        ))
+                                    }
+                                    Some(Pattern::VariableText(_) | Pattern::Artifact(_)) => {
+                                        return Err(errors::InvokerFailure(
+                                            "The impossible happened: stderr is bound to a file \
+                                             that is mapped to an external file or an artifact"
+                                                .to_string(),
+                                        ))
+                                    }
                                 }
-                            },
+                            }
+                            Pattern::Artifact(_) => {
+                                return Err(errors::InvokerFailure(
+                                    "The impossible happened: stderr is redirected to an artifact"
+                                        .to_string(),
+                                ))
+                            }
                         };
                         let file_type = &self.files[filename];
                         match file_type {
@@ -248,7 +690,19 @@ impl StrategyFactory {
         // Figure out the correct order
         let mut readers_and_writer_by_file: HashMap<&'a str, (Vec<usize>, Option<usize>)> =
             HashMap::new();
+        let mut consumers_by_artifact: HashMap<&'a str, Vec<usize>> = HashMap::new();
         for (i, block) in self.blocks.iter().enumerate() {
+            for pattern in block
+                .bindings
+                .values()
+                .map(|binding| &binding.source)
+                .chain(block.argv.iter())
+                .chain(block.stdin.iter())
+            {
+                if let Pattern::Artifact(ref name) = pattern {
+                    consumers_by_artifact.entry(name).or_default().push(i);
+                }
+            }
             for (_, binding) in block.bindings.iter() {
                 if let Pattern::File(ref name) = binding.source {
                     let (readers, writer) = readers_and_writer_by_file.entry(name).or_default();
@@ -328,6 +782,56 @@ impl StrategyFactory {
             }
         }
 
+        // The producer of an artifact goes strictly before its consumers, because an artifact is
+        // only staged when the producer terminates
+        for (name, consumers) in consumers_by_artifact.iter() {
+            let producer = producer_by_artifact[name];
+            for consumer in consumers {
+                if *consumer == producer {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' consumes artifact @{name}, which it outputs itself; this is \
+                         not allowed",
+                        self.blocks[producer].name
+                    )));
+                }
+                necessarily_after[*consumer].push(producer);
+                necessarily_before[producer].push(*consumer);
+            }
+        }
+
+        // Validators go strictly before the user program
+        let validators: Vec<usize> = (0..self.blocks.len())
+            .filter(|block| matches!(self.blocks[*block].tactic, Tactic::Validator))
+            .collect();
+        let users: Vec<usize> = (0..self.blocks.len())
+            .filter(|block| matches!(self.blocks[*block].tactic, Tactic::User))
+            .collect();
+        for validator in validators.iter() {
+            for user in users.iter() {
+                necessarily_after[*user].push(*validator);
+                necessarily_before[*validator].push(*user);
+            }
+        }
+
+        // Scorers go strictly after the checks, so that they only score data the checks accepted
+        let checks: Vec<usize> = (0..self.blocks.len())
+            .filter(|block| {
+                matches!(
+                    self.blocks[*block].tactic,
+                    Tactic::Testlib | Tactic::Interactor
+                )
+            })
+            .collect();
+        let scorers: Vec<usize> = (0..self.blocks.len())
+            .filter(|block| matches!(self.blocks[*block].tactic, Tactic::Scorer))
+            .collect();
+        for scorer in scorers.iter() {
+            for check in checks.iter() {
+                necessarily_after[*scorer].push(*check);
+                necessarily_before[*check].push(*scorer);
+            }
+        }
+
         // Find strongly connected components
         fn dfs_scc1(
             u: usize,
@@ -380,6 +884,29 @@ impl StrategyFactory {
             }
         }
 
+        // Only possible if the validator reads what the user program writes
+        for validator in validators.iter() {
+            for user in users.iter() {
+                if component_of_block[*validator] == component_of_block[*user] {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Validator block '{}' depends on the user block '{}'; this is not allowed",
+                        self.blocks[*validator].name, self.blocks[*user].name
+                    )));
+                }
+            }
+        }
+        for scorer in scorers.iter() {
+            for check in checks.iter() {
+                if component_of_block[*scorer] == component_of_block[*check] {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Scorer block '{}' must run after block '{}', which depends on it; this \
+                         is not allowed",
+                        self.blocks[*scorer].name, self.blocks[*check].name
+                    )));
+                }
+            }
+        }
+
         // Ensure lack of races
         for (name, (readers, writer)) in readers_and_writer_by_file.iter() {
             let writer = writer.unwrap();
@@ -426,6 +953,77 @@ impl StrategyFactory {
             }
         }
 
+        // An interactor that doesn't talk to the user program has nothing to judge
+        let mut interactive_blocks = HashSet::new();
+        for component in components.iter() {
+            let mut interactors = component
+                .iter()
+                .filter(|block| matches!(self.blocks[**block].tactic, Tactic::Interactor));
+            let users: Vec<usize> = component
+                .iter()
+                .copied()
+                .filter(|block| matches!(self.blocks[*block].tactic, Tactic::User))
+                .collect();
+            if let Some(interactor) = interactors.next() {
+                if users.is_empty() {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Interactor block '{}' is not connected to the user program via pipes; \
+                         this is not allowed",
+                        self.blocks[*interactor].name
+                    )));
+                }
+                if let Some(other) = interactors.next() {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Interactor blocks '{}' and '{}' are executed concurrently; this is not \
+                         allowed",
+                        self.blocks[*interactor].name, self.blocks[*other].name
+                    )));
+                }
+                interactive_blocks.extend(users);
+            }
+        }
+
+        // The answers of an output-only submission stand in for what the user program prints, so the
+        // strategy must not expect it to do anything else
+        if user_program.output_only {
+            for (i, block) in self.blocks.iter().enumerate() {
+                if block.command != "user" {
+                    continue;
+                }
+                if interactive_blocks.contains(&i) {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Block '{}' interacts with the user program, so output-only submissions \
+                         cannot be judged",
+                        block.name
+                    )));
+                }
+                let writes_stdout_to_file = match block.stdout {
+                    Some(Pattern::File(ref name)) => matches!(self.files[name], FileType::Regular),
+                    _ => false,
+                };
+                if !writes_stdout_to_file || !block.outputs.is_empty() {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "The output of block '{}' is not just its stdout redirected to a file, so \
+                         output-only submissions cannot be judged",
+                        block.name
+                    )));
+                }
+            }
+        }
+
+        for (name, consumers) in consumers_by_artifact.iter() {
+            let producer = producer_by_artifact[name];
+            for consumer in consumers {
+                if component_of_block[*consumer] == component_of_block[producer] {
+                    return Err(errors::ConfigurationFailure(format!(
+                        "Artifact @{name} is output by block '{}' and consumed by block '{}', but \
+                         these blocks are executed concurrently; this is not allowed",
+                        self.blocks[producer].name, self.blocks[*consumer].name
+                    )));
+                }
+            }
+        }
+
         // Create invocable instances of the program for each block. This may create more than one
         // instance of a program, but this is reasonable: two instances of the same program may be
         // run concurrently
@@ -451,12 +1049,20 @@ impl StrategyFactory {
             }
             invocable_programs.push(
                 program
-                    .into_invocable(format!("{build_id}-block-{i}"))
+                    .into_invocable(format!("{build_id}-block-{i}"), &sandbox_config.limits)
                     .await?,
             );
         }
 
-        // Create cgroups
+        if let Some(ref network) = sandbox_config.network {
+            for program in invocable_programs.iter_mut() {
+                program.rootfs.connect_network(network).await?;
+            }
+        }
+
+        // Create cgroups. They are shared by all strategies on the core, and a strategy may be made
+        // while another submission is being judged on the core, so they are only configured by
+        // Strategy::invoke, see configure_cgroups
         for i in 0..self.blocks.len() {
             let dir = format!("/sys/fs/cgroup/sunwalker_root/cpu_{core}/block-{i}");
             std::fs::create_dir(&dir)
@@ -496,6 +1102,30 @@ impl StrategyFactory {
             written_files_by_block[writer].push(name.to_string());
         }
 
+        let canaries = canary::Canaries::snapshot(&invocable_programs)?;
+
+        // A runtime only passes the standard streams to the program, see Runtime in
+        // image/config.rs
+        let runtimes = std::iter::zip(self.blocks.iter(), invocable_programs.iter())
+            .map(|(block, program)| {
+                let limit = &invocation_limits[&block.name];
+                let program = &program.program;
+                if !program.runtime.is_empty()
+                    && !program.output_only
+                    && !program.diagnostic
+                    && block.argv.is_empty()
+                    && block.bindings.is_empty()
+                    && block.outputs.is_empty()
+                    && limit.trace.is_none()
+                    && !limit.crash_report
+                {
+                    RuntimeSlot::Pending
+                } else {
+                    RuntimeSlot::None
+                }
+            })
+            .collect();
+
         Ok(Strategy {
             files: self.files.clone(),
             blocks: self.blocks.clone(),
@@ -504,8 +1134,15 @@ impl StrategyFactory {
             writer_by_file,
             written_files_by_block,
             invocation_limits,
+            exit_codes: self.exit_codes.clone(),
+            interactive_blocks,
             core,
             build_id,
+            system_thp: verdict::ThpMode::read_system(),
+            canaries,
+            quarantined: false,
+            runtimes,
+            sandbox_config: sandbox_config.clone(),
         })
     }
 }
@@ -514,26 +1151,226 @@ impl Strategy {
     pub async fn invoke(
         &mut self,
         test_path: PathBuf,
+        cpu_time_budget: Option<std::time::Duration>,
+        seed: u64,
     ) -> Result<verdict::TestJudgementResult, errors::Error> {
+        if self.quarantined {
+            return Err(errors::InvokerFailure(
+                "The sandbox failed an integrity check and is quarantined".to_string(),
+            ));
+        }
+
+        self.configure_cgroups()?;
+
+        self.start_runtimes().await;
+
         let aux = format!("/tmp/sunwalker_invoker/aux/{}", self.build_id);
 
         std::fs::create_dir(&aux).with_context_invoker(|| {
             format!("Failed to create directory {aux} to start running a strategy")
         })?;
 
-        (StrategyRun {
+        let user_time_budget = UserTimeBudget::new(
+            self.blocks
+                .iter()
+                .filter(|block| matches!(block.tactic, Tactic::User))
+                .map(|block| self.invocation_limits[&block.name].cpu_time),
+            cpu_time_budget,
+        );
+        let result = (StrategyRun {
             strategy: self,
             aux,
             test_path,
+            user_time_budget,
+            seed,
             removed: false,
         })
         .invoke()
-        .await
+        .await;
+
+        // Checked even if the run failed, as an escape attempt may well break the strategy
+        let cgroups: Vec<String> = (0..self.blocks.len())
+            .map(|i| format!("/sys/fs/cgroup/sunwalker_root/cpu_{}/block-{i}", self.core))
+            .collect();
+        // The check failing for reasons of its own says nothing about the test, whose result stands
+        let issues = match self
+            .canaries
+            .verify(&self.invocable_programs, &cgroups)
+            .await
+        {
+            Ok(issues) => issues,
+            Err(e) => {
+                errors::log_throttled(
+                    "verify canaries",
+                    format!(
+                        "Failed to check the integrity of sandbox {}: {e:?}",
+                        self.build_id
+                    ),
+                );
+                return result;
+            }
+        };
+        if issues.is_empty() {
+            return result;
+        }
+
+        self.quarantined = true;
+        for program in self.invocable_programs.iter_mut() {
+            program.rootfs.taint();
+        }
+        let issues = issues.join("; ");
+        println!(
+            "Sandbox {} failed an integrity check and is quarantined: {issues}",
+            self.build_id
+        );
+        let mut result = result.unwrap_or_else(|_| verdict::TestJudgementResult {
+            verdict: verdict::TestVerdict::Ignored,
+            logs: HashMap::new(),
+            invocation_stats: HashMap::new(),
+            seed: Some(seed),
+            fingerprint: None,
+            retries: 0,
+            failed_block: None,
+            test_name: None,
+        });
+        result.verdict = verdict::TestVerdict::SecurityViolation(issues);
+        Ok(result)
+    }
+
+    // The block cgroups are shared by all strategies on the core, and the limits differ between
+    // sandboxes, so they are set before each test. The caller runs tests on the core exclusively,
+    // so nothing else runs in the cgroups at this point
+    fn configure_cgroups(&self) -> Result<(), errors::Error> {
+        for i in 0..self.blocks.len() {
+            let dir = format!("/sys/fs/cgroup/sunwalker_root/cpu_{}/block-{i}", self.core);
+            cgroups::set_cpu_weight(&dir, cgroups::TEST_CPU_WEIGHT)?;
+            cgroups::configure_memory(&dir)?;
+            cgroups::set_max_pids(&dir, self.sandbox_config.max_pids)?;
+            cgroups::set_io_limits(
+                &dir,
+                self.sandbox_config.io_read_bps,
+                self.sandbox_config.io_write_bps,
+            )?;
+        }
+        Ok(())
+    }
+
+    // CPU time that counts towards group time budgets, i.e. the time spent by user blocks
+    pub fn user_cpu_time(&self, result: &verdict::TestJudgementResult) -> std::time::Duration {
+        self.blocks
+            .iter()
+            .filter(|block| matches!(block.tactic, Tactic::User))
+            .filter_map(|block| result.invocation_stats.get(&block.name))
+            .map(|stat| stat.cpu_time)
+            .sum()
+    }
+
+    // Files produced by the submission rather than by checkers, interactors, etc.
+    pub fn user_output_files(&self) -> Vec<String> {
+        self.blocks
+            .iter()
+            .zip(&self.written_files_by_block)
+            .filter(|(block, _)| matches!(block.tactic, Tactic::User))
+            .flat_map(|(_, files)| files.iter().cloned())
+            .collect()
+    }
+
+    // The argv of the program of the block, before the arguments of the block are appended
+    fn program_argv(&self, block_id: usize) -> Vec<String> {
+        let program = &self.invocable_programs[block_id].program;
+        let mut argv = program.argv.clone();
+
+        // A VM is told how large its heap may be, see MemoryModel in image/config.rs
+        let memory_model = &program.memory_model;
+        if !memory_model.heap_flags.is_empty() && !argv.is_empty() {
+            let heap_mib = (self.invocation_limits[&self.blocks[block_id].name].memory as u64
+                * memory_model.heap_percent as u64
+                / 100
+                / (1024 * 1024))
+                .max(1)
+                .to_string();
+            argv.splice(
+                1..1,
+                memory_model
+                    .heap_flags
+                    .iter()
+                    .map(|flag| flag.replace('%', &heap_mib)),
+            );
+        }
+        argv
+    }
+
+    // Runtimes are started on the first test rather than when the strategy is made, and reset before
+    // every test, or started again if they die or can't be reset. One that fails to start is given
+    // up on
+    async fn start_runtimes(&mut self) {
+        for block_id in 0..self.blocks.len() {
+            match self.runtimes[block_id] {
+                RuntimeSlot::None => continue,
+                RuntimeSlot::Pending => {}
+                RuntimeSlot::Running(ref runtime) => {
+                    if !runtime.has_terminated() {
+                        match runtime.reset() {
+                            Ok(true) => continue,
+                            // The previous test modified the files the runtime was started with
+                            Ok(false) => {}
+                            Err(e) => {
+                                errors::log_throttled(
+                                    "reset runtime",
+                                    format!(
+                                        "Failed to reset the runtime of block '{}' of {}, so it \
+                                         is started again: {e:?}",
+                                        self.blocks[block_id].name, self.build_id
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            // The previous runtime is stopped before a new one is started in its place
+            if let RuntimeSlot::Running(runtime) =
+                std::mem::replace(&mut self.runtimes[block_id], RuntimeSlot::Pending)
+            {
+                runtime.stop().await;
+            }
+
+            let block = &self.blocks[block_id];
+            match runtime::Runtime::start(
+                &self.invocable_programs[block_id].program,
+                self.program_argv(block_id),
+                &self.invocation_limits[&block.name],
+                format!("{}-runtime-{block_id}", self.build_id),
+                self.core,
+                &self.sandbox_config,
+            )
+            .await
+            {
+                Ok(runtime) => self.runtimes[block_id] = RuntimeSlot::Running(runtime),
+                Err(e) => {
+                    errors::log_throttled(
+                        "start runtime",
+                        format!(
+                            "Failed to start the runtime of block '{}' of {}, so the program is \
+                             started on every test instead: {e:?}",
+                            block.name, self.build_id
+                        ),
+                    );
+                    self.runtimes[block_id] = RuntimeSlot::None;
+                }
+            }
+        }
     }
 }
 
 impl<'a> StrategyRun<'a> {
     async fn invoke(mut self) -> Result<verdict::TestJudgementResult, errors::Error> {
+        // Artifacts are staged here, so they are removed together with the rest of aux
+        let artifacts = format!("{}/artifacts", self.aux);
+        std::fs::create_dir(&artifacts).with_context_invoker(|| {
+            format!("Failed to create directory {artifacts} to start running a strategy")
+        })?;
+
         // Create files on filesystem and in memory
         let mut pipes: HashMap<String, (OwnedFd, OwnedFd)> = HashMap::new();
         for (name, file_type) in self.strategy.files.iter() {
@@ -542,29 +1379,20 @@ impl<'a> StrategyRun<'a> {
                     // Handled later
                 }
                 FileType::Pipe => {
-                    let path = format!("{}/{name}", self.aux);
-                    nix::unistd::mkfifo::<str>(
-                        &path,
-                        nix::sys::stat::Mode::from_bits_truncate(0600),
-                    )
-                    .with_context_invoker(|| {
-                        format!(
-                            "Failed to mkfifo {}/{name} to start running a strategy",
-                            self.aux
-                        )
-                    })?;
-                    std::os::unix::fs::chown(
-                        &path,
-                        Some(ids::EXTERNAL_USER_UID),
-                        Some(ids::EXTERNAL_USER_GID),
-                    )
-                    .with_context_invoker(|| format!("Failed to chown {path}"))?;
+                    let (rx, tx) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+                        .with_context_invoker(|| {
+                            format!("Failed to create pipe %{name} to start running a strategy")
+                        })?;
+                    pipes.insert(name.clone(), unsafe {
+                        (OwnedFd::from_raw_fd(tx), OwnedFd::from_raw_fd(rx))
+                    });
                 }
             }
         }
 
         // Run programs
         let mut verdict = verdict::TestVerdict::Accepted;
+        let mut failed_block = None;
         let mut invocation_stats = HashMap::new();
         let mut logs = HashMap::new();
 
@@ -592,20 +1420,34 @@ impl<'a> StrategyRun<'a> {
                         })?;
                         std::os::unix::fs::chown(
                             &path,
-                            Some(ids::EXTERNAL_USER_UID),
-                            Some(ids::EXTERNAL_USER_GID),
+                            Some(program.rootfs.external_user_uid()),
+                            Some(program.rootfs.external_user_gid()),
                         )
                         .with_context_invoker(|| format!("Failed to chown {path}"))?;
                     }
                 }
 
-                // Filesystem bindings
+                // The files artifacts are output to are created in advance, like regular files
+                for path in block.outputs.values() {
+                    let path = format!("{}/space/{path}", program.rootfs.overlay());
+                    std::fs::write(&path, "").with_context_invoker(|| {
+                        format!("Failed to touch file {path} to start running a strategy")
+                    })?;
+                    std::os::unix::fs::chown(
+                        &path,
+                        Some(program.rootfs.external_user_uid()),
+                        Some(program.rootfs.external_user_gid()),
+                    )
+                    .with_context_invoker(|| format!("Failed to chown {path}"))?;
+                }
+
+                // Filesystem bindings
                 for (filename, binding) in block.bindings.iter() {
                     let outer_path = self.resolve_outer_path(&binding.source, None)?;
                     let inner_path = format!("{}/space/{filename}", program.rootfs.overlay());
                     std::fs::write(&inner_path, "")
                         .with_context_invoker(|| format!("Failed to create {inner_path}"))?;
-                    system::bind_mount_opt(
+                    supervisor::bind_mount_opt(
                         &outer_path,
                         &inner_path,
                         if binding.writable {
@@ -614,13 +1456,17 @@ impl<'a> StrategyRun<'a> {
                             0
                         },
                     )
-                    .with_context_invoker(|| {
-                        format!("Failed to bind-mount {outer_path:?} to {inner_path}")
+                    .map_err(|e| {
+                        self.file_error(
+                            &outer_path,
+                            e,
+                            format!("Failed to bind-mount {outer_path:?} to {inner_path}"),
+                        )
                     })?;
                 }
 
                 // Binding via arguments
-                let mut patched_argv = program.program.argv.clone();
+                let mut patched_argv = self.strategy.program_argv(*block_id);
                 for (i, arg) in block.argv.iter().enumerate() {
                     if let Pattern::VariableText(ref text) = arg {
                         if !text.contains('\0') {
@@ -633,13 +1479,32 @@ impl<'a> StrategyRun<'a> {
                     let inner_path = format!("{}/space/.arg-{i}", program.rootfs.overlay());
                     std::fs::write(&inner_path, "")
                         .with_context_invoker(|| format!("Failed to create {inner_path}"))?;
-                    system::bind_mount_opt(&outer_path, &inner_path, system::MS_RDONLY)
-                        .with_context_invoker(|| {
-                            format!("Failed to bind-mount {outer_path:?} to {inner_path}")
+                    supervisor::bind_mount_opt(&outer_path, &inner_path, system::MS_RDONLY)
+                        .map_err(|e| {
+                            self.file_error(
+                                &outer_path,
+                                e,
+                                format!("Failed to bind-mount {outer_path:?} to {inner_path}"),
+                            )
                         })?;
 
                     patched_argv.push(format!("/space/.arg-{i}"));
                 }
+                let response_file_path = format!("{}/space/.args", program.rootfs.overlay());
+                let patched_argv = args::fit(
+                    patched_argv,
+                    program.rootfs.env(),
+                    block
+                        .response_file
+                        .then_some((Path::new(&response_file_path), "/space/.args")),
+                )
+                .map_err(|e| match e {
+                    errors::ConfigurationFailure(message) => errors::ConfigurationFailure(format!(
+                        "Block '{}' cannot be started: {message}",
+                        block.name
+                    )),
+                    e => e,
+                })?;
 
                 // Prepare streams
                 let mut stdin: Option<std::fs::File> = None;
@@ -676,26 +1541,103 @@ impl<'a> StrategyRun<'a> {
                             .read(!writable)
                             .write(writable)
                             .open(&outer_path)
-                            .with_context_invoker(|| {
-                                format!("Failed to redirect {name} to {outer_path:?}")
+                            .map_err(|e| {
+                                self.file_error(
+                                    &outer_path,
+                                    e,
+                                    format!("Failed to redirect {name} to {outer_path:?}"),
+                                )
                             })?,
                     );
                 }
 
-                processes.push(sandbox::run_isolated(
+                // Output-only submissions are not run: the answer is written where the output would
+                // go, and the checker takes it from there. A missing answer is an empty output
+                if program.program.output_only {
+                    let mut stdout = stdout.unwrap();
+                    let answer = self
+                        .test_path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(|name| name.parse().ok())
+                        .and_then(|test| program.program.answer_to(test));
+                    if let Some(answer) = answer {
+                        let mut answer = std::fs::File::open(&answer)
+                            .with_context_invoker(|| format!("Failed to open {answer:?}"))?;
+                        std::io::copy(&mut answer, &mut stdout)
+                            .context_invoker("Failed to copy the answer")?;
+                    }
+                    processes.push(
+                        futures::future::ready(Ok((
+                            verdict::TestVerdict::Accepted,
+                            verdict::InvocationStat {
+                                real_time: std::time::Duration::ZERO,
+                                cpu_time: std::time::Duration::ZERO,
+                                user_time: std::time::Duration::ZERO,
+                                sys_time: std::time::Duration::ZERO,
+                                memory: 0,
+                                read_bytes: 0,
+                                written_bytes: 0,
+                                thp: None,
+                            },
+                            DebugLogs::default(),
+                        )))
+                        .boxed_local(),
+                    );
+                    continue;
+                }
+
+                // A copy of the program is forked by the runtime, which is in a sandbox of its
+                // own, so it is watched from the worker
+                if let RuntimeSlot::Running(ref runtime) = self.strategy.runtimes[*block_id] {
+                    let connection = runtime.connect()?;
+                    let cgroup = CgroupHandle::open(runtime.copies_cgroup().as_ref())
+                        .context_invoker("Failed to open runtime cgroup")?;
+                    let invocation_limit = self.invocation_limit(*block_id);
+                    let system_thp = self.strategy.system_thp;
+                    let (stdin, stdout, stderr) =
+                        (stdin.unwrap(), stdout.unwrap(), stderr.unwrap());
+                    processes.push(
+                        async move {
+                            tokio::task::spawn_blocking(move || {
+                                execute_in_runtime(
+                                    connection,
+                                    stdin,
+                                    stdout,
+                                    stderr,
+                                    invocation_limit,
+                                    cgroup,
+                                    system_thp,
+                                )
+                            })
+                            .await
+                            .context_invoker("Failed to run the program in the runtime")?
+                        }
+                        .boxed_local(),
+                    );
+                    continue;
+                }
+
+                let process = sandbox::run_isolated(
                     Box::new(
                         execute
                             .bind(patched_argv)
+                            .bind(program.program.syscalls.clone())
                             .bind(stdin.unwrap())
                             .bind(stdout.unwrap())
                             .bind(stderr.unwrap())
-                            .bind(
-                                self.strategy
-                                    .invocation_limits
-                                    .get(&block.name)
-                                    .unwrap()
-                                    .clone(),
-                            )
+                            .bind(self.invocation_limit(*block_id))
+                            // Only problem-supplied programs get the seed, so that solutions can't
+                            // depend on it
+                            .bind(match block.tactic {
+                                Tactic::User => None,
+                                Tactic::Testlib
+                                | Tactic::Filter
+                                | Tactic::Interactor
+                                | Tactic::Validator
+                                | Tactic::Scorer => Some(self.seed),
+                            })
+                            .bind(!matches!(block.tactic, Tactic::User))
                             // Open the cgroup files here because /sys/fs/cgroup is not mounted
                             // inside the sandbox and is owned by real root, not fake root
                             .bind(
@@ -707,10 +1649,22 @@ impl<'a> StrategyRun<'a> {
                                     .as_ref(),
                                 )
                                 .context_invoker("Failed to open user cgroup")?,
-                            ),
+                            )
+                            .bind(self.strategy.system_thp)
+                            .bind(program.program.diagnostic),
                     ),
                     &program.rootfs,
-                ));
+                    Some(HEARTBEAT_TIMEOUT),
+                );
+                processes.push(process.boxed_local());
+            }
+
+            // The programs hold the only copies of the pipes of the component from now on, so that a
+            // reader sees EOF when the writer terminates, e.g. when one side of an interaction dies
+            for block_id in component.iter() {
+                for name in self.strategy.written_files_by_block[*block_id].iter() {
+                    pipes.remove(name);
+                }
             }
 
             let mut process_results = Vec::new();
@@ -718,14 +1672,57 @@ impl<'a> StrategyRun<'a> {
                 process_results.push(res?);
             }
 
+            // A VM that runs out of heap exits with an error of its own rather than being killed by
+            // the cgroup
+            for (block_id, (test_verdict, _stat, _debug_logs)) in
+                std::iter::zip(component.iter(), process_results.iter_mut())
+            {
+                let memory_model = &self.strategy.invocable_programs[*block_id]
+                    .program
+                    .memory_model;
+                let code = match test_verdict {
+                    verdict::TestVerdict::RuntimeError(verdict::ExitStatus::ExitCode(code)) => {
+                        *code as i64
+                    }
+                    verdict::TestVerdict::RuntimeError(verdict::ExitStatus::Signal(signal)) => {
+                        -(*signal as i64)
+                    }
+                    _ => continue,
+                };
+                if memory_model.oom_exit_codes.contains(&code) {
+                    *test_verdict = verdict::TestVerdict::MemoryLimitExceeded;
+                }
+            }
+
             // Collect logs and stats
-            for (block_id, (_test_verdict, stat)) in
+            for (block_id, (_test_verdict, stat, debug_logs)) in
                 std::iter::zip(component.iter(), process_results.iter())
             {
                 let block = &self.strategy.blocks[*block_id];
                 let program = &self.strategy.invocable_programs[*block_id];
 
-                invocation_stats.insert(block.name.clone(), stat.clone());
+                // Checkers are kept apart, so that their stats are not mistaken for those of the
+                // submission
+                let stat_key = match block.tactic {
+                    Tactic::Testlib | Tactic::Scorer => format!("{}.checker", block.name),
+                    _ => block.name.clone(),
+                };
+                invocation_stats.insert(stat_key, stat.clone());
+
+                self.user_time_budget.charge(&block.tactic, stat.cpu_time);
+
+                if let Some(ref syscalls) = debug_logs.syscalls {
+                    logs.insert(format!("{}.syscalls", block.name), syscalls.clone());
+                }
+                if let Some(ref crash) = debug_logs.crash {
+                    logs.insert(format!("{}.crash", block.name), crash.clone());
+                }
+                if program.program.diagnostic {
+                    let reports = read_sanitizer_reports(&program.rootfs)?;
+                    if !reports.is_empty() {
+                        logs.insert(format!("{}.sanitizer", block.name), reports);
+                    }
+                }
 
                 for name in self.strategy.written_files_by_block[*block_id].iter() {
                     let file_type = self.strategy.files[name];
@@ -734,10 +1731,74 @@ impl<'a> StrategyRun<'a> {
                         logs.insert(name.to_string(), data);
                     }
                 }
+
+                // Stage artifacts. The copy is owned by root, so consumers cannot modify it even
+                // though it is readable by everyone.
+                for (name, path) in block.outputs.iter() {
+                    let data = program.rootfs.read(&format!("/space/{path}"))?;
+                    let staged_path = format!("{artifacts}/{name}");
+                    std::fs::write(&staged_path, &data)
+                        .with_context_invoker(|| format!("Failed to stage artifact @{name}"))?;
+                    std::fs::set_permissions(
+                        &staged_path,
+                        std::os::unix::fs::PermissionsExt::from_mode(0o644),
+                    )
+                    .with_context_invoker(|| format!("Failed to chmod {staged_path}"))?;
+                    // Artifacts may be large binary blobs, so only a summary goes to the logs
+                    let hash = signing::sha256_hex(&data);
+                    let summary = format!("{} bytes, sha256 {hash}\n", data.len());
+                    logs.insert(name.to_string(), summary.into_bytes());
+                }
+            }
+
+            // A broken test says nothing about the submission. Validators are in components of their
+            // own, before the user program
+            for (block_id, (test_verdict, _stat, _debug_logs)) in
+                std::iter::zip(component.iter(), process_results.iter())
+            {
+                let block = &self.strategy.blocks[*block_id];
+                if let Tactic::Validator = block.tactic {
+                    match *test_verdict {
+                        verdict::TestVerdict::Accepted => {}
+                        _ => {
+                            verdict = verdict::TestVerdict::InvalidTest(format!(
+                                "Validator '{}' rejected the test with verdict {}",
+                                block.name,
+                                test_verdict.to_short_string(),
+                            ));
+                            failed_block = Some(block.name.clone());
+                            break 'comps;
+                        }
+                    }
+                }
+            }
+
+            // When one side of an interaction dies, the other one usually fails too, so the cause has
+            // to be told from the consequence
+            if let Some(position) = component.iter().position(|block_id| {
+                matches!(self.strategy.blocks[*block_id].tactic, Tactic::Interactor)
+            }) {
+                let interactor = component[position];
+                let interactor_verdict =
+                    self.testlib_verdict(interactor, &process_results[position].0)?;
+                let user_verdicts = std::iter::zip(component.iter(), process_results.iter())
+                    .filter(|(block_id, _)| {
+                        matches!(self.strategy.blocks[**block_id].tactic, Tactic::User)
+                    })
+                    .map(|(block_id, (test_verdict, _stat, _debug_logs))| {
+                        (*block_id, test_verdict)
+                    });
+                if let Some((interaction_verdict, block_id)) =
+                    resolve_interaction(interactor, interactor_verdict, user_verdicts)
+                {
+                    verdict = interaction_verdict;
+                    failed_block = Some(self.strategy.blocks[block_id].name.clone());
+                    break 'comps;
+                }
             }
 
             // Collect user exit codes and exit immediately on failure
-            for (block_id, (test_verdict, _stat)) in
+            for (block_id, (test_verdict, _stat, _debug_logs)) in
                 std::iter::zip(component.iter(), process_results.iter())
             {
                 let block = &self.strategy.blocks[*block_id];
@@ -746,59 +1807,46 @@ impl<'a> StrategyRun<'a> {
                         verdict::TestVerdict::Accepted => {}
                         _ => {
                             verdict = test_verdict.clone();
+                            failed_block = Some(block.name.clone());
                             break 'comps;
                         }
                     }
                 }
             }
 
-            // Collect testlib exit codes and exit immediately on failure
-            for (block_id, (test_verdict, _stat)) in
+            // A failing filter is a problem in the problem, not in the solution
+            for (block_id, (test_verdict, _stat, _debug_logs)) in
                 std::iter::zip(component.iter(), process_results.iter())
             {
                 let block = &self.strategy.blocks[*block_id];
-                if let Tactic::Testlib = block.tactic {
-                    let program = &self.strategy.invocable_programs[*block_id];
-
-                    let filename = match block.stderr.as_ref().unwrap() {
-                        Pattern::File(ref filename) => filename,
-                        Pattern::VariableText(ref text) => {
-                            // It was asserted above that 'text' is an internal path
-                            match block.bindings[text].source {
-                                Pattern::File(ref filename) => filename,
-                                Pattern::VariableText(_) => {
-                                    return Err(errors::InvokerFailure(
-                                        "The impossible happened: stderr is bound to a file that \
-                                         is mapped to an external file"
-                                            .to_string(),
-                                    ))
-                                }
-                            }
-                        }
-                    };
-                    let testlib_stderr =
-                        program.rootfs.read(&format!("/space/.file-{filename}"))?;
-
-                    let exit_status = match *test_verdict {
-                        verdict::TestVerdict::Accepted => verdict::ExitStatus::ExitCode(0),
-                        verdict::TestVerdict::RuntimeError(exit_status) => exit_status,
+                if let Tactic::Filter = block.tactic {
+                    match *test_verdict {
+                        verdict::TestVerdict::Accepted => {}
                         _ => {
                             verdict = verdict::TestVerdict::Bug(format!(
-                                "Testlib task '{}' failed with verdict {}",
+                                "Filter task '{}' failed with verdict {}",
                                 block.name,
                                 test_verdict.to_short_string(),
                             ));
+                            failed_block = Some(block.name.clone());
                             break 'comps;
                         }
-                    };
-
-                    let current_verdict =
-                        verdict::TestVerdict::from_testlib(exit_status, &testlib_stderr);
+                    }
+                }
+            }
 
+            // Collect testlib exit codes and exit immediately on failure
+            for (block_id, (test_verdict, _stat, _debug_logs)) in
+                std::iter::zip(component.iter(), process_results.iter())
+            {
+                let block = &self.strategy.blocks[*block_id];
+                if let Tactic::Testlib | Tactic::Scorer = block.tactic {
+                    let current_verdict = self.testlib_verdict(*block_id, test_verdict)?;
                     match current_verdict {
                         verdict::TestVerdict::Accepted => (),
                         _ => {
                             verdict = current_verdict;
+                            failed_block = Some(block.name.clone());
                             break 'comps;
                         }
                     }
@@ -819,9 +1867,110 @@ impl<'a> StrategyRun<'a> {
             verdict,
             logs,
             invocation_stats,
+            seed: Some(self.seed),
+            fingerprint: None,
+            retries: 0,
+            failed_block,
+            test_name: None,
         })
     }
 
+    fn invocation_limit(&self, block_id: usize) -> verdict::InvocationLimit {
+        let block = &self.strategy.blocks[block_id];
+        let mut limit = self.strategy.invocation_limits[&block.name].clone();
+        limit.cpu_time = self.user_time_budget.limit(&block.tactic, limit.cpu_time);
+        if self.strategy.interactive_blocks.contains(&block_id) && limit.idleness.is_none() {
+            limit.idleness = Some(INTERACTIVE_IDLENESS_LIMIT);
+        }
+        limit
+    }
+
+    // Interprets the exit code of a problem-supplied program that reports the verdict the way
+    // testlib does, with comments in stderr
+    fn testlib_verdict(
+        &self,
+        block_id: usize,
+        test_verdict: &verdict::TestVerdict,
+    ) -> Result<verdict::TestVerdict, errors::Error> {
+        let block = &self.strategy.blocks[block_id];
+        let program = &self.strategy.invocable_programs[block_id];
+
+        let filename = match block.stderr.as_ref().unwrap() {
+            Pattern::File(ref filename) => filename,
+            Pattern::VariableText(ref text) => {
+                // It was asserted above that 'text' is an internal path
+                match block.bindings[text].source {
+                    Pattern::File(ref filename) => filename,
+                    Pattern::VariableText(_) | Pattern::Artifact(_) => {
+                        return Err(errors::InvokerFailure(
+                            "The impossible happened: stderr is bound to a file that is mapped to \
+                             an external file or an artifact"
+                                .to_string(),
+                        ))
+                    }
+                }
+            }
+            Pattern::Artifact(_) => {
+                return Err(errors::InvokerFailure(
+                    "The impossible happened: stderr is redirected to an artifact".to_string(),
+                ))
+            }
+        };
+        let testlib_stderr = program.rootfs.read(&format!("/space/.file-{filename}"))?;
+
+        let exit_status = match *test_verdict {
+            verdict::TestVerdict::Accepted => verdict::ExitStatus::ExitCode(0),
+            verdict::TestVerdict::RuntimeError(exit_status) => exit_status,
+            // The checker could not decide, which is a failure of the checker, not a bug in the
+            // invoker. Crashes are reported as Bug by from_checker
+            verdict::TestVerdict::TimeLimitExceeded
+            | verdict::TestVerdict::MemoryLimitExceeded
+            | verdict::TestVerdict::IdlenessLimitExceeded
+            | verdict::TestVerdict::OutputLimitExceeded => {
+                return Ok(verdict::TestVerdict::CheckerFailed);
+            }
+            _ => {
+                return Ok(verdict::TestVerdict::Bug(format!(
+                    "Testlib task '{}' failed with verdict {}",
+                    block.name,
+                    test_verdict.to_short_string(),
+                )));
+            }
+        };
+
+        Ok(verdict::TestVerdict::from_checker(
+            &self.strategy.exit_codes,
+            exit_status,
+            &testlib_stderr,
+        ))
+    }
+
+    // Test files and the artifacts of problem-supplied programs live in the problem cache, which
+    // may have been damaged, e.g. by an interrupted download or a disk failure. Failing to access
+    // them is reported separately so that the invoker can re-fetch the problem and retry the test.
+    // The same goes for executing a problem-supplied program, see executor_worker.
+    fn file_error(
+        &self,
+        outer_path: &std::path::Path,
+        e: std::io::Error,
+        message: String,
+    ) -> errors::Error {
+        let in_problem_cache = outer_path.starts_with(&self.test_path)
+            || std::iter::zip(
+                self.strategy.blocks.iter(),
+                self.strategy.invocable_programs.iter(),
+            )
+            .any(|(block, program)| {
+                !matches!(block.tactic, Tactic::User)
+                    && outer_path.starts_with(&program.program.artifacts_path)
+            });
+        if in_problem_cache && matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::EIO)) {
+            errors::ProblemCacheFailure(format!("{message}: {e}"))
+        } else {
+            e.context_invoker(&message)
+        }
+    }
+
     fn resolve_outer_path(
         &self,
         pat: &Pattern,
@@ -841,6 +1990,7 @@ impl<'a> StrategyRun<'a> {
                     Ok(format!("{}/{name}", self.aux).into())
                 }
             }
+            Pattern::Artifact(ref name) => Ok(format!("{}/artifacts/{name}", self.aux).into()),
             Pattern::VariableText(ref text) => {
                 if text.contains('\0') {
                     if !text.starts_with("\0test\0") || text.matches('\0').count() > 2 {
@@ -864,7 +2014,10 @@ impl Drop for StrategyRun<'_> {
     fn drop(&mut self) {
         if !self.removed {
             if let Err(e) = std::fs::remove_dir_all(&self.aux) {
-                println!("Failed to clean up {} in drop(): {e:?}", self.aux);
+                errors::log_throttled(
+                    "clean up strategy run",
+                    format!("Failed to clean up {} in drop(): {e:?}", self.aux),
+                );
             }
         }
     }
@@ -916,6 +2069,25 @@ impl std::ops::Sub for CgroupCpuStat {
 struct CgroupHandle {
     cgroup_procs: std::fs::File,
     cpu_stat: std::fs::File,
+    memory_max: std::fs::File,
+    memory_events: std::fs::File,
+    io_stat: std::fs::File,
+}
+
+struct CgroupIoStat {
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl std::ops::Sub for CgroupIoStat {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            // A device may disappear from io.stat along with its counters
+            read_bytes: self.read_bytes.saturating_sub(rhs.read_bytes),
+            written_bytes: self.written_bytes.saturating_sub(rhs.written_bytes),
+        }
+    }
 }
 
 impl CgroupHandle {
@@ -927,9 +2099,62 @@ impl CgroupHandle {
                 .context_invoker("Failed to open cgroup.procs")?,
             cpu_stat: std::fs::File::open(path.join("cpu.stat"))
                 .context_invoker("Failed to open cpu.stat")?,
+            memory_max: std::fs::File::options()
+                .write(true)
+                .open(path.join("memory.max"))
+                .context_invoker("Failed to open memory.max")?,
+            memory_events: std::fs::File::open(path.join("memory.events"))
+                .context_invoker("Failed to open memory.events")?,
+            io_stat: std::fs::File::open(path.join("io.stat"))
+                .context_invoker("Failed to open io.stat")?,
         })
     }
 
+    // Summed over all devices
+    fn io_stat(&mut self) -> Result<CgroupIoStat, errors::Error> {
+        self.io_stat
+            .rewind()
+            .context_invoker("Failed to rewind io.stat")?;
+
+        let mut buf = String::new();
+        self.io_stat
+            .read_to_string(&mut buf)
+            .context_invoker("Failed to read io.stat")?;
+
+        let mut stat = CgroupIoStat {
+            read_bytes: 0,
+            written_bytes: 0,
+        };
+
+        // Each line is "$MAJ:$MIN key=value key=value ..."
+        for line in buf.lines() {
+            for field in line.split_ascii_whitespace().skip(1) {
+                let (target, value) = match field.split_once('=') {
+                    Some(("rbytes", value)) => (&mut stat.read_bytes, value),
+                    Some(("wbytes", value)) => (&mut stat.written_bytes, value),
+                    _ => continue,
+                };
+                *target += value
+                    .parse::<u64>()
+                    .context_invoker("Invalid io.stat format")?;
+            }
+        }
+
+        Ok(stat)
+    }
+
+    fn set_memory_limit(&mut self, limit: usize) -> Result<(), errors::Error> {
+        self.memory_max
+            .write(format!("{limit}\n").as_bytes())
+            .context_invoker("Failed to write to memory.max")?;
+        Ok(())
+    }
+
+    // The number of processes killed by the OOM killer since the cgroup was created
+    fn oom_kills(&mut self) -> Result<u64, errors::Error> {
+        cgroups::oom_kills(&mut self.memory_events)
+    }
+
     fn add_process(&mut self, pid: libc::pid_t) -> Result<(), errors::Error> {
         self.cgroup_procs
             .write(format!("{pid}\n").as_bytes())
@@ -979,41 +2204,175 @@ impl CgroupHandle {
     }
 }
 
+// Returns the verdict along with the block it is attributed to, or None if the interaction went
+// well and judging should go on
+fn resolve_interaction<'a>(
+    interactor: usize,
+    interactor_verdict: verdict::TestVerdict,
+    user_verdicts: impl Iterator<Item = (usize, &'a verdict::TestVerdict)> + Clone,
+) -> Option<(verdict::TestVerdict, usize)> {
+    // The interactor is trusted: if it failed, nothing the user program did matters
+    if let verdict::TestVerdict::Bug(_) | verdict::TestVerdict::CheckerFailed = interactor_verdict {
+        return Some((interactor_verdict, interactor));
+    }
+
+    // A user program that ran out of resources stopped talking, so the interactor saw EOF and
+    // likely reported a wrong answer. The limit is the cause
+    if let Some((block_id, user_verdict)) = user_verdicts.clone().find(|(_, user_verdict)| {
+        matches!(
+            user_verdict,
+            verdict::TestVerdict::TimeLimitExceeded
+                | verdict::TestVerdict::MemoryLimitExceeded
+                | verdict::TestVerdict::IdlenessLimitExceeded
+                | verdict::TestVerdict::OutputLimitExceeded
+        )
+    }) {
+        return Some((user_verdict.clone(), block_id));
+    }
+
+    // Conversely, once the interactor gives up on a wrong answer and exits, the user program may
+    // crash writing to the closed pipe
+    if !matches!(interactor_verdict, verdict::TestVerdict::Accepted) {
+        return Some((interactor_verdict, interactor));
+    }
+
+    user_verdicts
+        .find(|(_, user_verdict)| !matches!(user_verdict, verdict::TestVerdict::Accepted))
+        .map(|(block_id, user_verdict)| (user_verdict.clone(), block_id))
+}
+
+// Produced by the tracer when it is enabled for the invocation
+#[derive(Default, Object)]
+struct DebugLogs {
+    syscalls: Option<Vec<u8>>,
+    // Empty crash reports, i.e. when the program did not crash, are not attached
+    crash: Option<Vec<u8>>,
+}
+
+// Inside the sandbox. Each process of the program writes its report to a file of its own, with the
+// PID appended to the name
+const SANITIZER_LOG_PATH: &str = "/space/.sanitizer";
+
+// The reports of all processes of the program, in the order of their PIDs
+fn read_sanitizer_reports(rootfs: &sandbox::RootFS) -> Result<Vec<u8>, errors::Error> {
+    let (directory, prefix) = SANITIZER_LOG_PATH.rsplit_once('/').unwrap();
+    let directory = format!("{}{directory}", rootfs.overlay());
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&directory)
+        .with_context_invoker(|| format!("Failed to list {directory}"))?
+    {
+        let entry = entry.with_context_invoker(|| format!("Failed to list {directory}"))?;
+        if let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|pid| pid.parse::<u64>().ok())
+        {
+            names.push((pid, entry.file_name()));
+        }
+    }
+    names.sort();
+
+    let mut reports = Vec::new();
+    for (_, name) in names {
+        reports.extend(rootfs.read(&format!("/space/{}", name.to_string_lossy()))?);
+    }
+    Ok(reports)
+}
+
+fn create_memfd(name: &str) -> Result<std::fs::File, errors::Error> {
+    let fd = nix::sys::memfd::memfd_create(
+        &CString::new(name).unwrap(),
+        nix::sys::memfd::MemFdCreateFlag::MFD_CLOEXEC,
+    )
+    .with_context_invoker(|| format!("Failed to create memfd for {name}"))?;
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+fn read_memfd(file: &mut std::fs::File) -> Result<Vec<u8>, errors::Error> {
+    let mut data = Vec::new();
+    file.rewind().context_invoker("Failed to rewind memfd")?;
+    file.read_to_end(&mut data)
+        .context_invoker("Failed to read memfd")?;
+    Ok(data)
+}
+
 #[multiprocessing::entrypoint]
 fn execute(
     argv: Vec<String>,
+    syscalls: Vec<String>,
     stdin: std::fs::File,
     stdout: std::fs::File,
     stderr: std::fs::File,
     invocation_limit: verdict::InvocationLimit,
+    seed: Option<u64>,
+    problem_program: bool,
     mut cgroup: CgroupHandle,
-) -> Result<(verdict::TestVerdict, verdict::InvocationStat), errors::Error> {
+    system_thp: Option<verdict::ThpMode>,
+    sanitized: bool,
+) -> Result<
+    (verdict::TestVerdict, verdict::InvocationStat, DebugLogs),
+    errors::Error,
+> {
     // Start process
     let (mut ours, theirs) =
-        multiprocessing::duplex().context_invoker("Failed to create a pipe")?;
+        multiprocessing::duplex().context_multiprocessing("Failed to create a pipe")?;
+
+    // The tracer writes the syscall log and the crash report here. A memfd is not visible in the
+    // sandbox filesystem, so the program can't tamper with them
+    let mut trace_log = match invocation_limit.trace {
+        Some(_) => Some(create_memfd("trace")?),
+        None => None,
+    };
+    let mut crash_log = if invocation_limit.crash_report {
+        Some(create_memfd("crash")?)
+    } else {
+        None
+    };
 
     let proc = executor_worker
         .spawn(
             argv,
+            syscalls,
             stdin,
-            stdout,
-            stderr,
+            stdout
+                .try_clone()
+                .context_invoker("Failed to dup(2) stdout")?,
+            stderr
+                .try_clone()
+                .context_invoker("Failed to dup(2) stderr")?,
             theirs,
-            invocation_limit.cpu_time,
+            invocation_limit.clone(),
+            seed,
+            problem_program,
+            trace_log
+                .as_ref()
+                .map(|log| log.try_clone())
+                .transpose()
+                .context_invoker("Failed to dup(2) the syscall log")?,
+            crash_log
+                .as_ref()
+                .map(|log| log.try_clone())
+                .transpose()
+                .context_invoker("Failed to dup(2) the crash report")?,
+            sanitized,
         )
-        .context_invoker("Failed to spawn the child")?;
+        .context_multiprocessing("Failed to spawn the child")?;
     let pid = proc.id();
 
     // Acquire pidfd for the process. This is safe because the process hasn't been awaited yet. We
-    // prefer younger pidfd to older signalfd because our process is PID 1 and therefore reaps all
-    // orphan processes, so SIGCHLD may fire for a process that was not our direct descendant.
+    // prefer younger pidfd to older signalfd because SIGCHLD may fire for a process other than this
+    // one.
     let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } as RawFd;
     if pidfd == -1 {
         return Err(std::io::Error::last_os_error())
             .context_invoker("Failed to open pidfd for child process");
     }
 
-    // Apply cgroup limits
+    // Apply cgroup limits. The cgroup is shared by all invocations of the block, and the memory
+    // limit may differ between them
+    cgroup.set_memory_limit(invocation_limit.memory)?;
     cgroup
         .add_process(pid)
         .context_invoker("Failed to move the child to user cgroup")?;
@@ -1021,6 +2380,8 @@ fn execute(
     // Acquire previous cgroup stats. We reuse cgroups across tests and even across submissions, so
     // we can't assume the stats are at zero at this moment.
     let cpu_stat_before = cgroup.cpu_stat()?;
+    let oom_kills_before = cgroup.oom_kills()?;
+    let io_stat_before = cgroup.io_stat()?;
 
     // Measure time. It would be slightly before execve, but it should not be a big problem
     let start = std::time::Instant::now();
@@ -1031,7 +2392,7 @@ fn execute(
         // on the pipe, i.e. a preparation failure
         return Err(ours
             .recv()
-            .context_invoker("Failed to read an error from the child")?
+            .context_multiprocessing("Failed to read an error from the child")?
             .context_invoker("The child terminated preemptively but did not report any error")?);
     }
 
@@ -1039,11 +2400,183 @@ fn execute(
     // pipe was closed automatically because it's CLOEXEC.
     if let Some(e) = ours
         .recv()
-        .context_invoker("Failed to read an error from the child")?
+        .context_multiprocessing("Failed to read an error from the child")?
     {
-        return Err(e.context_invoker("Child returned an error"));
+        return Err(match e {
+            errors::ProblemCacheFailure(_) => e,
+            e => e.context_invoker("Child returned an error"),
+        });
+    }
+
+    let (real_time_timeout, idleness_timeout) = watch(
+        pidfd,
+        || {
+            // Sending a signal to a zombie process is fine, so races are not a problem
+            nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGKILL)
+                .context_invoker("Failed to kill the process")
+        },
+        &mut cgroup,
+        cpu_stat_before,
+        &invocation_limit,
+        start,
+    )?;
+
+    // Collect real time statistics
+    let real_time = start.elapsed();
+
+    // Collect current stats; we will later compute the difference between the current stats and the
+    // previous stats
+    let cpu_stat = cgroup.cpu_stat()? - cpu_stat_before;
+    let io_stat = cgroup.io_stat()? - io_stat_before;
+
+    let wait_status = nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(pid), None)
+        .context_invoker("Failed to waitpid for process")?;
+
+    // The OOM killer sends SIGKILL, which is indistinguishable from any other SIGKILL by the exit
+    // status alone
+    let memory_limit_exceeded = cgroup.oom_kills()? > oom_kills_before;
+
+    let test_verdict = verdict_of(
+        wait_status,
+        &invocation_limit,
+        cpu_stat.total,
+        real_time,
+        idleness_timeout || real_time_timeout,
+        memory_limit_exceeded,
+        [&stdout, &stderr],
+    )?;
+
+    let mut debug_logs = DebugLogs::default();
+    if let Some(ref mut log) = trace_log {
+        debug_logs.syscalls = Some(read_memfd(log)?);
+    }
+    if let Some(ref mut log) = crash_log {
+        debug_logs.crash = Some(read_memfd(log)?).filter(|report| !report.is_empty());
     }
 
+    Ok((
+        test_verdict,
+        verdict::InvocationStat {
+            real_time,
+            cpu_time: cpu_stat.total,
+            user_time: cpu_stat.user,
+            sys_time: cpu_stat.system,
+            memory: 0, // TODO
+            read_bytes: io_stat.read_bytes,
+            written_bytes: io_stat.written_bytes,
+            thp: match invocation_limit.thp {
+                verdict::ThpPolicy::System => system_thp,
+                verdict::ThpPolicy::Disabled => Some(verdict::ThpMode::Never),
+            },
+        },
+        debug_logs,
+    ))
+}
+
+// The counterpart of execute for blocks with a runtime, see Runtime in image/config.rs. Runs in the
+// worker, as the copy of the program is forked by the runtime rather than started in the sandbox
+fn execute_in_runtime(
+    mut runtime: runtime::Connection,
+    stdin: std::fs::File,
+    stdout: std::fs::File,
+    stderr: std::fs::File,
+    invocation_limit: verdict::InvocationLimit,
+    mut cgroup: CgroupHandle,
+    system_thp: Option<verdict::ThpMode>,
+) -> Result<(verdict::TestVerdict, verdict::InvocationStat, DebugLogs), errors::Error> {
+    // Only the copies are in the cgroup, see Runtime
+    cgroup.set_memory_limit(invocation_limit.memory)?;
+
+    let cpu_stat_before = cgroup.cpu_stat()?;
+    let oom_kills_before = cgroup.oom_kills()?;
+    let io_stat_before = cgroup.io_stat()?;
+
+    let start = std::time::Instant::now();
+    runtime.fork([&stdin, &stdout, &stderr])?;
+    // Only the copy holds stdin from now on, so that a writer on the other end of a pipe notices
+    // when it terminates
+    drop(stdin);
+
+    let (real_time_timeout, idleness_timeout) = watch(
+        runtime.as_raw_fd(),
+        || runtime.kill_copies(),
+        &mut cgroup,
+        cpu_stat_before,
+        &invocation_limit,
+        start,
+    )?;
+
+    let real_time = start.elapsed();
+
+    // The runtime may fail to report the status because of the copy, which runs in the same
+    // sandbox and can kill, stop or trace it. The OOM kill takes the whole cgroup down too, which
+    // includes the runtime if it is caught in the middle of a fork. Either way, the runtime is
+    // started again before the next test, and the copy, which may have outlived it, is as good as
+    // killed by SIGKILL
+    let status = match runtime.read_status() {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            runtime.kill_copies()?;
+            libc::SIGKILL
+        }
+        Err(e) => {
+            errors::log_throttled(
+                "runtime status",
+                format!("The runtime did not report the status of the copy: {e:?}"),
+            );
+            runtime.kill_copies()?;
+            libc::SIGKILL
+        }
+    };
+
+    let cpu_stat = cgroup.cpu_stat()? - cpu_stat_before;
+    let io_stat = cgroup.io_stat()? - io_stat_before;
+    let memory_limit_exceeded = cgroup.oom_kills()? > oom_kills_before;
+    // The PID is not known, nor is it needed
+    let pid = nix::unistd::Pid::from_raw(0);
+    let wait_status = nix::sys::wait::WaitStatus::from_raw(pid, status)
+        .with_context_invoker(|| format!("The runtime reported invalid wait status {status}"))?;
+
+    let test_verdict = verdict_of(
+        wait_status,
+        &invocation_limit,
+        cpu_stat.total,
+        real_time,
+        idleness_timeout || real_time_timeout,
+        memory_limit_exceeded,
+        [&stdout, &stderr],
+    )?;
+
+    Ok((
+        test_verdict,
+        verdict::InvocationStat {
+            real_time,
+            cpu_time: cpu_stat.total,
+            user_time: cpu_stat.user,
+            sys_time: cpu_stat.system,
+            memory: 0, // TODO
+            read_bytes: io_stat.read_bytes,
+            written_bytes: io_stat.written_bytes,
+            thp: match invocation_limit.thp {
+                verdict::ThpPolicy::System => system_thp,
+                verdict::ThpPolicy::Disabled => Some(verdict::ThpMode::Never),
+            },
+        },
+        DebugLogs::default(),
+    ))
+}
+
+// Waits until the program terminates, which is when terminated becomes readable, or until it
+// exceeds its limits, in which case it is killed. Returns whether it was killed for exceeding the
+// real time limit and whether it was killed for being idle
+fn watch(
+    terminated: RawFd,
+    kill: impl Fn() -> Result<(), errors::Error>,
+    cgroup: &mut CgroupHandle,
+    cpu_stat_before: CgroupCpuStat,
+    invocation_limit: &verdict::InvocationLimit,
+    start: std::time::Instant,
+) -> Result<(bool, bool), errors::Error> {
     // Create a timerfd for tracking real time limit. It would perhaps be more correct to account
     // for the lapse of time between starting the process and creating the timerfd, but the
     // difference is negligible.
@@ -1066,7 +2599,7 @@ fn execute(
     epoll_ctl(
         epollfd,
         EpollOp::EpollCtlAdd,
-        pidfd,
+        terminated,
         &mut EpollEvent::new(EpollFlags::EPOLLIN, 0),
     )
     .context_invoker("Failed to configure epoll")?;
@@ -1097,17 +2630,40 @@ fn execute(
     //         sleep(guaranteed_real_time_left);
     //     }
 
+    // Idleness is checked in windows of invocation_limit.idleness wall-clock time each: if the CPU
+    // time spent during a window is below the threshold, the program is killed. This means an idle
+    // program is detected within one to two windows, depending on when it stopped working.
+    let mut idleness_window_start = (start, std::time::Duration::ZERO);
+
     let mut real_time_timeout = false;
+    let mut idleness_timeout = false;
     loop {
+        multiprocessing::heartbeat();
+
         let cpu_stat = cgroup.cpu_stat()? - cpu_stat_before;
 
         if cpu_stat.total > invocation_limit.cpu_time {
-            // Sending a signal to a zombie process is fine, so races are not a problem
-            nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::SIGKILL)
-                .context_invoker("Failed to kill the process")?;
+            kill()?;
             break;
         }
 
+        let mut idleness_timeout_left = None;
+        if let Some(idleness) = invocation_limit.idleness {
+            let (window_start, cpu_time_at_window_start) = idleness_window_start;
+            let elapsed = window_start.elapsed();
+            if elapsed >= idleness {
+                if cpu_stat.total - cpu_time_at_window_start < elapsed / IDLENESS_CPU_SHARE {
+                    idleness_timeout = true;
+                    kill()?;
+                    break;
+                }
+                idleness_window_start = (std::time::Instant::now(), cpu_stat.total);
+                idleness_timeout_left = Some(idleness);
+            } else {
+                idleness_timeout_left = Some(idleness - elapsed);
+            }
+        }
+
         let timeout = invocation_limit.cpu_time - cpu_stat.total;
         let n_events = epoll_wait(
             epollfd,
@@ -1120,7 +2676,13 @@ fn execute(
             // slow the judgment, not too small to steal resources from the solution in what is
             // effectively a spin lock, and allows SIGPROF to fire just at the right moment under
             // normal circumstances.
-            (timeout.as_millis().min(1000000) as isize) + 50,
+            //
+            // The timeout is capped so that heartbeats are sent often enough and idleness windows
+            // are checked in time.
+            (timeout.as_millis() + 50)
+                .min(HEARTBEAT_INTERVAL.as_millis())
+                .min(idleness_timeout_left.map_or(u128::MAX, |left| left.as_millis() + 1))
+                as isize,
         )
         .context_invoker("epoll_wait failed")?;
 
@@ -1132,17 +2694,12 @@ fn execute(
             1 => {
                 match events[0].data() {
                     0 => {
-                        // pidfd fired -- the process has terminated
+                        // The process has terminated
                     }
                     1 => {
                         // timerfd fired -- time out
                         real_time_timeout = true;
-                        // Sending a signal to a zombie process is fine, so races are not a problem
-                        nix::sys::signal::kill(
-                            nix::unistd::Pid::from_raw(pid),
-                            nix::sys::signal::SIGKILL,
-                        )
-                        .context_invoker("Failed to kill the process")?;
+                        kill()?;
                     }
                     _ => {
                         return Err(errors::InvokerFailure(
@@ -1159,26 +2716,51 @@ fn execute(
         }
     }
 
-    // Collect real time statistics
-    let real_time = start.elapsed();
-
-    // Collect current stats; we will later compute the difference between the current stats and the
-    // previous stats
-    let cpu_stat = cgroup.cpu_stat()? - cpu_stat_before;
-
-    let wait_status = nix::sys::wait::waitpid(nix::unistd::Pid::from_raw(pid), None)
-        .context_invoker("Failed to waitpid for process")?;
+    Ok((real_time_timeout, idleness_timeout))
+}
 
+// streams are the stdout and stderr of the program
+fn verdict_of(
+    wait_status: nix::sys::wait::WaitStatus,
+    invocation_limit: &verdict::InvocationLimit,
+    cpu_time: std::time::Duration,
+    real_time: std::time::Duration,
+    timed_out: bool,
+    memory_limit_exceeded: bool,
+    streams: [&std::fs::File; 2],
+) -> Result<verdict::TestVerdict, errors::Error> {
     let cpu_time_timeout = match wait_status {
         nix::sys::wait::WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGPROF, _) => true,
         _ => false,
     };
 
+    // A program that handles SIGXFSZ or ignores it gets EFBIG instead and may exit normally, so
+    // the redirected streams are checked too. Reaching the limit is as good as exceeding it, as
+    // RLIMIT_FSIZE doesn't let the file grow any larger. Other files are not checked, as the
+    // program may have opened them itself
+    let output_limit_exceeded = match invocation_limit.output_size {
+        Some(limit) => {
+            matches!(
+                wait_status,
+                nix::sys::wait::WaitStatus::Signaled(_, nix::sys::signal::Signal::SIGXFSZ, _)
+            ) || streams.iter().any(|file| {
+                file.metadata().map_or(false, |metadata| {
+                    metadata.is_file() && metadata.len() >= limit
+                })
+            })
+        }
+        None => false,
+    };
+
     // Into verdict
     let test_verdict;
-    if cpu_time_timeout || cpu_stat.total > invocation_limit.cpu_time {
+    if cpu_time_timeout || cpu_time > invocation_limit.cpu_time {
         test_verdict = verdict::TestVerdict::TimeLimitExceeded;
-    } else if real_time_timeout || real_time > invocation_limit.real_time {
+    } else if memory_limit_exceeded {
+        test_verdict = verdict::TestVerdict::MemoryLimitExceeded;
+    } else if output_limit_exceeded {
+        test_verdict = verdict::TestVerdict::OutputLimitExceeded;
+    } else if timed_out || real_time > invocation_limit.real_time {
         test_verdict = verdict::TestVerdict::IdlenessLimitExceeded;
     } else {
         match wait_status {
@@ -1204,32 +2786,66 @@ fn execute(
         }
     }
 
-    Ok((
-        test_verdict,
-        verdict::InvocationStat {
-            real_time,
-            cpu_time: cpu_stat.total,
-            user_time: cpu_stat.user,
-            sys_time: cpu_stat.system,
-            memory: 0, // TODO
-        },
-    ))
+    Ok(test_verdict)
 }
 
 #[multiprocessing::entrypoint]
 fn executor_worker(
     argv: Vec<String>,
+    syscalls: Vec<String>,
     stdin: std::fs::File,
     stdout: std::fs::File,
     stderr: std::fs::File,
     mut pipe: multiprocessing::Duplex<errors::Error, ()>,
-    cpu_time_limit: std::time::Duration,
+    invocation_limit: verdict::InvocationLimit,
+    seed: Option<u64>,
+    problem_program: bool,
+    trace_log: Option<std::fs::File>,
+    crash_log: Option<std::fs::File>,
+    sanitized: bool,
 ) {
     if let Err(e) = try {
         sandbox::drop_privileges().context_invoker("Failed to drop privileges")?;
 
         std::env::set_current_dir("/space").context_invoker("Failed to chdir to /space")?;
 
+        if invocation_limit.thp == verdict::ThpPolicy::Disabled
+            && unsafe { libc::prctl(libc::PR_SET_THP_DISABLE, 1, 0, 0, 0) } == -1
+        {
+            Err(std::io::Error::last_os_error()).context_invoker("Failed to disable THP")?;
+        }
+
+        // Inherited by execve and by the tracee
+        if let Some(output_size) = invocation_limit.output_size {
+            let limit = libc::rlimit {
+                rlim_cur: output_size,
+                rlim_max: output_size,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_FSIZE, &limit) } == -1 {
+                Err(std::io::Error::last_os_error())
+                    .context_invoker("Failed to set RLIMIT_FSIZE")?;
+            }
+        }
+
+        // The environment is inherited by execv
+        match seed {
+            Some(seed) => std::env::set_var("SUNWALKER_SEED", seed.to_string()),
+            None => std::env::remove_var("SUNWALKER_SEED"),
+        }
+
+        // Sanitizers write their reports to stderr by default, which the problem may redirect
+        // anywhere, or to <log_path>.<pid>. Options set by the package are kept
+        if sanitized {
+            for name in ["ASAN_OPTIONS", "UBSAN_OPTIONS"] {
+                let mut options = std::env::var(name).unwrap_or_default();
+                if !options.is_empty() {
+                    options.push(':');
+                }
+                options += &format!("log_path={SANITIZER_LOG_PATH}");
+                std::env::set_var(name, options);
+            }
+        }
+
         nix::unistd::dup2(stdin.as_raw_fd(), nix::libc::STDIN_FILENO)
             .context_invoker("dup2 for stdin failed")?;
         nix::unistd::dup2(stdout.as_raw_fd(), nix::libc::STDOUT_FILENO)
@@ -1237,6 +2853,11 @@ fn executor_worker(
         nix::unistd::dup2(stderr.as_raw_fd(), nix::libc::STDERR_FILENO)
             .context_invoker("dup2 for stderr failed")?;
 
+        // The invoker has checked the arguments against the package environment, but the
+        // environment has changed since
+        let env: Vec<(String, String)> = std::env::vars().collect();
+        args::check(&argv, &env)?;
+
         let mut args = Vec::with_capacity(argv.len());
         for arg in argv {
             args.push(
@@ -1246,7 +2867,7 @@ fn executor_worker(
         }
 
         pipe.recv()
-            .context_invoker("Failed to await confirmation from master process")?
+            .context_multiprocessing("Failed to await confirmation from master process")?
             .context_invoker("No confirmation from master process")?;
 
         // Fine to start the application now. We don't need to reset signals because we didn't
@@ -1257,6 +2878,9 @@ fn executor_worker(
         // applies to a single process, not a cgroup, and can be overwritten by the user program,
         // but this feature is not mission-critical. It merely saves us a few precious milliseconds
         // due to the (somewhat artificially deliberate) inefficiency of polling.
+        //
+        // When tracing, the timer is set in the tracee, as interval timers are not inherited by fork.
+        let cpu_time_limit = invocation_limit.cpu_time;
         let timer = libc::itimerval {
             it_interval: libc::timeval {
                 tv_sec: 0,
@@ -1267,21 +2891,244 @@ fn executor_worker(
                 tv_usec: cpu_time_limit.subsec_micros() as i64,
             },
         };
-        if unsafe {
-            libc::syscall(
-                libc::SYS_setitimer,
-                libc::ITIMER_PROF,
-                &timer as *const libc::itimerval,
-                std::ptr::null_mut::<libc::itimerval>(),
-            )
-        } == -1
-        {
-            Err(std::io::Error::last_os_error()).context_invoker("Failed to set interval timer")?;
-        }
+        let exec = || -> Result<(), errors::Error> {
+            if unsafe {
+                libc::syscall(
+                    libc::SYS_setitimer,
+                    libc::ITIMER_PROF,
+                    &timer as *const libc::itimerval,
+                    std::ptr::null_mut::<libc::itimerval>(),
+                )
+            } == -1
+            {
+                Err(std::io::Error::last_os_error())
+                    .context_invoker("Failed to set interval timer")?;
+            }
+
+            // This has to be the last step, as the filter is not meant for the invoker's own
+            // syscalls. If execve fails after that, reporting the error is likely to be denied too,
+            // so the parent sees the child killed by SIGSYS instead.
+            seccomp::apply(&syscalls)?;
+
+            // A problem-supplied program is bind-mounted from the problem cache, so it may be
+            // unreadable for the same reasons test files are, see StrategyRun::file_error
+            nix::unistd::execv(&args[0], &args).map_err(|e| match e {
+                nix::errno::Errno::ENOENT | nix::errno::Errno::EIO if problem_program => {
+                    errors::ProblemCacheFailure(format!("Failed to execute {:?}: {e}", args[0]))
+                }
+                e => e.context_invoker("execve failed"),
+            })?;
+            Ok(())
+        };
 
-        // Try block wraps return value in Ok(...)
-        nix::unistd::execv(&args[0], &args).context_invoker("execve failed")?;
+        let syscalls = match (trace_log, invocation_limit.trace) {
+            (Some(log), Some(filter)) => Some((filter, log)),
+            _ => None,
+        };
+        if syscalls.is_some() || crash_log.is_some() {
+            let outputs = trace::Outputs {
+                syscalls,
+                crash: crash_log,
+            };
+            trace::run_traced(outputs, pipe.as_raw_fd(), exec)?
+        } else {
+            exec()?
+        }
     } {
         pipe.send(&e).expect("Failed to report error to parent");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_pipeline(pipeline: Vec<Stage>) -> StrategyFactory {
+        StrategyFactory {
+            files: HashMap::new(),
+            blocks: Vec::new(),
+            programs: HashMap::new(),
+            language_multipliers: HashMap::new(),
+            exit_codes: verdict::ExitCodeConvention::default(),
+            checker_limits: None,
+            grader: HashMap::new(),
+            pipeline,
+            root: PathBuf::new(),
+        }
+    }
+
+    fn stage(name: &str, kind: StageKind, inputs: &[&str], output: Option<&str>) -> Stage {
+        Stage {
+            name: name.to_string(),
+            kind,
+            command: format!("{name}-program"),
+            argv: Vec::new(),
+            inputs: inputs
+                .iter()
+                .map(|input| Pattern::File(input.to_string()))
+                .collect(),
+            output: output.map(|output| output.to_string()),
+        }
+    }
+
+    fn file_name(pattern: &Option<Pattern>) -> Option<&str> {
+        match pattern {
+            Some(Pattern::File(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn lowers_stages_to_blocks() {
+        let mut factory = with_pipeline(vec![
+            stage("gen", StageKind::Generate, &[], Some("input")),
+            stage("solve", StageKind::Run, &["input"], Some("output")),
+            stage("check", StageKind::Check, &["input", "output"], None),
+            stage("score", StageKind::Score, &["output"], None),
+        ]);
+        factory.lower_pipeline().unwrap();
+
+        assert!(factory.pipeline.is_empty());
+        let mut files: Vec<&str> = factory.files.keys().map(|name| name.as_str()).collect();
+        files.sort();
+        assert_eq!(
+            files,
+            ["check.comments", "input", "output", "score.comments"]
+        );
+
+        let blocks = &factory.blocks;
+        assert_eq!(blocks.len(), 4);
+        assert!(matches!(blocks[0].tactic, Tactic::Filter));
+        assert_eq!(file_name(&blocks[0].stdout), Some("input"));
+
+        assert!(matches!(blocks[1].tactic, Tactic::User));
+        assert_eq!(blocks[1].command, "user");
+        assert_eq!(file_name(&blocks[1].stdin), Some("input"));
+        assert_eq!(file_name(&blocks[1].stdout), Some("output"));
+
+        assert!(matches!(blocks[2].tactic, Tactic::Testlib));
+        assert!(blocks[2].stdin.is_none());
+        assert_eq!(blocks[2].argv.len(), 2);
+        assert_eq!(file_name(&blocks[2].stderr), Some("check.comments"));
+
+        assert!(matches!(blocks[3].tactic, Tactic::Scorer));
+        assert_eq!(file_name(&blocks[3].stderr), Some("score.comments"));
+    }
+
+    #[test]
+    fn rejects_duplicate_stage_names() {
+        let mut factory = with_pipeline(vec![
+            stage("solve", StageKind::Run, &[], Some("first")),
+            stage("solve", StageKind::Run, &[], Some("second")),
+        ]);
+        assert!(matches!(
+            factory.lower_pipeline(),
+            Err(errors::ConfigurationFailure(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_file_names() {
+        let mut factory = with_pipeline(vec![
+            stage("gen", StageKind::Generate, &[], Some("data")),
+            stage("transform", StageKind::Transform, &["data"], Some("data")),
+        ]);
+        assert!(matches!(
+            factory.lower_pipeline(),
+            Err(errors::ConfigurationFailure(_))
+        ));
+
+        // The comments of a check are a file too
+        let mut factory = with_pipeline(vec![
+            stage("gen", StageKind::Generate, &[], Some("check.comments")),
+            stage("check", StageKind::Check, &["check.comments"], None),
+        ]);
+        assert!(matches!(
+            factory.lower_pipeline(),
+            Err(errors::ConfigurationFailure(_))
+        ));
+    }
+
+    fn block(name: &str, tactic: Tactic) -> Block {
+        Block {
+            name: name.to_string(),
+            tactic,
+            bindings: HashMap::new(),
+            command: "user".to_string(),
+            argv: Vec::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            outputs: HashMap::new(),
+            response_file: false,
+        }
+    }
+
+    #[test]
+    fn chained_user_runs_share_time_limit() {
+        let ms = std::time::Duration::from_millis;
+        // An encoder, a filter shuffling its output, and a decoder
+        let blocks = [
+            (block("encode", Tactic::User), ms(1000), ms(300)),
+            (block("shuffle", Tactic::Filter), ms(5000), ms(2000)),
+            (block("decode", Tactic::User), ms(1000), ms(100)),
+        ];
+        let user_limits = || {
+            blocks
+                .iter()
+                .filter(|(block, _, _)| matches!(block.tactic, Tactic::User))
+                .map(|(_, limit, _)| *limit)
+        };
+
+        let mut budget = UserTimeBudget::new(user_limits(), None);
+        let mut limits = Vec::new();
+        for (block, limit, used) in blocks.iter() {
+            limits.push(budget.limit(&block.tactic, *limit));
+            budget.charge(&block.tactic, *used);
+        }
+        assert_eq!(limits, [ms(1000), ms(5000), ms(700)]);
+
+        // The group time budget still applies on top
+        let mut budget = UserTimeBudget::new(user_limits(), Some(ms(500)));
+        let mut limits = Vec::new();
+        for (block, limit, used) in blocks.iter() {
+            limits.push(budget.limit(&block.tactic, *limit));
+            budget.charge(&block.tactic, *used);
+        }
+        assert_eq!(limits, [ms(500), ms(5000), ms(200)]);
+    }
+
+    #[test]
+    fn user_run_keeps_its_own_limit() {
+        let ms = std::time::Duration::from_millis;
+        // The decoder is given less time than the encoder, and must not get more than that even
+        // though more is left of the limit of the test
+        let blocks = [
+            (block("encode", Tactic::User), ms(1000), ms(100)),
+            (block("decode", Tactic::User), ms(200), ms(0)),
+        ];
+
+        for group in [None, Some(ms(2000))] {
+            let mut budget = UserTimeBudget::new(blocks.iter().map(|(_, limit, _)| *limit), group);
+            let mut limits = Vec::new();
+            for (block, limit, used) in blocks.iter() {
+                limits.push(budget.limit(&block.tactic, *limit));
+                budget.charge(&block.tactic, *used);
+            }
+            assert_eq!(limits, [ms(1000), ms(200)]);
+        }
+    }
+
+    #[test]
+    fn rejects_run_stage_with_several_inputs() {
+        let mut factory = with_pipeline(vec![
+            stage("gen", StageKind::Generate, &[], Some("input")),
+            stage("hint", StageKind::Generate, &[], Some("hint")),
+            stage("solve", StageKind::Run, &["input", "hint"], Some("output")),
+        ]);
+        assert!(matches!(
+            factory.lower_pipeline(),
+            Err(errors::ConfigurationFailure(_))
+        ));
+    }
+}
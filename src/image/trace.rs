@@ -0,0 +1,371 @@
+use crate::{
+    errors,
+    errors::ToResult,
+    image::{crash, seccomp},
+};
+use nix::sys::{
+    ptrace,
+    signal::Signal,
+    wait::{WaitPidFlag, WaitStatus},
+};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::unix::io::RawFd;
+
+// Problem setters debugging a checker or a solution that dies inside the sandbox can ask for a
+// strace-like log of the syscalls a block makes. The program is then started under a tracer, which
+// is the process the invoker spawned: it forks, the child execs the program, and the tracer mirrors
+// its exit status once it terminates. The tracer is in the same cgroup, so traced runs are much
+// slower and their timings are meaningless.
+//
+// The same tracer is used to report crashes. It then only stops the program on signals, which is
+// cheap enough not to distort timings.
+//
+// Syscalls are decoded with PTRACE_GET_SYSCALL_INFO, which works the same on all architectures.
+// Arguments are printed as raw numbers, as reading strings out of the tracee is not worth it here.
+
+// The log is attached to the test result, so it must not grow unbounded
+const MAX_LOG_SIZE: usize = 1024 * 1024;
+
+// From include/uapi/linux/ptrace.h, missing from libc
+const PTRACE_GET_SYSCALL_INFO: libc::c_uint = 0x420e;
+const PTRACE_SYSCALL_INFO_ENTRY: u8 = 1;
+const PTRACE_SYSCALL_INFO_EXIT: u8 = 2;
+
+#[repr(C)]
+#[allow(dead_code)]
+pub struct SyscallInfo {
+    op: u8,
+    pad: [u8; 3],
+    arch: u32,
+    pub instruction_pointer: u64,
+    pub stack_pointer: u64,
+    // entry: nr, args[6]; exit: rval, is_error
+    data: [u64; 8],
+}
+
+// Names are resolved with the seccomp table, so the same set of syscalls is known
+pub fn validate(syscalls: &[String]) -> Result<(), errors::Error> {
+    for name in syscalls {
+        if seccomp::syscall_number(name).is_none() {
+            return Err(errors::ConfigurationFailure(format!(
+                "Unknown syscall {name} in trace filter"
+            )));
+        }
+    }
+    Ok(())
+}
+
+struct Log {
+    file: std::fs::File,
+    size: usize,
+    truncated: bool,
+}
+
+impl Log {
+    fn line(&mut self, line: String) {
+        if self.truncated {
+            return;
+        }
+        if self.size + line.len() + 1 > MAX_LOG_SIZE {
+            self.truncated = true;
+            let _ = writeln!(self.file, "... (log truncated)");
+            return;
+        }
+        self.size += line.len() + 1;
+        // A failed write only loses a line of a debugging aid, which is no reason to abort the test
+        let _ = writeln!(self.file, "{line}");
+    }
+}
+
+pub fn syscall_info(pid: Pid) -> Result<SyscallInfo, errors::Error> {
+    let mut info: SyscallInfo = unsafe { std::mem::zeroed() };
+    if unsafe {
+        libc::ptrace(
+            PTRACE_GET_SYSCALL_INFO,
+            pid.as_raw(),
+            std::mem::size_of::<SyscallInfo>(),
+            &mut info as *mut SyscallInfo,
+        )
+    } == -1
+    {
+        return Err(std::io::Error::last_os_error())
+            .context_invoker("PTRACE_GET_SYSCALL_INFO failed");
+    }
+    Ok(info)
+}
+
+fn format_call(nr: u64, args: &[u64]) -> String {
+    let name = match seccomp::syscall_name(nr as libc::c_long) {
+        Some(name) => name.to_string(),
+        None => format!("syscall_{nr}"),
+    };
+    let args: Vec<String> = args.iter().map(|arg| format!("{arg:#x}")).collect();
+    format!("{name}({})", args.join(", "))
+}
+
+// What the tracer records. Either part may be disabled, but not both, as there would be no point in
+// tracing then.
+pub struct Outputs {
+    // Syscall numbers to log, or all of them if empty, and where to log them
+    pub syscalls: Option<(Vec<String>, std::fs::File)>,
+    // Where to put a summary of the state of the program when it is killed by a signal, see
+    // crash.rs
+    pub crash: Option<std::fs::File>,
+}
+
+// Signals that indicate a crash rather than a deliberate kill
+const CRASH_SIGNALS: [Signal; 6] = [
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGFPE,
+    Signal::SIGILL,
+    Signal::SIGABRT,
+    Signal::SIGTRAP,
+];
+
+struct Tracer {
+    child: Pid,
+    // None if syscalls are not traced, in which case only the child is traced, and only signals stop
+    // it
+    filter: Option<Vec<u64>>,
+    log: Option<Log>,
+    crash: Option<std::fs::File>,
+}
+
+// Forks; the child continues with exec, which is expected to replace the process. The parent traces
+// the child and never returns on success: it exits the same way the child did, so that the invoker
+// sees no difference. close_in_tracer is a file descriptor only the child may keep open, e.g. the
+// pipe whose closure on execve tells the invoker exec succeeded.
+pub fn run_traced(
+    outputs: Outputs,
+    close_in_tracer: RawFd,
+    exec: impl FnOnce() -> Result<(), errors::Error>,
+) -> Result<(), errors::Error> {
+    match unsafe { nix::unistd::fork() }.context_invoker("Failed to fork the tracee")? {
+        nix::unistd::ForkResult::Child => {
+            ptrace::traceme().context_invoker("PTRACE_TRACEME failed")?;
+            // Let the tracer configure the options before anything interesting happens
+            nix::sys::signal::raise(Signal::SIGSTOP).context_invoker("Failed to stop self")?;
+            exec()
+        }
+        nix::unistd::ForkResult::Parent { child } => {
+            let _ = nix::unistd::close(close_in_tracer);
+            let (filter, log) = match outputs.syscalls {
+                Some((filter, file)) => (
+                    Some(
+                        filter
+                            .iter()
+                            .filter_map(|name| seccomp::syscall_number(name))
+                            .map(|nr| nr as u64)
+                            .collect(),
+                    ),
+                    Some(Log {
+                        file,
+                        size: 0,
+                        truncated: false,
+                    }),
+                ),
+                None => (None, None),
+            };
+            let mut tracer = Tracer {
+                child,
+                filter,
+                log,
+                crash: outputs.crash,
+            };
+            let status = tracer.trace();
+            if let Err(ref e) = status {
+                let message = format!("+++ tracer failed: {e:?} +++");
+                if let Some(ref mut log) = tracer.log {
+                    log.line(message);
+                } else if let Some(ref mut crash) = tracer.crash {
+                    let _ = writeln!(crash, "{message}");
+                }
+            }
+            mirror_status(status.unwrap_or(WaitStatus::Exited(child, 127)))
+        }
+    }
+}
+
+impl Tracer {
+    fn line(&mut self, line: String) {
+        if let Some(ref mut log) = self.log {
+            log.line(line);
+        }
+    }
+
+    fn is_logged(&self, nr: u64) -> bool {
+        match self.filter {
+            Some(ref filter) => filter.is_empty() || filter.contains(&nr),
+            None => false,
+        }
+    }
+
+    fn resume(&self, pid: Pid, signal: Option<Signal>) -> nix::Result<()> {
+        if self.filter.is_some() {
+            ptrace::syscall(pid, signal)
+        } else {
+            ptrace::cont(pid, signal)
+        }
+    }
+
+    fn trace(&mut self) -> Result<WaitStatus, errors::Error> {
+        let child = self.child;
+        match nix::sys::wait::waitpid(child, Some(WaitPidFlag::__WALL))
+            .context_invoker("Failed to wait for the tracee to stop")?
+        {
+            WaitStatus::Stopped(_, Signal::SIGSTOP) => {}
+            status => {
+                return Err(errors::InvokerFailure(format!(
+                    "Tracee did not stop as expected: {status:?}"
+                )))
+            }
+        }
+
+        // EXITKILL makes sure nothing outlives the tracer, e.g. when the invoker kills it on timeout
+        let mut options = ptrace::Options::PTRACE_O_EXITKILL;
+        if self.filter.is_some() {
+            options |= ptrace::Options::PTRACE_O_TRACESYSGOOD
+                | ptrace::Options::PTRACE_O_TRACEFORK
+                | ptrace::Options::PTRACE_O_TRACEVFORK
+                | ptrace::Options::PTRACE_O_TRACECLONE
+                | ptrace::Options::PTRACE_O_TRACEEXEC;
+        }
+        ptrace::setoptions(child, options).context_invoker("Failed to set ptrace options")?;
+        self.resume(child, None)
+            .context_invoker("Failed to resume the tracee")?;
+
+        // Syscalls that have entered but not yet returned, per thread
+        let mut pending: HashMap<Pid, (u64, [u64; 6])> = HashMap::new();
+        // Threads and processes attached automatically start with SIGSTOP, which must not be
+        // delivered. The stop of a new tracee may be reported before the PTRACE_EVENT_* of its
+        // parent, so a tracee is new if either its pid has been announced by an event but has not
+        // stopped yet, or the pid has not been seen at all
+        let mut known = HashSet::from([child]);
+        let mut attached = HashSet::new();
+
+        loop {
+            let status = nix::sys::wait::waitpid(None, Some(WaitPidFlag::__WALL))
+                .context_invoker("Failed to wait for the tracees")?;
+            match status {
+                WaitStatus::PtraceSyscall(pid) => {
+                    let info = syscall_info(pid)?;
+                    match info.op {
+                        PTRACE_SYSCALL_INFO_ENTRY => {
+                            let mut args = [0; 6];
+                            args.copy_from_slice(&info.data[1..7]);
+                            pending.insert(pid, (info.data[0], args));
+                        }
+                        PTRACE_SYSCALL_INFO_EXIT => {
+                            if let Some((nr, args)) = pending.remove(&pid) {
+                                if self.is_logged(nr) {
+                                    let rval = info.data[0] as i64;
+                                    let result = if info.data[1] as u8 != 0 {
+                                        format!(
+                                            "-1 {:?}",
+                                            nix::errno::Errno::from_i32(-rval as i32)
+                                        )
+                                    } else {
+                                        rval.to_string()
+                                    };
+                                    self.line(format!(
+                                        "[{pid}] {} = {result}",
+                                        format_call(nr, &args)
+                                    ));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                    self.resume(pid, None)
+                        .context_invoker("Failed to resume a tracee")?;
+                }
+                WaitStatus::PtraceEvent(pid, _, event) => {
+                    if event == libc::PTRACE_EVENT_FORK
+                        || event == libc::PTRACE_EVENT_VFORK
+                        || event == libc::PTRACE_EVENT_CLONE
+                    {
+                        let new_pid =
+                            ptrace::getevent(pid).context_invoker("PTRACE_GETEVENTMSG failed")?;
+                        let new_pid = Pid::from_raw(new_pid as libc::pid_t);
+                        // Otherwise, the tracee has already stopped
+                        if known.insert(new_pid) {
+                            attached.insert(new_pid);
+                        }
+                    }
+                    self.resume(pid, None)
+                        .context_invoker("Failed to resume a tracee")?;
+                }
+                WaitStatus::Stopped(pid, signal) => {
+                    let signal = if signal == Signal::SIGSTOP
+                        && (attached.remove(&pid) || known.insert(pid))
+                    {
+                        None
+                    } else {
+                        self.line(format!("[{pid}] --- {signal:?} ---"));
+                        // A caught signal is not a crash yet: runtimes such as the JVM handle
+                        // SIGSEGV themselves all the time
+                        if pid == child
+                            && CRASH_SIGNALS.contains(&signal)
+                            && !crash::is_signal_caught(pid, signal)
+                        {
+                            if let Some(ref mut file) = self.crash {
+                                let _ = file.write_all(crash::summarize(pid, signal).as_bytes());
+                            }
+                        }
+                        Some(signal)
+                    };
+                    // The tracee may have been killed in the meantime
+                    let _ = self.resume(pid, signal);
+                }
+                WaitStatus::Exited(pid, code) => {
+                    if let Some((nr, args)) = pending.remove(&pid) {
+                        if self.is_logged(nr) {
+                            self.line(format!("[{pid}] {} = ?", format_call(nr, &args)));
+                        }
+                    }
+                    self.line(format!("[{pid}] +++ exited with {code} +++"));
+                    // The pid may be reused by a new tracee
+                    known.remove(&pid);
+                    attached.remove(&pid);
+                    if pid == child {
+                        return Ok(status);
+                    }
+                }
+                WaitStatus::Signaled(pid, signal, _) => {
+                    pending.remove(&pid);
+                    self.line(format!("[{pid}] +++ killed by {signal:?} +++"));
+                    known.remove(&pid);
+                    attached.remove(&pid);
+                    if pid == child {
+                        return Ok(status);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn mirror_status(status: WaitStatus) -> ! {
+    if let WaitStatus::Signaled(_, signal, _) = status {
+        // The core dump, if any, belongs to the tracee
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe {
+            libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            libc::signal(signal as libc::c_int, libc::SIG_DFL);
+        }
+        let _ = nix::sys::signal::raise(signal);
+        // Signals that don't terminate by default end up here
+        std::process::exit(128 + signal as i32);
+    }
+    match status {
+        WaitStatus::Exited(_, code) => std::process::exit(code),
+        _ => std::process::exit(127),
+    }
+}
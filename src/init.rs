@@ -1,4 +1,4 @@
-use crate::{cgroups, client};
+use crate::{cgroups, client, config, isolation};
 use anyhow::{bail, Context, Result};
 use clap::Parser;
 use libc::c_int;
@@ -11,7 +11,7 @@ pub struct CLIArgs {
     pub config: String,
 }
 
-fn watchdog_main(invoker_pid: libc::pid_t) -> Result<()> {
+fn watchdog_main(invoker_pid: libc::pid_t, cli_args: &CLIArgs) -> Result<()> {
     println!("Watchdog started for {}", invoker_pid);
 
     let mut status: c_int = 0;
@@ -26,6 +26,18 @@ fn watchdog_main(invoker_pid: libc::pid_t) -> Result<()> {
         .with_context(|| "Failed to remove dangling cpusets at boot")?;
     cgroups::create_root_cpuset().with_context(|| "Failed to create root cpuset")?;
 
+    // An invalid config is reported by the invoker itself
+    let config: Option<config::Config> = std::fs::read_to_string(&cli_args.config)
+        .ok()
+        .and_then(|config| toml::from_str(&config).ok());
+    let saved_irqs = match config {
+        Some(config) if config.environment.manage_irq_affinity => Some(
+            isolation::move_irqs_away(&config.environment.cpu_cores)
+                .with_context(|| "Failed to move IRQs away from judging cores")?,
+        ),
+        _ => None,
+    };
+
     // CONT invoker
     if unsafe { libc::kill(invoker_pid, libc::SIGCONT) } == -1 {
         bail!("Failed to send SIGCONT to the invoker at bootstrap");
@@ -39,6 +51,9 @@ fn watchdog_main(invoker_pid: libc::pid_t) -> Result<()> {
     // TODO: handle exit status here
 
     // Garbage cleanup
+    if let Some(saved_irqs) = saved_irqs {
+        isolation::restore_irqs(saved_irqs);
+    }
     cgroups::drop_existing_affine_cpusets()
         .with_context(|| "Failed to remove dangling cpusets at shutdown")?;
 
@@ -66,6 +81,10 @@ pub fn main() -> Result<()> {
          already running?)",
     );
 
+    // Checked before the watchdog starts creating cgroups, so that a machine without cgroups v2 gets
+    // a readable error rather than a failed write to some file under /sys/fs/cgroup
+    cgroups::verify_unified_hierarchy()?;
+
     // Spawn a watchdog
     let child_pid = unsafe { libc::fork() };
     if child_pid == -1 {
@@ -80,7 +99,7 @@ pub fn main() -> Result<()> {
 
         client::client_main(cli_parse)
     } else {
-        watchdog_main(child_pid)?;
+        watchdog_main(child_pid, &cli_parse)?;
         fcntl::flock(lock_fd, fcntl::FlockArg::Unlock)
             .with_context(|| "Failed to unlock /tmp/sunwalker_invoker/invoker.lock")
     }
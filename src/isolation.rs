@@ -0,0 +1,386 @@
+use crate::{cgroups, errors, errors::ToResult};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+
+// Making sunwalker_root a root partition only guarantees that other cgroups cannot schedule tasks
+// on the judging cores. Interrupts, tasks that were pinned before the partition was created, and
+// kernel timer ticks still steal time from solutions and make timings noisy. None of these are
+// fatal, as the invoker works correctly regardless, so the problems are reported to the conductor
+// in the handshake rather than refusing to start.
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IsolationReport {
+    pub isolated: bool,
+    pub issues: Vec<String>,
+}
+
+// PF_KTHREAD from include/linux/sched.h. Per-CPU kernel threads are bound to every core, and
+// nothing can be done about that.
+const PF_KTHREAD: u64 = 0x00200000;
+
+fn format_cores(cores: &BTreeSet<u64>) -> String {
+    cores
+        .iter()
+        .map(|core| core.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn read_cpuset_list(path: &Path) -> Result<Option<BTreeSet<u64>>, errors::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(list) if list.trim().is_empty() => Ok(Some(BTreeSet::new())),
+        Ok(list) => Ok(Some(
+            cgroups::parse_cpuset_list(&list)?.into_iter().collect(),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context_invoker(|| format!("Failed to read {path:?}")),
+    }
+}
+
+fn intersect(list: &BTreeSet<u64>, cores: &HashSet<u64>) -> BTreeSet<u64> {
+    list.iter()
+        .filter(|core| cores.contains(core))
+        .cloned()
+        .collect()
+}
+
+// Other cgroups that ask for the judging cores. The kernel removes the cores from their effective
+// cpusets, but the request itself means someone expects to run there. Cgroups may be removed while
+// they are being inspected, so those that vanish are skipped
+fn check_cpusets(
+    dir: &Path,
+    cores: &HashSet<u64>,
+    issues: &mut Vec<String>,
+) -> Result<(), errors::Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context_invoker(|| format!("Failed to readdir {dir:?}")),
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context_invoker(|| format!("Failed to readdir {dir:?}")),
+        };
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context_invoker(|| format!("Failed to stat {path:?}")),
+        };
+        if !file_type.is_dir() || path == Path::new("/sys/fs/cgroup/sunwalker_root") {
+            continue;
+        }
+        if let Some(list) = read_cpuset_list(&path.join("cpuset.cpus"))? {
+            let overlap = intersect(&list, cores);
+            if !overlap.is_empty() {
+                issues.push(format!(
+                    "cgroup {path:?} requests cores {}",
+                    format_cores(&overlap)
+                ));
+            }
+        }
+        check_cpusets(&path, cores, issues)?;
+    }
+    Ok(())
+}
+
+// Returns whether the task is a kernel thread. Tasks may exit while they are being inspected, so
+// the caller ignores errors
+fn is_kernel_thread(task: &Path) -> std::io::Result<bool> {
+    let stat = std::fs::read_to_string(task.join("stat"))?;
+    // comm may contain spaces and parentheses, so fields are counted from the last parenthesis
+    let flags = stat
+        .rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(6))
+        .and_then(|flags| flags.parse::<u64>().ok())
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+    Ok(flags & PF_KTHREAD != 0)
+}
+
+fn allowed_cores(task: &Path) -> std::io::Result<Option<BTreeSet<u64>>> {
+    let status = std::fs::read_to_string(task.join("status"))?;
+    Ok(status
+        .lines()
+        .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+        .and_then(|list| cgroups::parse_cpuset_list(list).ok())
+        .map(|list| list.into_iter().collect()))
+}
+
+fn in_sunwalker_cgroup(pid: &Path) -> std::io::Result<bool> {
+    Ok(std::fs::read_to_string(pid.join("cgroup"))?
+        .lines()
+        .any(|line| line.starts_with("0::/sunwalker_root")))
+}
+
+// User tasks outside of sunwalker_root whose affinity includes the judging cores. Most of them
+// cannot actually run there while the partition exists, but a task that is pinned to the judging
+// cores only is a sign of a misconfigured machine
+fn check_tasks(cores: &HashSet<u64>, issues: &mut Vec<String>) -> Result<(), errors::Error> {
+    let mut pinned = Vec::new();
+    for entry in std::fs::read_dir("/proc").context_invoker("Failed to readdir /proc")? {
+        let entry = entry.context_invoker("Failed to readdir /proc")?;
+        let pid = entry.path();
+        if !entry
+            .file_name()
+            .to_str()
+            .map_or(false, |name| name.bytes().all(|c| c.is_ascii_digit()))
+        {
+            continue;
+        }
+        if !matches!(in_sunwalker_cgroup(&pid), Ok(false)) {
+            continue;
+        }
+        let tasks = match std::fs::read_dir(pid.join("task")) {
+            Ok(tasks) => tasks,
+            Err(_) => continue,
+        };
+        for task in tasks.flatten() {
+            let task = task.path();
+            if !matches!(is_kernel_thread(&task), Ok(false)) {
+                continue;
+            }
+            if let Ok(Some(allowed)) = allowed_cores(&task) {
+                if !allowed.is_empty() && allowed.iter().all(|core| cores.contains(core)) {
+                    let comm = std::fs::read_to_string(task.join("comm")).unwrap_or_default();
+                    pinned.push(format!(
+                        "{} ({}) on cores {}",
+                        task.file_name().unwrap().to_string_lossy(),
+                        comm.trim(),
+                        format_cores(&allowed)
+                    ));
+                }
+            }
+        }
+    }
+    if !pinned.is_empty() {
+        issues.push(format!(
+            "Tasks are pinned to judging cores: {}",
+            pinned.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn check_irqs(cores: &HashSet<u64>, issues: &mut Vec<String>) -> Result<(), errors::Error> {
+    let mut irqs = Vec::new();
+    let mut targeted_cores = BTreeSet::new();
+    for entry in std::fs::read_dir("/proc/irq").context_invoker("Failed to readdir /proc/irq")? {
+        let entry = entry.context_invoker("Failed to readdir /proc/irq")?;
+        let irq = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        {
+            Some(irq) => irq,
+            None => continue,
+        };
+        // The effective affinity is what the interrupt controller is actually programmed with,
+        // but not all architectures report it
+        let mut list = read_cpuset_list(&entry.path().join("effective_affinity_list"))?;
+        if list.as_ref().map_or(true, |list| list.is_empty()) {
+            list = read_cpuset_list(&entry.path().join("smp_affinity_list"))?;
+        }
+        let overlap = intersect(&list.unwrap_or_default(), cores);
+        if !overlap.is_empty() {
+            irqs.push(irq);
+            targeted_cores.extend(overlap);
+        }
+    }
+    if !irqs.is_empty() {
+        irqs.sort_unstable();
+        issues.push(format!(
+            "IRQs {} may be delivered to cores {}",
+            irqs.iter()
+                .map(|irq| irq.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            format_cores(&targeted_cores)
+        ));
+    }
+    Ok(())
+}
+
+// irqbalance rewrites IRQ affinities every few seconds, so a clean /proc/irq at startup means
+// little if it is running. It leaves alone the cores listed in IRQBALANCE_BANNED_CPULIST or
+// --banned-cpulist, as well as isolcpus and nohz_full cores.
+fn check_irqbalance(
+    cores: &HashSet<u64>,
+    kernel_isolated: &BTreeSet<u64>,
+    issues: &mut Vec<String>,
+) -> Result<(), errors::Error> {
+    for entry in std::fs::read_dir("/proc").context_invoker("Failed to readdir /proc")? {
+        let entry = entry.context_invoker("Failed to readdir /proc")?;
+        let pid = entry.path();
+        match std::fs::read_to_string(pid.join("comm")) {
+            Ok(comm) if comm.trim() == "irqbalance" => {}
+            _ => continue,
+        }
+
+        let mut banned = kernel_isolated.clone();
+        let cmdline = std::fs::read(pid.join("cmdline")).unwrap_or_default();
+        let environ = std::fs::read(pid.join("environ")).unwrap_or_default();
+        let args: Vec<String> = cmdline
+            .split(|c| *c == 0)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        let mut lists: Vec<String> = environ
+            .split(|c| *c == 0)
+            .filter_map(|var| var.strip_prefix(b"IRQBALANCE_BANNED_CPULIST="))
+            .map(|list| String::from_utf8_lossy(list).into_owned())
+            .collect();
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(list) = arg.strip_prefix("--banned-cpulist=") {
+                lists.push(list.to_string());
+            } else if arg == "--banned-cpulist" {
+                lists.extend(args.get(i + 1).cloned());
+            }
+        }
+        for list in lists {
+            banned.extend(cgroups::parse_cpuset_list(&list).unwrap_or_default());
+        }
+
+        let unbanned: BTreeSet<u64> = cores
+            .iter()
+            .filter(|core| !banned.contains(core))
+            .cloned()
+            .collect();
+        if !unbanned.is_empty() {
+            issues.push(format!(
+                "irqbalance (pid {}) may move IRQs to cores {}",
+                entry.file_name().to_string_lossy(),
+                format_cores(&unbanned)
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn verify_core_isolation(
+    cores: &[u64],
+    require_nohz_full: bool,
+) -> Result<IsolationReport, errors::Error> {
+    let cores: HashSet<u64> = cores.iter().cloned().collect();
+    let mut issues = Vec::new();
+
+    check_cpusets(Path::new("/sys/fs/cgroup"), &cores, &mut issues)?;
+    check_tasks(&cores, &mut issues)?;
+    check_irqs(&cores, &mut issues)?;
+
+    // The files are missing if the kernel is built without support for the corresponding features
+    let nohz_full =
+        read_cpuset_list(Path::new("/sys/devices/system/cpu/nohz_full"))?.unwrap_or_default();
+    let isolcpus =
+        read_cpuset_list(Path::new("/sys/devices/system/cpu/isolated"))?.unwrap_or_default();
+    check_irqbalance(
+        &cores,
+        &nohz_full.union(&isolcpus).cloned().collect(),
+        &mut issues,
+    )?;
+
+    if require_nohz_full {
+        let missing: BTreeSet<u64> = cores
+            .iter()
+            .filter(|core| !nohz_full.contains(core))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            issues.push(format!(
+                "Cores {} are not in nohz_full",
+                format_cores(&missing)
+            ));
+        }
+    }
+
+    Ok(IsolationReport {
+        isolated: issues.is_empty(),
+        issues,
+    })
+}
+
+// Moving IRQs away from the judging cores is optional, as it affects the whole machine. It is done
+// by the watchdog rather than the invoker, so that the affinities are restored even if the invoker
+// crashes. irqbalance, if running, will undo this unless the cores are banned in its configuration.
+pub struct SavedIrqAffinities {
+    affinities: Vec<(String, String)>,
+}
+
+fn online_cores() -> Result<BTreeSet<u64>, errors::Error> {
+    read_cpuset_list(Path::new("/sys/devices/system/cpu/online"))?
+        .context_invoker("/sys/devices/system/cpu/online is missing")
+}
+
+pub fn move_irqs_away(cores: &[u64]) -> Result<SavedIrqAffinities, errors::Error> {
+    let cores: HashSet<u64> = cores.iter().cloned().collect();
+    let housekeeping: BTreeSet<u64> = online_cores()?
+        .into_iter()
+        .filter(|core| !cores.contains(core))
+        .collect();
+    if housekeeping.is_empty() {
+        return Err(errors::ConfigurationFailure(
+            "Cannot move IRQs away from judging cores, as all online cores are judging cores"
+                .to_string(),
+        ));
+    }
+    // default_smp_affinity only accepts a hex mask, in 32-bit groups separated by commas
+    let mut mask: Vec<u32> = vec![0; (*housekeeping.iter().last().unwrap() / 32 + 1) as usize];
+    for core in &housekeeping {
+        mask[(core / 32) as usize] |= 1 << (core % 32);
+    }
+    let housekeeping_mask = mask
+        .iter()
+        .rev()
+        .map(|word| format!("{word:08x}"))
+        .collect::<Vec<String>>()
+        .join(",");
+    let housekeeping = format_cores(&housekeeping);
+
+    // default_smp_affinity applies to IRQs that are registered later, e.g. when a device is
+    // hotplugged
+    let mut paths = vec!["/proc/irq/default_smp_affinity".to_string()];
+    for entry in std::fs::read_dir("/proc/irq").context_invoker("Failed to readdir /proc/irq")? {
+        let entry = entry.context_invoker("Failed to readdir /proc/irq")?;
+        if entry.path().join("smp_affinity_list").exists() {
+            paths.push(format!("{}/smp_affinity_list", entry.path().display()));
+        }
+    }
+
+    let mut saved = SavedIrqAffinities {
+        affinities: Vec::new(),
+    };
+    let mut immovable = Vec::new();
+    for path in paths {
+        let affinity = std::fs::read_to_string(&path)
+            .with_context_invoker(|| format!("Failed to read {path}"))?;
+        let new_affinity = if path.ends_with("default_smp_affinity") {
+            &housekeeping_mask
+        } else {
+            &housekeeping
+        };
+        // Per-CPU interrupts and managed IRQs of multiqueue devices cannot be moved, and writes to
+        // their affinities fail with EIO
+        match std::fs::write(&path, format!("{new_affinity}\n")) {
+            Ok(()) => saved.affinities.push((path, affinity)),
+            Err(_) => immovable.push(path),
+        }
+    }
+
+    if !immovable.is_empty() {
+        println!(
+            "Failed to move {} IRQs away from judging cores, they are probably per-CPU or managed",
+            immovable.len()
+        );
+    }
+    Ok(saved)
+}
+
+pub fn restore_irqs(saved: SavedIrqAffinities) {
+    for (path, affinity) in saved.affinities {
+        // The IRQ might have been freed in the meantime, nothing to restore then
+        if let Err(e) = std::fs::write(&path, affinity) {
+            println!("Failed to restore {path}: {e}");
+        }
+    }
+}
@@ -8,15 +8,26 @@
 )]
 
 mod image {
+    pub(crate) mod args;
+    pub(crate) mod canary;
     pub(crate) mod config;
+    pub(crate) mod crash;
+    pub(crate) mod diagnostics;
+    pub(crate) mod gc;
     pub(crate) mod ids;
     pub(crate) mod image;
+    pub(crate) mod integrity;
     pub(crate) mod language;
     pub(crate) mod mount;
+    pub(crate) mod network;
     pub(crate) mod package;
     pub(crate) mod program;
+    pub(crate) mod program_cache;
+    pub(crate) mod runtime;
     pub(crate) mod sandbox;
+    pub(crate) mod seccomp;
     pub(crate) mod strategy;
+    pub(crate) mod trace;
 }
 
 mod cgroups;
@@ -29,8 +40,16 @@ mod config;
 
 mod errors;
 
+mod idle;
+
 pub mod init;
 
+mod isolation;
+
+mod outputs;
+
+mod report;
+
 mod message {
     pub(crate) mod c2i;
     pub(crate) mod i2c;
@@ -38,12 +57,18 @@ mod message {
 
 mod problem {
     pub(crate) mod problem;
+    pub(crate) mod scoring;
+    pub(crate) mod source;
     pub(crate) mod store;
     pub(crate) mod verdict;
 }
 
+mod signing;
+
 mod submission;
 
+mod supervisor;
+
 mod system;
 
 mod worker;
@@ -1,4 +1,10 @@
-use crate::problem::verdict::InvocationLimit;
+use crate::{
+    config,
+    problem::{
+        problem::TestSelector,
+        verdict::{GroupTimeBudget, InvocationLimit},
+    },
+};
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -8,7 +14,12 @@ pub enum Message {
     PushToJudgementQueue(PushToJudgementQueue),
     CancelJudgementOnTests(CancelJudgementOnTests),
     FinalizeSubmission(FinalizeSubmission),
+    AbortSubmission(AbortSubmission),
     SupplyFile(SupplyFile),
+    CompareRevisions(CompareRevisions),
+    ExportReport(ExportReport),
+    CompareSubmissions(CompareSubmissions),
+    ReloadImage(ReloadImage),
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,15 +29,44 @@ pub struct AddSubmission {
     pub problem_id: String,
     pub revision_id: String,
     pub files: HashMap<String, Vec<u8>>,
-    pub language: String,
+    // Detected from the extensions of the files if unset, see Image::detect_language
+    #[serde(default)]
+    pub language: Option<String>,
     pub invocation_limits: HashMap<String, InvocationLimit>,
+    #[serde(default)]
+    pub group_time_budgets: Vec<GroupTimeBudget>,
+    #[serde(default)]
+    pub dialect: Option<String>,
+    // Dialects permitted by the contest. None means any dialect the language supports is allowed
+    #[serde(default)]
+    pub allowed_dialects: Option<Vec<String>>,
+    // The files are answers to the tests rather than sources, see Language::collect_answers. The
+    // language only provides the sandbox the answers are checked in
+    #[serde(default)]
+    pub output_only: bool,
+    // The name of the image to build and judge the submission with. Must agree with the problem if
+    // the problem asks for a particular image
+    #[serde(default)]
+    pub image: Option<String>,
+    // Build with the diagnostic profile of the language, e.g. with sanitizers, to find out why a
+    // submission crashes. Sanitizer reports are then attached to the logs of the tests
+    #[serde(default)]
+    pub diagnostic: bool,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PushToJudgementQueue {
     pub core: u64,
     pub submission_id: String,
-    pub tests: Vec<u64>,
+    pub tests: Vec<TestSelector>,
+    // Tests the conductor is likely to request next. They are run once the core is otherwise idle,
+    // and their results are held back until they are actually requested
+    #[serde(default)]
+    pub speculative_tests: Vec<TestSelector>,
+    // Other cores leased to the submission. The tests are spread across them and core, and the
+    // results are reported in the order of the tests
+    #[serde(default)]
+    pub extra_cores: Vec<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,8 +80,69 @@ pub struct FinalizeSubmission {
     pub submission_id: String,
 }
 
+// Stops judging the submission, e.g. because the contestant has resubmitted, and finalizes it. The
+// compilation or the tests in progress are killed, and nothing more is reported about the
+// submission
+#[derive(Debug, Deserialize)]
+pub struct AbortSubmission {
+    pub submission_id: String,
+}
+
+// Judges an already compiled submission against another revision of its problem, e.g. one with
+// updated tests, and reports which verdicts would change
+#[derive(Debug, Deserialize)]
+pub struct CompareRevisions {
+    pub core: u64,
+    pub submission_id: String,
+    pub candidate_revision_id: String,
+    // Resolved against both revisions
+    pub tests: Vec<TestSelector>,
+    // The blocks of the candidate revision may differ, so it may need other limits. The limits of
+    // the submission are used by default
+    #[serde(default)]
+    pub candidate_invocation_limits: Option<HashMap<String, InvocationLimit>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct SupplyFile {
     pub request_id: u64,
     pub contents: Vec<u8>,
 }
+
+// Asks for a signed report bundle of the submission, see report.rs. Only tests judged so far are
+// included, so this should be sent before the submission is finalized.
+#[derive(Debug, Deserialize)]
+pub struct ExportReport {
+    pub submission_id: String,
+}
+
+// Judges two compiled submissions of the same problem revision on the same tests, with the same
+// seeds, and reports the tests where their verdicts, files or timings diverge
+#[derive(Debug, Deserialize)]
+pub struct CompareSubmissions {
+    pub core: u64,
+    pub submission_id: String,
+    pub other_submission_id: String,
+    pub tests: Vec<TestSelector>,
+}
+
+// Mounts a new version of the image and switches new submissions to it. Submissions added before
+// keep using the old image until they are finalized, after which it is unmounted. The paths default
+// to those from the configuration file, which is what one needs when image.sfs is replaced in place
+#[derive(Debug, Deserialize)]
+pub struct ReloadImage {
+    // If there is no image with this name, it is added. The paths are required then
+    #[serde(default = "default_image_name")]
+    pub name: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub config: Option<String>,
+    // If the image is configured with a public key, the manifest must be signed with it
+    #[serde(default)]
+    pub manifest: Option<String>,
+}
+
+fn default_image_name() -> String {
+    config::DEFAULT_IMAGE.to_string()
+}
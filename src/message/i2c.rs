@@ -1,4 +1,13 @@
-use crate::{errors, problem::verdict::TestJudgementResult};
+use crate::{
+    errors,
+    image::diagnostics::Diagnostic,
+    isolation::IsolationReport,
+    problem::{
+        scoring::SubmissionScore,
+        verdict::{GroupJudgementResult, RevisionDiff, SubmissionDiff, TestJudgementResult},
+    },
+    signing::ResultSignature,
+};
 use serde::Serialize;
 
 #[derive(Debug, Serialize)]
@@ -7,13 +16,21 @@ pub enum Message {
     UpdateMode(UpdateMode),
     NotifyCompilationStatus(NotifyCompilationStatus),
     NotifyTestStatus(NotifyTestStatus),
+    NotifyGroupStatus(NotifyGroupStatus),
+    NotifySubmissionScore(NotifySubmissionScore),
     NotifySubmissionError(NotifySubmissionError),
+    NotifyTimeBudgetExhausted(NotifyTimeBudgetExhausted),
     RequestFile(RequestFile),
+    NotifyRevisionComparison(NotifyRevisionComparison),
+    NotifyReportBundle(NotifyReportBundle),
+    NotifySubmissionComparison(NotifySubmissionComparison),
+    NotifyImageReload(NotifyImageReload),
 }
 
 #[derive(Debug, Serialize)]
 pub struct Handshake {
     pub invoker_name: String,
+    pub core_isolation: IsolationReport,
 }
 
 #[derive(Debug, Serialize)]
@@ -26,7 +43,11 @@ pub struct UpdateMode {
 #[derive(Debug, Serialize)]
 pub struct NotifyCompilationStatus {
     pub submission_id: String,
+    // The language the submission was built with, which may have been detected by the invoker.
+    // None if the submission was rejected before that
+    pub language: Option<String>,
     pub result: Result<String, errors::Error>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,6 +55,31 @@ pub struct NotifyTestStatus {
     pub submission_id: String,
     pub test: u64,
     pub judgement_result: TestJudgementResult,
+    // None if the invoker has no signing key configured
+    pub signature: Option<ResultSignature>,
+}
+
+// Sent after the last test of a scoring group is judged, and again whenever one of its tests is
+// rejudged
+#[derive(Debug, Serialize)]
+pub struct NotifyGroupStatus {
+    pub submission_id: String,
+    pub group: String,
+    pub result: GroupJudgementResult,
+}
+
+// Sent after every test result, see problem::scoring
+#[derive(Debug, Serialize)]
+pub struct NotifySubmissionScore {
+    pub submission_id: String,
+    pub score: SubmissionScore,
+}
+
+// The submission used up its judging time budget, see problem::JudgingPolicy::time_budget, and was
+// finalized by the invoker. Tests pushed afterwards are rejected
+#[derive(Debug, Serialize)]
+pub struct NotifyTimeBudgetExhausted {
+    pub submission_id: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -42,8 +88,36 @@ pub struct NotifySubmissionError {
     pub error: errors::Error,
 }
 
+#[derive(Debug, Serialize)]
+pub struct NotifyRevisionComparison {
+    pub submission_id: String,
+    pub candidate_revision_id: String,
+    pub result: Result<RevisionDiff, errors::Error>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct RequestFile {
     pub request_id: u64,
     pub hash: String,
 }
+
+#[derive(Debug, Serialize)]
+pub struct NotifyReportBundle {
+    pub submission_id: String,
+    // An uncompressed tarball
+    pub result: Result<Vec<u8>, errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotifySubmissionComparison {
+    pub submission_id: String,
+    pub other_submission_id: String,
+    pub result: Result<SubmissionDiff, errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotifyImageReload {
+    pub name: String,
+    // The version of the new image
+    pub result: Result<String, errors::Error>,
+}
@@ -0,0 +1,173 @@
+use crate::{errors, errors::ToResult, problem::verdict, signing};
+use multiprocessing::Object;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Deserialize, Object)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    Nothing,
+    // Files written by user blocks only
+    Outputs,
+    // Every file of the strategy, including e.g. checker logs
+    Everything,
+}
+
+// Which files to keep on disk after a test is judged, depending on its verdict. Accepted outputs
+// are seldom looked at, while a crashed checker is hard to debug without its input and logs.
+#[derive(Clone, Debug, Deserialize, Object)]
+pub struct RetentionPolicy {
+    pub default: Retention,
+    // Keyed by short verdict codes, e.g. "WA" or "CF"
+    #[serde(default)]
+    pub verdicts: HashMap<String, Retention>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            default: Retention::Outputs,
+            verdicts: HashMap::from([
+                ("AC".to_string(), Retention::Nothing),
+                ("CF".to_string(), Retention::Everything),
+                ("FL".to_string(), Retention::Everything),
+            ]),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn for_verdict(&self, verdict: &verdict::TestVerdict) -> Retention {
+        // Partial solutions are reported as "PT <points>"
+        let short = verdict.to_short_string();
+        let code = short.split(' ').next().unwrap();
+        self.verdicts.get(code).copied().unwrap_or(self.default)
+    }
+}
+
+// Keeps the first and the last limit / 2 bytes of the log, with a marker in between saying how many
+// bytes were cut out. 0 means no limit
+pub fn cap_log(log: &mut Vec<u8>, limit: u64) {
+    let limit = limit as usize;
+    if limit == 0 || log.len() <= limit {
+        return;
+    }
+    let head = limit / 2;
+    let tail = log.len() - (limit - head);
+    let marker = format!("\n\n... {} bytes omitted ...\n\n", tail - head);
+    let mut capped = Vec::with_capacity(limit + marker.len());
+    capped.extend_from_slice(&log[..head]);
+    capped.extend_from_slice(marker.as_bytes());
+    capped.extend_from_slice(&log[tail..]);
+    *log = capped;
+}
+
+// Like cap_log, but never splits a character
+pub fn cap_text_log(log: String, limit: u64) -> String {
+    let limit = limit as usize;
+    if limit == 0 || log.len() <= limit {
+        return log;
+    }
+    let mut head = limit / 2;
+    while !log.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = log.len() - (limit - limit / 2);
+    while !log.is_char_boundary(tail) {
+        tail += 1;
+    }
+    format!(
+        "{}\n\n... {} bytes omitted ...\n\n{}",
+        &log[..head],
+        tail - head,
+        &log[tail..]
+    )
+}
+
+// Retained outputs of tests are stored content-addressed: each distinct output is written once,
+// under the name of its hash, and removed when no test refers to it anymore. Many tests of a
+// typical problem produce identical outputs (think "YES"), so this saves a lot of disk space.
+pub struct OutputStore {
+    root: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    refcounts: HashMap<String, usize>,
+    // test -> file name -> hash
+    retained: HashMap<u64, HashMap<String, String>>,
+}
+
+impl OutputStore {
+    pub fn new(root: PathBuf) -> Result<Self, errors::Error> {
+        std::fs::create_dir(&root)
+            .with_context_invoker(|| format!("Failed to create output store at {root:?}"))?;
+        Ok(OutputStore {
+            root,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    // Replaces whatever was retained for the test before, e.g. on a rejudge. Passing no outputs
+    // releases the test's files.
+    pub fn retain<'a>(
+        &self,
+        test: u64,
+        outputs: impl IntoIterator<Item = (&'a String, &'a Vec<u8>)>,
+    ) -> Result<(), errors::Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut hashes = HashMap::new();
+        for (name, data) in outputs {
+            let hash = signing::sha256_hex(data);
+            let refcount = state.refcounts.entry(hash.clone()).or_insert(0);
+            if *refcount == 0 {
+                let path = self.root.join(&hash);
+                std::fs::write(&path, data)
+                    .with_context_invoker(|| format!("Failed to write output to {path:?}"))?;
+            }
+            *refcount += 1;
+            hashes.insert(name.clone(), hash);
+        }
+
+        // New references are added before the old ones are dropped, so that unchanged outputs are
+        // not deleted and rewritten
+        if let Some(old_hashes) = state.retained.insert(test, hashes) {
+            for hash in old_hashes.into_values() {
+                self.unref(&mut state, hash)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Names and paths of the files retained for the test
+    pub fn retained_files(&self, test: u64) -> Vec<(String, PathBuf)> {
+        let state = self.state.lock().unwrap();
+        match state.retained.get(&test) {
+            Some(hashes) => hashes
+                .iter()
+                .map(|(name, hash)| (name.clone(), self.root.join(hash)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn unref(&self, state: &mut State, hash: String) -> Result<(), errors::Error> {
+        let refcount = state
+            .refcounts
+            .get_mut(&hash)
+            .context_invoker("Output store refcount is missing")?;
+        *refcount -= 1;
+        if *refcount == 0 {
+            state.refcounts.remove(&hash);
+            let path = self.root.join(&hash);
+            std::fs::remove_file(&path)
+                .with_context_invoker(|| format!("Failed to remove output at {path:?}"))?;
+        }
+        Ok(())
+    }
+}
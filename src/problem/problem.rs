@@ -1,13 +1,90 @@
-use crate::{errors, errors::ToResult, image::strategy};
+use crate::{
+    config, errors,
+    errors::ToResult,
+    image::strategy,
+    problem::{scoring, verdict},
+    signing,
+};
 use multiprocessing::Object;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ProblemRevision {
     pub dependency_graph: DependencyGraph,
     pub strategy_factory: strategy::StrategyFactory,
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<u64>>,
+    // IOI-style scoring, see ScoringGroup. The groups can be selected by name like those above
+    #[serde(default)]
+    pub scoring_groups: Vec<ScoringGroup>,
+    // How the score of the submission is computed, see scoring::ScoringPolicy
+    #[serde(default)]
+    pub scoring: scoring::ScoringPolicy,
+    // Optional human-readable metadata of the tests, by test number
+    #[serde(default)]
+    pub test_manifest: HashMap<u64, TestMetadata>,
+    #[serde(default)]
+    pub judging_policy: JudgingPolicy,
+    #[serde(default)]
+    pub limits: config::LimitsOverride,
+    // The name of the image the problem is judged with, e.g. because its checker needs a newer
+    // toolchain. The default image is used if unset
+    #[serde(default)]
+    pub image: Option<String>,
+    // Extra compiler flags by language name, e.g. -DONLINE_JUDGE. Each must be allowed by the
+    // configuration of the language
+    #[serde(default)]
+    pub compiler_flags: HashMap<String, Vec<String>>,
+    // SHA-256 of judging.msgpack
+    #[serde(skip)]
+    pub hash: String,
+    #[serde(skip)]
+    pub problem_id: String,
+    #[serde(skip)]
+    pub revision_id: String,
+    // The tests present in the problem cache, so that selectors can be checked against them
+    #[serde(skip)]
+    pub tests: BTreeSet<u64>,
+    // The CPU time the last judged submission spent on each test, for TestOrder::CheapestFirst.
+    // Shared by all submissions of the revision, as the revision is only loaded once
+    #[serde(skip)]
+    pub runtimes: Arc<Mutex<HashMap<u64, std::time::Duration>>>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct JudgingPolicy {
+    // Once a test fails, the remaining tests of the push are skipped, as if they all depended on it.
+    // If the push is judged on several cores, the failure stops all of them, so which tests are
+    // skipped then depends on how far the other cores have got
+    #[serde(default)]
+    pub fail_fast: bool,
+    #[serde(default)]
+    pub order: TestOrder,
+    // Wall-clock time the whole submission may take to judge, counted from when it is added. Once
+    // it runs out, the tests in progress or yet to be judged are reported as OutOfBudget and the
+    // invoker finalizes the submission by itself. Protects the invoker from problems with
+    // thousands of slow tests
+    #[serde(default)]
+    pub time_budget: Option<std::time::Duration>,
+}
+
+// How the tests pushed by the conductor are reordered. Whatever the order, a test is never judged
+// before the tests it depends on
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TestOrder {
+    // As pushed
+    #[default]
+    Given,
+    // The tests marked as samples in the test manifest go first
+    SamplesFirst,
+    // By the CPU time spent on the tests by previous submissions. Tests that have never been judged
+    // go first, in the order they were pushed in
+    CheapestFirst,
 }
 
 #[derive(Object, Clone, Deserialize, Serialize)]
@@ -15,35 +92,378 @@ pub struct DependencyGraph {
     pub dependents_of: HashMap<u64, Vec<u64>>,
 }
 
+// The points of a submission are the sum of the points of the groups. Whatever the policy, a group
+// is only judged if the groups it depends on are passed: a failed test disables the tests of the
+// dependent groups, just like dependents_of does for single tests.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ScoringGroup {
+    pub name: String,
+    pub tests: Vec<u64>,
+    pub points: u64,
+    #[serde(default)]
+    pub policy: GroupPolicy,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupPolicy {
+    // The points are scaled by the lowest score of the tests, so a single failure zeroes the group.
+    // The remaining tests of the group are skipped after that
+    #[default]
+    AllOrNothing,
+    // The points are scaled by the average score of the tests
+    PerTest,
+}
+
+// Tests are numbered, but problems often name them, e.g. sample-03, so that feedback is readable
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TestMetadata {
+    pub name: String,
+    // The test is added to the group with this name, which can be selected like the others
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    // The weight of the test under the weighted scoring policy, unless the policy says otherwise
+    #[serde(default)]
+    pub points: Option<u64>,
+    // Judged first under TestOrder::SamplesFirst
+    #[serde(default)]
+    pub sample: bool,
+}
+
+// Tests can be referred to in batches so that the conductor doesn't have to know the exact test
+// numbering of the problem
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TestSelector {
+    Test(u64),
+    // Inclusive on both sides
+    Range(u64, u64),
+    Group(String),
+}
+
 #[derive(Object, Clone)]
 pub struct InstantiatedDependencyGraph {
     pub graph: DependencyGraph,
-    pub disabled_tests: HashSet<u64>,
+    // Disabled test -> the failed test that caused it, which is the test itself if it failed
+    pub disabled_tests: HashMap<u64, u64>,
+    // See JudgingPolicy::fail_fast
+    pub fail_fast: bool,
+    pub first_failure: Option<u64>,
 }
 
 impl ProblemRevision {
     pub fn load_from_cache(path: &Path) -> Result<Self, errors::Error> {
-        let config = std::fs::read(path.join("judging.msgpack")).with_context_invoker(|| {
-            format!("Could not read judging.msgpack to load problem from cache at {path:?}")
-        })?;
+        let config_bytes =
+            std::fs::read(path.join("judging.msgpack")).with_context_invoker(|| {
+                format!("Could not read judging.msgpack to load problem from cache at {path:?}")
+            })?;
 
-        let mut config: Self = rmp_serde::from_slice(&config).map_err(|e| {
+        let mut config: Self = rmp_serde::from_slice(&config_bytes).map_err(|e| {
             errors::ConfigurationFailure(format!(
                 "Failed to parse judging.msgpack to load problem from cache at {path:?}: {e:?}"
             ))
         })?;
 
+        config.strategy_factory.lower_pipeline()?;
+        config.add_scoring_groups()?;
+        config.add_manifest_groups()?;
+        config.scoring.validate(&config.scoring_groups)?;
         config.strategy_factory.root = path.to_owned();
+        config.tests = Self::list_tests(path)?;
+        config.hash = signing::sha256_hex(&config_bytes);
 
         Ok(config)
     }
+
+    // Tests are stored in tests/<number>
+    fn list_tests(path: &Path) -> Result<BTreeSet<u64>, errors::Error> {
+        let mut tests = BTreeSet::new();
+        let entries = std::fs::read_dir(path.join("tests")).with_context_invoker(|| {
+            format!("Could not list the tests to load problem from cache at {path:?}")
+        })?;
+        for entry in entries {
+            let entry = entry.with_context_invoker(|| {
+                format!("Could not list the tests to load problem from cache at {path:?}")
+            })?;
+            if let Some(test) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                tests.insert(test);
+            }
+        }
+        Ok(tests)
+    }
+
+    // Lowers the groups into the dependency graph and makes them selectable by name
+    fn add_scoring_groups(&mut self) -> Result<(), errors::Error> {
+        let mut tests_by_group = HashMap::new();
+        for group in &self.scoring_groups {
+            if tests_by_group
+                .insert(group.name.clone(), group.tests.clone())
+                .is_some()
+            {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Scoring group {} is declared more than once",
+                    group.name
+                )));
+            }
+        }
+
+        for group in &self.scoring_groups {
+            if let GroupPolicy::AllOrNothing = group.policy {
+                for test in &group.tests {
+                    self.dependency_graph.add_dependents(*test, &group.tests);
+                }
+            }
+            for dependency in &group.depends_on {
+                let dependency_tests = tests_by_group.get(dependency).ok_or_else(|| {
+                    errors::ConfigurationFailure(format!(
+                        "Scoring group {} depends on group {dependency}, which does not exist",
+                        group.name
+                    ))
+                })?;
+                for test in dependency_tests {
+                    self.dependency_graph.add_dependents(*test, &group.tests);
+                }
+            }
+            self.groups
+                .entry(group.name.clone())
+                .or_insert_with(|| group.tests.clone());
+        }
+
+        Ok(())
+    }
+
+    fn add_manifest_groups(&mut self) -> Result<(), errors::Error> {
+        let mut names = HashSet::new();
+        // In the order of the tests, so that groups are judged in that order too
+        let mut tests: Vec<(&u64, &TestMetadata)> = self.test_manifest.iter().collect();
+        tests.sort_by_key(|(test, _)| **test);
+        for (test, metadata) in tests {
+            if !names.insert(&metadata.name) {
+                return Err(errors::ConfigurationFailure(format!(
+                    "Test name {} is used more than once",
+                    metadata.name
+                )));
+            }
+            if let Some(ref group) = metadata.group {
+                let group_tests = self.groups.entry(group.clone()).or_default();
+                if !group_tests.contains(test) {
+                    group_tests.push(*test);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn test_name(&self, test: u64) -> Option<String> {
+        self.test_manifest
+            .get(&test)
+            .map(|metadata| metadata.name.clone())
+    }
+
+    // The results of the scoring groups that contain the test and have all their tests judged
+    pub fn judge_groups_of(
+        &self,
+        test: u64,
+        results: &BTreeMap<u64, verdict::TestJudgementResult>,
+    ) -> Vec<(String, verdict::GroupJudgementResult)> {
+        self.scoring_groups
+            .iter()
+            .filter(|group| group.tests.contains(&test))
+            .filter_map(|group| Some((group.name.clone(), group.judge(results)?)))
+            .collect()
+    }
+
+    pub fn score(
+        &self,
+        results: &BTreeMap<u64, verdict::TestJudgementResult>,
+    ) -> scoring::SubmissionScore {
+        self.scoring
+            .aggregate(&self.scoring_groups, &self.test_manifest, results)
+    }
+
+    pub fn record_runtime(&self, test: u64, result: &verdict::TestJudgementResult) {
+        if result.invocation_stats.is_empty() {
+            return;
+        }
+        let cpu_time = result
+            .invocation_stats
+            .values()
+            .map(|stat| stat.cpu_time)
+            .sum();
+        self.runtimes.lock().unwrap().insert(test, cpu_time);
+    }
+
+    // Reorders the tests according to the judging policy. Among the tests whose dependencies have
+    // been judged, the one that goes first by the policy is picked. The dependency graph may have
+    // cycles, which are broken in the order of the tests
+    pub fn order_tests(&self, tests: Vec<u64>) -> Vec<u64> {
+        let runtimes = self.runtimes.lock().unwrap();
+        let key = |test: &u64| -> u128 {
+            match self.judging_policy.order {
+                TestOrder::Given => 0,
+                TestOrder::SamplesFirst => match self.test_manifest.get(test) {
+                    Some(metadata) if metadata.sample => 0,
+                    _ => 1,
+                },
+                TestOrder::CheapestFirst => runtimes.get(test).map_or(0, |time| time.as_nanos()),
+            }
+        };
+        if let TestOrder::Given = self.judging_policy.order {
+            return tests;
+        }
+
+        let index_of: HashMap<u64, usize> = tests
+            .iter()
+            .enumerate()
+            .map(|(i, test)| (*test, i))
+            .collect();
+        let (dependents_of, index_of, tests_ref) =
+            (&self.dependency_graph.dependents_of, &index_of, &tests);
+        let dependents = move |i: usize| {
+            dependents_of
+                .get(&tests_ref[i])
+                .into_iter()
+                .flatten()
+                .filter_map(move |test| index_of.get(test).copied())
+                .filter(move |j| *j != i)
+        };
+
+        let mut n_dependencies = vec![0usize; tests.len()];
+        for i in 0..tests.len() {
+            for j in dependents(i) {
+                n_dependencies[j] += 1;
+            }
+        }
+        let mut ready: BinaryHeap<Reverse<(u128, usize)>> = (0..tests.len())
+            .filter(|i| n_dependencies[*i] == 0)
+            .map(|i| Reverse((key(&tests[i]), i)))
+            .collect();
+        let mut judged = vec![false; tests.len()];
+        let mut ordered = Vec::with_capacity(tests.len());
+        while ordered.len() < tests.len() {
+            let i = match ready.pop() {
+                Some(Reverse((_, i))) => i,
+                None => (0..tests.len()).find(|i| !judged[*i]).unwrap(),
+            };
+            if judged[i] {
+                continue;
+            }
+            judged[i] = true;
+            ordered.push(tests[i]);
+            for j in dependents(i) {
+                n_dependencies[j] = n_dependencies[j].saturating_sub(1);
+                if n_dependencies[j] == 0 && !judged[j] {
+                    ready.push(Reverse((key(&tests[j]), j)));
+                }
+            }
+        }
+        ordered
+    }
+
+    // Tests are returned in the order they were selected in, without duplicates. Ranges only cover
+    // the tests the problem has, so that the conductor can say 1..=u64::MAX to mean all tests
+    pub fn resolve_tests(&self, selectors: Vec<TestSelector>) -> Result<Vec<u64>, errors::Error> {
+        let mut tests = Vec::new();
+        let mut seen = HashSet::new();
+        for selector in selectors {
+            let batch = match selector {
+                TestSelector::Test(test) => {
+                    if !self.tests.contains(&test) {
+                        return Err(errors::ConductorFailure(format!(
+                            "Test {test} does not exist"
+                        )));
+                    }
+                    vec![test]
+                }
+                TestSelector::Range(first, last) => {
+                    if first > last {
+                        return Err(errors::ConductorFailure(format!(
+                            "Test range {first}..={last} is empty"
+                        )));
+                    }
+                    self.tests.range(first..=last).copied().collect()
+                }
+                TestSelector::Group(name) => self
+                    .groups
+                    .get(&name)
+                    .ok_or_else(|| {
+                        errors::ConductorFailure(format!("Test group {name} does not exist"))
+                    })?
+                    .clone(),
+            };
+            for test in batch {
+                if seen.insert(test) {
+                    tests.push(test);
+                }
+            }
+        }
+        Ok(tests)
+    }
+}
+
+impl ScoringGroup {
+    // None until all the tests of the group are judged
+    pub fn judge(
+        &self,
+        results: &BTreeMap<u64, verdict::TestJudgementResult>,
+    ) -> Option<verdict::GroupJudgementResult> {
+        let mut scores = Vec::new();
+        let mut verdict = verdict::TestVerdict::Accepted;
+        for test in &self.tests {
+            let result = results.get(test)?;
+            scores.push(result.verdict.score());
+            // The first failure in the order of the tests explains the outcome best
+            if !result.verdict.is_successful() && verdict.is_successful() {
+                verdict = result.verdict.clone();
+            }
+        }
+
+        let score = match self.policy {
+            GroupPolicy::AllOrNothing => scores.iter().copied().min().unwrap_or(10000),
+            GroupPolicy::PerTest => {
+                if scores.is_empty() {
+                    10000
+                } else {
+                    scores.iter().sum::<u64>() / scores.len() as u64
+                }
+            }
+        };
+        if verdict.is_successful() && score < 10000 {
+            verdict = verdict::TestVerdict::PartialSolution(score);
+        }
+
+        Some(verdict::GroupJudgementResult {
+            verdict,
+            points: self.points * score,
+            max_points: self.points * 10000,
+        })
+    }
 }
 
 impl DependencyGraph {
-    pub fn instantiate(self) -> InstantiatedDependencyGraph {
+    fn add_dependents(&mut self, test: u64, dependents: &[u64]) {
+        let existing = self.dependents_of.entry(test).or_default();
+        for dependent in dependents {
+            if *dependent != test && !existing.contains(dependent) {
+                existing.push(*dependent);
+            }
+        }
+    }
+
+    pub fn instantiate(self, fail_fast: bool) -> InstantiatedDependencyGraph {
         InstantiatedDependencyGraph {
             graph: self,
-            disabled_tests: HashSet::new(),
+            disabled_tests: HashMap::new(),
+            fail_fast,
+            first_failure: None,
         }
     }
 }
@@ -51,22 +471,41 @@ impl DependencyGraph {
 impl InstantiatedDependencyGraph {
     fn _fail_test(
         dependents_of: &HashMap<u64, Vec<u64>>,
-        disabled_tests: &mut HashSet<u64>,
+        disabled_tests: &mut HashMap<u64, u64>,
         test: u64,
+        cause: u64,
     ) {
-        if !disabled_tests.contains(&test) {
-            disabled_tests.insert(test);
+        if !disabled_tests.contains_key(&test) {
+            disabled_tests.insert(test, cause);
             for dep_test in dependents_of.get(&test).unwrap_or(&Vec::new()).iter() {
-                Self::_fail_test(dependents_of, disabled_tests, *dep_test)
+                Self::_fail_test(dependents_of, disabled_tests, *dep_test, cause)
             }
         }
     }
 
     pub fn fail_test(&mut self, test: u64) {
-        Self::_fail_test(&self.graph.dependents_of, &mut self.disabled_tests, test)
+        if self.first_failure.is_none() {
+            self.first_failure = Some(test);
+        }
+        Self::_fail_test(
+            &self.graph.dependents_of,
+            &mut self.disabled_tests,
+            test,
+            test,
+        )
     }
 
     pub fn is_test_enabled(&self, test: u64) -> bool {
-        !self.disabled_tests.contains(&test)
+        !self.disabled_tests.contains_key(&test)
+            && !(self.fail_fast && self.first_failure.is_some())
+    }
+
+    // The verdict of a test that is not run because it's disabled
+    pub fn skipped_verdict(&self, test: u64) -> verdict::TestVerdict {
+        match (self.disabled_tests.get(&test), self.first_failure) {
+            (Some(cause), _) => verdict::TestVerdict::Skipped(*cause),
+            (None, Some(cause)) if self.fail_fast => verdict::TestVerdict::Skipped(cause),
+            (None, _) => verdict::TestVerdict::Ignored,
+        }
     }
 }
@@ -0,0 +1,85 @@
+use crate::{
+    errors,
+    problem::{problem, verdict},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// How the results of the tests add up to the score of the submission. The score only covers the
+// tests judged so far, as the invoker doesn't know which tests the conductor is going to run.
+// Skipped tests count as failed.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringPolicy {
+    // Every test is worth one point, scaled by its score
+    #[default]
+    Sum,
+    // The points of the scoring groups, see problem::ScoringGroup. With the default policy of the
+    // groups, a group gets its points scaled by the lowest score among its tests. Groups with tests
+    // yet to be judged earn nothing
+    Groups,
+    // Like Sum, but a test is worth as many points as its weight. Tests not listed weigh as many
+    // points as the test manifest says, or one
+    Weighted(HashMap<u64, u64>),
+}
+
+// Points are in 10000 increments, like those of PartialSolution
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct SubmissionScore {
+    pub points: u64,
+    pub max_points: u64,
+}
+
+impl ScoringPolicy {
+    pub fn validate(&self, scoring_groups: &[problem::ScoringGroup]) -> Result<(), errors::Error> {
+        if let ScoringPolicy::Groups = self {
+            if scoring_groups.is_empty() {
+                return Err(errors::ConfigurationFailure(
+                    "The score is aggregated by scoring groups, but the problem has none"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn aggregate(
+        &self,
+        scoring_groups: &[problem::ScoringGroup],
+        test_manifest: &HashMap<u64, problem::TestMetadata>,
+        results: &BTreeMap<u64, verdict::TestJudgementResult>,
+    ) -> SubmissionScore {
+        let mut score = SubmissionScore {
+            points: 0,
+            max_points: 0,
+        };
+        match self {
+            ScoringPolicy::Sum => {
+                for result in results.values() {
+                    score.points += result.verdict.score();
+                    score.max_points += 10000;
+                }
+            }
+            ScoringPolicy::Groups => {
+                for group in scoring_groups {
+                    if let Some(result) = group.judge(results) {
+                        score.points += result.points;
+                    }
+                    score.max_points += group.points * 10000;
+                }
+            }
+            ScoringPolicy::Weighted(weights) => {
+                for (test, result) in results.iter() {
+                    let weight = weights
+                        .get(test)
+                        .copied()
+                        .or_else(|| test_manifest.get(test)?.points)
+                        .unwrap_or(1);
+                    score.points += weight * result.verdict.score();
+                    score.max_points += weight * 10000;
+                }
+            }
+        }
+        score
+    }
+}
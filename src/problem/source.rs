@@ -0,0 +1,525 @@
+use crate::{communicator, config, errors, errors::ToResult, signing};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+// A problem revision is stored as a manifest, which lists the files of the revision along with the
+// hashes of their contents, and the files themselves, addressed by hash. By default, both are
+// requested from the conductor over the websocket. Deployments with many invokers may instead put
+// them into a shared storage and point invokers at it, so that the conductor doesn't have to proxy
+// every test.
+pub trait ProblemSource: Send + Sync {
+    // The key is either manifest/<topic> or the hash of a file
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, errors::Error>>;
+
+    // Downloads the file to path. If path already exists, it is assumed to contain a prefix of the
+    // file from an interrupted download, which sources that support ranges resume from
+    fn download<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), errors::Error>> {
+        Box::pin(async move {
+            let data = self.fetch(key).await?;
+            std::fs::write(path, data).with_context_invoker(|| format!("Failed to write {path:?}"))
+        })
+    }
+}
+
+// How many times an HTTP download is resumed after the connection breaks before giving up. The
+// request timeout applies to each attempt, so large files take several attempts on slow links
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+pub fn from_config(
+    config: &config::ProblemSourceConfig,
+    communicator: Arc<communicator::Communicator>,
+) -> Result<Box<dyn ProblemSource>, errors::Error> {
+    Ok(match config {
+        config::ProblemSourceConfig::Conductor => Box::new(ConductorSource { communicator }),
+        config::ProblemSourceConfig::Local { path } => Box::new(LocalSource {
+            root: PathBuf::from(path),
+        }),
+        config::ProblemSourceConfig::Http { url } => Box::new(HttpSource {
+            client: http_client()?,
+            base_url: url.trim_end_matches('/').to_string(),
+        }),
+        config::ProblemSourceConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            prefix,
+            access_key,
+            secret_key,
+        } => {
+            let endpoint = endpoint.trim_end_matches('/').to_string();
+            let host = endpoint
+                .split_once("://")
+                .map_or(endpoint.as_str(), |(_, rest)| rest)
+                .to_string();
+            Box::new(S3Source {
+                client: http_client()?,
+                endpoint,
+                host,
+                bucket: bucket.clone(),
+                region: region.clone(),
+                prefix: prefix.clone(),
+                access_key: access_key.clone(),
+                secret_key: secret_key.clone(),
+            })
+        }
+    })
+}
+
+// Keys and paths come from manifests, which are not trusted to stay within the storage or the
+// staging directory
+fn validate_key(key: &str) -> Result<(), errors::Error> {
+    if key.is_empty()
+        || !Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(errors::ConfigurationFailure(format!(
+            "Invalid key {key:?} in problem manifest"
+        )));
+    }
+    Ok(())
+}
+
+fn http_client() -> Result<reqwest::Client, errors::Error> {
+    reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(60))
+        .build()
+        .context_invoker("Failed to create HTTP client")
+}
+
+async fn http_get(request: reqwest::RequestBuilder, key: &str) -> Result<Vec<u8>, errors::Error> {
+    let response = request
+        .send()
+        .await
+        .with_context_invoker(|| format!("Failed to request {key}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(errors::InvokerFailure(format!(
+            "Request for {key} failed with status {status}"
+        )));
+    }
+    Ok(response
+        .bytes()
+        .await
+        .with_context_invoker(|| format!("Failed to download {key}"))?
+        .to_vec())
+}
+
+// Streams the file to the end of path, asking only for the missing part with a Range header.
+// make_request is called for every attempt, so that signed requests are signed anew
+async fn http_download(
+    make_request: impl Fn() -> reqwest::RequestBuilder,
+    key: &str,
+    path: &Path,
+) -> Result<(), errors::Error> {
+    let mut last_error = None;
+    for _ in 0..DOWNLOAD_ATTEMPTS {
+        let offset = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.with_context_invoker(|| format!("Failed to stat {path:?}"))),
+        };
+
+        let mut request = make_request();
+        if offset > 0 {
+            request = request.header("range", format!("bytes={offset}-"));
+        }
+        let mut response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                last_error = Some(e.with_context_invoker(|| format!("Failed to request {key}")));
+                continue;
+            }
+        };
+
+        let status = response.status();
+        let mut file = std::fs::OpenOptions::new();
+        file.create(true);
+        if status == reqwest::StatusCode::PARTIAL_CONTENT {
+            file.append(true);
+        } else if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE && offset > 0 {
+            // The previous attempt got everything but didn't notice. The hash is verified anyway
+            return Ok(());
+        } else if status.is_success() {
+            // The server ignored the range, so the file is downloaded anew
+            file.write(true).truncate(true);
+        } else {
+            return Err(errors::InvokerFailure(format!(
+                "Request for {key} failed with status {status}"
+            )));
+        }
+        let mut file = file
+            .open(path)
+            .with_context_invoker(|| format!("Failed to open {path:?}"))?;
+
+        let streamed: Result<(), errors::Error> = try {
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .with_context_invoker(|| format!("Failed to download {key}"))?
+            {
+                file.write_all(&chunk)
+                    .with_context_invoker(|| format!("Failed to write to {path:?}"))?;
+            }
+        };
+        match streamed {
+            Ok(()) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap()
+        .context_invoker("Download failed after several attempts"))
+}
+
+struct ConductorSource {
+    communicator: Arc<communicator::Communicator>,
+}
+
+impl ProblemSource for ConductorSource {
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, errors::Error>> {
+        Box::pin(self.communicator.request_file(key))
+    }
+}
+
+// A directory laid out the same way as the keys, e.g. a mounted network share
+struct LocalSource {
+    root: PathBuf,
+}
+
+impl ProblemSource for LocalSource {
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, errors::Error>> {
+        Box::pin(async move {
+            validate_key(key)?;
+            let path = self.root.join(key);
+            tokio::fs::read(&path)
+                .await
+                .with_context_invoker(|| format!("Failed to read {path:?}"))
+        })
+    }
+}
+
+// Keys are appended to the base URL
+struct HttpSource {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl ProblemSource for HttpSource {
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, errors::Error>> {
+        Box::pin(async move {
+            validate_key(key)?;
+            http_get(self.client.get(format!("{}/{key}", self.base_url)), key).await
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), errors::Error>> {
+        Box::pin(async move {
+            validate_key(key)?;
+            let url = format!("{}/{key}", self.base_url);
+            http_download(|| self.client.get(&url), key, path).await
+        })
+    }
+}
+
+// S3-compatible object storage. Objects are addressed path-style, i.e. as
+// <endpoint>/<bucket>/<prefix><key>, which all implementations support, and requests are signed
+// with AWS Signature Version 4.
+struct S3Source {
+    client: reqwest::Client,
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Source {
+    fn sign(&self, path: &str) -> Vec<(&'static str, String)> {
+        let (date, time) = utc_now();
+        let amz_date = format!("{date}T{time}Z");
+        let payload_hash = signing::sha256_hex(b"");
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "GET\n{path}\n\nhost:{}\nx-amz-content-sha256:{payload_hash}\n\
+             x-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+            self.host
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            signing::sha256_hex(canonical_request.as_bytes())
+        );
+
+        let mut key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = signing::to_hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        vec![
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+                     Signature={signature}",
+                    self.access_key
+                ),
+            ),
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", amz_date),
+        ]
+    }
+}
+
+impl S3Source {
+    // Range is not among the signed headers, which SigV4 allows
+    fn request(&self, key: &str) -> reqwest::RequestBuilder {
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket),
+            uri_encode(&format!("{}{key}", self.prefix))
+        );
+        let mut request = self.client.get(format!("{}{path}", self.endpoint));
+        for (name, value) in self.sign(&path) {
+            request = request.header(name, value);
+        }
+        request
+    }
+}
+
+impl ProblemSource for S3Source {
+    fn fetch<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Vec<u8>, errors::Error>> {
+        Box::pin(async move {
+            validate_key(key)?;
+            http_get(self.request(key), key).await
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        key: &'a str,
+        path: &'a Path,
+    ) -> BoxFuture<'a, Result<(), errors::Error>> {
+        Box::pin(async move {
+            validate_key(key)?;
+            http_download(|| self.request(key), key, path).await
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    ring::hmac::sign(&key, data).as_ref().to_vec()
+}
+
+// Percent-encodes everything but unreserved characters and slashes, as SigV4 requires
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+// Returns the current date as YYYYMMDD and time as HHMMSS in UTC
+fn utc_now() -> (String, String) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        format!("{year:04}{month:02}{day:02}"),
+        format!(
+            "{:02}{:02}{:02}",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60
+        ),
+    )
+}
+
+fn sha256_file(path: &Path) -> Result<String, errors::Error> {
+    let mut file =
+        std::fs::File::open(path).with_context_invoker(|| format!("Failed to open {path:?}"))?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context_invoker(|| format!("Failed to read {path:?}"))?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+    Ok(signing::to_hex(context.finish().as_ref()))
+}
+
+// Downloads the files listed in the manifest of the topic to target_path.
+//
+// The files are put into <target_path>.partial first, which survives failures, so that the next
+// attempt only downloads what is missing. Files are named by their SHA-256 hashes, so each one is
+// verified before it is moved into place, including those left over from a previous attempt. When
+// everything is in place, the staging directory is atomically exchanged with target_path, so
+// readers see either the old revision or the complete new one. .ready is created last.
+pub async fn download_archive(
+    source: &dyn ProblemSource,
+    topic: &str,
+    target_path: &Path,
+) -> Result<(), errors::Error> {
+    let mut staging_path = target_path.as_os_str().to_owned();
+    staging_path.push(".partial");
+    let staging_path = PathBuf::from(staging_path);
+    // Partially downloaded files, by hash
+    let parts_path = staging_path.join(".parts");
+    std::fs::create_dir_all(&parts_path)
+        .with_context_invoker(|| format!("Failed to create {parts_path:?}"))?;
+
+    let manifest = source
+        .fetch(&format!("manifest/{topic}"))
+        .await
+        .context_invoker("Failed to load manifest")?;
+
+    let manifest = std::str::from_utf8(&manifest).map_err(|e| {
+        errors::ConfigurationFailure(format!("Invalid manifest for topic {topic}: {e:?}"))
+    })?;
+
+    // Where a file with the hash has already been put, so that duplicates are downloaded once
+    let mut downloaded: HashMap<&str, PathBuf> = HashMap::new();
+
+    for mut line in manifest.lines() {
+        if line.ends_with('/') {
+            // Directory. Paths from the manifest must stay inside the staging directory, as the
+            // storage it comes from is not necessarily trusted as much as the invoker
+            validate_key(line)?;
+            let dir_path = staging_path.join(&line);
+            std::fs::create_dir_all(&dir_path)
+                .with_context_invoker(|| format!("Failed to create {dir_path:?}"))?;
+        } else {
+            // File
+            let mut executable = false;
+            if line.starts_with("+x ") {
+                executable = true;
+                line = &line[3..];
+            }
+
+            let (hash, file) = line
+                .split_once(' ')
+                .context_invoker("Invalid manifest: invalid line format")?;
+            validate_key(hash)?;
+            validate_key(file)?;
+
+            let file_path = staging_path.join(&file);
+            let mut present = false;
+            if file_path.exists() {
+                present = sha256_file(&file_path)? == hash;
+                if !present {
+                    std::fs::remove_file(&file_path)
+                        .with_context_invoker(|| format!("Failed to remove {file_path:?}"))?;
+                }
+            }
+
+            if present {
+                // Left over from a previous attempt
+            } else if let Some(copy_from) = downloaded.get(hash) {
+                std::fs::copy(copy_from, &file_path).with_context_invoker(|| {
+                    format!("Failed to copy {copy_from:?} to {file_path:?}")
+                })?;
+            } else {
+                let part_path = parts_path.join(hash);
+                source
+                    .download(hash, &part_path)
+                    .await
+                    .with_context_invoker(|| format!("Failed to download file {file}"))?;
+                let actual_hash = sha256_file(&part_path)?;
+                if actual_hash != hash {
+                    std::fs::remove_file(&part_path)
+                        .with_context_invoker(|| format!("Failed to remove {part_path:?}"))?;
+                    return Err(errors::InvokerFailure(format!(
+                        "File {file} is damaged: expected hash {hash}, got {actual_hash}"
+                    )));
+                }
+                std::fs::rename(&part_path, &file_path).with_context_invoker(|| {
+                    format!("Failed to move {part_path:?} to {file_path:?}")
+                })?;
+            }
+            downloaded.insert(hash, file_path.clone());
+
+            if executable {
+                let mut permissions = file_path
+                    .metadata()
+                    .with_context_invoker(|| {
+                        format!("Failed to get metadata of {file_path:?}")
+                    })?
+                    .permissions();
+
+                // Whoever can read can also execute
+                permissions.set_mode(permissions.mode() | ((permissions.mode() & 0o444) >> 2));
+
+                std::fs::set_permissions(&file_path, permissions).with_context_invoker(
+                    || format!("Failed to make {file_path:?} executable"),
+                )?
+            }
+        }
+    }
+
+    std::fs::remove_dir_all(&parts_path)
+        .with_context_invoker(|| format!("Failed to remove {parts_path:?}"))?;
+    let ready_path = staging_path.join(".ready");
+    std::fs::write(&ready_path, b"")
+        .with_context_invoker(|| format!("Failed to write to {ready_path:?}"))?;
+
+    // The new contents are swapped with the previous ones in a single step, so that readers of the
+    // revision, such as other submissions, never see a missing or half-written directory. The
+    // previous contents end up at staging_path and are removed from there
+    match nix::fcntl::renameat2(
+        None,
+        &staging_path,
+        None,
+        target_path,
+        nix::fcntl::RenameFlags::RENAME_EXCHANGE,
+    ) {
+        Ok(()) => std::fs::remove_dir_all(&staging_path).with_context_invoker(|| {
+            format!("Failed to delete the previous contents at {staging_path:?}")
+        })?,
+        Err(nix::errno::Errno::ENOENT) => std::fs::rename(&staging_path, target_path)
+            .context_invoker("Failed to move the downloaded archive into place")?,
+        Err(e) => {
+            return Err(e).context_invoker("Failed to swap the downloaded archive into place");
+        }
+    }
+
+    Ok(())
+}
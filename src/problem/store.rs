@@ -1,20 +1,31 @@
-use crate::{communicator, errors, errors::ToResult, problem::problem};
+use crate::{
+    errors,
+    errors::ToResult,
+    problem::{problem, source},
+};
 use anyhow::{bail, Context};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::Mutex;
 
 pub struct ProblemStore {
     local_storage_path: PathBuf,
-    problems: Mutex<HashMap<String, Arc<Mutex<Option<Arc<problem::ProblemRevision>>>>>>,
-    communicator: Arc<communicator::Communicator>,
+    problems: Mutex<HashMap<String, Slot>>,
+    source: Box<dyn source::ProblemSource>,
+}
+
+struct Slot {
+    revision: Arc<Mutex<Option<Arc<problem::ProblemRevision>>>>,
+    last_used: Instant,
 }
 
 impl ProblemStore {
     pub fn new(
         local_storage_path: PathBuf,
-        communicator: Arc<communicator::Communicator>,
+        source: Box<dyn source::ProblemSource>,
     ) -> anyhow::Result<ProblemStore> {
         let meta = std::fs::metadata(&local_storage_path)
             .with_context(|| "Problem store cache directory is inaccessible")?;
@@ -24,7 +35,7 @@ impl ProblemStore {
         Ok(ProblemStore {
             local_storage_path,
             problems: Mutex::new(HashMap::new()),
-            communicator,
+            source,
         })
     }
 
@@ -33,37 +44,124 @@ impl ProblemStore {
         problem_id: String,
         revision_id: String,
     ) -> Result<Arc<problem::ProblemRevision>, errors::Error> {
-        let topic = format!("problems/{problem_id}/{revision_id}");
-        let root_path = self.local_storage_path.join(problem_id).join(revision_id);
+        let mutex = self.get_slot(&problem_id, &revision_id).await;
+        let mut guard = mutex.lock().await;
+
+        if guard.is_none() {
+            *guard = Some(self.fetch(problem_id, revision_id, false).await?);
+        }
 
-        let mutex = self
-            .problems
-            .lock()
-            .await
-            .entry(topic.clone())
-            .or_insert_with(|| Arc::new(Mutex::new(None)))
-            .clone();
+        Ok(guard.as_ref().unwrap().clone())
+    }
 
+    // Downloads the revision anew after some of its cached files turned out to be missing or
+    // corrupted. If several submissions hit the same stale revision, only the first one downloads
+    // it again. Submissions that still use the stale revision may read its directory meanwhile,
+    // which is why download_archive stages the files elsewhere and swaps them in atomically.
+    pub async fn refetch_revision(
+        &self,
+        stale: &Arc<problem::ProblemRevision>,
+    ) -> Result<Arc<problem::ProblemRevision>, errors::Error> {
+        let mutex = self.get_slot(&stale.problem_id, &stale.revision_id).await;
         let mut guard = mutex.lock().await;
 
-        if guard.is_none() {
-            if !root_path.exists() {
-                std::fs::create_dir_all(&root_path)
-                    .with_context_invoker(|| format!("Failed to create directory {root_path:?}"))?;
+        match *guard {
+            Some(ref current) if !Arc::ptr_eq(current, stale) => {}
+            _ => {
+                *guard = Some(
+                    self.fetch(stale.problem_id.clone(), stale.revision_id.clone(), true)
+                        .await?,
+                );
             }
+        }
 
-            if !root_path.join(".ready").exists() {
-                self.communicator
-                    .download_archive(&topic, root_path.as_ref())
-                    .await
-                    .with_context_invoker(|| format!("Failed to load archive for topic {topic}"))?;
-            }
+        Ok(guard.as_ref().unwrap().clone())
+    }
+
+    async fn get_slot(
+        &self,
+        problem_id: &str,
+        revision_id: &str,
+    ) -> Arc<Mutex<Option<Arc<problem::ProblemRevision>>>> {
+        let mut problems = self.problems.lock().await;
+        let slot = problems
+            .entry(format!("problems/{problem_id}/{revision_id}"))
+            .or_insert_with(|| Slot {
+                revision: Arc::new(Mutex::new(None)),
+                last_used: Instant::now(),
+            });
+        slot.last_used = Instant::now();
+        slot.revision.clone()
+    }
 
-            *guard = Some(Arc::new(problem::ProblemRevision::load_from_cache(
-                &root_path,
-            )?));
+    // Forgets all but the floor most recently used revisions. Their files stay in the disk cache,
+    // so loading them again is cheap. Submissions that use a forgotten revision keep their reference
+    pub async fn shrink(&self, floor: usize) {
+        let mut problems = self.problems.lock().await;
+        let mut by_last_use: Vec<(Instant, String)> = problems
+            .iter()
+            .map(|(topic, slot)| (slot.last_used, topic.clone()))
+            .collect();
+        by_last_use.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, topic) in by_last_use.into_iter().skip(floor) {
+            problems.remove(&topic);
         }
+    }
 
-        Ok(guard.as_ref().unwrap().clone())
+    // Asks the kernel to evict the files of cached problems from the page cache. The pages are
+    // clean, so this only costs a disk read when a test is run again
+    pub fn release_page_cache(&self) -> Result<(), errors::Error> {
+        release_page_cache(&self.local_storage_path)
+    }
+
+    async fn fetch(
+        &self,
+        problem_id: String,
+        revision_id: String,
+        force: bool,
+    ) -> Result<Arc<problem::ProblemRevision>, errors::Error> {
+        let topic = format!("problems/{problem_id}/{revision_id}");
+        let root_path = self.local_storage_path.join(&problem_id).join(&revision_id);
+
+        if !root_path.exists() {
+            std::fs::create_dir_all(&root_path)
+                .with_context_invoker(|| format!("Failed to create directory {root_path:?}"))?;
+        }
+
+        if force || !root_path.join(".ready").exists() {
+            source::download_archive(self.source.as_ref(), &topic, root_path.as_ref())
+                .await
+                .with_context_invoker(|| format!("Failed to load archive for topic {topic}"))?;
+        }
+
+        let mut revision = problem::ProblemRevision::load_from_cache(&root_path)?;
+        revision.problem_id = problem_id;
+        revision.revision_id = revision_id;
+        Ok(Arc::new(revision))
+    }
+}
+
+fn release_page_cache(path: &Path) -> Result<(), errors::Error> {
+    for entry in
+        std::fs::read_dir(path).with_context_invoker(|| format!("Failed to read {path:?}"))?
+    {
+        let entry = entry.with_context_invoker(|| format!("Failed to read {path:?}"))?;
+        let file_type = entry
+            .file_type()
+            .with_context_invoker(|| format!("Failed to stat {:?}", entry.path()))?;
+        if file_type.is_dir() {
+            release_page_cache(&entry.path())?;
+        } else if file_type.is_file() {
+            // The file may be removed concurrently by a refetch, which is fine
+            if let Ok(file) = std::fs::File::open(entry.path()) {
+                let _ = nix::fcntl::posix_fadvise(
+                    file.as_raw_fd(),
+                    0,
+                    0,
+                    nix::fcntl::PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+                );
+            }
+        }
     }
+    Ok(())
 }
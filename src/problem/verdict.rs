@@ -29,16 +29,25 @@ use std::os::unix::process::ExitStatusExt;
 //     CheckerFailed(u64),
 // }
 
-#[derive(Object, Debug, Serialize, Clone)]
+#[derive(Object, Debug, Serialize, Clone, PartialEq)]
 pub enum TestVerdict {
     InQueue,
     Running,
     Ignored,
+    // Not run because a test it depends on failed, see problem::InstantiatedDependencyGraph. The
+    // value is the failed test. multiprocessing only supports tuple variants, hence no field name
+    Skipped(u64),
+    // Not run, or cut short, because the submission used up its judging time budget, see
+    // problem::JudgingPolicy::time_budget
+    OutOfBudget,
 
     Accepted,
     PartialSolution(u64), // in 10000 increments
 
     Bug(String),
+    // The validator of the problem rejected the input of the test, so the test data is broken and
+    // the verdict says nothing about the submission
+    InvalidTest(String),
 
     WrongAnswer,
     RuntimeError(ExitStatus),
@@ -47,6 +56,10 @@ pub enum TestVerdict {
     PresentationError,
     IdlenessLimitExceeded,
     CheckerFailed,
+    // A file the program wrote to reached InvocationLimit::output_size
+    OutputLimitExceeded,
+    // The sandbox failed an integrity check after the test, see image::canary
+    SecurityViolation(String),
 }
 
 #[derive(Object, Debug, Serialize)]
@@ -54,6 +67,167 @@ pub struct TestJudgementResult {
     pub verdict: TestVerdict,
     pub logs: HashMap<String, Vec<u8>>,
     pub invocation_stats: HashMap<String, InvocationStat>,
+    // The seed passed to problem-supplied programs, None if the test was not run
+    pub seed: Option<u64>,
+    // Filled in by the client right before the result is sent to the conductor
+    pub fingerprint: Option<MachineFingerprint>,
+    // How many times the test was rerun because of infrastructure failures
+    pub retries: u32,
+    // The block whose outcome determined the verdict if it is not successful, e.g. which of the runs
+    // of a run-twice problem crashed
+    pub failed_block: Option<String>,
+    // From the test manifest of the problem, see problem::TestMetadata. Filled in by the client like
+    // the fingerprint
+    pub test_name: Option<String>,
+}
+
+impl TestJudgementResult {
+    pub fn without_logs(&self) -> Self {
+        TestJudgementResult {
+            verdict: self.verdict.clone(),
+            logs: HashMap::new(),
+            invocation_stats: self.invocation_stats.clone(),
+            seed: self.seed,
+            fingerprint: self.fingerprint.clone(),
+            retries: self.retries,
+            failed_block: self.failed_block.clone(),
+            test_name: self.test_name.clone(),
+        }
+    }
+}
+
+// The outcome of a scoring group, see problem::ScoringGroup. Points are in 10000 increments, like
+// those of PartialSolution
+#[derive(Debug, Serialize, Clone)]
+pub struct GroupJudgementResult {
+    // Accepted, PartialSolution, or the first unsuccessful verdict among the tests
+    pub verdict: TestVerdict,
+    pub points: u64,
+    pub max_points: u64,
+}
+
+// The verdicts of a test in the revision the submission was added with and in a candidate revision.
+// None means the test does not exist in that revision.
+#[derive(Object, Debug, Serialize, Clone)]
+pub struct TestComparison {
+    pub test: u64,
+    pub baseline: Option<TestVerdict>,
+    pub candidate: Option<TestVerdict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevisionDiff {
+    pub unchanged: Vec<u64>,
+    pub changed: Vec<TestComparison>,
+}
+
+impl RevisionDiff {
+    pub fn new(comparisons: Vec<TestComparison>) -> Self {
+        let mut diff = RevisionDiff {
+            unchanged: Vec::new(),
+            changed: Vec::new(),
+        };
+        for comparison in comparisons {
+            if comparison.baseline == comparison.candidate {
+                diff.unchanged.push(comparison.test);
+            } else {
+                diff.changed.push(comparison);
+            }
+        }
+        diff
+    }
+}
+
+// CPU times of a block differ significantly if the larger one is this many times the smaller one
+// and exceeds MIN_SIGNIFICANT_CPU_TIME. Short runs are dominated by noise, e.g. process startup
+const CPU_TIME_DIVERGENCE_RATIO: f64 = 1.5;
+const MIN_SIGNIFICANT_CPU_TIME: std::time::Duration = std::time::Duration::from_millis(50);
+
+// How the results of two submissions on a test differ. Only tests where something differs are
+// reported.
+#[derive(Debug, Serialize)]
+pub struct TestDivergence {
+    pub test: u64,
+    pub verdicts: (TestVerdict, TestVerdict),
+    // Files of the strategy with different contents, including files only one run produced
+    pub changed_files: Vec<String>,
+    // CPU times of the blocks whose CPU times differ significantly
+    pub cpu_times: HashMap<String, (std::time::Duration, std::time::Duration)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubmissionDiff {
+    pub identical: Vec<u64>,
+    pub diverged: Vec<TestDivergence>,
+}
+
+impl SubmissionDiff {
+    pub fn new(
+        results: Vec<(u64, TestJudgementResult)>,
+        other_results: Vec<(u64, TestJudgementResult)>,
+    ) -> Self {
+        let mut diff = SubmissionDiff {
+            identical: Vec::new(),
+            diverged: Vec::new(),
+        };
+        for ((test, result), (_, other_result)) in results.into_iter().zip(other_results) {
+            let mut changed_files: Vec<String> = result
+                .logs
+                .iter()
+                .filter(|(name, data)| other_result.logs.get(*name) != Some(data))
+                .map(|(name, _)| name.clone())
+                .chain(
+                    other_result
+                        .logs
+                        .keys()
+                        .filter(|name| !result.logs.contains_key(*name))
+                        .cloned(),
+                )
+                .collect();
+            changed_files.sort();
+
+            let mut cpu_times = HashMap::new();
+            for (block, stat) in result.invocation_stats.iter() {
+                if let Some(other_stat) = other_result.invocation_stats.get(block) {
+                    let (min, max) = if stat.cpu_time < other_stat.cpu_time {
+                        (stat.cpu_time, other_stat.cpu_time)
+                    } else {
+                        (other_stat.cpu_time, stat.cpu_time)
+                    };
+                    if max > MIN_SIGNIFICANT_CPU_TIME
+                        && max.as_secs_f64() > min.as_secs_f64() * CPU_TIME_DIVERGENCE_RATIO
+                    {
+                        cpu_times.insert(block.clone(), (stat.cpu_time, other_stat.cpu_time));
+                    }
+                }
+            }
+
+            if result.verdict == other_result.verdict
+                && changed_files.is_empty()
+                && cpu_times.is_empty()
+            {
+                diff.identical.push(test);
+            } else {
+                diff.diverged.push(TestDivergence {
+                    test,
+                    verdicts: (result.verdict, other_result.verdict),
+                    changed_files,
+                    cpu_times,
+                });
+            }
+        }
+        diff
+    }
+}
+
+// Identifies the hardware and software a test was judged on, so that timing anomalies can be traced
+// back to a particular machine
+#[derive(Object, Debug, Serialize, Clone)]
+pub struct MachineFingerprint {
+    pub host_id: String,
+    pub invoker_name: String,
+    pub core: u64,
+    pub image_version: String,
 }
 
 #[derive(Object, Debug, Serialize, Clone)]
@@ -63,6 +237,11 @@ pub struct InvocationStat {
     pub user_time: std::time::Duration,
     pub sys_time: std::time::Duration,
     pub memory: usize,
+    // Disk I/O, not counting the page cache
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    // The THP mode the program ran with, None if the system setting could not be determined
+    pub thp: Option<ThpMode>,
 }
 
 #[derive(Object, Debug, Deserialize, Clone)]
@@ -70,6 +249,217 @@ pub struct InvocationLimit {
     pub real_time: std::time::Duration,
     pub cpu_time: std::time::Duration,
     pub memory: usize,
+    #[serde(default)]
+    pub thp: ThpPolicy,
+    // A program that spends this much wall-clock time with next to no CPU usage, e.g. blocked on a
+    // pipe nobody writes to, is killed with IdlenessLimitExceeded right away rather than when the
+    // real time limit expires
+    #[serde(default)]
+    pub idleness: Option<std::time::Duration>,
+    // If set, syscalls are traced and the log is attached to the result as "<block>.syscalls".
+    // Only the listed syscalls are logged, or all of them if the list is empty. Tracing slows the
+    // program down a lot, so this is for debugging only
+    #[serde(default)]
+    pub trace: Option<Vec<String>>,
+    // If set and the program is killed by a signal such as SIGSEGV, the signal, the faulting
+    // address and a symbolized guess at the stack are attached to the result as "<block>.crash"
+    #[serde(default)]
+    pub crash_report: bool,
+    // The largest file the program may write, in bytes, including stdout and stderr redirected to
+    // files. Enforced with RLIMIT_FSIZE, so that a runaway program doesn't fill the tmpfs and fail
+    // with a disk quota error instead
+    #[serde(default)]
+    pub output_size: Option<u64>,
+}
+
+// Languages differ in speed and memory footprint, so the limits of the submission are scaled by its
+// language. Problem-supplied programs are not affected
+#[derive(Object, Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct LimitMultipliers {
+    // Applies to both CPU and real time
+    #[serde(default = "default_multiplier")]
+    pub time: f64,
+    #[serde(default = "default_multiplier")]
+    pub memory: f64,
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+impl Default for LimitMultipliers {
+    fn default() -> Self {
+        Self {
+            time: 1.0,
+            memory: 1.0,
+        }
+    }
+}
+
+impl LimitMultipliers {
+    // Durations panic on negative and non-finite factors, and anything beyond this is a mistake
+    const MAX: f64 = 100.0;
+
+    pub fn validate(&self) -> Result<(), crate::errors::Error> {
+        for (name, value) in [("time", self.time), ("memory", self.memory)] {
+            if !(value > 0.0 && value <= Self::MAX) {
+                return Err(crate::errors::ConfigurationFailure(format!(
+                    "The {name} multiplier is {value}, which is out of range (0, {}]",
+                    Self::MAX
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The multipliers applied one after another
+    pub fn then(&self, other: &LimitMultipliers) -> LimitMultipliers {
+        LimitMultipliers {
+            time: self.time * other.time,
+            memory: self.memory * other.memory,
+        }
+    }
+
+    pub fn apply(&self, limit: &mut InvocationLimit) {
+        limit.real_time = limit.real_time.mul_f64(self.time);
+        limit.cpu_time = limit.cpu_time.mul_f64(self.time);
+        limit.memory = (limit.memory as f64 * self.memory) as usize;
+    }
+}
+
+// Limits of checkers set by the problem rather than by the conductor, as they depend on how the
+// checker is written and not on the limits of the submission
+#[derive(Object, Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct CheckerLimits {
+    pub real_time: std::time::Duration,
+    pub cpu_time: std::time::Duration,
+    pub memory: usize,
+}
+
+impl CheckerLimits {
+    // The rest of the settings, e.g. tracing, are kept if the conductor sent limits for the block
+    pub fn apply(&self, limit: Option<InvocationLimit>) -> InvocationLimit {
+        let mut limit = limit.unwrap_or(InvocationLimit {
+            real_time: self.real_time,
+            cpu_time: self.cpu_time,
+            memory: self.memory,
+            thp: ThpPolicy::default(),
+            idleness: None,
+            trace: None,
+            crash_report: false,
+            output_size: None,
+        });
+        limit.real_time = self.real_time;
+        limit.cpu_time = self.cpu_time;
+        limit.memory = self.memory;
+        limit
+    }
+}
+
+// khugepaged collapses pages into transparent hugepages at arbitrary moments, and page faults may
+// allocate hugepages or not depending on fragmentation, which shows up as noise in timings. THP can
+// be disabled for an invocation, which is inherited by its children and survives execve. Otherwise,
+// the system-wide setting applies.
+#[derive(Object, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ThpPolicy {
+    System,
+    Disabled,
+}
+
+impl Default for ThpPolicy {
+    fn default() -> Self {
+        ThpPolicy::System
+    }
+}
+
+// As in /sys/kernel/mm/transparent_hugepage/enabled
+#[derive(Object, Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ThpMode {
+    Always,
+    Madvise,
+    Never,
+}
+
+impl ThpMode {
+    pub fn read_system() -> Option<Self> {
+        let enabled =
+            std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled").ok()?;
+        // The current mode is in brackets, e.g. "always [madvise] never"
+        let mode = enabled.split_once('[')?.1.split_once(']')?.0;
+        match mode {
+            "always" => Some(ThpMode::Always),
+            "madvise" => Some(ThpMode::Madvise),
+            "never" => Some(ThpMode::Never),
+            _ => None,
+        }
+    }
+}
+
+// A group of tests sharing a common CPU time budget: the total CPU time spent by user blocks on
+// these tests must not exceed cpu_time. The budget is tracked per core, so the conductor is expected
+// to push all tests of a group to a single core.
+#[derive(Object, Debug, Deserialize, Clone)]
+pub struct GroupTimeBudget {
+    pub tests: Vec<u64>,
+    pub cpu_time: std::time::Duration,
+}
+
+// How the exit codes of problem-supplied checkers and interactors map to verdicts. Problems imported
+// from other systems often come with checkers built against a testlib configured for that system,
+// e.g. with -DEJUDGE, which changes the exit codes
+#[derive(Object, Clone, Debug, Default, Deserialize, Serialize)]
+pub enum ExitCodeConvention {
+    // The default testlib and Codeforces convention: 0 AC, 1 WA, 2 PE, 3 FL, 4 and 8 PE, 7 PT
+    #[default]
+    Testlib,
+    // testlib with EJUDGE defined: 0 AC, 4 PE, 5 WA, 6 FL, 7 PT
+    Ejudge,
+    // testlib with CONTESTER defined: 0xAC AC, 0xAB WA, 0xAA and 0xA6 PE, 0xA3 FL
+    Contester,
+    // Exit code -> outcome. Codes not in the table are reported as Bug
+    Custom(HashMap<u8, CheckerOutcome>),
+}
+
+#[derive(Object, Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum CheckerOutcome {
+    Accepted,
+    WrongAnswer,
+    PresentationError,
+    CheckerFailed,
+    // Partial solution, with the points in stderr as 'points <value>'
+    Points,
+}
+
+impl ExitCodeConvention {
+    pub fn outcome(&self, code: u8) -> Option<CheckerOutcome> {
+        use CheckerOutcome::*;
+        match self {
+            Self::Testlib => match code {
+                0 => Some(Accepted),
+                1 => Some(WrongAnswer),
+                2 | 4 | 8 => Some(PresentationError),
+                3 => Some(CheckerFailed),
+                7 => Some(Points),
+                _ => None,
+            },
+            Self::Ejudge => match code {
+                0 => Some(Accepted),
+                4 => Some(PresentationError),
+                5 => Some(WrongAnswer),
+                6 => Some(CheckerFailed),
+                7 => Some(Points),
+                _ => None,
+            },
+            Self::Contester => match code {
+                0xAC => Some(Accepted),
+                0xAB => Some(WrongAnswer),
+                0xAA | 0xA6 => Some(PresentationError),
+                0xA3 => Some(CheckerFailed),
+                _ => None,
+            },
+            Self::Custom(table) => table.get(&code).copied(),
+        }
+    }
 }
 
 #[derive(Object, PartialEq, Eq, Debug, Clone, Copy, Serialize)]
@@ -161,11 +551,14 @@ impl TestVerdict {
             Self::InQueue => "PD".to_string(),
             Self::Running => "RU".to_string(),
             Self::Ignored => "IG".to_string(),
+            Self::Skipped(_) => "SK".to_string(),
+            Self::OutOfBudget => "OB".to_string(),
 
             Self::Accepted => "AC".to_string(),
             Self::PartialSolution(points) => format!("PT {}", (*points as f64) / 10000.0),
 
             Self::Bug(_) => "CF".to_string(),
+            Self::InvalidTest(_) => "IT".to_string(),
 
             Self::WrongAnswer => "WA".to_string(),
             Self::RuntimeError(_) => "RE".to_string(),
@@ -174,6 +567,8 @@ impl TestVerdict {
             Self::PresentationError => "PE".to_string(),
             Self::IdlenessLimitExceeded => "IL".to_string(),
             Self::CheckerFailed => "FL".to_string(),
+            Self::OutputLimitExceeded => "OL".to_string(),
+            Self::SecurityViolation(_) => "SV".to_string(),
         }
     }
 
@@ -182,11 +577,14 @@ impl TestVerdict {
             Self::InQueue => panic!("Unexpected verdict"),
             Self::Running => panic!("Unexpected verdict"),
             Self::Ignored => false,
+            Self::Skipped(_) => false,
+            Self::OutOfBudget => false,
 
             Self::Accepted => true,
             Self::PartialSolution(_) => true,
 
             Self::Bug(_) => false,
+            Self::InvalidTest(_) => false,
 
             Self::WrongAnswer => false,
             Self::RuntimeError(_) => false,
@@ -195,65 +593,79 @@ impl TestVerdict {
             Self::PresentationError => false,
             Self::IdlenessLimitExceeded => false,
             Self::CheckerFailed => false,
+            Self::OutputLimitExceeded => false,
+            Self::SecurityViolation(_) => false,
         }
     }
 
-    pub fn from_testlib(status: ExitStatus, stderr: &[u8]) -> Self {
-        match status {
-            ExitStatus::ExitCode(code) => match code {
-                0 => Self::Accepted,
-                1 => Self::WrongAnswer,
-                2 => Self::PresentationError,
-                3 => Self::CheckerFailed,
-                4 => Self::PresentationError,
-                7 => {
-                    if !stderr.starts_with(b"points ") {
-                        return Self::Bug(
-                            "Testlib exit code is 7 (PT), but stderr does not start with 'points '"
-                                .to_string(),
-                        );
-                    }
-
-                    let mut points = &stderr[7..];
+    // The share of the points of the test the verdict earns, in 10000 increments
+    pub fn score(&self) -> u64 {
+        match self {
+            Self::Accepted => 10000,
+            Self::PartialSolution(points) => (*points).min(10000),
+            _ => 0,
+        }
+    }
 
-                    if let Some(idx) = stderr[7..]
-                        .iter()
-                        .position(|c| *c == b' ' || *c == b'\r' || *c == b'\n' || *c == b'\t')
-                    {
-                        points = &points[..idx];
-                    }
+    // Interprets the exit status of a checker or an interactor with comments in stderr
+    pub fn from_checker(
+        convention: &ExitCodeConvention,
+        status: ExitStatus,
+        stderr: &[u8],
+    ) -> Self {
+        match status {
+            ExitStatus::ExitCode(code) => match convention.outcome(code) {
+                Some(CheckerOutcome::Accepted) => Self::Accepted,
+                Some(CheckerOutcome::WrongAnswer) => Self::WrongAnswer,
+                Some(CheckerOutcome::PresentationError) => Self::PresentationError,
+                Some(CheckerOutcome::CheckerFailed) => Self::CheckerFailed,
+                Some(CheckerOutcome::Points) => Self::from_points(code, stderr),
+                None => Self::Bug(format!("Unknown checker exit code: {code}")),
+            },
+            ExitStatus::Signal(signal) => {
+                Self::Bug(format!("Checker terminated by signal {signal}"))
+            }
+        }
+    }
 
-                    let points = match std::str::from_utf8(points) {
-                        Ok(points) => points,
-                        Err(e) => {
-                            return Self::Bug(format!("{points:?} is not a UTF-8 string: {e:?}"))
-                        }
-                    };
+    // The points are the first word of stderr after 'points ', as in testlib
+    fn from_points(code: u8, stderr: &[u8]) -> Self {
+        if !stderr.starts_with(b"points ") {
+            return Self::Bug(format!(
+                "Checker exit code is {code} (PT), but stderr does not start with 'points '"
+            ));
+        }
 
-                    let points: f64 = match points.parse() {
-                        Ok(points) => points,
-                        Err(_) => {
-                            return Self::Bug(format!(
-                                "Testlib exit code is 7 (PT) and stderr starts with 'points \
-                                 {points}', but '{points}' is not a number convertible to f64"
-                            ))
-                        }
-                    };
+        let mut points = &stderr[7..];
 
-                    if !points.is_finite() {
-                        return Self::Bug(format!(
-                            "Partial result must be a finite number, not {points}"
-                        ));
-                    }
+        if let Some(idx) = stderr[7..]
+            .iter()
+            .position(|c| *c == b' ' || *c == b'\r' || *c == b'\n' || *c == b'\t')
+        {
+            points = &points[..idx];
+        }
 
-                    Self::PartialSolution((points * 10000.0).round() as u64)
-                }
-                8 => Self::PresentationError,
-                _ => Self::Bug(format!("Unknown testlib exit code: {code}")),
-            },
-            ExitStatus::Signal(signal) => {
-                Self::Bug(format!("Testlib terminated by signal {signal}"))
+        let points = match std::str::from_utf8(points) {
+            Ok(points) => points,
+            Err(e) => return Self::Bug(format!("{points:?} is not a UTF-8 string: {e:?}")),
+        };
+
+        let points: f64 = match points.parse() {
+            Ok(points) => points,
+            Err(_) => {
+                return Self::Bug(format!(
+                    "Checker exit code is {code} (PT) and stderr starts with 'points {points}', \
+                     but '{points}' is not a number convertible to f64"
+                ))
             }
+        };
+
+        if !points.is_finite() {
+            return Self::Bug(format!(
+                "Partial result must be a finite number, not {points}"
+            ));
         }
+
+        Self::PartialSolution((points * 10000.0).round() as u64)
     }
 }
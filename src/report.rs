@@ -0,0 +1,147 @@
+use crate::{errors, errors::ToResult, problem::verdict, signing, submission};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// A report bundle is everything the invoker knows about a judged submission, packed into a single
+// tarball that can be handed to a jury when a verdict is disputed:
+//
+//     manifest.msgpack        see Manifest
+//     manifest.sig            Ed25519 signature of manifest.msgpack
+//     source/<name>           source files as submitted
+//     tests/<test>/<name>     retained logs and outputs of the test
+//
+// The manifest lists the SHA-256 of every other file, so the signature covers the whole bundle.
+// Only outputs that were retained according to the retention policy can be included.
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    submission_id: &'a str,
+    problem_id: &'a str,
+    revision_id: &'a str,
+    problem_revision_hash: &'a str,
+    program_hash: Option<String>,
+    // Results as reported to the conductor, without logs
+    results: &'a BTreeMap<u64, verdict::TestJudgementResult>,
+    // Path in the tarball -> SHA-256
+    files: BTreeMap<String, String>,
+}
+
+pub async fn make_bundle(
+    submission: &submission::Submission,
+    signer: &signing::Signer,
+) -> Result<Vec<u8>, errors::Error> {
+    let mut files = BTreeMap::new();
+
+    for path in submission.source_files() {
+        let name = std::path::Path::new(path)
+            .strip_prefix(submission.source_root())
+            .ok()
+            .and_then(|name| name.to_str())
+            .context_invoker("Source file has an invalid name")?;
+        let content = std::fs::read(path)
+            .with_context_invoker(|| format!("Failed to read source file {path}"))?;
+        files.insert(format!("source/{name}"), content);
+    }
+
+    let results = submission.results();
+    for test in results.keys() {
+        for (name, path) in submission.outputs().retained_files(*test) {
+            let content = std::fs::read(&path)
+                .with_context_invoker(|| format!("Failed to read retained output {path:?}"))?;
+            files.insert(format!("tests/{test}/{name}"), content);
+        }
+    }
+
+    let problem_revision = submission.problem_revision();
+    let manifest = rmp_serde::to_vec(&Manifest {
+        submission_id: &submission.id,
+        problem_id: &problem_revision.problem_id,
+        revision_id: &problem_revision.revision_id,
+        problem_revision_hash: &problem_revision.hash,
+        program_hash: submission.program_hash().await,
+        results: &results,
+        files: files
+            .iter()
+            .map(|(path, content)| (path.clone(), signing::sha256_hex(content)))
+            .collect(),
+    })
+    .context_invoker("Failed to serialize report manifest")?;
+    let signature = signer.sign(&manifest);
+
+    let mut tar = Tar::new();
+    tar.append("manifest.msgpack", &manifest)?;
+    tar.append("manifest.sig", &signature)?;
+    for (path, content) in &files {
+        tar.append(path, content)?;
+    }
+    Ok(tar.finish())
+}
+
+// A minimal ustar writer: regular files only, with fixed ownership and mtime so that the same
+// bundle is produced byte for byte from the same data
+struct Tar {
+    data: Vec<u8>,
+}
+
+const BLOCK_SIZE: usize = 512;
+
+impl Tar {
+    fn new() -> Self {
+        Tar { data: Vec::new() }
+    }
+
+    fn append(&mut self, path: &str, content: &[u8]) -> Result<(), errors::Error> {
+        let mut header = [0u8; BLOCK_SIZE];
+
+        // Paths longer than 100 bytes are split between the prefix and name fields at a slash
+        let (prefix, name) = if path.len() <= 100 {
+            ("", path)
+        } else {
+            path.char_indices()
+                .filter(|(i, c)| *c == '/' && *i <= 155 && path.len() - i - 1 <= 100)
+                .map(|(i, _)| (&path[..i], &path[i + 1..]))
+                .next()
+                .with_context_invoker(|| format!("Path {path} is too long for a tarball"))?
+        };
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], 0o644);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], content.len() as u64);
+        write_octal(&mut header[136..148], 0);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // The checksum is computed with the checksum field filled with spaces
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|byte| *byte as u64).sum();
+        write_octal(&mut header[148..155], checksum);
+
+        self.data.extend_from_slice(&header);
+        self.data.extend_from_slice(content);
+        self.pad();
+        Ok(())
+    }
+
+    fn pad(&mut self) {
+        let padding = (BLOCK_SIZE - self.data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        self.data.resize(self.data.len() + padding, 0);
+    }
+
+    // The archive ends with two zero blocks
+    fn finish(mut self) -> Vec<u8> {
+        self.data.resize(self.data.len() + 2 * BLOCK_SIZE, 0);
+        self.data
+    }
+}
+
+// Zero-padded octal terminated by NUL, filling the whole field
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{value:0width$o}");
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
@@ -0,0 +1,108 @@
+use crate::problem::verdict;
+use anyhow::{bail, Context};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Signs judgement results with the invoker's Ed25519 key, so that they can be verified
+// independently of the conductor's database
+pub struct Signer {
+    key_pair: Ed25519KeyPair,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResultSignature {
+    pub problem_revision_hash: String,
+    pub program_hash: String,
+    pub signature: Vec<u8>,
+}
+
+// The signed message is the msgpack encoding of this structure. Maps are sorted by key so that a
+// verifier can reproduce the exact bytes from the NotifyTestStatus message.
+#[derive(Serialize)]
+struct SignedTestResult<'a> {
+    submission_id: &'a str,
+    test: u64,
+    problem_revision_hash: &'a str,
+    program_hash: &'a str,
+    verdict: &'a verdict::TestVerdict,
+    logs: BTreeMap<&'a String, &'a Vec<u8>>,
+    invocation_stats: BTreeMap<&'a String, &'a verdict::InvocationStat>,
+    seed: Option<u64>,
+    fingerprint: &'a Option<verdict::MachineFingerprint>,
+    retries: u32,
+    failed_block: &'a Option<String>,
+    test_name: &'a Option<String>,
+}
+
+impl Signer {
+    // The key is stored as PKCS#8 v2, as generated by e.g. `openssl genpkey -algorithm ed25519`
+    // followed by conversion to DER
+    pub fn load(path: &str) -> anyhow::Result<Signer> {
+        let pkcs8 = std::fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+        let key_pair = match Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8) {
+            Ok(key_pair) => key_pair,
+            Err(e) => bail!("{path} is not a valid PKCS#8 Ed25519 key: {e}"),
+        };
+        Ok(Signer { key_pair })
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        to_hex(self.key_pair.public_key().as_ref())
+    }
+
+    pub fn sign_test_result(
+        &self,
+        submission_id: &str,
+        test: u64,
+        problem_revision_hash: &str,
+        program_hash: &str,
+        judgement_result: &verdict::TestJudgementResult,
+    ) -> Result<ResultSignature, rmp_serde::encode::Error> {
+        let message = rmp_serde::to_vec(&SignedTestResult {
+            submission_id,
+            test,
+            problem_revision_hash,
+            program_hash,
+            verdict: &judgement_result.verdict,
+            logs: judgement_result.logs.iter().collect(),
+            invocation_stats: judgement_result.invocation_stats.iter().collect(),
+            seed: judgement_result.seed,
+            fingerprint: &judgement_result.fingerprint,
+            retries: judgement_result.retries,
+            failed_block: &judgement_result.failed_block,
+            test_name: &judgement_result.test_name,
+        })?;
+        Ok(ResultSignature {
+            problem_revision_hash: problem_revision_hash.to_string(),
+            program_hash: program_hash.to_string(),
+            signature: self.key_pair.sign(&message).as_ref().to_vec(),
+        })
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(message).as_ref().to_vec()
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!("Odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .with_context(|| format!("Invalid hex digits at position {i}"))
+        })
+        .collect()
+}
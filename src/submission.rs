@@ -1,33 +1,88 @@
 use crate::{
-    client, errors,
+    client, config, errors,
     errors::ToResult,
-    image::{language, program},
-    problem::{problem, verdict},
+    image::{diagnostics, language, program, program_cache, strategy},
+    outputs,
+    problem::{problem, scoring, verdict},
     worker,
 };
 use futures::stream::StreamExt;
 use itertools::Itertools;
 use multiprocessing::Object;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 #[derive(Clone, Debug, Object)]
 pub enum Command {
     Compile(String),
-    Test(Vec<u64>),
+    // Tests to judge and report, followed by tests to run speculatively afterwards
+    Test(Vec<u64>, Vec<u64>),
+    // The strategy and the limits of the candidate revision, and the tests to judge
+    CompareRevision(
+        strategy::StrategyFactory,
+        HashMap<String, verdict::InvocationLimit>,
+        Vec<u64>,
+    ),
+    // Tests to judge independently of each other, and the key to derive the seeds from
+    Rejudge(Vec<u64>, String),
     Finalize,
 }
 
+impl Command {
+    // What is left of the command once the worker has responded with n messages. Only Test
+    // responds with more than one
+    fn skip_responses(self, n: usize) -> Command {
+        match self {
+            Command::Test(tests, speculative_tests) => {
+                Command::Test(tests[n..].to_vec(), speculative_tests)
+            }
+            command => command,
+        }
+    }
+}
+
+// How many times a worker that dies in the middle of a command is respawned to finish it. A test
+// that kills the worker every time ends up as a failure of the command, which callers may retry
+// with a fresh worker once more
+const WORKER_RESPAWNS: usize = 2;
+
 pub struct Submission {
     pub id: String,
     instantiated_dependency_graph: RwLock<problem::InstantiatedDependencyGraph>,
     language: language::Language,
+    // Source files are stored under source_root by the names they were submitted with
+    source_root: String,
     source_files: Vec<String>,
+    output_only: bool,
     program: RwLock<Option<program::Program>>,
+    program_hash: RwLock<Option<String>>,
     workers: RwLock<HashMap<u64, Arc<RwLock<worker::Worker>>>>,
+    // A replaced worker may leave its sandboxes behind, so every worker gets a fresh ID
+    n_spawned_workers: std::sync::atomic::AtomicU64,
     problem_revision: Arc<problem::ProblemRevision>,
     invocation_limits: HashMap<String, verdict::InvocationLimit>,
+    group_time_budgets: Vec<verdict::GroupTimeBudget>,
+    seed_key: String,
+    outputs: Arc<outputs::OutputStore>,
+    retention: outputs::RetentionPolicy,
+    sandbox_config: config::SandboxConfig,
+    program_cache: Option<Arc<program_cache::ProgramCache>>,
+    spare_workers: Option<Arc<worker::SparePool>>,
+    // IDs of the spare workers the submission has taken. Their prewarmed rootfs's are named after
+    // them rather than after the submission, see residue_ids
+    adopted_spares: std::sync::Mutex<Vec<String>>,
+    // Results reported to the conductor, without logs, for report bundles. The logs that are kept
+    // are in outputs
+    results: std::sync::Mutex<BTreeMap<u64, verdict::TestJudgementResult>>,
+    // Set once the conductor aborts the submission. Results that arrive afterwards are dropped
+    aborted: std::sync::atomic::AtomicBool,
+    // When the judging time budget of the problem runs out, see problem::JudgingPolicy
+    deadline: Option<std::time::Instant>,
+    out_of_budget: std::sync::atomic::AtomicBool,
+    // Held while the submission is being compiled, so that finalization can wait for the build to
+    // wind down instead of racing with it
+    compilation: tokio::sync::Mutex<()>,
 }
 
 impl Submission {
@@ -36,28 +91,92 @@ impl Submission {
         problem_revision: Arc<problem::ProblemRevision>,
         language: language::Language,
         invocation_limits: HashMap<String, verdict::InvocationLimit>,
+        group_time_budgets: Vec<verdict::GroupTimeBudget>,
+        seed_key: String,
+        retention: outputs::RetentionPolicy,
+        sandbox_config: config::SandboxConfig,
+        output_only: bool,
+        program_cache: Option<Arc<program_cache::ProgramCache>>,
+        spare_workers: Option<Arc<worker::SparePool>>,
     ) -> Result<Submission, errors::Error> {
+        let mut sandbox_config = sandbox_config;
+        sandbox_config.limits = sandbox_config
+            .limits
+            .with_override(&problem_revision.limits);
+        // The configured limits are validated at startup, so only the problem can be at fault here
+        sandbox_config.limits.validate()?;
+
         let root = format!("/tmp/sunwalker_invoker/submissions/{id}");
         std::fs::create_dir(&root).with_context_invoker(|| {
             format!("Failed to create a directory for submission at {root}")
         })?;
+        let source_root = format!("{root}/sources");
+        std::fs::create_dir(&source_root).with_context_invoker(|| {
+            format!("Failed to create a directory for source files at {source_root}")
+        })?;
+
+        let outputs = Arc::new(outputs::OutputStore::new(
+            std::path::PathBuf::from(&root).join("outputs"),
+        )?);
+
+        let deadline = problem_revision
+            .judging_policy
+            .time_budget
+            .map(|budget| std::time::Instant::now() + budget);
 
         Ok(Submission {
             id,
             instantiated_dependency_graph: RwLock::new(
-                problem_revision.dependency_graph.clone().instantiate(),
+                problem_revision
+                    .dependency_graph
+                    .clone()
+                    .instantiate(problem_revision.judging_policy.fail_fast),
             ),
             language,
+            source_root,
             source_files: Vec::new(),
+            output_only,
             program: RwLock::new(None),
+            program_hash: RwLock::new(None),
             workers: RwLock::new(HashMap::new()),
+            n_spawned_workers: std::sync::atomic::AtomicU64::new(0),
             problem_revision,
             invocation_limits,
+            group_time_budgets,
+            seed_key,
+            outputs,
+            retention,
+            sandbox_config,
+            program_cache,
+            spare_workers,
+            adopted_spares: std::sync::Mutex::new(Vec::new()),
+            results: std::sync::Mutex::new(BTreeMap::new()),
+            aborted: std::sync::atomic::AtomicBool::new(false),
+            deadline,
+            out_of_budget: std::sync::atomic::AtomicBool::new(false),
+            compilation: tokio::sync::Mutex::new(()),
         })
     }
 
     pub fn add_source_file(&mut self, name: &str, content: &[u8]) -> Result<(), errors::Error> {
-        let path = format!("/tmp/sunwalker_invoker/submissions/{}/{name}", self.id);
+        // Names may contain directories, which languages that build whole projects rely on
+        let name_path = std::path::Path::new(name);
+        if name.is_empty()
+            || !name_path
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(errors::UserFailure(format!(
+                "Source file name {name:?} is invalid"
+            )));
+        }
+        let path = format!("{}/{name}", self.source_root);
+        if let Some(parent) = name_path.parent() {
+            let parent = std::path::Path::new(&self.source_root).join(parent);
+            std::fs::create_dir_all(&parent).with_context_invoker(|| {
+                format!("Failed to create a directory for source files at {parent:?}")
+            })?;
+        }
         std::fs::write(&path, content).with_context_invoker(|| {
             format!("Failed to write a source code file for submission at {path}")
         })?;
@@ -65,31 +184,145 @@ impl Submission {
         Ok(())
     }
 
+    // Executes the command on the worker of the core. A worker that dies in the middle of the
+    // command is respawned, and the part of the command it has not responded to is replayed on the
+    // new one, up to WORKER_RESPAWNS times. After that, the failures are passed on to the caller
     async fn execute_on_core(
-        &self,
+        self: &Arc<Self>,
         core: client::CoreHandle,
         command: Command,
         n_messages: usize,
+    ) -> Result<impl futures::stream::Stream<Item = worker::W2IMessage>, errors::Error> {
+        let core = core.get_core();
+        let mut stream = self
+            .start_on_core(core, command.clone(), n_messages)
+            .await?
+            .boxed();
+
+        let submission = self.clone();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut command = command;
+            let mut n_received = 0;
+            let mut n_left = n_messages;
+            let mut respawns = 0;
+            while let Some(message) = stream.next().await {
+                if let worker::W2IMessage::Failure(ref e) = message {
+                    if respawns < WORKER_RESPAWNS
+                        && !submission.is_aborted()
+                        && submission.has_worker_crashed(core).await
+                    {
+                        respawns += 1;
+                        errors::log_throttled(
+                            "worker crash",
+                            format!(
+                                "Worker of submission {} on core {core} crashed, respawning it: \
+                                 {e:?}",
+                                submission.id
+                            ),
+                        );
+                        // The worker is dead, so there is nothing to kill, and its mounts are
+                        // removed by the mount supervisor
+                        submission.workers.write().await.remove(&core);
+                        command = command.skip_responses(n_received);
+                        n_received = 0;
+                        match submission
+                            .start_on_core(core, command.clone(), n_left)
+                            .await
+                        {
+                            Ok(new_stream) => {
+                                stream = new_stream.boxed();
+                                continue;
+                            }
+                            Err(e) => {
+                                for _ in 0..n_left {
+                                    let _ = tx.send(worker::W2IMessage::Failure(e.clone()));
+                                }
+                                return;
+                            }
+                        }
+                    }
+                }
+
+                n_received += 1;
+                n_left -= 1;
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(rx))
+    }
+
+    async fn has_worker_crashed(&self, core: u64) -> bool {
+        let worker = self.workers.read().await.get(&core).cloned();
+        match worker {
+            Some(worker) => worker.read().await.has_crashed().await,
+            None => false,
+        }
+    }
+
+    async fn start_on_core(
+        &self,
+        core: u64,
+        command: Command,
+        n_messages: usize,
     ) -> Result<impl futures::stream::Stream<Item = worker::W2IMessage>, errors::Error> {
         use std::collections::hash_map::Entry;
 
+        if self.is_aborted() {
+            return Err(errors::ConductorFailure(
+                "The submission has been aborted".to_string(),
+            ));
+        }
+
         let mut workers = self.workers.write().await;
-        let worker = match workers.entry(core.get_core()) {
+        let worker = match workers.entry(core) {
             Entry::Occupied(occupied) => occupied.get().clone(),
-            Entry::Vacant(vacant) => vacant
-                .insert(Arc::new(RwLock::new(
-                    worker::Worker::new(
-                        self.language.clone(),
-                        self.source_files.clone(),
-                        core.get_core(),
-                        self.instantiated_dependency_graph.read().await.clone(),
-                        self.program.read().await.clone(),
-                        self.problem_revision.strategy_factory.clone(),
-                        self.invocation_limits.clone(),
-                    )
-                    .await?,
-                )))
-                .clone(),
+            Entry::Vacant(vacant) => {
+                let spare = self
+                    .spare_workers
+                    .as_ref()
+                    .and_then(|spare_workers| spare_workers.take(core));
+                let mut worker = match spare {
+                    Some(spare) => {
+                        if let Some(id) = spare.spare_id() {
+                            self.adopted_spares.lock().unwrap().push(id.to_string());
+                        }
+                        spare
+                    }
+                    None => worker::Worker::spawn(core, &self.sandbox_config, None, None).await?,
+                };
+                worker
+                    .set_up(worker::Setup {
+                        worker_id: format!(
+                            "{}-{}-{}",
+                            self.id,
+                            core,
+                            self.n_spawned_workers
+                                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                        ),
+                        language: self.language.clone(),
+                        source_root: self.source_root.clone(),
+                        source_files: self.source_files.clone(),
+                        output_only: self.output_only,
+                        instantiated_dependency_graph: self
+                            .instantiated_dependency_graph
+                            .read()
+                            .await
+                            .clone(),
+                        program: self.program.read().await.clone(),
+                        strategy_factory: self.problem_revision.strategy_factory.clone(),
+                        invocation_limits: self.invocation_limits.clone(),
+                        group_time_budgets: self.group_time_budgets.clone(),
+                        seed_key: self.seed_key.clone(),
+                        retention: self.retention.clone(),
+                        sandbox_config: self.sandbox_config.clone(),
+                    })
+                    .await?;
+                vacant.insert(Arc::new(RwLock::new(worker))).clone()
+            }
         };
         drop(workers);
 
@@ -97,20 +330,75 @@ impl Submission {
         worker.execute_command(command, n_messages).await
     }
 
-    pub async fn compile_on_core(&self, core: client::CoreHandle) -> Result<String, errors::Error> {
+    pub async fn compile_on_core(
+        self: &Arc<Self>,
+        core: client::CoreHandle,
+    ) -> Result<String, errors::Error> {
+        let _compilation = self.compilation.lock().await;
+
         if self.program.read().await.is_some() {
             return Err(errors::ConductorFailure(
                 "The submission is already compiled".to_string(),
             ));
         }
 
+        let build_id = format!("judge-{}", self.id);
+
+        // Output-only submissions are not built, so there is nothing to cache
+        let cache = match self.program_cache {
+            Some(ref cache) if !self.output_only => {
+                let key = program_cache::ProgramCache::key(
+                    &self.language,
+                    &self.source_root,
+                    &self.source_files,
+                )?;
+                match cache.load(&key, &self.language, &build_id) {
+                    Ok(Some((program, log))) => {
+                        *self.program_hash.write().await = Some(program.hash()?);
+                        *self.program.write().await = Some(program);
+                        return Ok(log);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        println!("Failed to load cached program {key}, building anew: {e:?}");
+                        if let Err(e) = cache.forget(&key) {
+                            println!("Failed to remove cached program {key}: {e:?}");
+                        }
+                    }
+                }
+                Some((cache, key))
+            }
+            _ => None,
+        };
+
         let response = self
-            .execute_on_core(core, Command::Compile(format!("judge-{}", self.id)), 1)
+            .execute_on_core(core, Command::Compile(build_id), 1)
             .await?
             .next()
             .await;
         match response {
+            // The build may have finished before the abort got to it, after the submission was
+            // finalized
+            Some(worker::W2IMessage::CompilationResult(program, _)) if self.is_aborted() => {
+                program.remove()?;
+                Err(errors::ConductorFailure(
+                    "The submission has been aborted".to_string(),
+                ))
+            }
+            Some(worker::W2IMessage::CompilationResult(program, _)) if self.is_out_of_budget() => {
+                program.remove()?;
+                Err(errors::ConductorFailure(
+                    "The judging time budget ran out before the compilation finished".to_string(),
+                ))
+            }
             Some(worker::W2IMessage::CompilationResult(program, log)) => {
+                if let Some((cache, key)) = cache {
+                    // Failing to cache is not a reason to fail the build
+                    if let Err(e) = cache.store(&key, &program, &log) {
+                        println!("Failed to cache program {key}: {e:?}");
+                    }
+                }
+                *self.program_hash.write().await = Some(program.hash()?);
                 *self.program.write().await = Some(program);
                 Ok(log)
             }
@@ -121,12 +409,99 @@ impl Submission {
         }
     }
 
-    pub async fn test_on_core(
+    pub fn parse_diagnostics(&self, log: &str) -> Vec<diagnostics::Diagnostic> {
+        if self.output_only {
+            return Vec::new();
+        }
+        self.language
+            .parse_diagnostics(log, self.source_files.iter().map(|s| s.as_ref()).collect())
+    }
+
+    // The version of the image the submission was added with. After the image is reloaded, the
+    // submission keeps using the old one until it is finalized
+    pub fn image_version(&self) -> &str {
+        &self.language.image().version
+    }
+
+    pub fn problem_revision(&self) -> &Arc<problem::ProblemRevision> {
+        &self.problem_revision
+    }
+
+    pub fn problem_revision_hash(&self) -> &str {
+        &self.problem_revision.hash
+    }
+
+    pub async fn program_hash(&self) -> Option<String> {
+        self.program_hash.read().await.clone()
+    }
+
+    pub fn language_name(&self) -> &str {
+        self.language.name()
+    }
+
+    pub fn source_root(&self) -> &str {
+        &self.source_root
+    }
+
+    pub fn source_files(&self) -> &[String] {
+        &self.source_files
+    }
+
+    pub fn outputs(&self) -> &outputs::OutputStore {
+        &self.outputs
+    }
+
+    // A rejudged test replaces its previous result
+    pub fn record_result(&self, test: u64, result: &verdict::TestJudgementResult) {
+        self.problem_revision.record_runtime(test, result);
+        self.results
+            .lock()
+            .unwrap()
+            .insert(test, result.without_logs());
+    }
+
+    pub fn results(&self) -> BTreeMap<u64, verdict::TestJudgementResult> {
+        self.results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(test, result)| (*test, result.without_logs()))
+            .collect()
+    }
+
+    // The scoring groups that contain the test and are complete, see problem::ScoringGroup
+    pub fn judge_groups_of(&self, test: u64) -> Vec<(String, verdict::GroupJudgementResult)> {
+        self.problem_revision
+            .judge_groups_of(test, &self.results.lock().unwrap())
+    }
+
+    pub fn test_name(&self, test: u64) -> Option<String> {
+        self.problem_revision.test_name(test)
+    }
+
+    pub fn order_tests(&self, tests: Vec<u64>) -> Vec<u64> {
+        self.problem_revision.order_tests(tests)
+    }
+
+    // Of the tests judged so far, see scoring::ScoringPolicy
+    pub fn score(&self) -> scoring::SubmissionScore {
+        self.problem_revision.score(&self.results.lock().unwrap())
+    }
+
+    pub fn resolve_tests(
         &self,
+        selectors: Vec<problem::TestSelector>,
+    ) -> Result<Vec<u64>, errors::Error> {
+        self.problem_revision.resolve_tests(selectors)
+    }
+
+    pub async fn test_on_core(
+        self: &Arc<Self>,
         core: client::CoreHandle,
         tests: Vec<u64>,
+        speculative_tests: Vec<u64>,
     ) -> Result<
-        impl futures::stream::Stream<Item = (u64, verdict::TestJudgementResult)>,
+        impl futures::stream::Stream<Item = (u64, Result<verdict::TestJudgementResult, errors::Error>)>,
         errors::Error,
     > {
         if self.program.read().await.is_none() {
@@ -136,34 +511,321 @@ impl Submission {
         }
 
         let mut i = 0usize;
+        let outputs = self.outputs.clone();
+        let submission = self.clone();
 
         Ok(self
-            .execute_on_core(core, Command::Test(tests.clone()), tests.len())
+            .execute_on_core(
+                core,
+                Command::Test(tests.clone(), speculative_tests),
+                tests.len(),
+            )
             .await?
             .map(move |judgement_result| {
                 let test = tests[i];
                 i += 1;
 
+                let mut retained = Vec::new();
                 let judgement_result = match judgement_result {
-                    worker::W2IMessage::TestResult(result) => Ok(result),
+                    worker::W2IMessage::TestResult(result, retained_logs) => {
+                        retained = retained_logs;
+                        Ok(result)
+                    }
                     worker::W2IMessage::Failure(e) => Err(e),
                     _ => Err(errors::InvokerFailure(format!(
                         "Unexpected response to judgement request: {judgement_result:?}"
                     ))),
                 };
-                (
+                let judgement_result = match judgement_result {
+                    // The worker answers the tests it has not started with failures, and the test
+                    // it was running ends up ignored
+                    Err(_)
+                    | Ok(verdict::TestJudgementResult {
+                        verdict: verdict::TestVerdict::Ignored,
+                        ..
+                    }) if submission.is_out_of_budget() => Ok(out_of_budget()),
+                    // The caller may retry the test, possibly after re-fetching the problem
+                    Err(e) if e.is_infrastructure_failure() => return (test, Err(e)),
+                    judgement_result => judgement_result.unwrap_or_else(failed_evaluation),
+                };
+
+                if let Err(e) = outputs.retain(
                     test,
-                    judgement_result.unwrap_or_else(|e| verdict::TestJudgementResult {
-                        verdict: verdict::TestVerdict::Bug(format!(
-                            "Failed to evaluate test: {e:?}"
-                        )),
-                        logs: HashMap::new(),
-                        invocation_stats: HashMap::new(),
-                    }),
-                )
+                    judgement_result
+                        .logs
+                        .iter()
+                        .filter(|(name, _)| retained.contains(name)),
+                ) {
+                    errors::log_throttled(
+                        "retain outputs",
+                        format!("Failed to retain outputs of test {test}: {e:?}"),
+                    );
+                }
+
+                (test, Ok(judgement_result))
             }))
     }
 
+    // Spreads the tests across the cores and merges the results back in the order of the tests. Tests
+    // that depend on each other or share a time budget are judged on the same core, in order, so
+    // that failures disable dependents and budgets are tracked just like on a single core. The core
+    // each result comes from is reported alongside it. Speculative tests go to the first core, which
+    // is used even if it has no tests of its own to judge
+    pub async fn test_on_cores(
+        self: &Arc<Self>,
+        cores: Vec<client::CoreHandle>,
+        tests: Vec<u64>,
+        speculative_tests: Vec<u64>,
+    ) -> Result<
+        impl futures::stream::Stream<
+                Item = (
+                    u64,
+                    u64,
+                    Result<verdict::TestJudgementResult, errors::Error>,
+                ),
+            > + Unpin,
+        errors::Error,
+    > {
+        let batches = self.split_tests(&tests, cores.len()).await;
+
+        let mut streams = Vec::new();
+        let mut core_of = HashMap::new();
+        let mut speculative_tests = Some(speculative_tests);
+        for (core, batch) in cores.into_iter().zip(batches) {
+            let has_speculative_tests = speculative_tests
+                .as_ref()
+                .map_or(false, |tests| !tests.is_empty());
+            if batch.is_empty() && !has_speculative_tests {
+                continue;
+            }
+            let core_id = core.get_core();
+            for test in &batch {
+                core_of.insert(*test, core_id);
+            }
+            let stream = self
+                .test_on_core(core, batch, speculative_tests.take().unwrap_or_default())
+                .await?;
+            streams.push(
+                stream
+                    .map(move |(test, result)| (test, core_id, result))
+                    .boxed(),
+            );
+        }
+
+        let submission = self.clone();
+        let fail_fast = self.problem_revision.judging_policy.fail_fast;
+
+        Ok(Box::pin(async_stream::stream! {
+            let mut results = futures::stream::select_all(streams);
+            // Results that arrived before those of the tests preceding them
+            let mut pending = HashMap::new();
+            let mut next = 0;
+            let mut failed = false;
+            while let Some((test, core, result)) = results.next().await {
+                // Under fail-fast, the worker that saw the failure stops by itself, and the others
+                // are told right away rather than when the result is reported in order. Skipped
+                // tests are the consequence of a failure rather than one
+                let is_failure = match result {
+                    Ok(ref result) => {
+                        !result.verdict.is_successful()
+                            && !matches!(
+                                result.verdict,
+                                verdict::TestVerdict::Skipped(_) | verdict::TestVerdict::Ignored
+                            )
+                    }
+                    Err(_) => false,
+                };
+                if fail_fast && is_failure && !failed {
+                    failed = true;
+                    if let Err(e) = submission.add_failed_tests(&[test]).await {
+                        errors::log_throttled(
+                            "propagate failure",
+                            format!(
+                                "Failed to stop the other cores after test {test} failed: {e:?}"
+                            ),
+                        );
+                    }
+                }
+                pending.insert(test, (core, result));
+                while let Some((core, result)) =
+                    tests.get(next).and_then(|test| pending.remove(test))
+                {
+                    yield (tests[next], core, result);
+                    next += 1;
+                }
+            }
+
+            // A core that stops short of its tests would otherwise hold up the results of the
+            // tests after them forever
+            for test in &tests[next..] {
+                yield match pending.remove(test) {
+                    Some((core, result)) => (*test, core, result),
+                    None => (
+                        *test,
+                        core_of[test],
+                        Err(errors::InvokerFailure(format!(
+                            "The worker did not report the result of test {test}"
+                        ))),
+                    ),
+                };
+            }
+        }))
+    }
+
+    // Groups the tests into at most n batches of about equal size, keeping tests that have to be
+    // judged together in the same batch
+    async fn split_tests(&self, tests: &[u64], n: usize) -> Vec<Vec<u64>> {
+        let graph = self.instantiated_dependency_graph.read().await;
+
+        // Tests that are not being judged take part too, as they may link tests that are, e.g. if
+        // A -> B -> C and only A and C are judged, A and C still have to go to the same batch
+        let mut index_of: HashMap<u64, usize> = tests
+            .iter()
+            .enumerate()
+            .map(|(i, test)| (*test, i))
+            .collect();
+        for (test, dependents) in graph.graph.dependents_of.iter() {
+            for test in std::iter::once(test).chain(dependents) {
+                let len = index_of.len();
+                index_of.entry(*test).or_insert(len);
+            }
+        }
+        let mut parent: Vec<usize> = (0..index_of.len()).collect();
+        fn find(parent: &mut Vec<usize>, i: usize) -> usize {
+            if parent[i] != i {
+                parent[i] = find(parent, parent[i]);
+            }
+            parent[i]
+        }
+        let mut join = |a: u64, b: u64| {
+            if let (Some(a), Some(b)) = (index_of.get(&a), index_of.get(&b)) {
+                let (a, b) = (find(&mut parent, *a), find(&mut parent, *b));
+                parent[a] = b;
+            }
+        };
+
+        for (test, dependents) in graph.graph.dependents_of.iter() {
+            for dependent in dependents {
+                join(*test, *dependent);
+            }
+        }
+        drop(graph);
+        for budget in self.group_time_budgets.iter() {
+            for pair in budget.tests.windows(2) {
+                join(pair[0], pair[1]);
+            }
+        }
+
+        // Larger components go first, each to the least loaded batch. The order of the tests is
+        // kept within a batch
+        let mut components: HashMap<usize, Vec<u64>> = HashMap::new();
+        for (i, test) in tests.iter().enumerate() {
+            components
+                .entry(find(&mut parent, i))
+                .or_default()
+                .push(*test);
+        }
+        let mut components: Vec<Vec<u64>> = components.into_values().collect();
+        components.sort_by_key(|component| (std::cmp::Reverse(component.len()), component[0]));
+        let mut batches: Vec<Vec<u64>> = vec![Vec::new(); n.max(1)];
+        for component in components {
+            let batch = batches.iter_mut().min_by_key(|batch| batch.len()).unwrap();
+            batch.extend(component);
+        }
+        for batch in batches.iter_mut() {
+            batch.sort_by_key(|test| index_of[test]);
+        }
+        batches
+    }
+
+    // Judges the tests against both the revision the submission was added with and the candidate
+    // revision. Tests are judged independently of each other: neither the dependency graph nor
+    // the time budgets apply, so that the verdicts are comparable.
+    pub async fn compare_on_core(
+        self: &Arc<Self>,
+        core: client::CoreHandle,
+        candidate: &problem::ProblemRevision,
+        candidate_invocation_limits: Option<HashMap<String, verdict::InvocationLimit>>,
+        tests: Vec<u64>,
+    ) -> Result<verdict::RevisionDiff, errors::Error> {
+        if self.program.read().await.is_none() {
+            return Err(errors::ConductorFailure(
+                "Cannot compare revisions before the program is built".to_string(),
+            ));
+        }
+
+        let response = self
+            .execute_on_core(
+                core,
+                Command::CompareRevision(
+                    candidate.strategy_factory.clone(),
+                    candidate_invocation_limits.unwrap_or_else(|| self.invocation_limits.clone()),
+                    tests,
+                ),
+                1,
+            )
+            .await?
+            .next()
+            .await;
+        match response {
+            Some(worker::W2IMessage::RevisionComparison(comparisons)) => {
+                Ok(verdict::RevisionDiff::new(comparisons))
+            }
+            Some(worker::W2IMessage::Failure(e)) => Err(e),
+            _ => Err(errors::InvokerFailure(format!(
+                "Unexpected response to revision comparison request: {response:?}"
+            ))),
+        }
+    }
+
+    // Judges the tests independently of each other, like compare_on_core, and returns the full
+    // results. The seeds are derived from seed_key rather than the submission's own key, so that two
+    // submissions can be judged with the same seeds.
+    pub async fn rejudge_on_core(
+        self: &Arc<Self>,
+        core: client::CoreHandle,
+        tests: Vec<u64>,
+        seed_key: String,
+    ) -> Result<Vec<(u64, verdict::TestJudgementResult)>, errors::Error> {
+        if self.program.read().await.is_none() {
+            return Err(errors::ConductorFailure(format!(
+                "Cannot rejudge submission {} before the program is built",
+                self.id
+            )));
+        }
+
+        let response = self
+            .execute_on_core(core, Command::Rejudge(tests, seed_key), 1)
+            .await?
+            .next()
+            .await;
+        match response {
+            Some(worker::W2IMessage::RejudgeResults(results)) => Ok(results),
+            Some(worker::W2IMessage::Failure(e)) => Err(e),
+            _ => Err(errors::InvokerFailure(format!(
+                "Unexpected response to rejudge request: {response:?}"
+            ))),
+        }
+    }
+
+    pub fn seed_key(&self) -> &str {
+        &self.seed_key
+    }
+
+    // Throws the worker of the core away, so that the next command on the core starts a new one
+    // with fresh sandboxes. The worker may be wedged, so it is killed rather than finalized.
+    pub async fn replace_worker(&self, core: u64) {
+        let worker = self.workers.write().await.remove(&core);
+        if let Some(worker) = worker {
+            if let Err(e) = worker.read().await.kill().await {
+                errors::log_throttled(
+                    "kill worker",
+                    format!("Failed to kill the worker on core {core}: {e:?}"),
+                );
+            }
+        }
+    }
+
     pub async fn add_failed_tests(&self, tests: &[u64]) -> Result<(), errors::Error> {
         {
             let mut instantiated_dependency_graph =
@@ -182,6 +844,67 @@ impl Submission {
         Ok(())
     }
 
+    // Names that the residue of the submission in /tmp/sunwalker_invoker may carry, see image::gc
+    pub fn residue_ids(&self) -> Vec<String> {
+        let mut ids = vec![self.id.clone()];
+        ids.extend(self.adopted_spares.lock().unwrap().iter().cloned());
+        ids
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // Stops the compilation or the tests in progress, then finalizes the workers. Nothing is
+    // reported about the submission afterwards, see is_aborted
+    pub async fn abort(&self) -> Result<(), errors::Error> {
+        self.aborted
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        let mut unresponsive = Vec::new();
+        for (core, worker) in self.workers.read().await.iter() {
+            if let Err(e) = worker.read().await.abort().await {
+                errors::log_throttled(
+                    "abort worker",
+                    format!("Failed to abort the worker on core {core}, killing it: {e:?}"),
+                );
+                unresponsive.push(*core);
+            }
+        }
+        // Finalization would hang on a wedged worker
+        for core in unresponsive {
+            self.replace_worker(core).await;
+        }
+
+        self.finalize().await
+    }
+
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline
+    }
+
+    pub fn is_out_of_budget(&self) -> bool {
+        self.out_of_budget
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // Cuts the tests in progress short once the judging time budget runs out. Unlike with abort,
+    // their results are still reported, as OutOfBudget. A build in progress is stopped too, and
+    // waited for, so that the caller can finalize the submission afterwards
+    pub async fn exhaust_time_budget(&self) {
+        self.out_of_budget
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        for (core, worker) in self.workers.read().await.iter() {
+            if let Err(e) = worker.read().await.abort().await {
+                errors::log_throttled(
+                    "abort worker",
+                    format!("Failed to stop the worker on core {core}: {e:?}"),
+                );
+            }
+        }
+        drop(self.compilation.lock().await);
+    }
+
     pub async fn finalize(&self) -> Result<(), errors::Error> {
         if let Some(program) = self.program.write().await.take() {
             program.remove()?;
@@ -208,3 +931,29 @@ impl Submission {
         }
     }
 }
+
+pub fn failed_evaluation(e: errors::Error) -> verdict::TestJudgementResult {
+    verdict::TestJudgementResult {
+        verdict: verdict::TestVerdict::Bug(format!("Failed to evaluate test: {e:?}")),
+        logs: HashMap::new(),
+        invocation_stats: HashMap::new(),
+        seed: None,
+        fingerprint: None,
+        retries: 0,
+        failed_block: None,
+        test_name: None,
+    }
+}
+
+fn out_of_budget() -> verdict::TestJudgementResult {
+    verdict::TestJudgementResult {
+        verdict: verdict::TestVerdict::OutOfBudget,
+        logs: HashMap::new(),
+        invocation_stats: HashMap::new(),
+        seed: None,
+        fingerprint: None,
+        retries: 0,
+        failed_block: None,
+        test_name: None,
+    }
+}
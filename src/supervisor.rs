@@ -0,0 +1,174 @@
+use crate::system;
+use lazy_static::lazy_static;
+use libc::{c_int, c_ulong};
+use multiprocessing::{Duplex, Object};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// All mount operations that affect the invoker's mount namespace are performed by a dedicated
+// supervisor process. Each process that needs to mount something (the main process and every
+// worker) has its own connection to the supervisor. The supervisor remembers what was mounted via
+// each connection, so if a worker crashes halfway through preparing a rootfs, its mounts are
+// removed as soon as the connection is closed, without polluting the namespace shared by everyone.
+//
+// Mounts performed inside sandboxes, i.e. after unshare(CLONE_NEWNS), don't go through the
+// supervisor, as they die with the sandbox anyway.
+
+#[derive(Object)]
+pub enum Request {
+    // source, target, fs_type, flags, data
+    Mount(PathBuf, PathBuf, String, c_ulong, Option<String>),
+    // path, flags
+    Umount(PathBuf, c_int),
+    Connect(Duplex<Response, Request>),
+}
+
+// The error is passed as errno (if any) and a message, as io::Error is not serializable
+pub type Response = std::result::Result<(), (Option<i32>, String)>;
+
+pub type Connection = Duplex<Request, Response>;
+
+lazy_static! {
+    static ref CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+}
+
+#[multiprocessing::entrypoint]
+fn supervisor_main(chan: Duplex<Response, Request>) {
+    serve(chan);
+}
+
+fn serve(mut chan: Duplex<Response, Request>) {
+    // Mountpoints created via this connection, in the order of mounting
+    let mut targets: Vec<PathBuf> = Vec::new();
+
+    while let Ok(Some(request)) = chan.recv() {
+        let result = match request {
+            Request::Mount(source, target, fs_type, flags, data) => {
+                system::mount(&source, &target, &fs_type, flags, data.as_deref())
+                    .map(|()| targets.push(target))
+            }
+            Request::Umount(path, flags) => system::umount_opt(&path, flags).map(|()| {
+                if let Some(index) = targets.iter().rposition(|target| *target == path) {
+                    targets.remove(index);
+                }
+            }),
+            Request::Connect(chan) => {
+                std::thread::spawn(move || serve(chan));
+                Ok(())
+            }
+        };
+        let response = result.map_err(|e| (e.raw_os_error(), e.to_string()));
+        if chan.send(&response).is_err() {
+            break;
+        }
+    }
+
+    // The peer has either finished or crashed, so whatever is still mounted is garbage
+    for target in targets.iter().rev() {
+        if let Err(e) = cleanup(target) {
+            println!("Supervisor failed to clean up {target:?}: {e:?}");
+        }
+    }
+}
+
+fn cleanup(target: &Path) -> Result<()> {
+    let mounts = system::get_mountinfo()?;
+    for path in system::mounts_to_unmount(&mounts, target, true) {
+        system::umount_opt(&path, system::MNT_DETACH)?;
+    }
+    Ok(())
+}
+
+fn request(request: Request) -> Result<()> {
+    let mut connection = CONNECTION
+        .lock()
+        .expect("Supervisor connection is poisoned");
+    let chan = connection.as_mut().ok_or_else(|| {
+        Error::new(
+            ErrorKind::Other,
+            "This process is not connected to the mount supervisor",
+        )
+    })?;
+    let to_io_error = |e: multiprocessing::Error| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to communicate with the mount supervisor: {e:?}"),
+        )
+    };
+    chan.send(&request).map_err(to_io_error)?;
+    match chan.recv().map_err(to_io_error)? {
+        Some(Ok(())) => Ok(()),
+        Some(Err((Some(errno), _))) => Err(Error::from_raw_os_error(errno)),
+        Some(Err((None, message))) => Err(Error::new(ErrorKind::Other, message)),
+        None => Err(Error::new(
+            ErrorKind::Other,
+            "The mount supervisor has terminated",
+        )),
+    }
+}
+
+// Starts the supervisor in the current mount namespace and connects the current process to it.
+// Must be called after the namespace is set up, and before any workers are started.
+pub fn start() -> multiprocessing::Result<()> {
+    let (chan, child_chan) = multiprocessing::duplex()?;
+    supervisor_main.spawn(child_chan)?;
+    attach(chan);
+    Ok(())
+}
+
+// Opens another connection to the supervisor, to be passed to a subprocess. The mounts made via
+// this connection are tracked separately from the ones made by the current process.
+pub fn connect() -> Result<Connection> {
+    let (chan, supervisor_chan) = multiprocessing::duplex().map_err(|e| {
+        Error::new(
+            ErrorKind::Other,
+            format!("Failed to create a connection to the mount supervisor: {e:?}"),
+        )
+    })?;
+    request(Request::Connect(supervisor_chan))?;
+    Ok(chan)
+}
+
+// Makes the process use a connection obtained from connect() by its parent
+pub fn attach(chan: Connection) {
+    *CONNECTION
+        .lock()
+        .expect("Supervisor connection is poisoned") = Some(chan);
+}
+
+pub fn mount<S: AsRef<Path>, T: AsRef<Path>>(
+    source: S,
+    target: T,
+    fs_type: &str,
+    flags: c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    request(Request::Mount(
+        source.as_ref().to_owned(),
+        target.as_ref().to_owned(),
+        fs_type.to_string(),
+        flags,
+        data.map(|data| data.to_string()),
+    ))
+}
+
+pub fn bind_mount_opt<S: AsRef<Path>, T: AsRef<Path>>(
+    source: S,
+    target: T,
+    flags: c_ulong,
+) -> Result<()> {
+    mount(source, target, "none", flags | system::MS_BIND, None)
+}
+
+pub fn bind_mount<S: AsRef<Path>, T: AsRef<Path>>(source: S, target: T) -> Result<()> {
+    bind_mount_opt(source, target, 0)
+}
+
+pub fn umount_opt<P: AsRef<Path>>(path: P, flags: c_int) -> Result<()> {
+    request(Request::Umount(path.as_ref().to_owned(), flags))
+}
+
+pub fn umount<P: AsRef<Path>>(path: P) -> Result<()> {
+    umount_opt(path, 0)
+}
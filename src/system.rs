@@ -7,10 +7,11 @@ pub use libc::{
     MS_UNBINDABLE, UMOUNT_NOFOLLOW,
 };
 
-use std::ffi::CString;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
 use std::io::{Error, ErrorKind, Result};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::null;
 
 pub fn to_cstring(data: &[u8]) -> Result<CString> {
@@ -93,3 +94,172 @@ pub fn umount_opt<P: AsRef<Path>>(path: P, flags: c_int) -> Result<()> {
 pub fn umount<P: AsRef<Path>>(path: P) -> Result<()> {
     umount_opt(path, 0)
 }
+
+// An entry of /proc/<pid>/mountinfo, see proc(5)
+#[derive(Debug, PartialEq)]
+pub struct MountInfo {
+    pub mount_id: u64,
+    pub parent_id: u64,
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+// The kernel escapes space, tab, newline and backslash in paths as \ooo
+fn unescape_mount_field(field: &[u8]) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\' {
+            let code = field
+                .get(i + 1..i + 4)
+                .and_then(|digits| std::str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "Invalid escape sequence in mountinfo",
+                    )
+                })?;
+            result.push(code);
+            i += 4;
+        } else {
+            result.push(field[i]);
+            i += 1;
+        }
+    }
+    Ok(result)
+}
+
+fn parse_mountinfo_line(line: &[u8]) -> Result<MountInfo> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid format of mountinfo");
+
+    let fields: Vec<&[u8]> = line.split(|c| *c == b' ').collect();
+    // The number of optional fields varies, so the ones after the separator are counted from it
+    let separator = fields
+        .iter()
+        .skip(6)
+        .position(|field| *field == b"-")
+        .ok_or_else(invalid)?
+        + 6;
+    if fields.len() < separator + 3 {
+        return Err(invalid());
+    }
+
+    let parse_id = |field: &[u8]| -> Result<u64> {
+        std::str::from_utf8(field)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let parse_path = |field: &[u8]| -> Result<PathBuf> {
+        Ok(OsStr::from_bytes(&unescape_mount_field(field)?).into())
+    };
+    let parse_string = |field: &[u8]| -> Result<String> {
+        Ok(String::from_utf8_lossy(&unescape_mount_field(field)?).into_owned())
+    };
+
+    Ok(MountInfo {
+        mount_id: parse_id(fields[0])?,
+        parent_id: parse_id(fields[1])?,
+        root: parse_path(fields[3])?,
+        mount_point: parse_path(fields[4])?,
+        fs_type: parse_string(fields[separator + 1])?,
+        source: parse_string(fields[separator + 2])?,
+    })
+}
+
+pub fn parse_mountinfo(data: &[u8]) -> Result<Vec<MountInfo>> {
+    data.split(|c| *c == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(parse_mountinfo_line)
+        .collect()
+}
+
+pub fn get_mountinfo() -> Result<Vec<MountInfo>> {
+    parse_mountinfo(&std::fs::read("/proc/self/mountinfo")?)
+}
+
+// Returns the mounts at or beneath prefix in an order they can be unmounted in: every mount comes
+// after the mounts on top of it and beneath it. Mount points alone are not enough to determine
+// this, because several filesystems may be mounted at the same path.
+pub fn mounts_to_unmount(mounts: &[MountInfo], prefix: &Path, inclusive: bool) -> Vec<PathBuf> {
+    let selected: Vec<&MountInfo> = mounts
+        .iter()
+        .filter(|mount| {
+            mount.mount_point.starts_with(prefix) && (inclusive || mount.mount_point != prefix)
+        })
+        .collect();
+    let selected_ids: HashSet<u64> = selected.iter().map(|mount| mount.mount_id).collect();
+
+    let mut children: HashMap<u64, Vec<&MountInfo>> = HashMap::new();
+    let mut roots = Vec::new();
+    for mount in selected.iter() {
+        if selected_ids.contains(&mount.parent_id) && mount.parent_id != mount.mount_id {
+            children.entry(mount.parent_id).or_default().push(mount);
+        } else {
+            roots.push(*mount);
+        }
+    }
+
+    fn visit(
+        mount: &MountInfo,
+        children: &HashMap<u64, Vec<&MountInfo>>,
+        order: &mut Vec<PathBuf>,
+    ) {
+        // Mounts created later are unmounted first, like in the kernel's own umount -R
+        for child in children.get(&mount.mount_id).into_iter().flatten().rev() {
+            visit(child, children, order);
+        }
+        order.push(mount.mount_point.clone());
+    }
+
+    let mut order = Vec::new();
+    for root in roots.into_iter().rev() {
+        visit(root, &children, &mut order);
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &[u8] = b"\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 0:25 / /tmp/a\\040b rw - tmpfs none rw
+31 30 0:26 / /tmp/a\\040b/c rw master:2 - tmpfs none rw
+32 30 0:27 / /tmp/a\\040b rw - tmpfs none\\134x rw
+33 32 0:28 /sub /tmp/a\\040b/d rw - overlay overlay rw,lowerdir=/x
+";
+
+    #[test]
+    fn parses_escapes_and_optional_fields() {
+        let mounts = parse_mountinfo(MOUNTINFO).unwrap();
+        assert_eq!(mounts.len(), 5);
+        assert_eq!(mounts[1].mount_point, PathBuf::from("/tmp/a b"));
+        assert_eq!(mounts[2].fs_type, "tmpfs");
+        assert_eq!(mounts[3].source, "none\\x");
+        assert_eq!(mounts[4].root, PathBuf::from("/sub"));
+        assert_eq!(mounts[4].parent_id, 32);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_mountinfo(b"22 1 8:1 / / rw shared:1 ext4 /dev/sda1 rw\n").is_err());
+        assert!(parse_mountinfo(b"22 1 8:1 / /a\\04 rw - ext4 /dev/sda1 rw\n").is_err());
+    }
+
+    #[test]
+    fn unmounts_children_and_overmounts_first() {
+        let mounts = parse_mountinfo(MOUNTINFO).unwrap();
+        let order = mounts_to_unmount(&mounts, Path::new("/tmp/a b"), true);
+        let order: Vec<&str> = order.iter().map(|path| path.to_str().unwrap()).collect();
+        assert_eq!(order, ["/tmp/a b/d", "/tmp/a b", "/tmp/a b/c", "/tmp/a b"]);
+        assert_eq!(
+            mounts_to_unmount(&mounts, Path::new("/tmp/a"), true),
+            Vec::<PathBuf>::new()
+        );
+    }
+}
@@ -1,12 +1,13 @@
 use crate::{
-    errors,
-    errors::{ToError, ToResult},
-    image::{language, program, sandbox, strategy},
+    cgroups, config, errors,
+    errors::{ToError, ToMultiprocessingResult, ToResult},
+    image::{ids, language, program, sandbox, strategy},
+    outputs,
     problem::{problem, verdict},
-    submission,
+    submission, supervisor,
 };
 use futures::{
-    future::{AbortHandle, Abortable},
+    future::{AbortHandle, AbortRegistration, Abortable, Aborted},
     StreamExt,
 };
 use multiprocessing::tokio::{channel, Child, Receiver, Sender};
@@ -18,17 +19,47 @@ use tokio::sync::{mpsc, Mutex, RwLock};
 #[derive(Object)]
 enum I2WUrgentCommand {
     AddFailedTests(Vec<u64>),
+    Abort,
 }
 
 #[derive(Debug, Object)]
 pub enum W2IMessage {
     CompilationResult(program::Program, String),
-    TestResult(verdict::TestJudgementResult),
+    // The names of the logs to keep on disk are decided by the worker, as it knows the strategy
+    TestResult(verdict::TestJudgementResult, Vec<String>),
+    RevisionComparison(Vec<verdict::TestComparison>),
+    RejudgeResults(Vec<(u64, verdict::TestJudgementResult)>),
     Finalized,
     Failure(errors::Error),
 }
 
+const W2I_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+// What a worker needs to know about the submission it judges. Sent once the worker is assigned to
+// a submission, which may be long after it is spawned, see SparePool
+#[derive(Object)]
+pub struct Setup {
+    // Has to be unique among live workers, as the names of sandboxes are derived from it
+    pub worker_id: String,
+    pub language: language::Language,
+    pub source_root: String,
+    pub source_files: Vec<String>,
+    pub output_only: bool,
+    pub instantiated_dependency_graph: problem::InstantiatedDependencyGraph,
+    pub program: Option<program::Program>,
+    pub strategy_factory: strategy::StrategyFactory,
+    pub invocation_limits: HashMap<String, verdict::InvocationLimit>,
+    pub group_time_budgets: Vec<verdict::GroupTimeBudget>,
+    pub seed_key: String,
+    pub retention: outputs::RetentionPolicy,
+    pub sandbox_config: config::SandboxConfig,
+}
+
 pub struct Worker {
+    // None once the worker is set up
+    tx_i2w_setup: Option<Sender<Setup>>,
+    // Set if the worker was spawned as a spare, see SparePool
+    spare_id: Option<String>,
     tx_i2w_command: Option<Arc<Mutex<Sender<submission::Command>>>>,
     tx_i2w_urgent: Option<Mutex<Sender<I2WUrgentCommand>>>,
     rx_w2i: Arc<Mutex<Receiver<W2IMessage>>>,
@@ -36,38 +67,50 @@ pub struct Worker {
 }
 
 impl Worker {
-    pub async fn new(
-        language: language::Language,
-        source_files: Vec<String>,
+    // The worker does nothing until it is set up. If warm_up is given, a rootfs of the package of
+    // the language is made in the meantime, with the given ID, see sandbox::prewarm
+    pub async fn spawn(
         core: u64,
-        instantiated_dependency_graph: problem::InstantiatedDependencyGraph,
-        program: Option<program::Program>,
-        strategy_factory: strategy::StrategyFactory,
-        invocation_limits: HashMap<String, verdict::InvocationLimit>,
+        sandbox_config: &config::SandboxConfig,
+        spare_id: Option<String>,
+        warm_up: Option<(language::Language, String)>,
     ) -> Result<Worker, errors::Error> {
+        let (tx_i2w_setup, rx_i2w_setup) =
+            channel().context_multiprocessing("Failed to create an IPC channel")?;
         let (tx_i2w_command, rx_i2w_command) =
-            channel().context_invoker("Failed to create an IPC channel")?;
+            channel().context_multiprocessing("Failed to create an IPC channel")?;
         let (tx_i2w_urgent, rx_i2w_urgent) =
-            channel().context_invoker("Failed to create an IPC channel")?;
-        let (tx_w2i, rx_w2i) = channel().context_invoker("Failed to create an IPC channel")?;
+            channel().context_multiprocessing("Failed to create an IPC channel")?;
+        let (mut tx_w2i, rx_w2i) =
+            channel().context_multiprocessing("Failed to create an IPC channel")?;
+        // Compile logs and test outputs are large and compress well. The setting travels to the
+        // worker along with the sender
+        tx_w2i.set_compression_threshold(Some(W2I_COMPRESSION_THRESHOLD));
+
+        // The worker gets its own connection to the mount supervisor, so that the rootfs it
+        // mounts is cleaned up even if it crashes
+        let supervisor =
+            supervisor::connect().context_invoker("Failed to connect to the mount supervisor")?;
 
         let child = subprocess_main
             .spawn_tokio(
+                rx_i2w_setup,
                 rx_i2w_command,
                 rx_i2w_urgent,
                 tx_w2i,
-                language,
-                source_files,
                 core,
-                instantiated_dependency_graph,
-                program,
-                strategy_factory,
-                invocation_limits,
+                sandbox_config.rootfs_backend,
+                sandbox_config.user_ids,
+                sandbox_config.limits.clone(),
+                warm_up,
+                supervisor,
             )
             .await
-            .context_invoker("Failed to spawn a worker subprocess")?;
+            .context_multiprocessing("Failed to spawn a worker subprocess")?;
 
         Ok(Worker {
+            tx_i2w_setup: Some(tx_i2w_setup),
+            spare_id,
             tx_i2w_command: Some(Arc::new(Mutex::new(tx_i2w_command))),
             tx_i2w_urgent: Some(Mutex::new(tx_i2w_urgent)),
             rx_w2i: Arc::new(Mutex::new(rx_w2i)),
@@ -75,6 +118,34 @@ impl Worker {
         })
     }
 
+    // Commands may be sent right after this, without waiting for the worker to apply the setup
+    pub async fn set_up(&mut self, setup: Setup) -> Result<(), errors::Error> {
+        let mut tx_i2w_setup = self
+            .tx_i2w_setup
+            .take()
+            .context_invoker("The worker is already set up")?;
+        tx_i2w_setup
+            .send(&setup)
+            .await
+            .context_multiprocessing("Failed to send setup to the worker")
+    }
+
+    pub fn spare_id(&self) -> Option<&str> {
+        self.spare_id.as_deref()
+    }
+
+    // Stops a worker that was never set up
+    pub async fn discard(mut self) -> Result<(), errors::Error> {
+        self.tx_i2w_setup = None;
+        self.child
+            .lock()
+            .await
+            .0
+            .join()
+            .await
+            .context_multiprocessing("Failed to join the discarded worker")?
+    }
+
     pub async fn execute_command(
         &self,
         command: submission::Command,
@@ -101,13 +172,13 @@ impl Worker {
                     .await
                     .send(&command)
                     .await
-                    .context_invoker("Failed to send command to the worker")?;
+                    .context_multiprocessing("Failed to send command to the worker")?;
 
                 for _ in 0..n_messages {
                     let msg = rx_w2i
                         .recv()
                         .await
-                        .context_invoker("Failed to receive response from the worker")?;
+                        .context_multiprocessing("Failed to receive response from the worker")?;
 
                     let msg = match msg {
                         Some(msg) => msg,
@@ -121,7 +192,7 @@ impl Worker {
                                         .0
                                         .join()
                                         .await
-                                        .context_invoker("Failed to join child")?
+                                        .context_multiprocessing("Failed to join child")?
                                         .err()
                                         .unwrap_or_else(|| {
                                             errors::InvokerFailure(
@@ -142,13 +213,19 @@ impl Worker {
                     };
 
                     if let Err(e) = tx.send(msg) {
-                        println!("Response to a command is ignored: {:?}", e);
+                        errors::log_throttled(
+                            "ignored response",
+                            format!("Response to a command is ignored: {e:?}"),
+                        );
                     }
                 }
             };
 
             if let Err(e) = res {
-                println!("Error while executing a worker command: {:?}", e);
+                errors::log_throttled(
+                    "worker command",
+                    format!("Error while executing a worker command: {e:?}"),
+                );
             }
         });
 
@@ -163,7 +240,42 @@ impl Worker {
             .await
             .send(&I2WUrgentCommand::AddFailedTests(tests))
             .await
-            .context_invoker("Failed to notify the worker subprocess about failed tests")
+            .context_multiprocessing("Failed to notify the worker subprocess about failed tests")
+    }
+
+    // Stops the command the worker is executing, if any. Tests that are yet to be run are answered
+    // with failures, so the worker can be finalized right after
+    pub async fn abort(&self) -> Result<(), errors::Error> {
+        self.tx_i2w_urgent
+            .as_ref()
+            .context_invoker("Cannot abort the worker after finalization")?
+            .lock()
+            .await
+            .send(&I2WUrgentCommand::Abort)
+            .await
+            .context_multiprocessing("Failed to notify the worker subprocess about abortion")
+    }
+
+    // Whether the worker subprocess has terminated before it was finalized. This is only noticed
+    // when a response to a command is awaited
+    pub async fn has_crashed(&self) -> bool {
+        self.child.lock().await.1.is_some()
+    }
+
+    // Unlike finalize, works even if the worker is wedged. Mounts made by the worker are removed by
+    // the mount supervisor once the worker is dead.
+    pub async fn kill(&self) -> Result<(), errors::Error> {
+        let mut child = self.child.lock().await;
+        child
+            .0
+            .kill()
+            .context_multiprocessing("Failed to kill the worker")?;
+        child
+            .0
+            .wait()
+            .await
+            .context_multiprocessing("Failed to wait for the killed worker")?;
+        Ok(())
     }
 
     pub async fn finalize(&mut self) -> Result<(), errors::Error> {
@@ -184,19 +296,223 @@ impl Worker {
     }
 }
 
+// Workers spawned ahead of time, one per core, so that the first command of a submission on a core
+// doesn't wait for the subprocess to start, nor for a rootfs of the most common language if one is
+// configured. A spare that is taken is replaced in the background
+pub struct SparePool {
+    spares: std::sync::Mutex<HashMap<u64, Worker>>,
+    sandbox_config: config::SandboxConfig,
+    language: std::sync::Mutex<Option<language::Language>>,
+    n_spawned: std::sync::atomic::AtomicU64,
+}
+
+impl SparePool {
+    pub fn new(
+        sandbox_config: config::SandboxConfig,
+        language: Option<language::Language>,
+    ) -> SparePool {
+        SparePool {
+            spares: std::sync::Mutex::new(HashMap::new()),
+            sandbox_config,
+            language: std::sync::Mutex::new(language),
+            n_spawned: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    pub async fn fill(&self, core: u64) -> Result<(), errors::Error> {
+        if self.lock().contains_key(&core) {
+            return Ok(());
+        }
+
+        // Spares are not named after a submission, so the garbage collector is told about them
+        // separately, see live_ids
+        let id = format!(
+            "spare-{core}-{}",
+            self.n_spawned
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        );
+        let language = self
+            .language
+            .lock()
+            .expect("Failed to acquire the language of spare workers")
+            .clone();
+        let warm_up = language.map(|language| (language, format!("run-{id}")));
+        let worker = Worker::spawn(core, &self.sandbox_config, Some(id), warm_up).await?;
+
+        // Another spare may have been spawned for the core in the meantime
+        let redundant = {
+            let mut spares = self.lock();
+            if spares.contains_key(&core) {
+                Some(worker)
+            } else {
+                spares.insert(core, worker);
+                None
+            }
+        };
+        if let Some(worker) = redundant {
+            worker.discard().await?;
+        }
+        Ok(())
+    }
+
+    pub fn take(self: &Arc<Self>, core: u64) -> Option<Worker> {
+        let spare = self.lock().remove(&core)?;
+        let pool = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pool.fill(core).await {
+                errors::log_throttled(
+                    "spawn spare worker",
+                    format!("Failed to spawn a spare worker on core {core}: {e:?}"),
+                );
+            }
+        });
+        Some(spare)
+    }
+
+    pub fn live_ids(&self) -> Vec<String> {
+        self.lock()
+            .values()
+            .filter_map(|spare| spare.spare_id().map(|id| id.to_string()))
+            .collect()
+    }
+
+    // E.g. when the image is reloaded. The spares hold on to the old image, so they are replaced
+    pub async fn set_language(&self, language: Option<language::Language>) {
+        *self
+            .language
+            .lock()
+            .expect("Failed to acquire the language of spare workers") = language;
+        let spares: Vec<(u64, Worker)> = self.lock().drain().collect();
+        for (core, spare) in spares {
+            if let Err(e) = spare.discard().await {
+                errors::log_throttled(
+                    "discard spare worker",
+                    format!("Failed to discard a spare worker on core {core}: {e:?}"),
+                );
+            }
+            if let Err(e) = self.fill(core).await {
+                errors::log_throttled(
+                    "spawn spare worker",
+                    format!("Failed to spawn a spare worker on core {core}: {e:?}"),
+                );
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<HashMap<u64, Worker>> {
+        self.spares
+            .lock()
+            .expect("Failed to acquire the spare worker pool")
+    }
+}
+
 struct Subprocess {
+    worker_id: String,
     current_test: Mutex<Option<(u64, AbortHandle)>>,
+    aborted: std::sync::atomic::AtomicBool,
+    activity: std::sync::Mutex<Activity>,
     language: language::Language,
+    source_root: String,
     source_files: Vec<String>,
+    output_only: bool,
     instantiated_dependency_graph: RwLock<problem::InstantiatedDependencyGraph>,
     core: u64,
+    sandbox_config: config::SandboxConfig,
+}
+
+// Which cgroups of the core the worker has processes in, see kill_sandboxed_processes. With
+// oversubscription, another submission may be judged on the core while this one compiles
+#[derive(Clone, Copy)]
+enum Activity {
+    Idle,
+    Compiling,
+    Judging,
 }
 
 struct SubprocessMain {
     tx_w2i: Sender<W2IMessage>,
     strategy_factory: strategy::StrategyFactory,
     strategy: Option<strategy::Strategy>,
+    // Kept to build strategies for other revisions of the problem
+    program: Option<program::Program>,
     invocation_limits: Option<HashMap<String, verdict::InvocationLimit>>,
+    time_budgets: TimeBudgets,
+    seed_key: String,
+    retention: outputs::RetentionPolicy,
+    // Results of tests that were run before the invoker asked for them, along with the CPU time
+    // budget they were run with
+    speculative_results: HashMap<u64, (Option<std::time::Duration>, verdict::TestJudgementResult)>,
+}
+
+// Remaining CPU time of each group with a shared time budget. As workers are per-core, so is the
+// accounting.
+struct TimeBudgets {
+    group_of_test: HashMap<u64, usize>,
+    remaining: Vec<std::time::Duration>,
+}
+
+impl TimeBudgets {
+    fn new(group_time_budgets: Vec<verdict::GroupTimeBudget>) -> Self {
+        let mut group_of_test = HashMap::new();
+        let mut remaining = Vec::with_capacity(group_time_budgets.len());
+        for (i, budget) in group_time_budgets.into_iter().enumerate() {
+            for test in budget.tests {
+                group_of_test.insert(test, i);
+            }
+            remaining.push(budget.cpu_time);
+        }
+        TimeBudgets {
+            group_of_test,
+            remaining,
+        }
+    }
+
+    fn remaining_for(&self, test: u64) -> Option<std::time::Duration> {
+        self.group_of_test
+            .get(&test)
+            .map(|group| self.remaining[*group])
+    }
+
+    // Returns false if the budget was exceeded
+    fn consume(&mut self, test: u64, cpu_time: std::time::Duration) -> bool {
+        match self.group_of_test.get(&test) {
+            Some(group) => {
+                let remaining = &mut self.remaining[*group];
+                let fits = cpu_time <= *remaining;
+                *remaining = remaining.saturating_sub(cpu_time);
+                fits
+            }
+            None => true,
+        }
+    }
+}
+
+// Seeds must not change between rejudges or invoker versions, so std hashers are not an option.
+// This is 64-bit FNV-1a.
+fn test_seed(seed_key: &str, test: u64) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in seed_key.bytes().chain(test.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// None if the test does not exist in the revision the strategy belongs to
+async fn judge_in_revision(
+    strategy: &mut strategy::Strategy,
+    root: &std::path::Path,
+    test: u64,
+    seed: u64,
+) -> Option<verdict::TestVerdict> {
+    let test_path = root.join("tests").join(test.to_string());
+    if !test_path.exists() {
+        return None;
+    }
+    Some(match strategy.invoke(test_path, None, seed).await {
+        Ok(result) => result.verdict,
+        Err(e) => submission::failed_evaluation(e).verdict,
+    })
 }
 
 // multithreading does not interact with sandboxing well. For one thing, unshare only seems to apply
@@ -207,20 +523,65 @@ struct SubprocessMain {
 #[multiprocessing::entrypoint]
 #[tokio::main(flavor = "current_thread")]
 pub async fn subprocess_main(
+    mut rx_i2w_setup: Receiver<Setup>,
     mut rx_i2w_command: Receiver<submission::Command>,
     mut rx_i2w_urgent: Receiver<I2WUrgentCommand>,
     tx_w2i: Sender<W2IMessage>,
-    language: language::Language,
-    source_files: Vec<String>,
     core: u64,
-    instantiated_dependency_graph: problem::InstantiatedDependencyGraph,
-    program: Option<program::Program>,
-    strategy_factory: strategy::StrategyFactory,
-    invocation_limits: HashMap<String, verdict::InvocationLimit>,
+    rootfs_backend: config::RootfsBackend,
+    user_ids: config::UserIdRange,
+    limits: config::LimitsConfig,
+    warm_up: Option<(language::Language, String)>,
+    supervisor: supervisor::Connection,
 ) -> Result<(), errors::Error> {
+    supervisor::attach(supervisor);
+    sandbox::set_backend(rootfs_backend);
+    ids::set_external_user_ids(user_ids);
+
     let mut tx_w2i = {
         sandbox::enter_worker_space(core).context_invoker("Failed to enter worker space")?;
 
+        // A failed warm-up only costs time
+        if let Some((language, id)) = warm_up {
+            let quotas = sandbox::DiskQuotas {
+                space: limits.run_space,
+                max_inodes: limits.run_inodes,
+            };
+            if let Err(e) = sandbox::prewarm(language.package(), quotas, id).await {
+                errors::log_throttled(
+                    "prewarm worker",
+                    format!("Failed to prewarm a worker on core {core}: {e:?}"),
+                );
+            }
+        }
+
+        let Setup {
+            worker_id,
+            language,
+            source_root,
+            source_files,
+            output_only,
+            instantiated_dependency_graph,
+            program,
+            strategy_factory,
+            invocation_limits,
+            group_time_budgets,
+            seed_key,
+            retention,
+            sandbox_config,
+        } = match rx_i2w_setup
+            .recv()
+            .await
+            .context_multiprocessing("Failed to receive setup from invoker")?
+        {
+            Some(setup) => setup,
+            // A spare that is no longer needed
+            None => {
+                sandbox::drain_pool().context_invoker("Failed to remove pooled rootfs's")?;
+                return Ok(());
+            }
+        };
+
         let mut invocation_limits = Some(invocation_limits);
 
         let strategy = match program {
@@ -230,7 +591,8 @@ pub async fn subprocess_main(
                         program,
                         invocation_limits.take().unwrap(),
                         core,
-                        format!("run-{core}"),
+                        format!("run-{worker_id}"),
+                        &sandbox_config,
                     )
                     .await?,
             ),
@@ -238,11 +600,17 @@ pub async fn subprocess_main(
         };
 
         let subprocess = Arc::new(Subprocess {
+            worker_id,
             current_test: Mutex::new(None),
+            aborted: std::sync::atomic::AtomicBool::new(false),
+            activity: std::sync::Mutex::new(Activity::Idle),
             language,
+            source_root,
             source_files,
+            output_only,
             instantiated_dependency_graph: RwLock::new(instantiated_dependency_graph),
             core,
+            sandbox_config,
         });
 
         let proc = subprocess.clone();
@@ -251,15 +619,92 @@ pub async fn subprocess_main(
                 tx_w2i,
                 strategy_factory,
                 strategy,
+                program,
                 invocation_limits,
+                time_budgets: TimeBudgets::new(group_time_budgets),
+                seed_key,
+                retention,
+                speculative_results: HashMap::new(),
             };
 
-            while let Some(command) = rx_i2w_command
-                .recv()
-                .await
-                .context_invoker("Failed to receive command from invoker")?
-            {
-                proc.handle_core_command(command.clone(), &mut main).await?;
+            // Receiving from an IPC channel is not cancel-safe, so commands are forwarded to a
+            // local queue, which can be polled while a speculative test is running
+            let (tx_commands, mut rx_commands) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                loop {
+                    let command = rx_i2w_command
+                        .recv()
+                        .await
+                        .context_multiprocessing("Failed to receive command from invoker")
+                        .transpose();
+                    let stop = !matches!(command, Some(Ok(_)));
+                    if let Some(command) = command {
+                        if tx_commands.send(command).is_err() {
+                            break;
+                        }
+                    }
+                    if stop {
+                        break;
+                    }
+                }
+            });
+
+            let mut speculative_tests = std::collections::VecDeque::new();
+
+            loop {
+                let command = match speculative_tests.pop_front() {
+                    Some(test) => {
+                        proc.set_activity(Activity::Judging);
+                        let (cancel, reg) = AbortHandle::new_pair();
+                        let speculation = proc.speculate(test, (cancel.clone(), reg), &mut main);
+                        tokio::pin!(speculation);
+                        let command = tokio::select! {
+                            biased;
+                            res = &mut speculation => {
+                                res?;
+                                continue;
+                            }
+                            command = rx_commands.recv() => command,
+                        };
+                        // Finish the speculation if it turned out to be right; otherwise, the
+                        // core is needed for something else. The speculation is never dropped
+                        // halfway, as current_test has to be reset and the processes killed,
+                        // just like on an urgent abort.
+                        let is_wanted = match command {
+                            Some(Ok(submission::Command::Test(ref tests, _))) => {
+                                tests.contains(&test)
+                            }
+                            _ => false,
+                        };
+                        if !is_wanted {
+                            cancel.abort();
+                            proc.kill_sandboxed_processes()?;
+                        }
+                        speculation.await?;
+                        command
+                    }
+                    None => {
+                        proc.set_activity(Activity::Idle);
+                        rx_commands.recv().await
+                    }
+                };
+
+                let command = match command {
+                    Some(command) => command?,
+                    None => break,
+                };
+
+                speculative_tests.clear();
+                if let submission::Command::Test(_, ref speculative) = command {
+                    speculative_tests.extend(speculative.iter().copied());
+                }
+
+                proc.set_activity(match command {
+                    submission::Command::Compile(_) => Activity::Compiling,
+                    submission::Command::Finalize => Activity::Idle,
+                    _ => Activity::Judging,
+                });
+                proc.handle_core_command(command, &mut main).await?;
             }
 
             Ok(main.tx_w2i)
@@ -269,7 +714,7 @@ pub async fn subprocess_main(
             while let Some(command) = rx_i2w_urgent
                 .recv()
                 .await
-                .context_invoker("Failed to receive urgent command from invoker")?
+                .context_multiprocessing("Failed to receive urgent command from invoker")?
             {
                 subprocess.handle_urgent_command(command).await?;
             }
@@ -287,10 +732,12 @@ pub async fn subprocess_main(
         tx_w2i
     };
 
+    sandbox::drain_pool().context_invoker("Failed to remove pooled rootfs's")?;
+
     tx_w2i
         .send(&W2IMessage::Finalized)
         .await
-        .context_invoker("Failed to send finalization notification to invoker")?;
+        .context_multiprocessing("Failed to send finalization notification to invoker")?;
 
     Ok(())
 }
@@ -304,83 +751,160 @@ impl Subprocess {
         match command {
             submission::Command::Compile(build_id) => {
                 let res: Result<W2IMessage, errors::Error> = try {
-                    let (program, log) = self
-                        .language
-                        .build(
+                    let (program, log) = if self.output_only {
+                        self.language.collect_answers(
                             self.source_files.iter().map(|s| s.as_ref()).collect(),
                             build_id,
-                        )
-                        .await?;
+                        )?
+                    } else {
+                        sandbox::enter_compilation_space(self.core)?;
+                        let build_result = self
+                            .language
+                            .build(
+                                self.source_files.iter().map(|s| s.as_ref()).collect(),
+                                std::path::Path::new(&self.source_root),
+                                build_id,
+                                self.core,
+                                &self.sandbox_config,
+                            )
+                            .await;
+                        sandbox::enter_worker_space(self.core)?;
+                        build_result?
+                    };
                     main.strategy = Some(
                         main.strategy_factory
                             .make(
                                 &program,
                                 main.invocation_limits.take().unwrap(),
                                 self.core,
-                                format!("build-{}", self.core),
+                                format!("build-{}", self.worker_id),
+                                &self.sandbox_config,
                             )
                             .await?,
                     );
-                    W2IMessage::CompilationResult(program, log)
+                    main.program = Some(program.clone());
+                    W2IMessage::CompilationResult(
+                        program,
+                        outputs::cap_text_log(
+                            log,
+                            self.sandbox_config.log_limits.compilation_log_size,
+                        ),
+                    )
                 };
-                let res = res.unwrap_or_else(|e| W2IMessage::Failure(e));
+                // Compilation errors carry the log too
+                let res = res.unwrap_or_else(|e| match e {
+                    errors::UserFailure(log) => {
+                        W2IMessage::Failure(errors::UserFailure(outputs::cap_text_log(
+                            log,
+                            self.sandbox_config.log_limits.compilation_log_size,
+                        )))
+                    }
+                    e => W2IMessage::Failure(e),
+                });
                 main.tx_w2i
                     .send(&res)
                     .await
-                    .context_invoker("Failed to send command result to invoker")
+                    .context_multiprocessing("Failed to send command result to invoker")
             }
 
-            submission::Command::Test(tests) => {
-                let strategy = main.strategy.as_mut().context_invoker(
+            submission::Command::Test(tests, _) => {
+                main.strategy.as_ref().context_invoker(
                     "Attempted to judge a program on a core before the core acquired a reference \
                      to the built program",
                 )?;
 
                 for test in tests {
-                    if !self
-                        .instantiated_dependency_graph
-                        .read()
-                        .await
-                        .is_test_enabled(test)
-                    {
+                    if self.is_aborted() {
+                        main.speculative_results.remove(&test);
                         main.tx_w2i
-                            .send(&W2IMessage::TestResult(verdict::TestJudgementResult {
-                                verdict: verdict::TestVerdict::Ignored,
-                                logs: HashMap::new(),
-                                invocation_stats: HashMap::new(),
-                            }))
+                            .send(&W2IMessage::Failure(errors::ConductorFailure(
+                                "The submission has been aborted".to_string(),
+                            )))
                             .await
                             .context_invoker("Failed to send command result to invoker")?;
                         continue;
                     }
 
-                    let (handle, reg) = AbortHandle::new_pair();
-                    *self.current_test.lock().await = Some((test, handle));
-
-                    let result = Abortable::new(
-                        async {
-                            match strategy
-                                .invoke(
-                                    main.strategy_factory
-                                        .root
-                                        .join("tests")
-                                        .join(test.to_string()),
-                                )
-                                .await
-                            {
-                                Ok(result) => W2IMessage::TestResult(result),
-                                Err(e) => W2IMessage::Failure(e),
-                            }
-                        },
-                        reg,
-                    )
-                    .await;
+                    let graph = self.instantiated_dependency_graph.read().await;
+                    if !graph.is_test_enabled(test) {
+                        let verdict = graph.skipped_verdict(test);
+                        drop(graph);
+                        main.speculative_results.remove(&test);
+                        main.tx_w2i
+                            .send(&W2IMessage::TestResult(
+                                verdict::TestJudgementResult {
+                                    verdict,
+                                    logs: HashMap::new(),
+                                    invocation_stats: HashMap::new(),
+                                    seed: None,
+                                    fingerprint: None,
+                                    retries: 0,
+                                    failed_block: None,
+                                    test_name: None,
+                                },
+                                Vec::new(),
+                            ))
+                            .await
+                            .context_invoker("Failed to send command result to invoker")?;
+                        continue;
+                    }
+
+                    drop(graph);
 
-                    *self.current_test.lock().await = None;
+                    let cpu_time_budget = main.time_budgets.remaining_for(test);
+
+                    // The group has already used up its time budget on the previous tests, so
+                    // there is no point in running the solution
+                    if cpu_time_budget == Some(std::time::Duration::ZERO) {
+                        self.instantiated_dependency_graph
+                            .write()
+                            .await
+                            .fail_test(test);
+                        main.tx_w2i
+                            .send(&W2IMessage::TestResult(
+                                verdict::TestJudgementResult {
+                                    verdict: verdict::TestVerdict::TimeLimitExceeded,
+                                    logs: HashMap::new(),
+                                    invocation_stats: HashMap::new(),
+                                    seed: None,
+                                    fingerprint: None,
+                                    retries: 0,
+                                    failed_block: None,
+                                    test_name: None,
+                                },
+                                Vec::new(),
+                            ))
+                            .await
+                            .context_invoker("Failed to send command result to invoker")?;
+                        continue;
+                    }
+
+                    // A speculative result can only be reused if the test would have been run with
+                    // the same limits now
+                    let mut result = match main.speculative_results.remove(&test) {
+                        Some((budget, result)) if budget == cpu_time_budget => {
+                            Ok(W2IMessage::TestResult(result, Vec::new()))
+                        }
+                        _ => {
+                            self.run_test(test, cpu_time_budget, AbortHandle::new_pair(), main)
+                                .await?
+                        }
+                    };
+
+                    let strategy = main.strategy.as_ref().unwrap();
+                    if let Ok(W2IMessage::TestResult(ref mut result, _)) = result {
+                        if !main
+                            .time_budgets
+                            .consume(test, strategy.user_cpu_time(result))
+                            && result.verdict.is_successful()
+                        {
+                            result.verdict = verdict::TestVerdict::TimeLimitExceeded;
+                        }
+                    }
 
                     // As the dependency graph may have cycles, fail_test has to be called after
                     // result is evaluated and the abortable finishes.
-                    if let Ok(W2IMessage::TestResult(ref result)) = result {
+                    if let Ok(W2IMessage::TestResult(ref result, _)) = result {
                         if !result.verdict.is_successful() {
                             self.instantiated_dependency_graph
                                 .write()
@@ -389,31 +913,214 @@ impl Subprocess {
                         }
                     }
 
-                    let message = result.unwrap_or_else(|_| {
-                        W2IMessage::TestResult(verdict::TestJudgementResult {
-                            verdict: verdict::TestVerdict::Ignored,
-                            logs: HashMap::new(),
-                            invocation_stats: HashMap::new(),
-                        })
+                    // The run is only aborted when the test is disabled while it's running
+                    let skipped_verdict = self
+                        .instantiated_dependency_graph
+                        .read()
+                        .await
+                        .skipped_verdict(test);
+                    let mut message = result.unwrap_or_else(|_| {
+                        W2IMessage::TestResult(
+                            verdict::TestJudgementResult {
+                                verdict: skipped_verdict,
+                                logs: HashMap::new(),
+                                invocation_stats: HashMap::new(),
+                                seed: None,
+                                fingerprint: None,
+                                retries: 0,
+                                failed_block: None,
+                                test_name: None,
+                            },
+                            Vec::new(),
+                        )
                     });
 
+                    // The verdict is final at this point, so it's time to decide what to keep
+                    if let W2IMessage::TestResult(ref mut result, ref mut retained) = message {
+                        self.cap_logs(result);
+                        *retained = match main.retention.for_verdict(&result.verdict) {
+                            outputs::Retention::Nothing => Vec::new(),
+                            outputs::Retention::Outputs => strategy
+                                .user_output_files()
+                                .into_iter()
+                                .filter(|name| result.logs.contains_key(name))
+                                .collect(),
+                            outputs::Retention::Everything => result.logs.keys().cloned().collect(),
+                        };
+                    }
+
                     main.tx_w2i
                         .send(&message)
                         .await
-                        .context_invoker("Failed to send command result to invoker")?;
+                        .context_multiprocessing("Failed to send command result to invoker")?;
                 }
 
                 Ok(())
             }
 
+            submission::Command::CompareRevision(strategy_factory, invocation_limits, tests) => {
+                let res: Result<W2IMessage, errors::Error> = try {
+                    let program = main.program.as_ref().context_invoker(
+                        "Attempted to compare revisions on a core before the core acquired a \
+                         reference to the built program",
+                    )?;
+                    // The candidate strategy is dropped at the end of the comparison, which removes
+                    // its rootfs
+                    let mut candidate = strategy_factory
+                        .make(
+                            program,
+                            invocation_limits,
+                            self.core,
+                            format!("candidate-{}", self.worker_id),
+                            &self.sandbox_config,
+                        )
+                        .await?;
+                    let baseline = main.strategy.as_mut().context_invoker(
+                        "Attempted to compare revisions on a core before the core instantiated a \
+                         strategy",
+                    )?;
+
+                    let mut comparisons = Vec::with_capacity(tests.len());
+                    for test in tests {
+                        let seed = test_seed(&main.seed_key, test);
+                        comparisons.push(verdict::TestComparison {
+                            test,
+                            baseline: judge_in_revision(
+                                baseline,
+                                &main.strategy_factory.root,
+                                test,
+                                seed,
+                            )
+                            .await,
+                            candidate: judge_in_revision(
+                                &mut candidate,
+                                &strategy_factory.root,
+                                test,
+                                seed,
+                            )
+                            .await,
+                        });
+                    }
+                    W2IMessage::RevisionComparison(comparisons)
+                };
+                let res = res.unwrap_or_else(W2IMessage::Failure);
+                main.tx_w2i
+                    .send(&res)
+                    .await
+                    .context_multiprocessing("Failed to send command result to invoker")
+            }
+
+            submission::Command::Rejudge(tests, seed_key) => {
+                let res: Result<W2IMessage, errors::Error> = try {
+                    let strategy = main.strategy.as_mut().context_invoker(
+                        "Attempted to rejudge tests on a core before the core acquired a reference \
+                         to the built program",
+                    )?;
+                    let mut results = Vec::with_capacity(tests.len());
+                    for test in tests {
+                        let test_path = main
+                            .strategy_factory
+                            .root
+                            .join("tests")
+                            .join(test.to_string());
+                        let mut result = strategy
+                            .invoke(test_path, None, test_seed(&seed_key, test))
+                            .await
+                            .unwrap_or_else(submission::failed_evaluation);
+                        self.cap_logs(&mut result);
+                        results.push((test, result));
+                    }
+                    W2IMessage::RejudgeResults(results)
+                };
+                let res = res.unwrap_or_else(W2IMessage::Failure);
+                main.tx_w2i
+                    .send(&res)
+                    .await
+                    .context_multiprocessing("Failed to send command result to invoker")
+            }
+
             submission::Command::Finalize => main
                 .tx_w2i
                 .send(&W2IMessage::Finalized)
                 .await
-                .context_invoker("Failed to send command result to invoker"),
+                .context_multiprocessing("Failed to send command result to invoker"),
         }
     }
 
+    // Runs a single test, which can be aborted via current_test or the passed handle
+    async fn run_test(
+        &self,
+        test: u64,
+        cpu_time_budget: Option<std::time::Duration>,
+        (handle, reg): (AbortHandle, AbortRegistration),
+        main: &mut SubprocessMain,
+    ) -> Result<Result<W2IMessage, Aborted>, errors::Error> {
+        // Callers check that the program is built
+        let strategy = main.strategy.as_mut().unwrap();
+
+        *self.current_test.lock().await = Some((test, handle));
+
+        let result = Abortable::new(
+            async {
+                match strategy
+                    .invoke(
+                        main.strategy_factory
+                            .root
+                            .join("tests")
+                            .join(test.to_string()),
+                        cpu_time_budget,
+                        test_seed(&main.seed_key, test),
+                    )
+                    .await
+                {
+                    Ok(result) => W2IMessage::TestResult(result, Vec::new()),
+                    Err(e) => W2IMessage::Failure(e),
+                }
+            },
+            reg,
+        )
+        .await;
+
+        *self.current_test.lock().await = None;
+
+        Ok(result)
+    }
+
+    // Runs a test the invoker is likely to ask for next and stores the result until it does. Budgets
+    // and the dependency graph are only updated when the result is claimed.
+    async fn speculate(
+        &self,
+        test: u64,
+        abort: (AbortHandle, AbortRegistration),
+        main: &mut SubprocessMain,
+    ) -> Result<(), errors::Error> {
+        if self.is_aborted()
+            || main.speculative_results.contains_key(&test)
+            || !self
+                .instantiated_dependency_graph
+                .read()
+                .await
+                .is_test_enabled(test)
+        {
+            return Ok(());
+        }
+
+        let cpu_time_budget = main.time_budgets.remaining_for(test);
+        if cpu_time_budget == Some(std::time::Duration::ZERO) {
+            return Ok(());
+        }
+
+        // Neither aborted runs nor failures are worth keeping: the test will be rerun if requested
+        if let Ok(W2IMessage::TestResult(result, _)) =
+            self.run_test(test, cpu_time_budget, abort, main).await?
+        {
+            main.speculative_results
+                .insert(test, (cpu_time_budget, result));
+        }
+
+        Ok(())
+    }
+
     async fn handle_urgent_command(&self, command: I2WUrgentCommand) -> Result<(), errors::Error> {
         match command {
             I2WUrgentCommand::AddFailedTests(tests) => {
@@ -428,8 +1135,69 @@ impl Subprocess {
                     }
                 }
             }
+
+            I2WUrgentCommand::Abort => {
+                self.aborted
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                if let Some((_, ref handle)) = *self.current_test.lock().await {
+                    handle.abort();
+                }
+                self.kill_sandboxed_processes()?;
+            }
         }
 
         Ok(())
     }
+
+    fn cap_logs(&self, result: &mut verdict::TestJudgementResult) {
+        for log in result.logs.values_mut() {
+            outputs::cap_log(log, self.sandbox_config.log_limits.test_log_size);
+        }
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn set_activity(&self, activity: Activity) {
+        *self
+            .activity
+            .lock()
+            .expect("Failed to acquire the activity of the worker") = activity;
+    }
+
+    // Aborting a test only drops the future that waits for it, and a build cannot be aborted at
+    // all, so the processes they started are killed via the cgroups of the core. Only the cgroups
+    // the worker is using are touched. The worker itself is in the compilation cgroup while it
+    // builds the program, hence it is spared. Runtimes are killed as a whole and are restarted by
+    // the strategy before the next test
+    fn kill_sandboxed_processes(&self) -> Result<(), errors::Error> {
+        let dir = format!("/sys/fs/cgroup/sunwalker_root/cpu_{}", self.core);
+        let spare = std::process::id() as libc::pid_t;
+        let activity = *self
+            .activity
+            .lock()
+            .expect("Failed to acquire the activity of the worker");
+        match activity {
+            Activity::Idle => {}
+            Activity::Compiling => {
+                cgroups::kill_processes(&format!("{dir}/compilation"), spare)?;
+            }
+            Activity::Judging => {
+                for entry in std::fs::read_dir(&dir)
+                    .with_context_invoker(|| format!("Failed to list {dir}"))?
+                {
+                    let entry = entry.with_context_invoker(|| format!("Failed to list {dir}"))?;
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    let is_own_runtime = name.contains("-runtime-")
+                        && format!("-{name}-").contains(&format!("-{}-", self.worker_id));
+                    if name.starts_with("block-") || is_own_runtime {
+                        cgroups::kill_processes(&format!("{dir}/{name}"), spare)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 }